@@ -0,0 +1,83 @@
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+// Built into the kernel as /bin/<name>: the programs in ../user/programs that
+// `make user` built, static Linux programs put in ../user/linux and
+// WebAssembly modules put in ../user/wasm. Those that weren't built are left
+// out with a warning. The files in ../user/etc are built in as /etc/<name>.
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let user = Path::new(&manifest_dir).join("../user");
+    let programs = user.join("programs");
+    let binaries = user.join("target/x86_64-unknown-rustyos-user/release");
+    println!("cargo:rerun-if-changed={}", programs.display());
+
+    let mut names: Vec<String> = match fs::read_dir(&programs) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+
+    let mut table = String::from("static BUILTIN_PROGRAMS: &[(&str, &[u8])] = &[\n");
+    for name in names {
+        let binary = binaries.join(&name);
+        println!("cargo:rerun-if-changed={}", binary.display());
+        match binary.canonicalize() {
+            Ok(path) => writeln!(table, "    ({:?}, include_bytes!({:?})),", name, path).unwrap(),
+            Err(_) => println!(
+                "cargo:warning=user program {} isn't built, run `make user`",
+                name
+            ),
+        }
+    }
+
+    // Static Linux programs and WebAssembly modules are added as they are,
+    // there's nothing to build
+    prebuilt(&mut table, &user.join("linux"), b"\x7fELF");
+    prebuilt(&mut table, &user.join("wasm"), b"\0asm");
+    table.push_str("];\n");
+
+    table.push_str("static BUILTIN_FILES: &[(&str, &[u8])] = &[\n");
+    prebuilt(&mut table, &user.join("etc"), b"");
+    table.push_str("];\n");
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("builtin_programs.rs"), table).unwrap();
+}
+
+// Add the files in `dir` that start with `magic` to `table`, by file name
+fn prebuilt(table: &mut String, dir: &Path, magic: &[u8]) {
+    println!("cargo:rerun-if-changed={}", dir.display());
+    let mut files: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| starts_with(path, magic))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    files.sort();
+    for file in files {
+        println!("cargo:rerun-if-changed={}", file.display());
+        let name = file.file_name().unwrap().to_string_lossy();
+        let path = file.canonicalize().unwrap();
+        writeln!(table, "    ({:?}, include_bytes!({:?})),", name, path).unwrap();
+    }
+}
+
+// Whether `path` is a file starting with `magic`
+fn starts_with(path: &Path, magic: &[u8]) -> bool {
+    let mut bytes = vec![0u8; magic.len()];
+    path.is_file()
+        && fs::File::open(path)
+            .and_then(|mut file| file.read_exact(&mut bytes))
+            .is_ok()
+        && bytes == magic
+}
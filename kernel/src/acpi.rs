@@ -0,0 +1,284 @@
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use spin::Once;
+
+#[cfg(test)]
+use crate::{print, println};
+
+// Advanced Configuration and Power Interface (ACPI) Specification, version 6.4
+// 5.2.5 Root System Description Pointer (RSDP)
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+// 5.2.6 System Description Table Header
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+// 5.2.3.2 Generic Address Structure (GAS)
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub address_space_id: u8,
+    pub register_bit_width: u8,
+    pub register_bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+// IA-PC HPET (High Precision Event Timers) Specification 1.0a
+// 3.2.4 The ACPI 2.0 HPET Description Table (HPET)
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed)]
+pub struct Hpet {
+    pub header: SdtHeader,
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+// 5.2.12 Multiple APIC Description Table (MADT)
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed)]
+pub struct Madt {
+    pub header: SdtHeader,
+    pub local_apic_address: u32,
+    pub flags: u32,
+}
+
+// 5.2.12.2 Processor Local APIC Structure, Flags, also used by 5.2.12.12
+// Processor Local x2APIC Structure
+pub const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+#[derive(Debug, Copy, Clone)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_uid: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        address: u32,
+        gsi_base: u32,
+    },
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    LocalX2Apic {
+        x2apic_id: u32,
+        flags: u32,
+        processor_uid: u32,
+    },
+    Other(u8),
+}
+
+impl Madt {
+    pub fn entries(&self) -> MadtIter {
+        let start = self as *const Madt as usize;
+        MadtIter {
+            current: start + size_of::<Madt>(),
+            end: start + self.header.length as usize,
+        }
+    }
+
+    // APIC IDs of the enabled processors. Those that don't fit in 8 bits
+    // only have a local x2APIC entry, and firmware may list the others in
+    // both forms, which are reported once.
+    pub fn processors(&self) -> impl Iterator<Item = u32> + '_ {
+        let has_local_apic = move |id: u32| {
+            self.entries().any(|entry| {
+                matches!(entry, MadtEntry::LocalApic { apic_id, flags, .. }
+                    if apic_id as u32 == id && flags & LOCAL_APIC_ENABLED != 0)
+            })
+        };
+        self.entries().filter_map(move |entry| match entry {
+            MadtEntry::LocalApic { apic_id, flags, .. } if flags & LOCAL_APIC_ENABLED != 0 => {
+                Some(apic_id as u32)
+            }
+            MadtEntry::LocalX2Apic {
+                x2apic_id, flags, ..
+            } if flags & LOCAL_APIC_ENABLED != 0 && !has_local_apic(x2apic_id) => Some(x2apic_id),
+            _ => None,
+        })
+    }
+}
+
+pub struct MadtIter {
+    current: usize,
+    end: usize,
+}
+
+impl Iterator for MadtIter {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        if self.current + 2 > self.end {
+            return None;
+        }
+
+        let p = self.current as *const u8;
+        let (entry_type, length) = unsafe { (*p, *p.add(1)) };
+        if length < 2 {
+            return None;
+        }
+        self.current += length as usize;
+
+        unsafe {
+            let entry = match entry_type {
+                0 => MadtEntry::LocalApic {
+                    processor_uid: *p.add(2),
+                    apic_id: *p.add(3),
+                    flags: read_unaligned(p.add(4) as *const u32),
+                },
+                1 => MadtEntry::IoApic {
+                    id: *p.add(2),
+                    address: read_unaligned(p.add(4) as *const u32),
+                    gsi_base: read_unaligned(p.add(8) as *const u32),
+                },
+                2 => MadtEntry::InterruptSourceOverride {
+                    bus: *p.add(2),
+                    source: *p.add(3),
+                    gsi: read_unaligned(p.add(4) as *const u32),
+                    flags: read_unaligned(p.add(8) as *const u16),
+                },
+                9 => MadtEntry::LocalX2Apic {
+                    x2apic_id: read_unaligned(p.add(4) as *const u32),
+                    flags: read_unaligned(p.add(8) as *const u32),
+                    processor_uid: read_unaligned(p.add(12) as *const u32),
+                },
+                other => MadtEntry::Other(other),
+            };
+            Some(entry)
+        }
+    }
+}
+
+// Root table and the width of its entries (RSDT: 4 bytes, XSDT: 8 bytes)
+struct RootTable {
+    header: *const SdtHeader,
+    entry_size: usize,
+}
+
+unsafe impl Send for RootTable {}
+unsafe impl Sync for RootTable {}
+
+static ROOT: Once<RootTable> = Once::new();
+
+pub fn initialize(rsdp: u64) {
+    let rsdp = unsafe { &*(rsdp as *const Rsdp) };
+
+    if &rsdp.signature != b"RSD PTR " || !checksum_ok(rsdp as *const _ as *const u8, 20) {
+        crate::println!("ACPI: invalid RSDP");
+        return;
+    }
+
+    // ACPI 2.0+ provides 64-bit XSDT, older firmware only the RSDT
+    let root = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        RootTable {
+            header: rsdp.xsdt_address as *const SdtHeader,
+            entry_size: size_of::<u64>(),
+        }
+    } else {
+        RootTable {
+            header: rsdp.rsdt_address as u64 as *const SdtHeader,
+            entry_size: size_of::<u32>(),
+        }
+    };
+
+    ROOT.call_once(|| root);
+}
+
+fn checksum_ok(ptr: *const u8, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, len) };
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+// Find a system description table by its signature
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    let root = ROOT.get()?;
+    let header = unsafe { read_unaligned(root.header) };
+    let entries = (header.length as usize - size_of::<SdtHeader>()) / root.entry_size;
+    let base = root.header as usize + size_of::<SdtHeader>();
+
+    (0..entries)
+        .map(|i| {
+            let entry = base + i * root.entry_size;
+            unsafe {
+                if root.entry_size == size_of::<u64>() {
+                    read_unaligned(entry as *const u64)
+                } else {
+                    read_unaligned(entry as *const u32) as u64
+                }
+            }
+        })
+        .map(|addr| unsafe { &*(addr as *const SdtHeader) })
+        .find(|table| {
+            &table.signature == signature
+                && checksum_ok(*table as *const _ as *const u8, table.length as usize)
+        })
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+    find_table(b"HPET").map(|header| unsafe { &*(header as *const SdtHeader as *const Hpet) })
+}
+
+pub fn madt() -> Option<&'static Madt> {
+    find_table(b"APIC").map(|header| unsafe { &*(header as *const SdtHeader as *const Madt) })
+}
+
+// Processors are listed once, whichever entries name them
+#[test_case]
+fn madt_processors() {
+    print!("madt processors... ");
+    let mut bytes = [0u8; 108];
+    bytes[4..8].copy_from_slice(&108u32.to_le_bytes());
+    let entries: [&[u8]; 5] = [
+        // Local APIC 0 enabled, 1 disabled
+        &[0, 8, 0, 0, 1, 0, 0, 0],
+        &[0, 8, 1, 1, 0, 0, 0, 0],
+        // Local x2APIC 0 again, 300 enabled, 301 disabled
+        &[9, 16, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0],
+        &[9, 16, 0, 0, 44, 1, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0],
+        &[9, 16, 0, 0, 45, 1, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0],
+    ];
+    let mut offset = size_of::<Madt>();
+    for entry in entries {
+        bytes[offset..offset + entry.len()].copy_from_slice(entry);
+        offset += entry.len();
+    }
+    assert_eq!(offset, bytes.len());
+
+    let madt = unsafe { &*(bytes.as_ptr() as *const Madt) };
+    let mut processors = madt.processors();
+    assert_eq!(processors.next(), Some(0));
+    assert_eq!(processors.next(), Some(300));
+    assert_eq!(processors.next(), None);
+    println!("[ok]");
+}
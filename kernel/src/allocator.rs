@@ -0,0 +1,55 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// The heap lives in .bss, which the loader zeroes and which is identity
+// mapped like the rest of the kernel image
+const HEAP_SIZE: usize = 16 * 1024 * 1024;
+
+#[repr(C, align(4096))]
+struct HeapArea([u8; HEAP_SIZE]);
+
+static mut HEAP_AREA: HeapArea = HeapArea([0; HEAP_SIZE]);
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator(Mutex::new(Heap::empty()));
+
+// Interrupts are disabled while the lock is held, otherwise a task switch from
+// the timer interrupt could free memory while the heap is locked
+struct KernelAllocator(Mutex<Heap>);
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| {
+            self.0
+                .lock()
+                .allocate_first_fit(layout)
+                .map_or(ptr::null_mut(), |p| p.as_ptr())
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| {
+            self.0
+                .lock()
+                .deallocate(NonNull::new_unchecked(ptr), layout)
+        })
+    }
+}
+
+pub fn initialize() {
+    unsafe {
+        let start = ptr::addr_of_mut!(HEAP_AREA) as *mut u8;
+        ALLOCATOR.0.lock().init(start, HEAP_SIZE);
+    }
+}
+
+// Bytes currently allocated and the total heap size
+pub fn usage() -> (usize, usize) {
+    interrupts::without_interrupts(|| {
+        let heap = ALLOCATOR.0.lock();
+        (heap.used(), heap.size())
+    })
+}
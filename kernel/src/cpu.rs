@@ -0,0 +1,428 @@
+use crate::println;
+use core::fmt;
+use lazy_static::lazy_static;
+use raw_cpuid::{cpuid, CpuId};
+
+#[cfg(test)]
+use crate::print;
+
+lazy_static! {
+    static ref CPU_INFO: CpuInfo = CpuInfo::collect();
+}
+
+// Information about the bootstrap processor, gathered once from CPUID. All
+// processors are assumed to be identical.
+pub fn info() -> &'static CpuInfo {
+    &CPU_INFO
+}
+
+pub fn has(feature: Feature) -> bool {
+    CPU_INFO.has(feature)
+}
+
+// Initial APIC ID of the calling processor, available even without an APIC.
+// The extended topology leaf has all 32 bits of an x2APIC ID.
+pub fn current_apic_id() -> u32 {
+    let cpuid = CpuId::new();
+    match cpuid
+        .get_extended_topology_info()
+        .and_then(|mut levels| levels.next())
+    {
+        Some(level) => level.x2apic_id(),
+        None => cpuid
+            .get_feature_info()
+            .map_or(0, |f| f.initial_local_apic_id() as u32),
+    }
+}
+
+pub fn initialize() {
+    println!("{}", *CPU_INFO);
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Vendor {
+    Intel,
+    Amd,
+    Hygon,
+    Unknown,
+}
+
+impl Vendor {
+    fn from_id(id: &[u8]) -> Vendor {
+        match id {
+            b"GenuineIntel" => Vendor::Intel,
+            b"AuthenticAMD" => Vendor::Amd,
+            b"HygonGenuine" => Vendor::Hygon,
+            _ => Vendor::Unknown,
+        }
+    }
+}
+
+// Optional CPU features the kernel cares about
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Feature {
+    Fpu,
+    Tsc,
+    Msr,
+    Pae,
+    Apic,
+    Pge,
+    Pat,
+    Fxsr,
+    Sse,
+    Sse2,
+    Sse3,
+    Ssse3,
+    Sse41,
+    Sse42,
+    MonitorMwait,
+    Pcid,
+    X2Apic,
+    TscDeadline,
+    Xsave,
+    Osxsave,
+    Xsaveopt,
+    Avx,
+    Avx2,
+    Avx512F,
+    Rdrand,
+    Hypervisor,
+    Fsgsbase,
+    Smep,
+    Smap,
+    Invpcid,
+    SyscallSysret,
+    ExecuteDisable,
+    Page1Gb,
+    Rdtscp,
+    InvariantTsc,
+    ArchPerfmon,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CacheType {
+    Data,
+    Instruction,
+    Unified,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Cache {
+    pub level: u8,
+    pub cache_type: CacheType,
+    pub size: usize,
+    pub line_size: usize,
+    pub ways: usize,
+    // Number of logical processors sharing this cache
+    pub shared_by: usize,
+}
+
+const MAX_CACHES: usize = 8;
+
+pub struct CpuInfo {
+    pub vendor: Vendor,
+    vendor_id: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    features: u64,
+    caches: [Option<Cache>; MAX_CACHES],
+    pub physical_address_bits: u8,
+    pub linear_address_bits: u8,
+    // Version of architectural performance monitoring, 0 when unsupported
+    pub perfmon_version: u8,
+}
+
+impl CpuInfo {
+    fn collect() -> CpuInfo {
+        let cpuid = CpuId::new();
+
+        let mut info = CpuInfo {
+            vendor: Vendor::Unknown,
+            vendor_id: [b'?'; 12],
+            brand: [b' '; 48],
+            family: 0,
+            model: 0,
+            stepping: 0,
+            features: 0,
+            caches: [None; MAX_CACHES],
+            // Architectural minimums when leaf 0x80000008 is missing
+            physical_address_bits: 36,
+            linear_address_bits: 48,
+            perfmon_version: 0,
+        };
+
+        if let Some(vf) = cpuid.get_vendor_info() {
+            let id = vf.as_str().as_bytes();
+            let len = id.len().min(info.vendor_id.len());
+            info.vendor_id[..len].copy_from_slice(&id[..len]);
+            info.vendor = Vendor::from_id(id);
+        }
+
+        if let Some(brand) = cpuid.get_processor_brand_string() {
+            let brand = brand.as_str().trim().as_bytes();
+            let len = brand.len().min(info.brand.len());
+            info.brand[..len].copy_from_slice(&brand[..len]);
+        }
+
+        if let Some(f) = cpuid.get_feature_info() {
+            let (family, model) = decode_family_model(
+                info.vendor,
+                f.base_family_id() as u32,
+                f.extended_family_id() as u32,
+                f.base_model_id() as u32,
+                f.extended_model_id() as u32,
+            );
+            info.family = family;
+            info.model = model;
+            info.stepping = f.stepping_id() as u32;
+
+            info.set(Feature::Fpu, f.has_fpu());
+            info.set(Feature::Tsc, f.has_tsc());
+            info.set(Feature::Msr, f.has_msr());
+            info.set(Feature::Pae, f.has_pae());
+            info.set(Feature::Apic, f.has_apic());
+            info.set(Feature::Pge, f.has_pge());
+            info.set(Feature::Pat, f.has_pat());
+            info.set(Feature::Fxsr, f.has_fxsave_fxstor());
+            info.set(Feature::Sse, f.has_sse());
+            info.set(Feature::Sse2, f.has_sse2());
+            info.set(Feature::Sse3, f.has_sse3());
+            info.set(Feature::Ssse3, f.has_ssse3());
+            info.set(Feature::Sse41, f.has_sse41());
+            info.set(Feature::Sse42, f.has_sse42());
+            info.set(Feature::MonitorMwait, f.has_monitor_mwait());
+            info.set(Feature::Pcid, f.has_pcid());
+            info.set(Feature::X2Apic, f.has_x2apic());
+            info.set(Feature::TscDeadline, f.has_tsc_deadline());
+            info.set(Feature::Xsave, f.has_xsave());
+            info.set(Feature::Osxsave, f.has_oxsave());
+            info.set(Feature::Avx, f.has_avx());
+            info.set(Feature::Rdrand, f.has_rdrand());
+            info.set(Feature::Hypervisor, f.has_hypervisor());
+        }
+
+        if let Some(ef) = cpuid.get_extended_feature_info() {
+            info.set(Feature::Avx2, ef.has_avx2());
+            info.set(Feature::Avx512F, ef.has_avx512f());
+            info.set(Feature::Fsgsbase, ef.has_fsgsbase());
+            info.set(Feature::Smep, ef.has_smep());
+            info.set(Feature::Smap, ef.has_smap());
+            info.set(Feature::Invpcid, ef.has_invpcid());
+        }
+
+        if let Some(es) = cpuid.get_extended_state_info() {
+            info.set(Feature::Xsaveopt, es.has_xsaveopt());
+        }
+
+        if let Some(ext) = cpuid.get_extended_processor_and_feature_identifiers() {
+            info.set(Feature::SyscallSysret, ext.has_syscall_sysret());
+            info.set(Feature::ExecuteDisable, ext.has_execute_disable());
+            info.set(Feature::Page1Gb, ext.has_1gib_pages());
+            info.set(Feature::Rdtscp, ext.has_rdtscp());
+        }
+
+        if let Some(apm) = cpuid.get_advanced_power_mgmt_info() {
+            info.set(Feature::InvariantTsc, apm.has_invariant_tsc());
+        }
+
+        if let Some(capacity) = cpuid.get_processor_capacity_feature_info() {
+            info.physical_address_bits = capacity.physical_address_bits();
+            info.linear_address_bits = capacity.linear_address_bits();
+        }
+
+        if let Some(pmu) = cpuid.get_performance_monitoring_info() {
+            if pmu.version_id() > 0
+                && pmu.number_of_counters() > 0
+                && !pmu.is_core_cyc_ev_unavailable()
+            {
+                info.set(Feature::ArchPerfmon, true);
+                info.perfmon_version = pmu.version_id();
+            }
+        }
+
+        info.collect_caches();
+
+        info
+    }
+
+    // Intel reports caches with leaf 4, AMD and Hygon use the same layout in
+    // leaf 0x8000001d
+    fn collect_caches(&mut self) {
+        let leaf: u32 = match self.vendor {
+            Vendor::Intel => {
+                if max_leaf() < 4 {
+                    return;
+                }
+                4
+            }
+            Vendor::Amd | Vendor::Hygon => {
+                if max_extended_leaf() < 0x8000_001d {
+                    return;
+                }
+                0x8000_001d
+            }
+            Vendor::Unknown => return,
+        };
+
+        for (index, slot) in self.caches.iter_mut().enumerate() {
+            let r = cpuid!(leaf, index);
+            let cache_type = match r.eax & 0x1f {
+                1 => CacheType::Data,
+                2 => CacheType::Instruction,
+                3 => CacheType::Unified,
+                _ => break,
+            };
+
+            let line_size = (r.ebx & 0xfff) as usize + 1;
+            let partitions = ((r.ebx >> 12) & 0x3ff) as usize + 1;
+            let ways = ((r.ebx >> 22) & 0x3ff) as usize + 1;
+            let sets = r.ecx as usize + 1;
+
+            *slot = Some(Cache {
+                level: ((r.eax >> 5) & 0x7) as u8,
+                cache_type,
+                size: ways * partitions * line_size * sets,
+                line_size,
+                ways,
+                shared_by: ((r.eax >> 14) & 0xfff) as usize + 1,
+            });
+        }
+    }
+
+    fn set(&mut self, feature: Feature, present: bool) {
+        if present {
+            self.features |= 1 << feature as u8;
+        }
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        self.features & (1 << feature as u8) != 0
+    }
+
+    pub fn caches(&self) -> impl Iterator<Item = &Cache> {
+        self.caches.iter().flatten()
+    }
+
+    pub fn vendor_id(&self) -> &str {
+        core::str::from_utf8(&self.vendor_id).unwrap_or("?")
+    }
+
+    pub fn brand(&self) -> &str {
+        core::str::from_utf8(&self.brand).unwrap_or("").trim_end()
+    }
+}
+
+fn max_leaf() -> u32 {
+    cpuid!(0).eax
+}
+
+fn max_extended_leaf() -> u32 {
+    cpuid!(0x8000_0000).eax
+}
+
+// The extended family is only added for family 0xf, Intel additionally uses
+// the extended model for family 6
+fn decode_family_model(
+    vendor: Vendor,
+    base_family: u32,
+    extended_family: u32,
+    base_model: u32,
+    extended_model: u32,
+) -> (u32, u32) {
+    let family = if base_family == 0xf {
+        base_family + extended_family
+    } else {
+        base_family
+    };
+
+    let use_extended_model = match vendor {
+        Vendor::Intel => base_family == 0x6 || base_family == 0xf,
+        _ => base_family == 0xf,
+    };
+    let model = if use_extended_model {
+        extended_model << 4 | base_model
+    } else {
+        base_model
+    };
+
+    (family, model)
+}
+
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "CPU: {} ({:?}) family 0x{:x} model 0x{:x} stepping {}",
+            self.vendor_id(),
+            self.vendor,
+            self.family,
+            self.model,
+            self.stepping
+        )?;
+        if !self.brand().is_empty() {
+            writeln!(f, "CPU: {}", self.brand())?;
+        }
+        writeln!(
+            f,
+            "CPU: address bits physical {} linear {}",
+            self.physical_address_bits, self.linear_address_bits
+        )?;
+        for cache in self.caches() {
+            writeln!(
+                f,
+                "CPU: L{} {:?} cache {} KiB, {}-way, {} byte lines, shared by {}",
+                cache.level,
+                cache.cache_type,
+                cache.size / 1024,
+                cache.ways,
+                cache.line_size,
+                cache.shared_by
+            )?;
+        }
+        write!(f, "CPU: features")?;
+        for bit in 0..=Feature::ArchPerfmon as u8 {
+            if self.features & (1 << bit) != 0 {
+                // Safety: every value up to the last variant is a Feature
+                let feature: Feature = unsafe { core::mem::transmute(bit) };
+                write!(f, " {:?}", feature)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn vendor_from_id() {
+    print!("cpu vendor... ");
+    assert_eq!(Vendor::from_id(b"GenuineIntel"), Vendor::Intel);
+    assert_eq!(Vendor::from_id(b"AuthenticAMD"), Vendor::Amd);
+    assert_eq!(Vendor::from_id(b"HygonGenuine"), Vendor::Hygon);
+    assert_eq!(Vendor::from_id(b"TCGTCGTCGTCG"), Vendor::Unknown);
+    println!("[ok]");
+}
+
+#[test_case]
+fn family_model() {
+    print!("cpu family and model... ");
+    // Intel Skylake: family 6, extended model 5, model 0xe
+    assert_eq!(decode_family_model(Vendor::Intel, 6, 0, 0xe, 5), (6, 0x5e));
+    // AMD Zen 2: family 0xf + 8, extended model 3, model 1
+    assert_eq!(decode_family_model(Vendor::Amd, 0xf, 8, 1, 3), (0x17, 0x31));
+    // Hygon Dhyana: family 0xf + 9
+    assert_eq!(decode_family_model(Vendor::Hygon, 0xf, 9, 0, 0), (0x18, 0));
+    // Extended model is ignored on AMD below family 0xf
+    assert_eq!(decode_family_model(Vendor::Amd, 6, 0, 8, 1), (6, 8));
+    println!("[ok]");
+}
+
+#[test_case]
+fn collect() {
+    print!("cpu info... ");
+    let info = CpuInfo::collect();
+    assert!(info.has(Feature::Fpu));
+    assert!(info.linear_address_bits >= 48);
+    println!("[ok]");
+}
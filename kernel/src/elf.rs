@@ -0,0 +1,538 @@
+use crate::cpu::{self, Feature};
+use crate::paging::{AddressSpace, MapError, USER_END, USER_START};
+use crate::process::Personality;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use goblin::elf::header::{EM_X86_64, ET_DYN, ET_EXEC};
+use goblin::elf::program_header::{PF_W, PF_X, PT_INTERP, PT_LOAD, PT_PHDR};
+use goblin::elf::Elf;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+#[cfg(test)]
+use crate::syscall::SYS_EXIT;
+#[cfg(test)]
+use crate::usermode::{Asm, Fault, FaultKind, Reg, UserExit};
+#[cfg(test)]
+use crate::{print, println, process};
+#[cfg(test)]
+use goblin::elf::program_header::PT_NOTE;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+// Position independent executables are loaded here, like Linux does
+pub const PIE_BASE: u64 = 0x5555_5555_0000;
+
+// The user stack ends where user space does, with an unmapped guard page
+// below it
+pub const STACK_SIZE: u64 = 0x20000;
+const STACK_TOP: u64 = USER_END;
+const STACK_BOTTOM: u64 = STACK_TOP - STACK_SIZE;
+
+// `mmap` places memory above the heap, up to the stack's guard page
+pub const MMAP_START: u64 = 0x7000_0000_0000;
+pub const MMAP_END: u64 = STACK_BOTTOM - PAGE_SIZE;
+
+// The heap after the program's segments may grow up to there
+pub const HEAP_LIMIT: u64 = MMAP_START;
+
+// Programs linked with librustyos carry a note with this name and type. Any
+// other program is taken to be built for Linux.
+const NOTE_NAME: &str = "rustyos";
+const NT_RUSTYOS_ABI: u32 = 1;
+
+// Most bytes of arguments and environment strings, with their pointers
+pub const ARG_MAX: usize = 0x8000;
+
+// Auxiliary vector entry types, System V ABI AMD64 supplement 3.4.3
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_FLAGS: u64 = 8;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_PLATFORM: u64 = 15;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+
+const PLATFORM: &[u8] = b"x86_64\0";
+
+#[derive(Debug)]
+pub enum LoadError {
+    // Rejected by goblin
+    Malformed(goblin::error::Error),
+    NotElf64,
+    BigEndian,
+    WrongMachine(u16),
+    // Neither an executable nor a position independent one
+    NotExecutable(u16),
+    // Needs a dynamic linker, which isn't supported
+    Interpreter,
+    NoLoadSegments,
+    // File size larger than memory size, or data past the end of the file
+    SegmentOutsideFile,
+    // Virtual address and file offset differ modulo the page size
+    MisalignedSegment,
+    SegmentOutsideUserSpace,
+    // The entry point isn't in an executable segment
+    BadEntry(u64),
+    ArgumentsTooLong,
+    Map(MapError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Malformed(error) => write!(f, "malformed ELF file: {}", error),
+            LoadError::NotElf64 => write!(f, "not a 64-bit ELF file"),
+            LoadError::BigEndian => write!(f, "big endian ELF file"),
+            LoadError::WrongMachine(machine) => write!(f, "not built for x86-64 ({})", machine),
+            LoadError::NotExecutable(e_type) => write!(f, "not an executable (type {})", e_type),
+            LoadError::Interpreter => write!(f, "dynamically linked programs aren't supported"),
+            LoadError::NoLoadSegments => write!(f, "no loadable segments"),
+            LoadError::SegmentOutsideFile => write!(f, "segment extends past the end of the file"),
+            LoadError::MisalignedSegment => write!(f, "segment offset and address misaligned"),
+            LoadError::SegmentOutsideUserSpace => write!(f, "segment outside of user space"),
+            LoadError::BadEntry(entry) => write!(f, "entry point 0x{:x} isn't executable", entry),
+            LoadError::ArgumentsTooLong => write!(f, "arguments and environment too long"),
+            LoadError::Map(error) => write!(f, "mapping failed: {:?}", error),
+        }
+    }
+}
+
+impl From<MapError> for LoadError {
+    fn from(error: MapError) -> LoadError {
+        LoadError::Map(error)
+    }
+}
+
+// A program ready to be started with `Process::start(entry, stack)`
+pub struct Program {
+    pub space: AddressSpace,
+    pub entry: VirtAddr,
+    pub stack: VirtAddr,
+    // End of the highest segment, where a heap can begin
+    pub end: u64,
+    pub personality: Personality,
+}
+
+// Load the executable in `image` into a new address space, with a stack
+// holding `argv`, `envp` and the auxiliary vector like the System V ABI
+// process initialization expects (AMD64 supplement 3.4.1)
+pub fn load(image: &[u8], argv: &[&[u8]], envp: &[&[u8]]) -> Result<Program, LoadError> {
+    let elf = Elf::parse(image).map_err(LoadError::Malformed)?;
+    if !elf.is_64 {
+        return Err(LoadError::NotElf64);
+    }
+    if !elf.little_endian {
+        return Err(LoadError::BigEndian);
+    }
+    if elf.header.e_machine != EM_X86_64 {
+        return Err(LoadError::WrongMachine(elf.header.e_machine));
+    }
+    let bias = match elf.header.e_type {
+        ET_EXEC => 0,
+        ET_DYN => PIE_BASE,
+        e_type => return Err(LoadError::NotExecutable(e_type)),
+    };
+    if elf.program_headers.iter().any(|ph| ph.p_type == PT_INTERP) {
+        return Err(LoadError::Interpreter);
+    }
+
+    // Flags of every page, segments may share one at their ends
+    let mut pages: BTreeMap<u64, PageTableFlags> = BTreeMap::new();
+    let mut end = 0;
+    let mut entry_executable = false;
+    let entry = elf.entry.wrapping_add(bias);
+    let segments = || elf.program_headers.iter().filter(|ph| ph.p_type == PT_LOAD);
+    for ph in segments() {
+        let file_end = ph.p_offset.checked_add(ph.p_filesz);
+        if ph.p_filesz > ph.p_memsz || file_end.is_none_or(|e| e > image.len() as u64) {
+            return Err(LoadError::SegmentOutsideFile);
+        }
+        if ph.p_vaddr % PAGE_SIZE != ph.p_offset % PAGE_SIZE {
+            return Err(LoadError::MisalignedSegment);
+        }
+        let start = ph
+            .p_vaddr
+            .checked_add(bias)
+            .ok_or(LoadError::SegmentOutsideUserSpace)?;
+        let segment_end = start
+            .checked_add(ph.p_memsz)
+            .ok_or(LoadError::SegmentOutsideUserSpace)?;
+        if start < USER_START || segment_end > HEAP_LIMIT {
+            return Err(LoadError::SegmentOutsideUserSpace);
+        }
+        if ph.p_flags & PF_X != 0 && (start..segment_end).contains(&entry) {
+            entry_executable = true;
+        }
+        end = end.max(segment_end);
+
+        let flags = page_flags(ph.p_flags);
+        let mut page = start & !(PAGE_SIZE - 1);
+        while page < segment_end {
+            pages
+                .entry(page)
+                .and_modify(|shared| *shared = merge_flags(*shared, flags))
+                .or_insert(flags);
+            page += PAGE_SIZE;
+        }
+    }
+    if pages.is_empty() {
+        return Err(LoadError::NoLoadSegments);
+    }
+    if !entry_executable {
+        return Err(LoadError::BadEntry(entry));
+    }
+
+    let space = AddressSpace::new()?;
+    for (&page, &flags) in &pages {
+        space.map(page, PAGE_SIZE, flags)?;
+    }
+    // The rest up to the memory size stays zero
+    for ph in segments() {
+        let data = &image[ph.p_offset as usize..(ph.p_offset + ph.p_filesz) as usize];
+        space.copy_to(ph.p_vaddr + bias, data)?;
+    }
+
+    // Where the program headers ended up, for the C library to find TLS and
+    // the like
+    let phdr = elf
+        .program_headers
+        .iter()
+        .find(|ph| ph.p_type == PT_PHDR)
+        .map(|ph| ph.p_vaddr + bias)
+        .or_else(|| {
+            let phoff = elf.header.e_phoff;
+            segments()
+                .find(|ph| (ph.p_offset..ph.p_offset + ph.p_filesz).contains(&phoff))
+                .map(|ph| ph.p_vaddr + bias + (phoff - ph.p_offset))
+        })
+        .unwrap_or(0);
+    let auxv = [
+        (AT_PHDR, phdr),
+        (AT_PHENT, elf.header.e_phentsize as u64),
+        (AT_PHNUM, elf.header.e_phnum as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, 0),
+        (AT_FLAGS, 0),
+        (AT_ENTRY, entry),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        (AT_SECURE, 0),
+    ];
+
+    space.map(
+        STACK_BOTTOM,
+        STACK_SIZE,
+        PageTableFlags::WRITABLE | no_execute(),
+    )?;
+    let stack = write_stack(&space, argv, envp, &auxv)?;
+
+    let native = elf.iter_note_headers(image).is_some_and(|mut notes| {
+        notes.any(|note| {
+            note.is_ok_and(|note| note.name == NOTE_NAME && note.n_type == NT_RUSTYOS_ABI)
+        })
+    });
+    Ok(Program {
+        space,
+        entry: VirtAddr::new(entry),
+        stack: VirtAddr::new(stack),
+        end,
+        personality: match native {
+            true => Personality::Native,
+            false => Personality::Linux,
+        },
+    })
+}
+
+pub fn no_execute() -> PageTableFlags {
+    // The bit is reserved without IA32_EFER.NXE
+    if cpu::has(Feature::ExecuteDisable) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+// Everything is readable, there is no way to map a page write or execute only
+fn page_flags(p_flags: u32) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+    if p_flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if p_flags & PF_X == 0 {
+        flags |= no_execute();
+    }
+    flags
+}
+
+// A page shared by two segments allows what either of them does
+fn merge_flags(a: PageTableFlags, b: PageTableFlags) -> PageTableFlags {
+    let no_execute = a & b & PageTableFlags::NO_EXECUTE;
+    ((a | b) - PageTableFlags::NO_EXECUTE) | no_execute
+}
+
+// Lay out the initial stack below `STACK_TOP` and return the stack pointer,
+// which points at argc and is 16 byte aligned:
+//
+//   argc, argv[0..argc], 0, envp[..], 0, auxv pairs, AT_NULL, 0
+//   ... strings, AT_RANDOM bytes and the platform name at the top
+fn write_stack(
+    space: &AddressSpace,
+    argv: &[&[u8]],
+    envp: &[&[u8]],
+    auxv: &[(u64, u64)],
+) -> Result<u64, LoadError> {
+    let strings_len =
+        PLATFORM.len() + 16 + argv.iter().chain(envp).map(|s| s.len() + 1).sum::<usize>();
+    // argc, the two null terminated arrays, AT_PLATFORM, AT_RANDOM and
+    // AT_NULL
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 2 * (auxv.len() + 3);
+    if strings_len + words * 8 > ARG_MAX {
+        return Err(LoadError::ArgumentsTooLong);
+    }
+
+    let strings_start = (STACK_TOP - strings_len as u64) & !0xf;
+    let sp = (strings_start - words as u64 * 8) & !0xf;
+    let mut buffer = vec![0u8; (STACK_TOP - sp) as usize];
+
+    let mut string_at = strings_start;
+    let mut push_string = |buffer: &mut Vec<u8>, bytes: &[u8], nul: bool| {
+        let offset = (string_at - sp) as usize;
+        buffer[offset..offset + bytes.len()].copy_from_slice(bytes);
+        let address = string_at;
+        string_at += bytes.len() as u64 + nul as u64;
+        address
+    };
+    let platform = push_string(&mut buffer, PLATFORM, false);
+    let random = push_string(&mut buffer, &random_bytes(), false);
+    let mut pointers = Vec::with_capacity(words);
+    pointers.push(argv.len() as u64);
+    for arg in argv {
+        pointers.push(push_string(&mut buffer, arg, true));
+    }
+    pointers.push(0);
+    for var in envp {
+        pointers.push(push_string(&mut buffer, var, true));
+    }
+    pointers.push(0);
+    for &(key, value) in
+        auxv.iter()
+            .chain(&[(AT_PLATFORM, platform), (AT_RANDOM, random), (AT_NULL, 0)])
+    {
+        pointers.push(key);
+        pointers.push(value);
+    }
+    for (i, pointer) in pointers.iter().enumerate() {
+        buffer[i * 8..i * 8 + 8].copy_from_slice(&pointer.to_le_bytes());
+    }
+
+    space.copy_to(sp, &buffer)?;
+    Ok(sp)
+}
+
+// Seed for the C library's stack protector and pointer mangling
+fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    for chunk in bytes.chunks_mut(8) {
+        chunk.copy_from_slice(&random_u64().to_le_bytes());
+    }
+    bytes
+}
+
+pub fn random_u64() -> u64 {
+    if cpu::has(Feature::Rdrand) {
+        // Retried as recommended, Intel SDM Vol. 1 7.3.17.1
+        for _ in 0..10 {
+            let value: u64;
+            let ok: u8;
+            unsafe {
+                core::arch::asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok);
+            }
+            if ok != 0 {
+                return value;
+            }
+        }
+    }
+    // splitmix64 of the time stamp counter, good enough to differ per process
+    let mut x = unsafe { core::arch::x86_64::_rdtsc() }.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+// The note of a rustyos program, see `NOTE_NAME`
+#[cfg(test)]
+const TEST_NOTE: &[u8] = b"\x08\0\0\0\x04\0\0\0\x01\0\0\0rustyos\0\0\0\0\0";
+
+// A rustyos program with one segment holding the headers and `code` at
+// `base`, and the extra program header `extra` pointing at `data` after the
+// code
+#[cfg(test)]
+pub fn test_image(e_type: u16, base: u64, code: &[u8], extra: Option<(u32, &[u8])>) -> Vec<u8> {
+    let mut headers = vec![(PT_NOTE, TEST_NOTE)];
+    headers.extend(extra);
+    build_test_image(e_type, base, code, &headers)
+}
+
+// The same for Linux, without the note
+#[cfg(test)]
+pub fn linux_test_image(e_type: u16, base: u64, code: &[u8]) -> Vec<u8> {
+    build_test_image(e_type, base, code, &[])
+}
+
+#[cfg(test)]
+fn build_test_image(e_type: u16, base: u64, code: &[u8], extra: &[(u32, &[u8])]) -> Vec<u8> {
+    let phnum = 1 + extra.len() as u16;
+    let code_offset = 64 + 56 * phnum as u64;
+    let data_offset = code_offset + code.len() as u64;
+    let data_len: usize = extra.iter().map(|(_, data)| data.len()).sum();
+    let file_len = data_offset + data_len as u64;
+
+    let mut image = Vec::new();
+    image.extend_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    image.extend_from_slice(&e_type.to_le_bytes());
+    image.extend_from_slice(&EM_X86_64.to_le_bytes());
+    image.extend_from_slice(&1u32.to_le_bytes());
+    image.extend_from_slice(&(base + code_offset).to_le_bytes()); // e_entry
+    image.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+    image.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    image.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    for half in [64u16, 56, phnum, 64, 0, 0] {
+        image.extend_from_slice(&half.to_le_bytes());
+    }
+    let mut program_header =
+        |p_type: u32, p_flags: u32, offset: u64, vaddr: u64, size: u64, align: u64| {
+            image.extend_from_slice(&p_type.to_le_bytes());
+            image.extend_from_slice(&p_flags.to_le_bytes());
+            for word in [offset, vaddr, vaddr, size, size, align] {
+                image.extend_from_slice(&word.to_le_bytes());
+            }
+        };
+    program_header(
+        PT_LOAD,
+        PF_X | goblin::elf::program_header::PF_R,
+        0,
+        base,
+        file_len,
+        PAGE_SIZE,
+    );
+    let mut offset = data_offset;
+    for &(p_type, data) in extra {
+        program_header(p_type, 0, offset, base + offset, data.len() as u64, 4);
+        offset += data.len() as u64;
+    }
+    image.extend_from_slice(code);
+    for (_, data) in extra {
+        image.extend_from_slice(data);
+    }
+    image
+}
+
+#[test_case]
+fn load_and_run() {
+    print!("ELF loader... ");
+    let code = Asm::new()
+        .raw(&[0x40, 0xf6, 0xc4, 0x0f]) // test spl, 0xf
+        .raw(&[0x74, 0x02]) // jz +2
+        .raw(&[0x0f, 0x0b]) // ud2
+        .load(Reg::Rax, Reg::Rsp, 16) // argv[1]
+        .raw(&[0x0f, 0xb6, 0x38]) // movzx edi, byte ptr [rax]
+        .raw(&[0x48, 0x03, 0x3c, 0x24]) // add rdi, [rsp] (argc)
+        .syscall(SYS_EXIT)
+        .code();
+    let argv: [&[u8]; 2] = [b"test", b"b"];
+    for (e_type, base) in [(ET_EXEC, USER_START + 0x400000), (ET_DYN, 0)] {
+        let image = test_image(e_type, base, &code, None);
+        let program = load(&image, &argv, &[b"HOME=/"]).expect("load");
+        let load_address = if e_type == ET_DYN { PIE_BASE } else { base };
+        assert_eq!(program.entry.as_u64(), load_address + 64 + 2 * 56);
+        let process = process::spawn_program("elf", program);
+        assert_eq!(process.wait_exit(), UserExit::Exit(b'b' as i64 + 2));
+    }
+
+    // The code segment isn't writable
+    let code = [
+        0x48, 0x8d, 0x05, 0xf9, 0xff, 0xff, 0xff, // lea rax, [rip - 7]
+        0xc6, 0x00, 0x90, // mov byte ptr [rax], 0x90
+    ];
+    let program = load(&test_image(ET_DYN, 0, &code, None), &[], &[]).expect("load");
+    let entry = program.entry.as_u64();
+    let process = process::spawn_program("elf", program);
+    assert!(matches!(
+        process.wait_exit(),
+        UserExit::Fault(Fault {
+            kind: FaultKind::PageFault,
+            address: Some(address),
+            ..
+        }) if address == entry
+    ));
+    println!("[ok]");
+}
+
+#[test_case]
+fn load_errors() {
+    print!("ELF loader errors... ");
+    let code = [0x0f, 0x0b];
+    let image = test_image(ET_EXEC, USER_START, &code, None);
+    assert!(matches!(
+        load(&image[..40], &[], &[]),
+        Err(LoadError::Malformed(_))
+    ));
+    // Program header claims more than the file has
+    assert!(matches!(
+        load(&image[..image.len() - 1], &[], &[]),
+        Err(LoadError::SegmentOutsideFile)
+    ));
+
+    let image = test_image(
+        ET_EXEC,
+        USER_START,
+        &code,
+        Some((PT_INTERP, b"/lib/ld.so\0")),
+    );
+    assert!(matches!(
+        load(&image, &[], &[]),
+        Err(LoadError::Interpreter)
+    ));
+
+    // Linked where the kernel lives
+    let image = test_image(ET_EXEC, 0x400000, &code, None);
+    assert!(matches!(
+        load(&image, &[], &[]),
+        Err(LoadError::SegmentOutsideUserSpace)
+    ));
+
+    let mut image = test_image(ET_EXEC, USER_START, &code, None);
+    image[18] = 3; // EM_386
+    assert!(matches!(
+        load(&image, &[], &[]),
+        Err(LoadError::WrongMachine(3))
+    ));
+
+    let mut image = test_image(ET_EXEC, USER_START, &code, None);
+    image[24..32].copy_from_slice(&(USER_START + 0x10000).to_le_bytes());
+    assert!(matches!(
+        load(&image, &[], &[]),
+        Err(LoadError::BadEntry(_))
+    ));
+
+    let image = test_image(ET_EXEC, USER_START, &code, None);
+    let long = vec![b'x'; ARG_MAX];
+    assert!(matches!(
+        load(&image, &[&long], &[]),
+        Err(LoadError::ArgumentsTooLong)
+    ));
+    println!("[ok]");
+}
@@ -0,0 +1,228 @@
+use crate::{percpu, scheduler, sync};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::interrupts;
+
+#[cfg(test)]
+use crate::{print, println, time};
+#[cfg(test)]
+use core::time::Duration;
+
+// Futures that may be woken at once before the executor gets to run them
+const READY_QUEUE_SIZE: usize = 128;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct FutureId(u64);
+
+impl FutureId {
+    fn new() -> FutureId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        FutureId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+// Bounded queue filled by an interrupt handler and drained by a future. The
+// handler can't allocate or block, so values that don't fit are dropped and
+// counted.
+pub struct InterruptQueue<T> {
+    queue: spin::Once<ArrayQueue<T>>,
+    waker: AtomicWaker,
+    dropped: AtomicU64,
+}
+
+impl<T> InterruptQueue<T> {
+    pub const fn new() -> InterruptQueue<T> {
+        InterruptQueue {
+            queue: spin::Once::new(),
+            waker: AtomicWaker::new(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    // Allocate the buffer. Values pushed before this are dropped.
+    pub fn initialize(&self, capacity: usize) {
+        self.queue.call_once(|| ArrayQueue::new(capacity));
+    }
+
+    // Called from the interrupt handler
+    pub fn push(&self, value: T) {
+        match self.queue.get() {
+            Some(queue) if queue.push(value).is_ok() => self.waker.wake(),
+            _ => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // Take the next value, or register the waker of `cx` to be woken by the
+    // next push
+    pub fn poll_pop(&self, cx: &mut Context) -> Poll<T> {
+        let queue = self.queue.get().expect("interrupt queue not initialized");
+        if let Some(value) = queue.pop() {
+            return Poll::Ready(value);
+        }
+
+        // A value pushed between the pop above and registering would not wake
+        // us, so look again afterwards
+        self.waker.register(cx.waker());
+        match queue.pop() {
+            Some(value) => {
+                self.waker.take();
+                Poll::Ready(value)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+struct FutureWaker {
+    id: FutureId,
+    ready: Arc<ArrayQueue<FutureId>>,
+    cpu_index: usize,
+}
+
+impl Wake for FutureWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    // May be called from interrupt handlers and other processors
+    fn wake_by_ref(self: &Arc<Self>) {
+        if self.ready.push(self.id).is_err() {
+            panic!("executor ready queue full");
+        }
+        if percpu::current().cpu_index != self.cpu_index {
+            scheduler::kick(self.cpu_index);
+        }
+    }
+}
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// Runs futures on the processor it was created on, halting while none of them
+// can make progress. Interrupt handlers wake futures through their wakers.
+pub struct Executor {
+    futures: BTreeMap<FutureId, BoxFuture>,
+    wakers: BTreeMap<FutureId, Waker>,
+    ready: Arc<ArrayQueue<FutureId>>,
+    cpu_index: usize,
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        Executor {
+            futures: BTreeMap::new(),
+            wakers: BTreeMap::new(),
+            ready: Arc::new(ArrayQueue::new(READY_QUEUE_SIZE)),
+            cpu_index: percpu::current().cpu_index,
+        }
+    }
+
+    pub fn spawn<F>(&mut self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let id = FutureId::new();
+        self.futures.insert(id, Box::pin(future));
+        self.ready.push(id).expect("executor ready queue full");
+    }
+
+    // Poll every future that was woken since the last call
+    fn run_ready(&mut self) {
+        while let Some(id) = self.ready.pop() {
+            // Woken after it completed
+            let future = match self.futures.get_mut(&id) {
+                Some(future) => future,
+                None => continue,
+            };
+            let ready = self.ready.clone();
+            let cpu_index = self.cpu_index;
+            let waker = self.wakers.entry(id).or_insert_with(|| {
+                Waker::from(Arc::new(FutureWaker {
+                    id,
+                    ready,
+                    cpu_index,
+                }))
+            });
+            let mut cx = Context::from_waker(waker);
+            if future.as_mut().poll(&mut cx).is_ready() {
+                self.futures.remove(&id);
+                self.wakers.remove(&id);
+            }
+        }
+    }
+
+    // Run the futures forever. Meant to be called from the idle task, so
+    // ready kernel tasks take turns with it.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready();
+
+            // Checked with interrupts disabled so that a wake between the
+            // check and halting isn't lost
+            interrupts::disable();
+            if self.ready.is_empty() {
+                scheduler::idle_once();
+            } else {
+                interrupts::enable();
+            }
+        }
+    }
+}
+
+struct TaskWaker {
+    woken: AtomicBool,
+    queue: sync::WaitQueue,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        self.queue.wake_all();
+    }
+}
+
+// Run `future` to completion on the calling task, blocking it while the
+// future is pending
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let task_waker = Arc::new(TaskWaker {
+        woken: AtomicBool::new(false),
+        queue: sync::WaitQueue::new(),
+    });
+    let waker = Waker::from(task_waker.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        task_waker
+            .queue
+            .wait_until(|| task_waker.woken.swap(false, Ordering::Acquire));
+    }
+}
+
+#[test_case]
+fn timer_future() {
+    print!("timer future... ");
+    let start = time::ticks();
+    block_on(time::Timer::after(Duration::from_millis(50)));
+    assert!(time::ticks() >= start + time::duration_to_ticks(Duration::from_millis(50)));
+    println!("[ok]");
+}
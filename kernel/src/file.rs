@@ -0,0 +1,657 @@
+use crate::signal::SIGINT;
+use crate::sync::WaitQueue;
+use crate::syscall::Errno;
+use crate::{print, process, programs};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+#[cfg(test)]
+use crate::println;
+
+// Most file descriptors a process can have open
+pub const MAX_FDS: usize = 256;
+
+// Access modes of `open`, in the low bits of its flags
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_ACCMODE: u64 = 3;
+
+// What a path or file descriptor refers to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileKind {
+    Regular,
+    Directory,
+    CharDevice,
+    Fifo,
+}
+
+// Something file descriptors refer to. Reads and writes may block, in which
+// case they use `process::wait_interruptible` and fail with EINTR if a
+// signal arrives before anything was transferred.
+pub trait File: Send + Sync {
+    // Read into `buffer`, returning how many bytes were read, zero at the end
+    fn read(&self, _buffer: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    // Write from `data`, returning how many bytes were written
+    fn write(&self, _data: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    fn kind(&self) -> FileKind;
+
+    // Whether it is a terminal, which takes terminal ioctls
+    fn is_terminal(&self) -> bool {
+        false
+    }
+
+    // Move the position reads start at, returning it as an offset from the
+    // start. Devices without a position stay at 0.
+    fn seek(&self, _to: SeekFrom) -> Result<u64, Errno> {
+        Ok(0)
+    }
+
+    // Size in bytes, 0 for anything but a regular file
+    fn size(&self) -> u64 {
+        0
+    }
+
+    // Whether a read and a write would return without blocking, for poll.
+    // Files that block wake `READINESS` when that may have changed.
+    fn ready(&self) -> Ready {
+        Ready {
+            read: true,
+            write: true,
+        }
+    }
+}
+
+// Where `seek` counts from
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Ready {
+    pub read: bool,
+    pub write: bool,
+}
+
+// Woken whenever a file may have become ready, so that poll can wait for any
+// number of them
+pub static READINESS: WaitQueue = WaitQueue::new();
+
+// An open file with the access it was opened for. Shared by file descriptors
+// made by `dup` or inherited by `fork`, the file is closed when the last of
+// them is.
+pub struct OpenFile {
+    file: Box<dyn File>,
+    readable: bool,
+    writable: bool,
+}
+
+impl OpenFile {
+    pub fn new(file: Box<dyn File>, readable: bool, writable: bool) -> Arc<OpenFile> {
+        Arc::new(OpenFile {
+            file,
+            readable,
+            writable,
+        })
+    }
+
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, Errno> {
+        if !self.readable {
+            return Err(Errno::EBADF);
+        }
+        self.file.read(buffer)
+    }
+
+    pub fn write(&self, data: &[u8]) -> Result<usize, Errno> {
+        if !self.writable {
+            return Err(Errno::EBADF);
+        }
+        self.file.write(data)
+    }
+
+    pub fn kind(&self) -> FileKind {
+        self.file.kind()
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        self.file.is_terminal()
+    }
+
+    pub fn seek(&self, to: SeekFrom) -> Result<u64, Errno> {
+        self.file.seek(to)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.file.size()
+    }
+
+    pub fn ready(&self) -> Ready {
+        self.file.ready()
+    }
+
+    // The access mode it was opened with, as `open` flags
+    pub fn access_mode(&self) -> u64 {
+        match (self.readable, self.writable) {
+            (true, true) => O_RDWR,
+            (false, true) => O_WRONLY,
+            _ => O_RDONLY,
+        }
+    }
+}
+
+// File descriptors of a process, indexes into `files`
+#[derive(Clone, Default)]
+pub struct FdTable {
+    files: Vec<Option<Descriptor>>,
+}
+
+#[derive(Clone)]
+struct Descriptor {
+    file: Arc<OpenFile>,
+    // Closed by `execve`
+    cloexec: bool,
+}
+
+impl FdTable {
+    // No open files
+    pub const fn new() -> FdTable {
+        FdTable { files: Vec::new() }
+    }
+
+    // The console as standard input, output and error
+    pub fn console() -> FdTable {
+        let console = Descriptor {
+            file: OpenFile::new(Box::new(Console), true, true),
+            cloexec: false,
+        };
+        FdTable {
+            files: vec![Some(console.clone()), Some(console.clone()), Some(console)],
+        }
+    }
+
+    pub fn get(&self, fd: u64) -> Result<Arc<OpenFile>, Errno> {
+        let descriptor = self.files.get(fd as usize).and_then(Option::as_ref);
+        descriptor
+            .map(|descriptor| descriptor.file.clone())
+            .ok_or(Errno::EBADF)
+    }
+
+    // Open `file` as the lowest free file descriptor
+    pub fn insert(&mut self, file: Arc<OpenFile>) -> Result<u64, Errno> {
+        self.insert_from(file, 0, false)
+    }
+
+    // Open `file` as the lowest free file descriptor not below `min`, closed
+    // on exec if `cloexec` is set
+    pub fn insert_from(
+        &mut self,
+        file: Arc<OpenFile>,
+        min: u64,
+        cloexec: bool,
+    ) -> Result<u64, Errno> {
+        if min as usize >= MAX_FDS {
+            return Err(Errno::EINVAL);
+        }
+        let min = min as usize;
+        if self.files.len() < min {
+            self.files.resize(min, None);
+        }
+        let fd = match self.files[min..].iter().position(Option::is_none) {
+            Some(i) => min + i,
+            None if self.files.len() < MAX_FDS => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(Errno::EMFILE),
+        };
+        self.files[fd] = Some(Descriptor { file, cloexec });
+        Ok(fd as u64)
+    }
+
+    // Close `fd`. The file is returned so that the caller can drop it, which
+    // may wake tasks, after unlocking the table.
+    pub fn close(&mut self, fd: u64) -> Result<Arc<OpenFile>, Errno> {
+        let descriptor = self.files.get_mut(fd as usize).and_then(Option::take);
+        let descriptor = descriptor.ok_or(Errno::EBADF)?;
+        while let Some(None) = self.files.last() {
+            self.files.pop();
+        }
+        Ok(descriptor.file)
+    }
+
+    // A new file descriptor for the file of `fd`
+    pub fn dup(&mut self, fd: u64) -> Result<u64, Errno> {
+        let file = self.get(fd)?;
+        self.insert(file)
+    }
+
+    // Make `new` refer to the file of `old`, closing what it referred to
+    // before, which is returned like by `close`. `new` stays open on exec.
+    pub fn dup2(&mut self, old: u64, new: u64) -> Result<Option<Arc<OpenFile>>, Errno> {
+        let file = self.get(old)?;
+        if new as usize >= MAX_FDS {
+            return Err(Errno::EBADF);
+        }
+        let new = new as usize;
+        if self.files.len() <= new {
+            self.files.resize(new + 1, None);
+        }
+        let descriptor = Descriptor {
+            file,
+            cloexec: false,
+        };
+        Ok(self.files[new].replace(descriptor).map(|old| old.file))
+    }
+
+    // Whether `fd` is closed on exec
+    pub fn cloexec(&self, fd: u64) -> Result<bool, Errno> {
+        let descriptor = self.files.get(fd as usize).and_then(Option::as_ref);
+        descriptor
+            .map(|descriptor| descriptor.cloexec)
+            .ok_or(Errno::EBADF)
+    }
+
+    pub fn set_cloexec(&mut self, fd: u64, cloexec: bool) -> Result<(), Errno> {
+        let descriptor = self.files.get_mut(fd as usize).and_then(Option::as_mut);
+        descriptor.ok_or(Errno::EBADF)?.cloexec = cloexec;
+        Ok(())
+    }
+
+    // Close the file descriptors marked close-on-exec, returning their files
+    // like `close`
+    pub fn close_on_exec(&mut self) -> Vec<Arc<OpenFile>> {
+        let mut closed = Vec::new();
+        for slot in self.files.iter_mut() {
+            if slot.as_ref().is_some_and(|descriptor| descriptor.cloexec) {
+                closed.extend(slot.take().map(|descriptor| descriptor.file));
+            }
+        }
+        while let Some(None) = self.files.last() {
+            self.files.pop();
+        }
+        closed
+    }
+}
+
+// Open the file at `path` with `flags` like `open`. There is no file system
+// yet, only the devices below, and directories can be opened for reading to
+// refer to them. The programs `execve` runs can be opened for reading.
+pub fn open(path: &[u8], flags: u64) -> Result<Arc<OpenFile>, Errno> {
+    let (readable, writable) = match flags & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(Errno::EINVAL),
+    };
+    let file: Box<dyn File> = match path {
+        b"/dev/console" => Box::new(Console),
+        b"/dev/null" => Box::new(Null),
+        b"/dev/zero" => Box::new(Zero),
+        _ => match metadata(path)? {
+            (FileKind::Directory, _) if writable => return Err(Errno::EISDIR),
+            (FileKind::Directory, _) => Box::new(Directory),
+            _ if writable => return Err(Errno::EACCES),
+            _ => Box::new(Image {
+                data: programs::find(path).ok_or(Errno::ENOENT)?,
+                position: Mutex::new(0),
+            }),
+        },
+    };
+    Ok(OpenFile::new(file, readable, writable))
+}
+
+// What is at `path`, with its size for regular files. Besides the devices
+// there are the programs `execve` can run, and the directories they are in.
+pub fn metadata(path: &[u8]) -> Result<(FileKind, u64), Errno> {
+    match path {
+        b"/dev/console" | b"/dev/null" | b"/dev/zero" => Ok((FileKind::CharDevice, 0)),
+        b"/" | b"/dev" => Ok((FileKind::Directory, 0)),
+        _ => match programs::find(path) {
+            Some(image) => Ok((FileKind::Regular, image.len() as u64)),
+            None if programs::is_directory(path) => Ok((FileKind::Directory, 0)),
+            None => Err(Errno::ENOENT),
+        },
+    }
+}
+
+// `path` from the root without "." and ".." components and repeated slashes
+pub fn normalize(path: &[u8]) -> Vec<u8> {
+    let mut components: Vec<&[u8]> = Vec::new();
+    for component in path.split(|&byte| byte == b'/') {
+        match component {
+            b"" | b"." => {}
+            b".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    let mut normalized = Vec::with_capacity(path.len() + 1);
+    for component in components {
+        normalized.push(b'/');
+        normalized.extend_from_slice(component);
+    }
+    if normalized.is_empty() {
+        normalized.push(b'/');
+    }
+    normalized
+}
+
+// A program built into the kernel, read from the start
+struct Image {
+    data: &'static [u8],
+    position: Mutex<u64>,
+}
+
+impl File for Image {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Errno> {
+        let mut position = self.position.lock();
+        let start = (*position).min(self.data.len() as u64) as usize;
+        let data = &self.data[start..];
+        let len = data.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&data[..len]);
+        *position += len as u64;
+        Ok(len)
+    }
+
+    // The position can be past the end, where reads return nothing, but not
+    // past what an off_t holds
+    fn seek(&self, to: SeekFrom) -> Result<u64, Errno> {
+        let mut position = self.position.lock();
+        let new = match to {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => position.checked_add_signed(offset),
+            SeekFrom::End(offset) => (self.data.len() as u64).checked_add_signed(offset),
+        };
+        *position = new
+            .filter(|&new| new <= i64::MAX as u64)
+            .ok_or(Errno::EINVAL)?;
+        Ok(*position)
+    }
+
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn kind(&self) -> FileKind {
+        FileKind::Regular
+    }
+}
+
+// A directory, which can't be read yet
+struct Directory;
+
+impl File for Directory {
+    fn read(&self, _buffer: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EISDIR)
+    }
+
+    fn kind(&self) -> FileKind {
+        FileKind::Directory
+    }
+}
+
+// Lines typed on the keyboard or the serial console, waiting to be read from
+// the console
+struct ConsoleInput {
+    // Finished lines
+    lines: VecDeque<u8>,
+    // The line being typed, which can still be edited
+    line: Vec<u8>,
+}
+
+static CONSOLE_INPUT: Mutex<ConsoleInput> = Mutex::new(ConsoleInput {
+    lines: VecDeque::new(),
+    line: Vec::new(),
+});
+
+// Woken when a line is finished
+static CONSOLE_READABLE: WaitQueue = WaitQueue::new();
+
+// Ctrl-C, which interrupts the programs on the console
+pub const CONSOLE_INTERRUPT: u8 = 0x03;
+
+// Add a character typed on the console. Like a terminal in canonical mode,
+// backspace removes the last character of the current line and readers get it
+// once it is finished with a newline. Ctrl-C discards the current line and
+// sends SIGINT to every process but init, as there is no job control to tell
+// which of them are in the foreground.
+pub fn console_input(byte: u8) {
+    if byte == CONSOLE_INTERRUPT {
+        interrupts::without_interrupts(|| CONSOLE_INPUT.lock().line.clear());
+        process::signal_all(SIGINT);
+        return;
+    }
+
+    let finished = interrupts::without_interrupts(|| {
+        let mut input = CONSOLE_INPUT.lock();
+        match byte {
+            b'\r' | b'\n' => {
+                let line = core::mem::take(&mut input.line);
+                input.lines.extend(line);
+                input.lines.push_back(b'\n');
+                true
+            }
+            0x08 | 0x7f => {
+                input.line.pop();
+                false
+            }
+            _ => {
+                input.line.push(byte);
+                false
+            }
+        }
+    });
+    if finished {
+        CONSOLE_READABLE.wake_all();
+        READINESS.wake_all();
+    }
+}
+
+// Writes go to the display, reads block until a line was typed and return at
+// most that line
+struct Console;
+
+impl File for Console {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Errno> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let mut read = 0;
+        process::wait_interruptible(&CONSOLE_READABLE, || {
+            let mut input = CONSOLE_INPUT.lock();
+            let line = match input.lines.iter().position(|&byte| byte == b'\n') {
+                Some(end) => end + 1,
+                None => return false,
+            };
+            read = buffer.len().min(line);
+            for (byte, value) in buffer.iter_mut().zip(input.lines.drain(..read)) {
+                *byte = value;
+            }
+            true
+        })?;
+        Ok(read)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, Errno> {
+        print!("{}", String::from_utf8_lossy(data));
+        Ok(data.len())
+    }
+
+    fn kind(&self) -> FileKind {
+        FileKind::CharDevice
+    }
+
+    fn seek(&self, _to: SeekFrom) -> Result<u64, Errno> {
+        Err(Errno::ESPIPE)
+    }
+
+    fn is_terminal(&self) -> bool {
+        true
+    }
+
+    fn ready(&self) -> Ready {
+        let input = interrupts::without_interrupts(|| !CONSOLE_INPUT.lock().lines.is_empty());
+        Ready {
+            read: input,
+            write: true,
+        }
+    }
+}
+
+// Discards writes and is always at the end
+struct Null;
+
+impl File for Null {
+    fn read(&self, _buffer: &mut [u8]) -> Result<usize, Errno> {
+        Ok(0)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, Errno> {
+        Ok(data.len())
+    }
+
+    fn kind(&self) -> FileKind {
+        FileKind::CharDevice
+    }
+}
+
+// Reads as zeros, discards writes
+struct Zero;
+
+impl File for Zero {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Errno> {
+        buffer.fill(0);
+        Ok(buffer.len())
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, Errno> {
+        Ok(data.len())
+    }
+
+    fn kind(&self) -> FileKind {
+        FileKind::CharDevice
+    }
+}
+
+#[test_case]
+fn descriptor_table() {
+    print!("file descriptors... ");
+    let mut table = FdTable::new();
+    let null = open(b"/dev/null", O_RDONLY).unwrap();
+    let zero = open(b"/dev/zero", O_RDWR).unwrap();
+    assert_eq!(table.insert(null), Ok(0));
+    assert_eq!(table.insert(zero), Ok(1));
+    assert_eq!(table.dup(1), Ok(2));
+
+    // Lowest free first
+    assert!(table.close(0).is_ok());
+    assert_eq!(table.dup(2), Ok(0));
+    assert!(table.close(0).is_ok());
+    assert_eq!(table.close(0).err(), Some(Errno::EBADF));
+
+    let mut buffer = [1u8; 4];
+    assert_eq!(table.get(2).unwrap().read(&mut buffer), Ok(4));
+    assert_eq!(buffer, [0; 4]);
+    assert!(table.dup2(2, 7).unwrap().is_none());
+    assert_eq!(table.get(7).unwrap().write(b"x"), Ok(1));
+    assert_eq!(table.dup2(2, MAX_FDS as u64).err(), Some(Errno::EBADF));
+
+    // Close-on-exec is per file descriptor, duplicates don't inherit it
+    let null = open(b"/dev/null", O_RDONLY).unwrap();
+    assert_eq!(table.insert_from(null, 3, true), Ok(3));
+    assert_eq!(table.cloexec(3), Ok(true));
+    assert_eq!(table.dup(3), Ok(0));
+    assert_eq!(table.cloexec(0), Ok(false));
+    assert!(table.set_cloexec(2, true).is_ok());
+    assert_eq!(table.close_on_exec().len(), 2);
+    assert_eq!(table.get(2).err(), Some(Errno::EBADF));
+    assert_eq!(table.get(3).err(), Some(Errno::EBADF));
+    assert!(table.get(0).is_ok() && table.get(7).is_ok());
+    assert_eq!(table.cloexec(3), Err(Errno::EBADF));
+
+    // Access modes are checked
+    let table = FdTable::console();
+    let null = open(b"/dev/null", O_RDONLY).unwrap();
+    assert_eq!(null.write(b"x"), Err(Errno::EBADF));
+    assert!(table.get(1).is_ok());
+    assert_eq!(table.get(3).err(), Some(Errno::EBADF));
+    assert_eq!(open(b"/dev/missing", O_RDONLY).err(), Some(Errno::ENOENT));
+    let dev = open(b"/dev", O_RDONLY).unwrap();
+    assert_eq!(dev.kind(), FileKind::Directory);
+    assert_eq!(dev.read(&mut buffer), Err(Errno::EISDIR));
+    assert_eq!(open(b"/dev", O_RDWR).err(), Some(Errno::EISDIR));
+    println!("[ok]");
+}
+
+// Typed lines are read one at a time, after editing and discarding
+#[test_case]
+fn console_lines() {
+    print!("console input... ");
+    let console = open(b"/dev/console", O_RDONLY).unwrap();
+    assert!(!console.ready().read);
+    for &byte in b"x\x03ab\x7fc\rsecond" {
+        console_input(byte);
+    }
+    assert!(console.ready().read);
+    let mut buffer = [0u8; 16];
+    assert_eq!(console.read(&mut buffer), Ok(3));
+    assert_eq!(&buffer[..3], b"ac\n");
+    assert!(!console.ready().read);
+    console_input(b'\n');
+    assert_eq!(console.read(&mut buffer[..4]), Ok(4));
+    assert_eq!(console.read(&mut buffer[4..]), Ok(3));
+    assert_eq!(&buffer[..7], b"second\n");
+    println!("[ok]");
+}
+
+#[test_case]
+fn seek_images() {
+    print!("file positions... ");
+    programs::register("/bin/seek-test", b"0123456789");
+    let image = open(b"/bin/seek-test", O_RDONLY).unwrap();
+    assert_eq!(image.size(), 10);
+    let mut buffer = [0u8; 8];
+    assert_eq!(image.read(&mut buffer[..4]), Ok(4));
+    assert_eq!(image.seek(SeekFrom::Current(2)), Ok(6));
+    assert_eq!(image.read(&mut buffer), Ok(4));
+    assert_eq!(&buffer[..4], b"6789");
+    assert_eq!(image.seek(SeekFrom::End(-3)), Ok(7));
+    assert_eq!(image.read(&mut buffer), Ok(3));
+    assert_eq!(image.seek(SeekFrom::Start(20)), Ok(20));
+    assert_eq!(image.read(&mut buffer), Ok(0));
+    assert_eq!(image.seek(SeekFrom::Current(-30)), Err(Errno::EINVAL));
+    assert_eq!(image.seek(SeekFrom::Current(0)), Ok(20));
+
+    let null = open(b"/dev/null", O_RDONLY).unwrap();
+    assert_eq!(null.seek(SeekFrom::End(5)), Ok(0));
+    assert_eq!(null.size(), 0);
+    let console = open(b"/dev/console", O_RDONLY).unwrap();
+    assert_eq!(console.seek(SeekFrom::Start(0)), Err(Errno::ESPIPE));
+    println!("[ok]");
+}
+
+#[test_case]
+fn normalize_paths() {
+    print!("path normalization... ");
+    assert_eq!(normalize(b"/bin/../dev//./null"), b"/dev/null");
+    assert_eq!(normalize(b"bin/echo/"), b"/bin/echo");
+    assert_eq!(normalize(b"/../.."), b"/");
+    assert_eq!(normalize(b"."), b"/");
+    println!("[ok]");
+}
@@ -0,0 +1,258 @@
+use crate::cpu::{self, Feature};
+use crate::{println, scheduler};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use raw_cpuid::cpuid;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+#[cfg(test)]
+use crate::{print, task};
+
+// Intel SDM Vol. 1 13.1 XSAVE-Supported Features and State-Component Bitmaps
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+const XCR0_OPMASK: u64 = 1 << 5;
+const XCR0_ZMM_HI256: u64 = 1 << 6;
+const XCR0_HI16_ZMM: u64 = 1 << 7;
+
+// Vol. 1 10.5.1 FXSAVE Area: x87 control word and MXCSR. The power-up values
+// mask all floating point exceptions.
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+const FCW_DEFAULT: u16 = 0x037f;
+const MXCSR_DEFAULT: u32 = 0x1f80;
+
+const FXSAVE_AREA_SIZE: usize = 512;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+static USE_XSAVEOPT: AtomicBool = AtomicBool::new(false);
+static XCR0: AtomicU64 = AtomicU64::new(0);
+static STATE_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_AREA_SIZE);
+
+// Set up the x87 FPU, SSE and, where XSAVE is available, AVX and AVX-512 on
+// the calling processor. Runs on every processor before it switches tasks.
+pub fn initialize() {
+    assert!(
+        cpu::has(Feature::Fxsr) && cpu::has(Feature::Sse),
+        "FXSAVE and SSE are required"
+    );
+
+    // Vol. 3A 13.1.3 Initialization of the SSE Extensions: no emulation,
+    // native x87 error reporting and WAIT honouring TS, which stays clear
+    // since state is switched eagerly
+    unsafe {
+        Cr0::update(|cr0| {
+            cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|cr4| {
+            cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+            if cpu::has(Feature::Xsave) {
+                cr4.insert(Cr4Flags::OSXSAVE);
+            }
+        });
+    }
+
+    if cpu::has(Feature::Xsave) {
+        // Vol. 1 13.2 Enumeration of CPU Support for XSAVE Instructions and
+        // XSAVE-Supported Features
+        let supported = cpuid!(0xd, 0);
+        let supported = supported.eax as u64 | (supported.edx as u64) << 32;
+        let wanted = XCR0_X87 | XCR0_SSE | XCR0_AVX | XCR0_OPMASK | XCR0_ZMM_HI256 | XCR0_HI16_ZMM;
+        let mut xcr0 = supported & wanted;
+        // AVX-512 state can only be enabled as a whole
+        if xcr0 & (XCR0_OPMASK | XCR0_ZMM_HI256 | XCR0_HI16_ZMM)
+            != XCR0_OPMASK | XCR0_ZMM_HI256 | XCR0_HI16_ZMM
+        {
+            xcr0 &= !(XCR0_OPMASK | XCR0_ZMM_HI256 | XCR0_HI16_ZMM);
+        }
+        unsafe { xsetbv(xcr0) };
+
+        // EBX is the area size for the features now enabled in XCR0
+        let size = cpuid!(0xd, 0).ebx as usize;
+        if XCR0.swap(xcr0, Ordering::Relaxed) == 0 {
+            STATE_SIZE.store(size, Ordering::Relaxed);
+            USE_XSAVE.store(true, Ordering::Relaxed);
+            USE_XSAVEOPT.store(cpu::has(Feature::Xsaveopt), Ordering::Relaxed);
+            println!(
+                "FPU: XSAVE{} with XCR0 0x{:x}, {} byte state",
+                if cpu::has(Feature::Xsaveopt) {
+                    "OPT"
+                } else {
+                    ""
+                },
+                xcr0,
+                size
+            );
+        }
+    } else if XCR0.swap(XCR0_X87 | XCR0_SSE, Ordering::Relaxed) == 0 {
+        println!("FPU: FXSAVE, {} byte state", FXSAVE_AREA_SIZE);
+    }
+
+    unsafe {
+        asm!("fninit", options(nomem, nostack));
+        let mxcsr = MXCSR_DEFAULT;
+        asm!("ldmxcsr [{}]", in(reg) &mxcsr, options(nostack, readonly));
+    }
+}
+
+unsafe fn xsetbv(value: u64) {
+    asm!(
+        "xsetbv",
+        in("ecx") 0,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nomem, nostack),
+    );
+}
+
+pub fn has_avx() -> bool {
+    XCR0.load(Ordering::Relaxed) & XCR0_AVX != 0
+}
+
+pub fn has_avx512() -> bool {
+    XCR0.load(Ordering::Relaxed) & XCR0_HI16_ZMM != 0
+}
+
+// XSAVE needs 64 byte alignment, FXSAVE 16
+#[repr(C, align(64))]
+#[derive(Copy, Clone)]
+struct Block([u8; 64]);
+
+// Saved x87, SSE and AVX registers of a task
+#[derive(Clone)]
+pub struct FpuState {
+    area: Vec<Block>,
+}
+
+impl FpuState {
+    // The state after `initialize`, with all registers zero and all floating
+    // point exceptions masked
+    pub fn new() -> FpuState {
+        let size = STATE_SIZE.load(Ordering::Relaxed);
+        let mut area = vec![Block([0; 64]); size.div_ceil(64)];
+        // With an all zero XSAVE header XRSTOR initializes every component
+        // except MXCSR, which is always loaded
+        let legacy = &mut area[0].0;
+        legacy[FCW_OFFSET..FCW_OFFSET + 2].copy_from_slice(&FCW_DEFAULT.to_le_bytes());
+        legacy[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&MXCSR_DEFAULT.to_le_bytes());
+        FpuState { area }
+    }
+
+    // Store the calling processor's registers
+    pub fn save(&mut self) {
+        let area = self.area.as_mut_ptr();
+        unsafe {
+            if USE_XSAVEOPT.load(Ordering::Relaxed) {
+                asm!("xsaveopt64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX,
+                    options(nostack));
+            } else if USE_XSAVE.load(Ordering::Relaxed) {
+                asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX,
+                    options(nostack));
+            } else {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+            }
+        }
+    }
+
+    // Load the saved registers into the calling processor
+    pub fn restore(&self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX,
+                    options(nostack, readonly));
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) area, options(nostack, readonly));
+            }
+        }
+    }
+}
+
+// Run `f`, which may use any vector registers, e.g. code compiled with
+// `#[target_feature(enable = "avx2")]` after checking `has_avx`. Kernel code
+// is otherwise only compiled for SSE, and interrupt handlers only preserve
+// the registers the compiler knows about. The registers of the interrupted
+// context are saved around `f` and it runs with preemption disabled.
+pub fn with_simd<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _guard = scheduler::preempt_disable();
+    let mut saved = FpuState::new();
+    saved.save();
+    let result = f();
+    saved.restore();
+    result
+}
+
+#[cfg(test)]
+fn mxcsr() -> u32 {
+    let mut mxcsr = 0u32;
+    unsafe { asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack)) };
+    mxcsr
+}
+
+#[cfg(test)]
+fn set_mxcsr(mxcsr: u32) {
+    unsafe { asm!("ldmxcsr [{}]", in(reg) &mxcsr, options(nostack, readonly)) };
+}
+
+// MXCSR bits 13-14 select the rounding mode
+#[cfg(test)]
+const MXCSR_ROUNDING_MASK: u32 = 0b11 << 13;
+
+// Each task keeps its own rounding mode while they take turns on a processor
+#[test_case]
+fn state_is_per_task() {
+    print!("fpu state is per task... ");
+    let handles: Vec<_> = (0..4u32)
+        .map(|mode| {
+            task::Builder::new().pin(0).spawn(move || {
+                let expected = MXCSR_DEFAULT & !MXCSR_ROUNDING_MASK | mode << 13;
+                set_mxcsr(expected);
+                for _ in 0..100 {
+                    task::yield_now();
+                    if mxcsr() != expected {
+                        return false;
+                    }
+                }
+                true
+            })
+        })
+        .collect();
+    assert!(handles.into_iter().all(|handle| handle.join()));
+    println!("[ok]");
+}
+
+#[test_case]
+fn simd_region_restores_state() {
+    print!("simd region... ");
+    let before = mxcsr();
+    with_simd(|| set_mxcsr(MXCSR_DEFAULT | MXCSR_ROUNDING_MASK));
+    assert_eq!(mxcsr(), before);
+    println!("[ok]");
+}
+
+#[cfg(test)]
+#[target_feature(enable = "avx")]
+unsafe fn add_avx(a: [f64; 4], b: [f64; 4]) -> [f64; 4] {
+    use core::arch::x86_64::{_mm256_add_pd, _mm256_loadu_pd, _mm256_storeu_pd};
+    let mut sum = [0.0; 4];
+    let v = _mm256_add_pd(_mm256_loadu_pd(a.as_ptr()), _mm256_loadu_pd(b.as_ptr()));
+    _mm256_storeu_pd(sum.as_mut_ptr(), v);
+    sum
+}
+
+#[test_case]
+fn avx_in_simd_region() {
+    print!("avx in simd region... ");
+    if has_avx() {
+        let sum = with_simd(|| unsafe { add_avx([1.0, 2.0, 3.0, 4.0], [0.5; 4]) });
+        assert_eq!(sum, [1.5, 2.5, 3.5, 4.5]);
+    }
+    println!("[ok]");
+}
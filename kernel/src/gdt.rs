@@ -1,61 +1,114 @@
-use lazy_static::lazy_static;
 use x86_64::instructions::segmentation::{Segment, CS, SS};
 use x86_64::instructions::tables::load_tss;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::VirtAddr;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+
+// Every processor's GDT has the same layout. SYSRET expects the user data
+// segment right after the kernel data segment and the 64-bit user code
+// segment after that (Intel SDM Vol. 3A 5.8.8 Fast System Calls in 64-Bit
+// Mode).
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+
+const IST_STACK_SIZE: usize = 4096 * 5;
 
 // https://os.phil-opp.com/double-fault-exceptions/#the-ist-and-tss
 // Double Faults - The IST and TSS - Writing an OS in Rust Philipp Oppermann's blog
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            
-            stack_start + STACK_SIZE
-        };
-        tss
-    };
+#[repr(C, align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+impl IstStack {
+    fn top(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self) + IST_STACK_SIZE
+    }
 }
 
 struct Selectors {
     pub kernel_code_selector: SegmentSelector,
     pub kernel_data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
     pub tss_selector: SegmentSelector,
 }
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
+// Descriptor tables of a single processor. A TSS descriptor is marked busy
+// once loaded and can't be loaded by a second processor, so every processor
+// needs its own TSS and a GDT describing it.
+pub struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    tss: TaskStateSegment,
+    selectors: Selectors,
+    double_fault_stack: IstStack,
+    // NMIs can arrive at any instruction, even while the kernel stack is in an
+    // inconsistent state, so they always run on a known good stack
+    nmi_stack: IstStack,
+}
 
-        let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+impl CpuTables {
+    pub const fn new() -> CpuTables {
+        CpuTables {
+            gdt: GlobalDescriptorTable::new(),
+            tss: TaskStateSegment::new(),
+            selectors: Selectors {
+                kernel_code_selector: SegmentSelector(0),
+                kernel_data_selector: SegmentSelector(0),
+                user_data_selector: SegmentSelector(0),
+                user_code_selector: SegmentSelector(0),
+                tss_selector: SegmentSelector(0),
+            },
+            double_fault_stack: IstStack([0; IST_STACK_SIZE]),
+            nmi_stack: IstStack([0; IST_STACK_SIZE]),
+        }
+    }
 
-        (
+    // Build the tables and load them on the calling processor. `percpu` is
+    // stored at the top of the NMI stack for `interrupt::nmi_entry`.
+    pub fn load(&'static mut self, percpu: u64) {
+        let CpuTables {
             gdt,
-            Selectors {
-                kernel_code_selector,
-                kernel_data_selector,
-                tss_selector,
-            },
-        )
-    };
-}
+            tss,
+            selectors,
+            double_fault_stack,
+            nmi_stack,
+        } = self;
+
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack.top();
+        let nmi_stack_top = nmi_stack.top() - 16u64;
+        unsafe { nmi_stack_top.as_mut_ptr::<u64>().write(percpu) };
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = nmi_stack_top;
+        let tss: &'static TaskStateSegment = tss;
+
+        selectors.kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        selectors.kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        selectors.user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        selectors.user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        selectors.tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+        let gdt: &'static GlobalDescriptorTable = gdt;
 
-pub fn initialize() {
-    GDT.0.load();
+        assert_eq!(selectors.kernel_code_selector, KERNEL_CODE_SELECTOR);
+        assert_eq!(selectors.kernel_data_selector, KERNEL_DATA_SELECTOR);
+        assert_eq!(selectors.user_data_selector, USER_DATA_SELECTOR);
+        assert_eq!(selectors.user_code_selector, USER_CODE_SELECTOR);
+
+        gdt.load();
+
+        unsafe {
+            SS::set_reg(selectors.kernel_data_selector);
+            CS::set_reg(selectors.kernel_code_selector);
+            load_tss(selectors.tss_selector);
+        }
+    }
 
-    unsafe {
-        SS::set_reg(GDT.1.kernel_data_selector);
-        CS::set_reg(GDT.1.kernel_code_selector);
-        load_tss(GDT.1.tss_selector);
+    // Where the TSS keeps the stack pointer loaded on entry from user mode
+    // (Vol. 3A 6.12.1 Exception- or Interrupt-Handler Procedures)
+    pub fn kernel_stack_slot(&mut self) -> *mut u64 {
+        core::ptr::addr_of_mut!(self.tss.privilege_stack_table) as *mut u64
     }
 }
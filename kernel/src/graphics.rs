@@ -125,10 +125,11 @@ impl<'a> OriginDimensions for GopDisplay<'a> {
 
 // unsafe impl<'a> Send for GopDisplay<'a> {}
 
-pub fn initialize(fb: *mut FrameBuffer, mi: *mut ModeInfo) {
+// Safety: `fb` and `mi` point to the loader's frame buffer and mode info
+pub unsafe fn initialize(fb: *mut FrameBuffer, mi: *mut ModeInfo) {
     // Fill window black
-    let hor_res = unsafe { (*mi).hor_res } as usize;
-    let ver_res = unsafe { (*mi).ver_res } as usize;
+    let hor_res = (*mi).hor_res as usize;
+    let ver_res = (*mi).ver_res as usize;
 
     let character_style = MonoTextStyle::new(&FONT_8X13, Rgb888::BLACK);
     let text_style = TextStyleBuilder::new()
@@ -137,7 +138,7 @@ pub fn initialize(fb: *mut FrameBuffer, mi: *mut ModeInfo) {
         .build();
 
     GOP_DISPLAY.lock().replace(GopDisplay {
-        base: unsafe { (*fb).base },
+        base: (*fb).base,
         x: CHAR_WIDTH,
         y: CHAR_HEIGHT,
         hor_res,
@@ -200,6 +201,18 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+// Blank the console and start writing at the top left again
+pub fn clear() {
+    if let Some(display) = GOP_DISPLAY.lock().as_mut() {
+        display.clear(RgbColor::WHITE).unwrap();
+        display.x = CHAR_WIDTH;
+        display.y = CHAR_HEIGHT;
+    }
+
+    // ANSI erase display and cursor home for the terminal on the other end
+    crate::serial::write_str("\x1b[2J\x1b[H");
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     GOP_DISPLAY
@@ -0,0 +1,114 @@
+use crate::acpi;
+use spin::Once;
+
+// IA-PC HPET (High Precision Event Timers) Specification 1.0a
+// 2.3 Register Descriptions
+
+const GENERAL_CAPABILITIES: usize = 0x000;
+const GENERAL_CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+
+const fn timer_configuration(n: usize) -> usize {
+    0x100 + 0x20 * n
+}
+
+const fn timer_comparator(n: usize) -> usize {
+    0x108 + 0x20 * n
+}
+
+const fn timer_fsb_route(n: usize) -> usize {
+    0x110 + 0x20 * n
+}
+
+const ENABLE_CNF: u64 = 1 << 0;
+
+const TN_INT_ENB_CNF: u64 = 1 << 2;
+const TN_TYPE_CNF: u64 = 1 << 3;
+const TN_PER_INT_CAP: u64 = 1 << 4;
+const TN_VAL_SET_CNF: u64 = 1 << 6;
+const TN_FSB_EN_CNF: u64 = 1 << 14;
+const TN_FSB_INT_DEL_CAP: u64 = 1 << 15;
+
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+pub struct Hpet {
+    base: u64,
+    // Main counter tick period in femtoseconds
+    period: u64,
+    timers: usize,
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+pub fn initialize() -> Option<&'static Hpet> {
+    if let Some(hpet) = HPET.get() {
+        return Some(hpet);
+    }
+
+    let table = acpi::hpet()?;
+    let base = table.base_address.address;
+
+    let capabilities = unsafe { read(base, GENERAL_CAPABILITIES) };
+    let hpet = Hpet {
+        base,
+        period: capabilities >> 32,
+        timers: ((capabilities >> 8) & 0x1f) as usize + 1,
+    };
+
+    unsafe {
+        let config = read(base, GENERAL_CONFIGURATION);
+        write(base, GENERAL_CONFIGURATION, config | ENABLE_CNF);
+    }
+
+    Some(HPET.call_once(|| hpet))
+}
+
+unsafe fn read(base: u64, offset: usize) -> u64 {
+    core::ptr::read_volatile((base as usize + offset) as *const u64)
+}
+
+unsafe fn write(base: u64, offset: usize, value: u64) {
+    core::ptr::write_volatile((base as usize + offset) as *mut u64, value);
+}
+
+impl Hpet {
+    pub fn counter(&self) -> u64 {
+        unsafe { read(self.base, MAIN_COUNTER) }
+    }
+
+    pub fn ticks_per_second(&self) -> u64 {
+        FEMTOSECONDS_PER_SECOND / self.period
+    }
+
+    // Find a comparator which can fire periodically and deliver its interrupt
+    // as a front side bus message, i.e. without any IO APIC routing
+    pub fn find_periodic_fsb_timer(&self) -> Option<usize> {
+        (0..self.timers).find(|n| {
+            let config = unsafe { read(self.base, timer_configuration(*n)) };
+            config & TN_PER_INT_CAP != 0 && config & TN_FSB_INT_DEL_CAP != 0
+        })
+    }
+
+    // Program comparator `n` to send the MSI `address`/`data` pair every
+    // `interval` main counter ticks
+    pub fn start_periodic_fsb(&self, n: usize, interval: u64, address: u32, data: u32) {
+        unsafe {
+            write(
+                self.base,
+                timer_fsb_route(n),
+                (address as u64) << 32 | data as u64,
+            );
+
+            let config = read(self.base, timer_configuration(n));
+            write(
+                self.base,
+                timer_configuration(n),
+                config | TN_FSB_EN_CNF | TN_TYPE_CNF | TN_VAL_SET_CNF | TN_INT_ENB_CNF,
+            );
+            // With TN_VAL_SET_CNF the first write sets the comparator and the
+            // second one sets the period
+            write(self.base, timer_comparator(n), self.counter() + interval);
+            write(self.base, timer_comparator(n), interval);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use crate::cpu::{self, Feature};
+use crate::interrupt::{self, TIMER_HZ};
+use crate::percpu::{self, PerCpu};
+use crate::{println, time};
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use raw_cpuid::cpuid;
+use x86_64::instructions::interrupts;
+
+#[cfg(test)]
+use crate::print;
+#[cfg(test)]
+use core::sync::atomic::AtomicU64;
+#[cfg(test)]
+use core::time::Duration;
+
+// Idle processors sleep at most this long without a timer interrupt
+const MAX_IDLE_NS: u64 = 1_000_000_000;
+
+// Idle periods expected to last at least this long use the deepest MWAIT
+// state, whose exit latency is too high for shorter ones
+const DEEP_IDLE_NS: u64 = 2_000_000;
+
+const TICK_NS: u64 = 1_000_000_000 / TIMER_HZ as u64;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IdleState {
+    Halt,
+    // MWAIT in C1, and in the deepest C-state the processor reports
+    Shallow,
+    Deep,
+}
+
+pub const IDLE_STATES: usize = 3;
+
+impl IdleState {
+    pub const ALL: [IdleState; IDLE_STATES] =
+        [IdleState::Halt, IdleState::Shallow, IdleState::Deep];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            IdleState::Halt => "hlt",
+            IdleState::Shallow => "C1",
+            IdleState::Deep => "deep",
+        }
+    }
+}
+
+static TICKLESS: AtomicBool = AtomicBool::new(false);
+static USE_MWAIT: AtomicBool = AtomicBool::new(false);
+// MWAIT hint for the deepest C-state
+static DEEP_HINT: AtomicU32 = AtomicU32::new(0);
+
+// Choose how idle processors wait. Stopping the timer needs the local APIC
+// timer and the TSC to keep time meanwhile.
+pub fn initialize() {
+    TICKLESS.store(interrupt::has_apic() && time::has_tsc(), Ordering::Relaxed);
+
+    // Intel SDM Vol. 2B MWAIT, Table 4-11 MWAIT Extension Register (ECX) and
+    // Table 4-12 MWAIT Hints Register (EAX): use it only if interrupts wake
+    // it and the C-states are enumerated
+    if cpu::has(Feature::MonitorMwait) {
+        let leaf = cpuid!(5);
+        if leaf.ecx & 0b11 == 0b11 {
+            // EDX holds the number of sub C-states of C0 to C7 in 4 bits each
+            let deepest = (1..8).rev().find(|&c| (leaf.edx >> (c * 4)) & 0xf != 0);
+            if let Some(c) = deepest {
+                let sub_states = (leaf.edx >> (c * 4)) & 0xf;
+                DEEP_HINT.store((c - 1) << 4 | (sub_states - 1), Ordering::Relaxed);
+                USE_MWAIT.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    println!(
+        "idle: {}, {}",
+        if USE_MWAIT.load(Ordering::Relaxed) {
+            "MWAIT"
+        } else {
+            "HLT"
+        },
+        if TICKLESS.load(Ordering::Relaxed) {
+            "tickless"
+        } else {
+            "periodic tick"
+        }
+    );
+}
+
+// Sleep until an interrupt or, with MWAIT, a write to `need_resched` wakes
+// the processor. Called with interrupts disabled once the run queue is found
+// empty, returns with them enabled.
+pub fn enter() {
+    let cpu = percpu::current();
+    let now = time::nanos();
+
+    let expected_ns = if TICKLESS.load(Ordering::Relaxed) {
+        // Nothing needs this processor's timer until the next timer expires
+        let sleep_ns = time::next_timer()
+            .map_or(MAX_IDLE_NS, |tick| {
+                time::tick_to_nanos(tick).saturating_sub(now)
+            })
+            .min(MAX_IDLE_NS);
+        interrupt::local_apic().set_timer_one_shot(sleep_ns);
+        cpu.tickless.store(true, Ordering::Relaxed);
+        sleep_ns
+    } else {
+        TICK_NS
+    };
+
+    let state = if !USE_MWAIT.load(Ordering::Relaxed) {
+        IdleState::Halt
+    } else if expected_ns >= DEEP_IDLE_NS {
+        IdleState::Deep
+    } else {
+        IdleState::Shallow
+    };
+
+    cpu.idle_since.store(now, Ordering::Relaxed);
+    cpu.idle_state.store(state as u8 + 1, Ordering::Relaxed);
+
+    match state {
+        IdleState::Halt => interrupts::enable_and_hlt(),
+        IdleState::Shallow => mwait(cpu, 0),
+        IdleState::Deep => mwait(cpu, DEEP_HINT.load(Ordering::Relaxed)),
+    }
+
+    interrupts::without_interrupts(|| exit(cpu));
+}
+
+fn mwait(cpu: &PerCpu, hint: u32) {
+    // `scheduler::kick` writes `need_resched` instead of sending an IPI while
+    // `mwait_idle` is set. Clearing `need_resched` first and checking it after
+    // arming the monitor means no write is missed.
+    cpu.need_resched.store(false, Ordering::SeqCst);
+    cpu.mwait_idle.store(true, Ordering::SeqCst);
+    unsafe {
+        asm!(
+            "monitor",
+            in("rax") &cpu.need_resched as *const AtomicBool,
+            in("ecx") 0,
+            in("edx") 0,
+            options(nostack),
+        );
+        if cpu.need_resched.load(Ordering::SeqCst) {
+            interrupts::enable();
+        } else {
+            // Like `sti; hlt`, interrupts are only taken once MWAIT waits
+            asm!("sti", "mwait", in("eax") hint, in("ecx") 0, options(nostack));
+        }
+    }
+    cpu.mwait_idle.store(false, Ordering::SeqCst);
+}
+
+// Record the time spent idle and restart the periodic timer. Called with
+// interrupts disabled when the processor wakes up, or when an interrupt
+// switches to a task first.
+pub fn exit(cpu: &PerCpu) {
+    let state = cpu.idle_state.swap(0, Ordering::Relaxed);
+    if state == 0 {
+        return;
+    }
+
+    let index = state as usize - 1;
+    let elapsed = time::nanos().saturating_sub(cpu.idle_since.load(Ordering::Relaxed));
+    cpu.idle_residency_ns[index].fetch_add(elapsed, Ordering::Relaxed);
+    cpu.idle_entries[index].fetch_add(1, Ordering::Relaxed);
+
+    if cpu.tickless.swap(false, Ordering::Relaxed) {
+        interrupt::local_apic().set_timer_periodic();
+    }
+}
+
+// A processor with nothing to do wakes up in time for the next timer, with or
+// without its periodic tick
+#[test_case]
+fn wakes_for_next_timer() {
+    static FIRED: AtomicBool = AtomicBool::new(false);
+
+    print!("idle until next timer... ");
+    let cpu = percpu::current();
+    let entries = |cpu: &PerCpu| -> u64 {
+        cpu.idle_entries
+            .iter()
+            .map(|entries: &AtomicU64| entries.load(Ordering::Relaxed))
+            .sum()
+    };
+    let before = entries(cpu);
+
+    let deadline = time::deadline_after(Duration::from_millis(30));
+    time::add_timer(deadline, || FIRED.store(true, Ordering::Release));
+    loop {
+        interrupts::disable();
+        if FIRED.load(Ordering::Acquire) {
+            interrupts::enable();
+            break;
+        }
+        enter();
+    }
+
+    assert!(time::ticks() >= deadline);
+    assert!(entries(cpu) > before);
+    assert!(!cpu.tickless.load(Ordering::Relaxed));
+    println!("[ok]");
+}
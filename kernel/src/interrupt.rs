@@ -1,26 +1,150 @@
-use crate::{println, serial};
+use crate::cpu::{self, Feature};
+use crate::usermode::{self, FaultKind};
+use crate::{
+    acpi, gdt, ioapic, ipi, keyboard, percpu, pic, pit, println, process, scheduler, serial, time,
+    user_entry, watchdog,
+};
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use lazy_static::lazy_static;
-use raw_cpuid::CpuId;
 use x86_64::instructions::interrupts;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::{
+    InterruptDescriptorTable, InterruptStackFrame, InterruptStackFrameValue, PageFaultErrorCode,
+};
+use x86_64::VirtAddr;
 
 const T_IRQ0: u8 = 0x20;
 const IRQ_TIMER: u8 = 0;
+pub const IRQ_KEYBOARD: u8 = 1;
+pub const IRQ_COM1: u8 = 4;
+const IRQ_PIC_SPURIOUS_MASTER: u8 = 7;
+const IRQ_PIC_SPURIOUS_SLAVE: u8 = 15;
 const APIC_BASE: u32 = 0xFEE00000;
+const APIC_SPURIOUS_VECTOR: u8 = 0xff;
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+// Intel SDM Vol. 3A 10.12 Extended XAPIC (x2APIC). In x2APIC mode the
+// registers are MSRs from 0x800 on, and APIC IDs are 32 bits wide.
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+const X2APIC_MSR_BASE: u32 = 0x800;
+// Largest APIC ID xAPIC mode can send IPIs to, 0xff is the broadcast
+pub const XAPIC_MAX_ID: u32 = 0xfe;
+
+// Frequency of the timer interrupt, from the local APIC timer or the 8254
+pub const TIMER_HZ: u32 = 100;
+
+// Intel 64 and IA-32 Architectures Software Developer's Manual Vol. 3A
+// 10.5.4 APIC Timer
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_MASKED: u32 = 1 << 16;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// Initial count giving TIMER_HZ interrupts per second, measured once by the
+// bootstrap processor. All local APIC timers run at the same bus frequency.
+static TIMER_INITIAL_COUNT: AtomicU32 = AtomicU32::new(0);
+
+// Every vector that can arrive while a user context runs goes through a
+// stub swapping GS, see `usermode`
+user_entry!(divide_error_entry, divide_error_handler);
+user_entry!(breakpoint_entry, breakpoint_handler);
+user_entry!(overflow_entry, overflow_handler);
+user_entry!(bound_range_exceeded_entry, bound_range_exceeded_handler);
+user_entry!(invalid_opcode_entry, invalid_opcode_handler);
+user_entry!(device_not_available_entry, device_not_available_handler);
+user_entry!(
+    segment_not_present_entry,
+    segment_not_present_handler,
+    error_code
+);
+user_entry!(
+    stack_segment_fault_entry,
+    stack_segment_fault_handler,
+    error_code
+);
+user_entry!(
+    general_protection_fault_entry,
+    general_protection_fault_handler,
+    error_code
+);
+user_entry!(page_fault_entry, page_fault_handler, error_code);
+user_entry!(x87_floating_point_entry, x87_floating_point_handler);
+user_entry!(alignment_check_entry, alignment_check_handler, error_code);
+user_entry!(simd_floating_point_entry, simd_floating_point_handler);
+user_entry!(timer_entry, timer_handler);
+user_entry!(keyboard_entry, keyboard::interrupt_handler);
+user_entry!(serial_entry, serial::interrupt_handler);
+user_entry!(pic_spurious_master_entry, pic_spurious_master_handler);
+user_entry!(pic_spurious_slave_entry, pic_spurious_slave_handler);
+user_entry!(call_function_entry, ipi::call_function_handler);
+user_entry!(reschedule_entry, scheduler::reschedule_handler);
+user_entry!(apic_spurious_entry, apic_spurious_handler);
+
+fn entry_addr(entry: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(entry as *const () as u64)
+}
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
         idt.double_fault.set_handler_fn(double_fault_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            idt.divide_error
+                .set_handler_addr(entry_addr(divide_error_entry));
+            idt.breakpoint
+                .set_handler_addr(entry_addr(breakpoint_entry));
+            idt.overflow.set_handler_addr(entry_addr(overflow_entry));
+            idt.bound_range_exceeded
+                .set_handler_addr(entry_addr(bound_range_exceeded_entry));
+            idt.invalid_opcode
+                .set_handler_addr(entry_addr(invalid_opcode_entry));
+            idt.device_not_available
+                .set_handler_addr(entry_addr(device_not_available_entry));
+            idt.segment_not_present
+                .set_handler_addr(entry_addr(segment_not_present_entry));
+            idt.stack_segment_fault
+                .set_handler_addr(entry_addr(stack_segment_fault_entry));
+            idt.general_protection_fault
+                .set_handler_addr(entry_addr(general_protection_fault_entry));
+            idt.page_fault
+                .set_handler_addr(entry_addr(page_fault_entry));
+            idt.x87_floating_point
+                .set_handler_addr(entry_addr(x87_floating_point_entry));
+            idt.alignment_check
+                .set_handler_addr(entry_addr(alignment_check_entry));
+            idt.simd_floating_point
+                .set_handler_addr(entry_addr(simd_floating_point_entry));
+            idt.non_maskable_interrupt
+                .set_handler_addr(entry_addr(nmi_entry))
+                .set_stack_index(gdt::NMI_IST_INDEX);
 
-        idt[(T_IRQ0 + IRQ_TIMER) as usize].set_handler_fn(timer_handler);
+            idt[(T_IRQ0 + IRQ_TIMER) as usize].set_handler_addr(entry_addr(timer_entry));
+            idt[(T_IRQ0 + IRQ_KEYBOARD) as usize].set_handler_addr(entry_addr(keyboard_entry));
+            idt[(T_IRQ0 + IRQ_COM1) as usize].set_handler_addr(entry_addr(serial_entry));
+            idt[(T_IRQ0 + IRQ_PIC_SPURIOUS_MASTER) as usize]
+                .set_handler_addr(entry_addr(pic_spurious_master_entry));
+            idt[(T_IRQ0 + IRQ_PIC_SPURIOUS_SLAVE) as usize]
+                .set_handler_addr(entry_addr(pic_spurious_slave_entry));
+            idt[ipi::CALL_FUNCTION_VECTOR as usize]
+                .set_handler_addr(entry_addr(call_function_entry));
+            idt[scheduler::RESCHEDULE_VECTOR as usize]
+                .set_handler_addr(entry_addr(reschedule_entry));
+            idt[APIC_SPURIOUS_VECTOR as usize].set_handler_addr(entry_addr(apic_spurious_entry));
+        }
         idt
     };
     static ref LAPIC: &'static Apic = unsafe { Apic::get() };
 }
 
+// Whether interrupts go through the local APIC or the legacy 8259 PIC
+static USE_APIC: AtomicBool = AtomicBool::new(false);
+
+// Whether every local APIC is used in x2APIC mode. Only when the firmware
+// enabled it or a processor has an APIC ID xAPIC mode can't address.
+static X2APIC: AtomicBool = AtomicBool::new(false);
+
 #[repr(C)]
 pub struct Apic {
     _researved1: [u32; 2],
@@ -61,29 +185,170 @@ impl Apic {
     }
 
     pub fn initialize(&self) {
+        if X2APIC.load(Ordering::Relaxed) {
+            // Going back to xAPIC mode would take disabling the APIC, so
+            // this is for good
+            unsafe {
+                let mut base = Msr::new(IA32_APIC_BASE);
+                let value = base.read();
+                base.write(value | APIC_BASE_GLOBAL_ENABLE | APIC_BASE_X2APIC_ENABLE);
+            }
+        }
+
+        // Firmware enables the bootstrap processor's APIC, but after INIT an
+        // application processor's APIC is software disabled and ignores LVT
+        // writes
+        self.write(
+            Offset::SpuriousInterruptVector,
+            APIC_SOFTWARE_ENABLE | APIC_SPURIOUS_VECTOR as u32,
+        );
+
+        let mut count = TIMER_INITIAL_COUNT.load(Ordering::Relaxed);
+        if count == 0 {
+            count = self.calibrate_timer();
+            TIMER_INITIAL_COUNT.store(count, Ordering::Relaxed);
+        }
+
         self.write(
             Offset::TimerLocalVectorTableEntry,
-            0x20000 | (T_IRQ0 + IRQ_TIMER) as u32,
+            TIMER_PERIODIC | (T_IRQ0 + IRQ_TIMER) as u32,
         );
-        self.write(Offset::TimerDivideConfiguration, 0b1011);
-        self.write(Offset::TimerInitialCount, 200000000);
+        self.write(Offset::TimerDivideConfiguration, TIMER_DIVIDE_BY_16);
+        self.write(Offset::TimerInitialCount, count);
         self.eoi();
     }
 
+    // Interrupt once after `nanos`, or as late as the counter allows
+    pub fn set_timer_one_shot(&self, nanos: u64) {
+        let count_per_tick = TIMER_INITIAL_COUNT.load(Ordering::Relaxed) as u128;
+        let tick_ns = 1_000_000_000 / TIMER_HZ as u128;
+        let count = (nanos as u128 * count_per_tick / tick_ns).clamp(1, u32::MAX as u128);
+        self.write(
+            Offset::TimerLocalVectorTableEntry,
+            (T_IRQ0 + IRQ_TIMER) as u32,
+        );
+        self.write(Offset::TimerInitialCount, count as u32);
+    }
+
+    // Go back to interrupting TIMER_HZ times per second
+    pub fn set_timer_periodic(&self) {
+        self.write(
+            Offset::TimerLocalVectorTableEntry,
+            TIMER_PERIODIC | (T_IRQ0 + IRQ_TIMER) as u32,
+        );
+        self.write(
+            Offset::TimerInitialCount,
+            TIMER_INITIAL_COUNT.load(Ordering::Relaxed),
+        );
+    }
+
+    // Count how far the timer gets in 10 ms, measured with the 8254, and
+    // return the initial count for a period of 1 / TIMER_HZ seconds
+    fn calibrate_timer(&self) -> u32 {
+        self.write(
+            Offset::TimerLocalVectorTableEntry,
+            TIMER_MASKED | (T_IRQ0 + IRQ_TIMER) as u32,
+        );
+        self.write(Offset::TimerDivideConfiguration, TIMER_DIVIDE_BY_16);
+        self.write(Offset::TimerInitialCount, u32::MAX);
+        pit::wait_us(10_000);
+        let elapsed = u32::MAX - self.read(Offset::TimerCurrentCount);
+        self.write(Offset::TimerInitialCount, 0);
+
+        let count = (elapsed as u64 * 100 / TIMER_HZ as u64).max(1) as u32;
+        println!(
+            "APIC timer: {} kHz bus clock, initial count {}",
+            elapsed as u64 * 16 / 10,
+            count
+        );
+        count
+    }
+
     pub fn eoi(&self) {
         self.write(Offset::EndOfInterrupt, 0);
     }
 
+    // Send an INIT IPI, which resets the target processor and leaves it
+    // waiting for a startup IPI
+    pub fn send_init(&self, apic_id: u32) {
+        self.send_command(apic_id, ICR_DELIVERY_MODE_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    // Send a startup IPI, the target starts executing in real mode at
+    // `page` << 12
+    pub fn send_startup(&self, apic_id: u32, page: u8) {
+        self.send_command(
+            apic_id,
+            ICR_DELIVERY_MODE_STARTUP | ICR_LEVEL_ASSERT | page as u32,
+        );
+    }
+
+    // Writing the low half of the interrupt command register sends the IPI.
+    // An interrupt handler sending its own IPI between the two writes would
+    // redirect this one, so interrupts are off. NMIs can't be kept out, so
+    // the high half is put back for a send the NMI may have interrupted.
+    // In x2APIC mode it is one register written at once, but the write
+    // isn't serializing, so earlier stores are fenced for the target to see.
+    pub fn send_command(&self, apic_id: u32, command: u32) {
+        if X2APIC.load(Ordering::Relaxed) {
+            let icr = X2APIC_MSR_BASE + (Offset::InterruptCommand as u32 >> 4);
+            unsafe {
+                asm!("mfence", "lfence", options(nostack, preserves_flags));
+                Msr::new(icr).write((apic_id as u64) << 32 | command as u64);
+            }
+            return;
+        }
+        interrupts::without_interrupts(|| {
+            self.wait_for_delivery();
+            let high = self.read(Offset::InterruptCommandHigh);
+            self.write(Offset::InterruptCommandHigh, apic_id << 24);
+            self.write(Offset::InterruptCommand, command);
+            self.wait_for_delivery();
+            self.write(Offset::InterruptCommandHigh, high);
+        });
+    }
+
+    fn wait_for_delivery(&self) {
+        while self.read(Offset::InterruptCommand) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        if X2APIC.load(Ordering::Relaxed) {
+            self.read(Offset::Id)
+        } else {
+            self.read(Offset::Id) >> 24
+        }
+    }
+
+    pub fn read(&self, index: Offset) -> u32 {
+        if X2APIC.load(Ordering::Relaxed) {
+            return unsafe { Msr::new(X2APIC_MSR_BASE + (index as u32 >> 4)).read() as u32 };
+        }
+        unsafe { core::ptr::read_volatile((APIC_BASE + index as u32) as *const u32) }
+    }
+
     pub fn write(&self, index: Offset, value: u32) {
+        if X2APIC.load(Ordering::Relaxed) {
+            unsafe { Msr::new(X2APIC_MSR_BASE + (index as u32 >> 4)).write(value as u64) };
+            return;
+        }
         unsafe {
             core::ptr::write_volatile((APIC_BASE + index as u32) as *mut u32, value);
         }
     }
 }
 
+// Interrupt command register fields
+const ICR_DELIVERY_MODE_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_MODE_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
 #[repr(usize)]
 pub enum Offset {
-    _Id = 0x20,
+    Id = 0x20,
     _Version = 0x30,
     _TaskPriority = 0x80,
     _ArbitrationPriority = 0x90,
@@ -92,20 +357,21 @@ pub enum Offset {
     _RemoteRead = 0xc0,
     _LocalDestination = 0xd0,
     _DestinationFormat = 0xe0,
-    _SpuriousInterruptVector = 0xf0,
+    SpuriousInterruptVector = 0xf0,
     _InService = 0x100,
     _TriggerMode = 0x180,
     _InterruptRequest = 0x200,
     _ErrorStatus = 0x280,
-    _InterruptCommand = 0x300,
+    InterruptCommand = 0x300,
+    InterruptCommandHigh = 0x310,
     TimerLocalVectorTableEntry = 0x320,
     _ThermalLocalVectorTableEntry = 0x330,
-    _PerformanceCounterLocalVectorTableEntry = 0x340,
+    PerformanceCounterLocalVectorTableEntry = 0x340,
     _LocalInterrupt0VectorTableEntry = 0x350,
     _LocalInterrupt1VectorTableEntry = 0x360,
     _ErrorVectorTableEntry = 0x370,
     TimerInitialCount = 0x380,
-    _TimerCurrentCount = 0x390,
+    TimerCurrentCount = 0x390,
     TimerDivideConfiguration = 0x3e0,
     _ExtendedApicFeature = 0x400,
     _ExtendedApicControl = 0x410,
@@ -116,40 +382,78 @@ pub enum Offset {
 
 pub fn init() {
     IDT.load();
-    unsafe {
-        disable_pic_8259();
-    }
 
-    Apic::initialize(&LAPIC);
+    // Even with a local APIC the 8259 is remapped first, so that a spurious
+    // IRQ it raises before being masked doesn't look like a CPU exception
+    pic::initialize(T_IRQ0);
+
+    if cpu::has(Feature::Apic) {
+        pic::disable();
+        USE_APIC.store(true, Ordering::Relaxed);
+        X2APIC.store(needs_x2apic(), Ordering::Relaxed);
+        Apic::initialize(&LAPIC);
+        if !ioapic::initialize() {
+            println!("No IOAPIC, device interrupts are unavailable");
+        }
+    } else {
+        println!("No local APIC, using 8259 PIC and 8254 PIT");
+        pit::initialize(TIMER_HZ);
+        pic::unmask(IRQ_TIMER);
+    }
 }
 
-unsafe fn disable_pic_8259() {
-    use x86_64::instructions::port::*;
+// Whether the local APICs have to be used in x2APIC mode: the firmware left
+// the bootstrap processor's in it, or the MADT lists a processor that can
+// only be started with it
+fn needs_x2apic() -> bool {
+    let enabled = unsafe { Msr::new(IA32_APIC_BASE).read() } & APIC_BASE_X2APIC_ENABLE != 0;
+    let large_id =
+        acpi::madt().is_some_and(|madt| madt.processors().any(|apic_id| apic_id > XAPIC_MAX_ID));
+    if !enabled && large_id && !cpu::has(Feature::X2Apic) {
+        println!(
+            "No x2APIC, processors with APIC IDs above {} stay off",
+            XAPIC_MAX_ID
+        );
+        return false;
+    }
+    enabled || large_id
+}
 
-    const PIC1: u16 = 0x20; // IO base address for master PIC
-    const PIC2: u16 = 0xA0; // IO base address for slave PIC
-    const PIC1_DATA: u16 = PIC1 + 1;
-    const PIC2_DATA: u16 = PIC2 + 1;
+// Interrupt setup for application processors, the IDT is shared and each
+// processor has its own local APIC at the same address
+pub fn init_ap() {
+    IDT.load();
+    Apic::initialize(&LAPIC);
+}
 
-    u8::write_to_port(PIC1_DATA, 0xFF);
-    u8::write_to_port(PIC2_DATA, 0xFF);
+// Start delivering ISA interrupt `irq` to the bootstrap processor
+pub fn enable_irq(irq: u8) {
+    if has_apic() {
+        ioapic::route_isa_irq(irq, T_IRQ0 + irq, LAPIC.id());
+    } else {
+        pic::unmask(irq);
+    }
 }
 
-pub fn check_apic() -> bool {
-    let mut apic_supported = false;
-    let cpuid = CpuId::new();
+pub fn has_apic() -> bool {
+    USE_APIC.load(Ordering::Relaxed)
+}
 
-    if let Some(vf) = cpuid.get_vendor_info() {
-        assert!(vf.as_str() == "GenuineIntel" || vf.as_str() == "AuthenticAMD");
-    }
+pub fn is_x2apic() -> bool {
+    X2APIC.load(Ordering::Relaxed)
+}
 
-    if let Some(finfo) = cpuid.get_feature_info() {
-        if finfo.has_apic() {
-            apic_supported = true;
-        }
+// Signal the end of the handler for `irq` to whichever controller delivered it
+pub fn end_of_interrupt(irq: u8) {
+    if has_apic() {
+        LAPIC.eoi();
+    } else {
+        pic::end_of_interrupt(irq);
     }
+}
 
-    apic_supported
+pub fn local_apic() -> &'static Apic {
+    &LAPIC
 }
 
 pub fn enable() {
@@ -161,15 +465,36 @@ pub fn disable() {
 }
 
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
 
+    let address = Cr2::read().as_u64();
+    // Writes to copy-on-write pages, from user mode or while copying to user
+    // memory for a system call
+    if error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && process::copy_on_write(address)
+    {
+        return;
+    }
+    if usermode::handle_fault(
+        &stack_frame,
+        FaultKind::PageFault,
+        error_code.bits(),
+        Some(address),
+    ) || usermode::fixup_fault(&mut stack_frame)
+    {
+        return;
+    }
+
     println!("EXCEPTION: PAGE FAULT\n{:#?}", stack_frame);
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
-    loop {}
+    loop {
+        x86_64::instructions::hlt();
+    }
 }
 
 extern "x86-interrupt" fn double_fault_handler(
@@ -179,20 +504,201 @@ extern "x86-interrupt" fn double_fault_handler(
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+// Exceptions that are bugs in kernel mode, and faults of the current task in
+// user mode
+macro_rules! exception_handler {
+    ($handler:ident, $kind:ident, $name:literal) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+            if !usermode::handle_fault(&stack_frame, FaultKind::$kind, 0, None) {
+                panic!(concat!("EXCEPTION: ", $name, "\n{:#?}"), stack_frame);
+            }
+        }
+    };
+    ($handler:ident, $kind:ident, $name:literal, error_code) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            if !usermode::handle_fault(&stack_frame, FaultKind::$kind, error_code, None) {
+                panic!(
+                    concat!("EXCEPTION: ", $name, " 0x{:x}\n{:#?}"),
+                    error_code, stack_frame
+                );
+            }
+        }
+    };
 }
 
+exception_handler!(divide_error_handler, DivideError, "DIVIDE ERROR");
+exception_handler!(breakpoint_handler, Breakpoint, "BREAKPOINT");
+exception_handler!(overflow_handler, Overflow, "OVERFLOW");
+exception_handler!(
+    bound_range_exceeded_handler,
+    BoundRangeExceeded,
+    "BOUND RANGE EXCEEDED"
+);
+exception_handler!(invalid_opcode_handler, InvalidOpcode, "INVALID OPCODE");
+exception_handler!(
+    device_not_available_handler,
+    DeviceNotAvailable,
+    "DEVICE NOT AVAILABLE"
+);
+exception_handler!(
+    segment_not_present_handler,
+    SegmentNotPresent,
+    "SEGMENT NOT PRESENT",
+    error_code
+);
+exception_handler!(
+    stack_segment_fault_handler,
+    StackSegmentFault,
+    "STACK SEGMENT FAULT",
+    error_code
+);
+exception_handler!(
+    general_protection_fault_handler,
+    GeneralProtection,
+    "GENERAL PROTECTION FAULT",
+    error_code
+);
+exception_handler!(
+    x87_floating_point_handler,
+    FloatingPoint,
+    "x87 FLOATING POINT"
+);
+exception_handler!(
+    alignment_check_handler,
+    AlignmentCheck,
+    "ALIGNMENT CHECK",
+    error_code
+);
+exception_handler!(
+    simd_floating_point_handler,
+    SimdFloatingPoint,
+    "SIMD FLOATING POINT"
+);
+
 pub extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
     disable();
 
-    serial::write_byte(b'*');
+    percpu!(ticks).fetch_add(1, Ordering::Relaxed);
+    time::tick();
+    scheduler::tick();
+
+    end_of_interrupt(IRQ_TIMER);
+
+    // May switch to another task, the interrupt frame stays on this task's
+    // stack until it runs again
+    scheduler::preempt();
+}
+
+// General purpose registers saved by `nmi_entry` and `usermode`, in the
+// reverse order of the pushes, followed by the frame pushed by the CPU
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+extern "C" {
+    fn nmi_entry();
+}
+
+// The x86-interrupt calling convention does not expose the interrupted general
+// purpose registers, which the watchdog needs to dump, so save them by hand.
+// The CPU aligns the stack to 16 bytes before pushing the 5 word frame, and
+// the 17 pushes below, 15 registers and the interrupted GS base, restore that
+// alignment for the call.
+//
+// An NMI may arrive in user mode, or in the kernel right before or after a
+// `swapgs`, so whether GS base points at the per-processor area can't be told
+// from CS. The pointer stored above the frame at the top of the NMI stack is
+// loaded instead and the interrupted GS base restored afterwards.
+global_asm!(
+    ".global nmi_entry",
+    "nmi_entry:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov ecx, {gs_base}",
+    "rdmsr",
+    "push rdx",
+    "push rax",
+    "mov rax, [rsp + 17 * 8 + 40]",
+    "mov rdx, rax",
+    "shr rdx, 32",
+    "wrmsr",
+    "lea rdi, [rsp + 16]",
+    "cld",
+    "call {handler}",
+    "pop rax",
+    "pop rdx",
+    "mov ecx, {gs_base}",
+    "wrmsr",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+    handler = sym nmi_handler,
+    gs_base = const 0xc000_0101u32,
+);
 
-    LAPIC.eoi();
+extern "C" fn nmi_handler(frame: &mut TrapFrame) {
+    percpu!(nmis).fetch_add(1, Ordering::Relaxed);
+    watchdog::handle_nmi(frame);
+}
+
+extern "x86-interrupt" fn pic_spurious_master_handler(_stack_frame: InterruptStackFrame) {
+    if !pic::is_spurious(IRQ_PIC_SPURIOUS_MASTER) {
+        pic::end_of_interrupt(IRQ_PIC_SPURIOUS_MASTER);
+    }
+}
 
-    enable();
+extern "x86-interrupt" fn pic_spurious_slave_handler(_stack_frame: InterruptStackFrame) {
+    if !pic::is_spurious(IRQ_PIC_SPURIOUS_SLAVE) {
+        pic::end_of_interrupt(IRQ_PIC_SPURIOUS_SLAVE);
+    }
 }
 
+// Spurious interrupts from the local APIC must not be acknowledged with an EOI
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: InterruptStackFrame) {}
+
 #[allow(dead_code)]
 pub fn check_double_fault() {
     unsafe {
@@ -0,0 +1,150 @@
+use crate::acpi::{self, MadtEntry};
+use crate::println;
+use spin::Mutex;
+
+// Intel 82093AA I/O Advanced Programmable Interrupt Controller (IOAPIC)
+// 3.1 Memory Mapped Registers for Accessing IOAPIC Registers
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+// 3.2 IOAPIC Registers
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+// 3.2.4 I/O Redirection Table Registers
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+
+// ACPI 6.4 5.2.12.5 Interrupt Source Override Structure, MPS INTI Flags
+const POLARITY_MASK: u16 = 0b11;
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MODE_MASK: u16 = 0b11 << 2;
+const TRIGGER_MODE_LEVEL: u16 = 0b11 << 2;
+
+const MAX_IOAPICS: usize = 4;
+
+#[derive(Debug, Copy, Clone)]
+struct IoApic {
+    base: u64,
+    gsi_base: u32,
+    redirection_entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+            core::ptr::read_volatile((self.base + IOWIN) as *const u32)
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            core::ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+            core::ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+        }
+    }
+
+    fn set_redirection(&self, index: u32, entry: u64) {
+        // Write the high half first so that the entry is never unmasked with
+        // a stale destination
+        self.write(IOREDTBL + index * 2 + 1, (entry >> 32) as u32);
+        self.write(IOREDTBL + index * 2, entry as u32);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.redirection_entries
+    }
+}
+
+struct IoApics {
+    apics: [Option<IoApic>; MAX_IOAPICS],
+}
+
+static IOAPICS: Mutex<IoApics> = Mutex::new(IoApics {
+    apics: [None; MAX_IOAPICS],
+});
+
+// Find the IOAPICs in the MADT and mask all of their inputs. Returns false if
+// there are none.
+pub fn initialize() -> bool {
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return false,
+    };
+
+    let mut ioapics = IOAPICS.lock();
+    let mut count = 0;
+    for entry in madt.entries() {
+        if let MadtEntry::IoApic {
+            address, gsi_base, ..
+        } = entry
+        {
+            if count == MAX_IOAPICS {
+                break;
+            }
+            let mut ioapic = IoApic {
+                base: address as u64,
+                gsi_base,
+                redirection_entries: 0,
+            };
+            ioapic.redirection_entries = ((ioapic.read(IOAPICVER) >> 16) & 0xff) + 1;
+            for index in 0..ioapic.redirection_entries {
+                ioapic.set_redirection(index, REDIRECTION_MASKED);
+            }
+            println!(
+                "IOAPIC at 0x{:x}: GSI {}-{}",
+                address,
+                gsi_base,
+                gsi_base + ioapic.redirection_entries - 1
+            );
+            ioapics.apics[count] = Some(ioapic);
+            count += 1;
+        }
+    }
+
+    count > 0
+}
+
+// Deliver ISA interrupt `irq` as `vector` to the local APIC `apic_id`. ISA
+// interrupts are edge triggered and active high unless the MADT overrides it.
+pub fn route_isa_irq(irq: u8, vector: u8, apic_id: u32) {
+    let mut gsi = irq as u32;
+    let mut flags = 0;
+    if let Some(madt) = acpi::madt() {
+        for entry in madt.entries() {
+            if let MadtEntry::InterruptSourceOverride {
+                source,
+                gsi: override_gsi,
+                flags: override_flags,
+                ..
+            } = entry
+            {
+                if source == irq {
+                    gsi = override_gsi;
+                    flags = override_flags;
+                }
+            }
+        }
+    }
+
+    let mut entry = vector as u64 | (apic_id as u64) << 56;
+    if flags & POLARITY_MASK == POLARITY_ACTIVE_LOW {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if flags & TRIGGER_MODE_MASK == TRIGGER_MODE_LEVEL {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+
+    let ioapics = IOAPICS.lock();
+    match ioapics
+        .apics
+        .iter()
+        .flatten()
+        .find(|ioapic| ioapic.handles(gsi))
+    {
+        Some(ioapic) => ioapic.set_redirection(gsi - ioapic.gsi_base, entry),
+        None => println!("IOAPIC: no IOAPIC handles GSI {} (IRQ {})", gsi, irq),
+    }
+}
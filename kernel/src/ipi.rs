@@ -0,0 +1,152 @@
+use crate::interrupt;
+use crate::percpu;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::structures::idt::InterruptStackFrame;
+
+// Vector used to run a function on other processors
+pub const CALL_FUNCTION_VECTOR: u8 = 0xf0;
+
+// Intel 64 and IA-32 Architectures Software Developer's Manual Vol. 3A
+// 10.6.1 Interrupt Command Register (ICR)
+const ICR_DELIVERY_MODE_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_SHORTHAND_SELF: u32 = 0b01 << 18;
+const ICR_SHORTHAND_ALL_INCLUDING_SELF: u32 = 0b10 << 18;
+const ICR_SHORTHAND_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+#[derive(Debug, Copy, Clone)]
+pub enum Destination {
+    // A single processor by APIC ID
+    Apic(u32),
+    SelfOnly,
+    AllIncludingSelf,
+    AllExcludingSelf,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum DeliveryMode {
+    // Raise the interrupt with the given vector
+    Fixed(u8),
+    Nmi,
+}
+
+pub fn send(destination: Destination, mode: DeliveryMode) {
+    let (apic_id, shorthand) = match destination {
+        Destination::Apic(id) => (id, 0),
+        Destination::SelfOnly => (0, ICR_SHORTHAND_SELF),
+        Destination::AllIncludingSelf => (0, ICR_SHORTHAND_ALL_INCLUDING_SELF),
+        Destination::AllExcludingSelf => (0, ICR_SHORTHAND_ALL_EXCLUDING_SELF),
+    };
+    let mode = match mode {
+        DeliveryMode::Fixed(vector) => ICR_DELIVERY_MODE_FIXED | vector as u32,
+        DeliveryMode::Nmi => ICR_DELIVERY_MODE_NMI,
+    };
+
+    interrupt::local_apic().send_command(apic_id, ICR_LEVEL_ASSERT | shorthand | mode);
+}
+
+// Send to the processor with index `cpu_index`, if it is online
+pub fn send_to_cpu(cpu_index: usize, mode: DeliveryMode) {
+    if let Some(cpu) = percpu::get(cpu_index) {
+        send(Destination::Apic(cpu.apic_id.load(Ordering::Relaxed)), mode);
+    }
+}
+
+// A cross-processor function call in flight. Only one call is active at a
+// time, `CALL_LOCK` serializes the callers.
+static CALL_LOCK: Mutex<()> = Mutex::new(());
+static CALL_FUNCTION: AtomicUsize = AtomicUsize::new(0);
+static CALL_ARGUMENT: AtomicUsize = AtomicUsize::new(0);
+// Processors which still have to run the function, bit n is CPU index n
+static CALL_PENDING: AtomicU64 = AtomicU64::new(0);
+
+// Run `function(argument)` on every processor in `targets` except the caller
+// and wait until all of them have returned. `argument` may point to the
+// caller's stack as it stays alive until then.
+//
+// The caller must not hold locks the function needs, and must not run with
+// interrupts disabled unless no other processor can be waiting for the call
+// lock with interrupts disabled either.
+pub fn call_function(targets: u64, function: fn(usize), argument: usize) {
+    let current = percpu::current().cpu_index;
+    let targets = targets & !(1 << current);
+
+    let mut requested = 0;
+    for cpu in percpu::online() {
+        if targets & (1 << cpu.cpu_index) != 0 {
+            requested |= 1 << cpu.cpu_index;
+        }
+    }
+    if requested == 0 {
+        return;
+    }
+
+    let _guard = lock_servicing_calls();
+
+    CALL_FUNCTION.store(function as usize, Ordering::Relaxed);
+    CALL_ARGUMENT.store(argument, Ordering::Relaxed);
+    CALL_PENDING.store(requested, Ordering::Release);
+
+    for cpu_index in 0..64 {
+        if requested & (1 << cpu_index) != 0 {
+            send_to_cpu(cpu_index, DeliveryMode::Fixed(CALL_FUNCTION_VECTOR));
+        }
+    }
+
+    while CALL_PENDING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+pub fn call_on(cpu_index: usize, function: fn(usize), argument: usize) {
+    if cpu_index == percpu::current().cpu_index {
+        function(argument);
+    } else {
+        call_function(1 << cpu_index, function, argument);
+    }
+}
+
+pub fn call_on_others(function: fn(usize), argument: usize) {
+    call_function(u64::MAX, function, argument);
+}
+
+// Run the function on every processor, including the caller
+pub fn call_on_all(function: fn(usize), argument: usize) {
+    call_on_others(function, argument);
+    function(argument);
+}
+
+// Two processors calling each other would deadlock if the one waiting for the
+// lock had interrupts disabled, so run any call addressed to us while waiting
+fn lock_servicing_calls() -> MutexGuard<'static, ()> {
+    loop {
+        if let Some(guard) = CALL_LOCK.try_lock() {
+            return guard;
+        }
+        run_pending_call();
+        core::hint::spin_loop();
+    }
+}
+
+fn run_pending_call() {
+    let bit = 1 << percpu::current().cpu_index;
+    if CALL_PENDING.load(Ordering::Acquire) & bit == 0 {
+        return;
+    }
+
+    let function = CALL_FUNCTION.load(Ordering::Relaxed);
+    let argument = CALL_ARGUMENT.load(Ordering::Relaxed);
+    // Safety: CALL_FUNCTION was stored from a `fn(usize)` before the pending
+    // bit was published
+    let function: fn(usize) = unsafe { core::mem::transmute(function) };
+    function(argument);
+
+    CALL_PENDING.fetch_and(!bit, Ordering::Release);
+}
+
+pub extern "x86-interrupt" fn call_function_handler(_stack_frame: InterruptStackFrame) {
+    run_pending_call();
+    interrupt::local_apic().eoi();
+}
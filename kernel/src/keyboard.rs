@@ -0,0 +1,95 @@
+use crate::executor::InterruptQueue;
+use crate::interrupt::{self, IRQ_KEYBOARD};
+use crate::{file, print, println};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::stream::{Stream, StreamExt};
+use x86_64::instructions::port::PortRead;
+use x86_64::structures::idt::InterruptStackFrame;
+
+// 8042 PS/2 controller output buffer
+const DATA_PORT: u16 = 0x60;
+
+const SCANCODE_QUEUE_SIZE: usize = 128;
+
+static SCANCODES: InterruptQueue<u8> = InterruptQueue::new();
+
+// Needs the heap for the scancode buffer
+pub fn initialize() {
+    SCANCODES.initialize(SCANCODE_QUEUE_SIZE);
+    interrupt::enable_irq(IRQ_KEYBOARD);
+}
+
+pub extern "x86-interrupt" fn interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let scancode = unsafe { u8::read_from_port(DATA_PORT) };
+    SCANCODES.push(scancode);
+    interrupt::end_of_interrupt(IRQ_KEYBOARD);
+}
+
+// Raw scancodes (set 1 unless the firmware changed it) in the order received
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> ScancodeStream {
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        SCANCODES.poll_pop(cx).map(Some)
+    }
+}
+
+// ASCII for set 1 scancodes up to the space bar, without and with shift, zero
+// for keys that have none
+const KEYMAP: &[u8; 0x3a] =
+    b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const KEYMAP_SHIFT: &[u8; 0x3a] =
+    b"\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+const LEFT_CTRL: u8 = 0x1d;
+const LEFT_SHIFT: u8 = 0x2a;
+const RIGHT_SHIFT: u8 = 0x36;
+const BACKSPACE: u8 = 0x0e;
+const BREAK: u8 = 0x80;
+
+// Type the pressed keys on the console and show them
+pub async fn console_input() {
+    let mut scancodes = ScancodeStream::new();
+    let mut shift = false;
+    let mut ctrl = false;
+    while let Some(scancode) = scancodes.next().await {
+        // Break codes, sent when a key is released, have the top bit set
+        let pressed = scancode & BREAK == 0;
+        match scancode & !BREAK {
+            LEFT_SHIFT | RIGHT_SHIFT => shift = pressed,
+            LEFT_CTRL => ctrl = pressed,
+            _ if !pressed => {}
+            key => {
+                let keymap = if shift { KEYMAP_SHIFT } else { KEYMAP };
+                let mut byte = keymap.get(key as usize).copied().unwrap_or(0);
+                if byte == 0 {
+                    continue;
+                }
+                // Ctrl with a letter types its control character, shown as
+                // e.g. ^C. Ctrl-C also ends the line, which it discards.
+                if ctrl && byte.is_ascii_alphabetic() {
+                    byte &= 0x1f;
+                    print!("^{}", (byte + b'@') as char);
+                    if byte == file::CONSOLE_INTERRUPT {
+                        println!();
+                    }
+                } else if key != BACKSPACE {
+                    // The display can't take characters back
+                    print!("{}", byte as char);
+                }
+                file::console_input(byte);
+            }
+        }
+    }
+}
@@ -0,0 +1,141 @@
+#![no_std]
+#![cfg_attr(test, no_main)]
+#![feature(abi_x86_interrupt)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+// Constructors are `const fn new` for statics and safety requirements are
+// plain comments, the library is only exported to the kernel binary
+#![allow(clippy::new_without_default, clippy::missing_safety_doc)]
+
+extern crate alloc;
+
+pub mod acpi;
+pub mod allocator;
+pub mod cpu;
+pub mod elf;
+pub mod executor;
+pub mod file;
+pub mod fpu;
+pub mod gdt;
+pub mod graphics;
+pub mod hpet;
+pub mod idle;
+pub mod interrupt;
+pub mod ioapic;
+pub mod ipi;
+pub mod keyboard;
+pub mod linux;
+pub mod paging;
+pub mod percpu;
+pub mod pic;
+pub mod pipe;
+pub mod pit;
+pub mod process;
+pub mod programs;
+pub mod scheduler;
+pub mod serial;
+pub mod signal;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod time;
+pub mod tlb;
+pub mod top;
+pub mod usermode;
+pub mod wasi;
+pub mod wasm;
+pub mod watchdog;
+
+use core::time::Duration;
+use graphics::{FrameBuffer, ModeInfo};
+
+#[cfg(test)]
+use core::panic::PanicInfo;
+
+// Bring up the processors, memory, interrupts and tasks with what the loader
+// passed to `kernel_main`, and make the built-in programs available.
+// Safety: `fb` and `mi` point to the loader's frame buffer and mode info
+pub unsafe fn initialize(
+    fb: *mut FrameBuffer,
+    mi: *mut ModeInfo,
+    mm: &paging::MemoryMap,
+    rsdp: u64,
+) {
+    interrupt::disable();
+
+    serial::initialize();
+    serial::write_byte(b'A');
+    serial::write_byte(b'\n');
+    serial::write_str("Hello serial\n");
+
+    graphics::initialize(fb, mi);
+
+    cpu::initialize();
+    fpu::initialize();
+    time::initialize();
+
+    paging::initialize(mm);
+    allocator::initialize();
+
+    acpi::initialize(rsdp);
+
+    percpu::initialize(0);
+    interrupt::init();
+    syscall::initialize();
+
+    if interrupt::has_apic() {
+        serial::write_str("CPU supports APIC\n");
+    }
+    idle::initialize();
+
+    interrupt::enable();
+
+    watchdog::initialize();
+
+    smp::initialize();
+
+    task::initialize();
+
+    programs::register_builtin();
+}
+
+// Handle console input and refresh `top` on the boot processor, forever
+pub fn run() -> ! {
+    keyboard::initialize();
+    serial::initialize_receive();
+
+    let mut executor = executor::Executor::new();
+    executor.spawn(keyboard::console_input());
+    executor.spawn(serial::console_input());
+    executor.spawn(top::refresh(Duration::from_secs(2)));
+    executor.run();
+}
+
+#[cfg(test)]
+#[no_mangle]
+extern "C" fn kernel_main(
+    fb: *mut FrameBuffer,
+    mi: *mut ModeInfo,
+    mm: &paging::MemoryMap,
+    rsdp: u64,
+) {
+    unsafe { initialize(fb, mi, mm, rsdp) };
+    test_main();
+    run();
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("{:?}", info);
+    loop {}
+}
+
+pub fn test_runner(tests: &[&dyn Fn()]) {
+    println!("Running {} tests", tests.len());
+    for test in tests {
+        test();
+    }
+}
@@ -0,0 +1,946 @@
+// The Linux x86-64 system call ABI, for static Linux programs such as ones
+// linked with musl. Programs without the rustyos ELF note make these calls
+// instead of the native ones. Calls that work the same way are handled by the
+// native handlers, the rest is translated here. Only what a C library and a
+// simple shell need is there, everything else fails with ENOSYS.
+use crate::file::{self, FileKind, SeekFrom, O_ACCMODE};
+use crate::interrupt::TrapFrame;
+use crate::paging::USER_END;
+use crate::process::{self, Pid};
+use crate::syscall::{self, args, Errno, Handler, IO_CHUNK};
+use crate::usermode::{self, UserExit};
+use crate::{elf, signal, task, time};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+use x86_64::structures::paging::PageTableFlags;
+
+#[cfg(test)]
+use crate::paging::USER_START;
+#[cfg(test)]
+use crate::usermode::{Asm, Reg};
+#[cfg(test)]
+use crate::{print, println};
+#[cfg(test)]
+use goblin::elf::header::ET_EXEC;
+
+// Linux system call numbers
+const SYS_READ: usize = 0;
+const SYS_WRITE: usize = 1;
+const SYS_OPEN: usize = 2;
+const SYS_CLOSE: usize = 3;
+const SYS_STAT: usize = 4;
+const SYS_FSTAT: usize = 5;
+const SYS_LSTAT: usize = 6;
+const SYS_POLL: usize = 7;
+const SYS_LSEEK: usize = 8;
+const SYS_MMAP: usize = 9;
+const SYS_MPROTECT: usize = 10;
+const SYS_MUNMAP: usize = 11;
+const SYS_BRK: usize = 12;
+const SYS_RT_SIGACTION: usize = 13;
+const SYS_RT_SIGPROCMASK: usize = 14;
+const SYS_RT_SIGRETURN: usize = 15;
+const SYS_IOCTL: usize = 16;
+const SYS_READV: usize = 19;
+const SYS_WRITEV: usize = 20;
+const SYS_ACCESS: usize = 21;
+const SYS_PIPE: usize = 22;
+const SYS_SCHED_YIELD: usize = 24;
+const SYS_MADVISE: usize = 28;
+const SYS_DUP: usize = 32;
+const SYS_DUP2: usize = 33;
+const SYS_NANOSLEEP: usize = 35;
+const SYS_GETPID: usize = 39;
+const SYS_CLONE: usize = 56;
+const SYS_FORK: usize = 57;
+const SYS_VFORK: usize = 58;
+const SYS_EXECVE: usize = 59;
+const SYS_EXIT: usize = 60;
+const SYS_WAIT4: usize = 61;
+const SYS_KILL: usize = 62;
+const SYS_UNAME: usize = 63;
+const SYS_FCNTL: usize = 72;
+const SYS_GETCWD: usize = 79;
+const SYS_UMASK: usize = 95;
+const SYS_GETTIMEOFDAY: usize = 96;
+const SYS_GETUID: usize = 102;
+const SYS_GETGID: usize = 104;
+const SYS_GETEUID: usize = 107;
+const SYS_GETEGID: usize = 108;
+const SYS_SETPGID: usize = 109;
+const SYS_GETPPID: usize = 110;
+const SYS_GETPGRP: usize = 111;
+const SYS_GETPGID: usize = 121;
+const SYS_GETSID: usize = 124;
+const SYS_ARCH_PRCTL: usize = 158;
+const SYS_GETTID: usize = 186;
+const SYS_TKILL: usize = 200;
+const SYS_TIME: usize = 201;
+const SYS_SET_TID_ADDRESS: usize = 218;
+const SYS_CLOCK_GETTIME: usize = 228;
+const SYS_EXIT_GROUP: usize = 231;
+const SYS_TGKILL: usize = 234;
+const SYS_OPENAT: usize = 257;
+const SYS_NEWFSTATAT: usize = 262;
+const SYS_FACCESSAT: usize = 269;
+const SYS_DUP3: usize = 292;
+const SYS_PIPE2: usize = 293;
+const SYSCALL_COUNT: usize = 294;
+
+pub static TABLE: [Option<Handler>; SYSCALL_COUNT] = {
+    let mut table: [Option<Handler>; SYSCALL_COUNT] = [None; SYSCALL_COUNT];
+    table[SYS_READ] = Some(syscall::sys_read);
+    table[SYS_WRITE] = Some(syscall::sys_write);
+    table[SYS_OPEN] = Some(sys_open);
+    table[SYS_CLOSE] = Some(syscall::sys_close);
+    table[SYS_STAT] = Some(sys_stat);
+    table[SYS_FSTAT] = Some(sys_fstat);
+    table[SYS_LSTAT] = Some(sys_stat);
+    table[SYS_POLL] = Some(sys_poll);
+    table[SYS_LSEEK] = Some(sys_lseek);
+    table[SYS_MMAP] = Some(sys_mmap);
+    table[SYS_MPROTECT] = Some(sys_mprotect);
+    table[SYS_MUNMAP] = Some(sys_munmap);
+    table[SYS_BRK] = Some(syscall::sys_brk);
+    table[SYS_RT_SIGACTION] = Some(sys_rt_sigaction);
+    table[SYS_RT_SIGPROCMASK] = Some(sys_rt_sigprocmask);
+    table[SYS_RT_SIGRETURN] = Some(syscall::sys_sigreturn);
+    table[SYS_IOCTL] = Some(sys_ioctl);
+    table[SYS_READV] = Some(sys_readv);
+    table[SYS_WRITEV] = Some(sys_writev);
+    table[SYS_ACCESS] = Some(sys_access);
+    table[SYS_PIPE] = Some(syscall::sys_pipe);
+    table[SYS_SCHED_YIELD] = Some(syscall::sys_yield);
+    table[SYS_MADVISE] = Some(sys_madvise);
+    table[SYS_DUP] = Some(syscall::sys_dup);
+    table[SYS_DUP2] = Some(syscall::sys_dup2);
+    table[SYS_NANOSLEEP] = Some(sys_nanosleep);
+    table[SYS_GETPID] = Some(syscall::sys_getpid);
+    table[SYS_CLONE] = Some(sys_clone);
+    table[SYS_FORK] = Some(syscall::sys_fork);
+    // Without threads sharing the address space, vfork is fork
+    table[SYS_VFORK] = Some(syscall::sys_fork);
+    table[SYS_EXECVE] = Some(syscall::sys_execve);
+    table[SYS_EXIT] = Some(syscall::sys_exit);
+    table[SYS_WAIT4] = Some(sys_wait4);
+    table[SYS_KILL] = Some(syscall::sys_kill);
+    table[SYS_UNAME] = Some(sys_uname);
+    table[SYS_FCNTL] = Some(sys_fcntl);
+    table[SYS_GETCWD] = Some(sys_getcwd);
+    table[SYS_UMASK] = Some(sys_umask);
+    table[SYS_GETTIMEOFDAY] = Some(sys_gettimeofday);
+    table[SYS_GETUID] = Some(sys_getuid);
+    table[SYS_GETGID] = Some(sys_getuid);
+    table[SYS_GETEUID] = Some(sys_getuid);
+    table[SYS_GETEGID] = Some(sys_getuid);
+    table[SYS_SETPGID] = Some(sys_setpgid);
+    table[SYS_GETPPID] = Some(syscall::sys_getppid);
+    table[SYS_GETPGRP] = Some(syscall::sys_getpid);
+    table[SYS_GETPGID] = Some(sys_getpgid);
+    table[SYS_GETSID] = Some(sys_getpgid);
+    table[SYS_ARCH_PRCTL] = Some(sys_arch_prctl);
+    table[SYS_GETTID] = Some(syscall::sys_gettid);
+    table[SYS_TKILL] = Some(sys_tkill);
+    table[SYS_TIME] = Some(sys_time);
+    table[SYS_SET_TID_ADDRESS] = Some(syscall::sys_gettid);
+    table[SYS_CLOCK_GETTIME] = Some(sys_clock_gettime);
+    table[SYS_EXIT_GROUP] = Some(syscall::sys_exit);
+    table[SYS_TGKILL] = Some(sys_tgkill);
+    table[SYS_OPENAT] = Some(sys_openat);
+    table[SYS_NEWFSTATAT] = Some(sys_newfstatat);
+    table[SYS_FACCESSAT] = Some(sys_faccessat);
+    table[SYS_DUP3] = Some(sys_dup3);
+    table[SYS_PIPE2] = Some(sys_pipe2);
+    table
+};
+
+// The directory file descriptor for paths relative to the working directory
+const AT_FDCWD: i64 = -100;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+// Close-on-exec flag of `openat`, `dup3` and `pipe2`. The other flags of
+// `openat` are accepted but have no effect, nothing is ever created,
+// truncated or non-blocking.
+const O_CLOEXEC: u64 = 0o2000000;
+
+// Flag of F_GETFD and F_SETFD
+const FD_CLOEXEC: u64 = 1;
+
+// Bits of `st_mode`
+const S_IFIFO: u32 = 0o010000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+// Size of `struct stat`
+const STAT_SIZE: usize = 144;
+
+// A path from user memory, relative to `dirfd` unless it's absolute. The
+// working directory is always the root.
+fn resolve(dirfd: u64, path: u64) -> Result<Vec<u8>, Errno> {
+    let path = syscall::copy_path(path)?;
+    if path.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+    if path[0] != b'/' && dirfd as i64 != AT_FDCWD {
+        // No open file is a directory
+        syscall::file(dirfd)?;
+        return Err(Errno::ENOTDIR);
+    }
+    Ok(file::normalize(&path))
+}
+
+fn open_at(dirfd: u64, path: u64, flags: u64) -> Result<u64, Errno> {
+    let path = resolve(dirfd, path)?;
+    let file = file::open(&path, flags & O_ACCMODE)?;
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    process.with_files(|files| files.insert_from(file, 0, flags & O_CLOEXEC != 0))
+}
+
+// open(path, flags, mode)
+fn sys_open(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [path, flags, ..] = args(frame);
+    open_at(AT_FDCWD as u64, path, flags)
+}
+
+// openat(dirfd, path, flags, mode)
+fn sys_openat(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [dirfd, path, flags, ..] = args(frame);
+    open_at(dirfd, path, flags)
+}
+
+// Store a `struct stat` for a file of `kind` and `size` at `stat`
+fn write_stat(stat: u64, kind: FileKind, size: u64) -> Result<(), Errno> {
+    let mode = match kind {
+        FileKind::Regular => S_IFREG | 0o755,
+        FileKind::Directory => S_IFDIR | 0o755,
+        FileKind::CharDevice => S_IFCHR | 0o666,
+        FileKind::Fifo => S_IFIFO | 0o600,
+    };
+    let mut bytes = [0u8; STAT_SIZE];
+    bytes[16..24].copy_from_slice(&1u64.to_le_bytes()); // st_nlink
+    bytes[24..28].copy_from_slice(&mode.to_le_bytes()); // st_mode
+    bytes[48..56].copy_from_slice(&size.to_le_bytes()); // st_size
+    bytes[56..64].copy_from_slice(&4096u64.to_le_bytes()); // st_blksize
+    bytes[64..72].copy_from_slice(&size.div_ceil(512).to_le_bytes()); // st_blocks
+    usermode::copy_to_user(stat, &bytes)
+}
+
+// stat(path, stat) and lstat(path, stat), there are no symbolic links
+fn sys_stat(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [path, stat, ..] = args(frame);
+    let (kind, size) = file::metadata(&resolve(AT_FDCWD as u64, path)?)?;
+    write_stat(stat, kind, size)?;
+    Ok(0)
+}
+
+// fstat(fd, stat)
+fn sys_fstat(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [fd, stat, ..] = args(frame);
+    let file = syscall::file(fd)?;
+    write_stat(stat, file.kind(), file.size())?;
+    Ok(0)
+}
+
+// newfstatat(dirfd, path, stat, flags): with AT_EMPTY_PATH and an empty path
+// the file of `dirfd` itself
+fn sys_newfstatat(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [dirfd, path, stat, flags, ..] = args(frame);
+    if flags & AT_EMPTY_PATH != 0 && syscall::copy_path(path)?.is_empty() {
+        write_stat(stat, syscall::file(dirfd)?.kind(), 0)?;
+        return Ok(0);
+    }
+    let (kind, size) = file::metadata(&resolve(dirfd, path)?)?;
+    write_stat(stat, kind, size)?;
+    Ok(0)
+}
+
+const X_OK: u64 = 1;
+
+fn access_at(dirfd: u64, path: u64, mode: u64) -> Result<u64, Errno> {
+    let (kind, _) = file::metadata(&resolve(dirfd, path)?)?;
+    if mode & X_OK != 0 && matches!(kind, FileKind::CharDevice | FileKind::Fifo) {
+        return Err(Errno::EACCES);
+    }
+    Ok(0)
+}
+
+// access(path, mode): everyone is root, so only whether it exists and, for
+// X_OK, is a program or directory
+fn sys_access(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [path, mode, ..] = args(frame);
+    access_at(AT_FDCWD as u64, path, mode)
+}
+
+// faccessat(dirfd, path, mode, flags)
+fn sys_faccessat(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [dirfd, path, mode, ..] = args(frame);
+    access_at(dirfd, path, mode)
+}
+
+const POLLIN: u16 = 0x1;
+const POLLOUT: u16 = 0x4;
+const POLLNVAL: u16 = 0x20;
+
+// poll(fds, count, timeout): wait until one of the files is ready for what is
+// asked, for at most timeout milliseconds unless it is negative
+fn sys_poll(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [fds, count, timeout, ..] = args(frame);
+    if count > file::MAX_FDS as u64 {
+        return Err(Errno::EINVAL);
+    }
+    // struct pollfd { int fd; short events; short revents; }
+    let mut pollfds = vec![0u8; count as usize * 8];
+    usermode::copy_from_user(&mut pollfds, fds)?;
+    let polled: Vec<_> = pollfds
+        .chunks(8)
+        .map(|pollfd| {
+            let fd = i32::from_le_bytes([pollfd[0], pollfd[1], pollfd[2], pollfd[3]]);
+            let events = u16::from_le_bytes([pollfd[4], pollfd[5]]);
+            let file = match fd {
+                fd if fd < 0 => None,
+                fd => Some(syscall::file(fd as u64)),
+            };
+            (file, events)
+        })
+        .collect();
+
+    let mut revents = vec![0u16; polled.len()];
+    let mut ready = 0;
+    let mut check = || {
+        for ((file, events), revents) in polled.iter().zip(revents.iter_mut()) {
+            *revents = match file {
+                None => 0,
+                Some(Err(_)) => POLLNVAL,
+                Some(Ok(file)) => {
+                    let state = file.ready();
+                    let mut revents = 0;
+                    if state.read {
+                        revents |= POLLIN;
+                    }
+                    if state.write {
+                        revents |= POLLOUT;
+                    }
+                    revents & events
+                }
+            };
+        }
+        ready = revents.iter().filter(|&&revents| revents != 0).count();
+        ready > 0
+    };
+    let timeout = match timeout as i32 {
+        0 => Some(Duration::ZERO),
+        timeout if timeout < 0 => None,
+        timeout => Some(Duration::from_millis(timeout as u64)),
+    };
+    process::wait_interruptible_timeout(&file::READINESS, &mut check, timeout)?;
+
+    for (i, revents) in revents.iter().enumerate() {
+        usermode::copy_to_user(fds + i as u64 * 8 + 6, &revents.to_le_bytes())?;
+    }
+    Ok(ready as u64)
+}
+
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+// lseek(fd, offset, whence): the new position. Pipes and terminals can't
+// seek, the devices stay at 0.
+fn sys_lseek(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [fd, offset, whence, ..] = args(frame);
+    let file = syscall::file(fd)?;
+    let offset = offset as i64;
+    let to = match whence {
+        SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => return Err(Errno::EINVAL),
+    };
+    file.seek(to)
+}
+
+const PROT_WRITE: u64 = 0x2;
+const PROT_EXEC: u64 = 0x4;
+const MAP_PRIVATE: u64 = 0x2;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+// mmap(address, len, prot, flags, fd, offset): only private anonymous memory.
+// `address` is a hint that is ignored without MAP_FIXED.
+fn sys_mmap(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [address, len, prot, flags, ..] = args(frame);
+    if flags & MAP_ANONYMOUS == 0 {
+        return Err(Errno::ENODEV);
+    }
+    if flags & MAP_PRIVATE == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let page_flags = match prot {
+        0 => None,
+        prot => {
+            let mut page_flags = PageTableFlags::empty();
+            if prot & PROT_WRITE != 0 {
+                page_flags |= PageTableFlags::WRITABLE;
+            }
+            if prot & PROT_EXEC == 0 {
+                page_flags |= elf::no_execute();
+            }
+            Some(page_flags)
+        }
+    };
+    let fixed = (flags & MAP_FIXED != 0).then_some(address);
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    process.mmap(fixed, len, page_flags)
+}
+
+// mprotect(address, len, prot): accepted, but pages keep the protection they
+// were mapped with
+fn sys_mprotect(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [address, ..] = args(frame);
+    if !address.is_multiple_of(4096) {
+        return Err(Errno::EINVAL);
+    }
+    Ok(0)
+}
+
+// munmap(address, len)
+fn sys_munmap(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [address, len, ..] = args(frame);
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    process.munmap(address, len)?;
+    Ok(0)
+}
+
+// madvise(address, len, advice): advice is only advice
+fn sys_madvise(_frame: &mut TrapFrame) -> Result<u64, Errno> {
+    Ok(0)
+}
+
+// The size of the signal sets Linux' rt_ calls take
+const SIGSET_SIZE: u64 = 8;
+
+// rt_sigaction(sig, action, old, sigsetsize): the same structure as the
+// native sigaction
+fn sys_rt_sigaction(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [sig, action, old, sigsetsize, ..] = args(frame);
+    if sigsetsize != SIGSET_SIZE {
+        return Err(Errno::EINVAL);
+    }
+    signal::sigaction(sig, action, old)?;
+    Ok(0)
+}
+
+// rt_sigprocmask(how, set, old, sigsetsize)
+fn sys_rt_sigprocmask(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [how, set, old, sigsetsize, ..] = args(frame);
+    if sigsetsize != SIGSET_SIZE {
+        return Err(Errno::EINVAL);
+    }
+    signal::sigprocmask(how, set, old)?;
+    Ok(0)
+}
+
+const TCGETS: u64 = 0x5401;
+const TCSETS: u64 = 0x5402;
+const TCSETSW: u64 = 0x5403;
+const TCSETSF: u64 = 0x5404;
+const TIOCGPGRP: u64 = 0x540f;
+const TIOCSPGRP: u64 = 0x5410;
+const TIOCGWINSZ: u64 = 0x5413;
+
+// The console's `struct termios`: canonical mode with echo, the usual control
+// characters, 38400 baud 8 bit characters
+const TERMIOS: [u8; 36] = {
+    let mut termios = [0u8; 36];
+    let iflag = 0o2400u32.to_le_bytes(); // ICRNL | IXON
+    let oflag = 0o5u32.to_le_bytes(); // OPOST | ONLCR
+    let cflag = 0o277u32.to_le_bytes(); // B38400 | CS8 | CREAD
+    let lflag = 0o105073u32.to_le_bytes(); // ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | ECHOKE | IEXTEN
+    let mut i = 0;
+    while i < 4 {
+        termios[i] = iflag[i];
+        termios[4 + i] = oflag[i];
+        termios[8 + i] = cflag[i];
+        termios[12 + i] = lflag[i];
+        i += 1;
+    }
+    // c_cc after c_line: INTR QUIT ERASE KILL EOF TIME MIN SWTC START STOP
+    // SUSP EOL REPRINT DISCARD WERASE LNEXT
+    let cc = [
+        3, 0x1c, 0x7f, 0x15, 4, 0, 1, 0, 0x11, 0x13, 0x1a, 0, 0x12, 0x0f, 0x17, 0x16,
+    ];
+    let mut i = 0;
+    while i < cc.len() {
+        termios[17 + i] = cc[i];
+        i += 1;
+    }
+    termios
+};
+
+// Rows and columns of text reported for the console
+const CONSOLE_ROWS: u16 = 25;
+const CONSOLE_COLUMNS: u16 = 80;
+
+// ioctl(fd, request, arg): the terminal requests a C library makes on the
+// console. Settings can't be changed and are accepted as they are. The caller
+// is always in the foreground process group, which is its own process.
+fn sys_ioctl(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [fd, request, arg, ..] = args(frame);
+    if !syscall::file(fd)?.is_terminal() {
+        return Err(Errno::ENOTTY);
+    }
+    match request {
+        TCGETS => usermode::copy_to_user(arg, &TERMIOS)?,
+        TCSETS | TCSETSW | TCSETSF | TIOCSPGRP => {}
+        TIOCGPGRP => {
+            let process = process::current().ok_or(Errno::ESRCH)?;
+            usermode::copy_to_user(arg, &(process.pid().as_u64() as u32).to_le_bytes())?;
+        }
+        TIOCGWINSZ => {
+            let mut winsize = [0u8; 8];
+            winsize[..2].copy_from_slice(&CONSOLE_ROWS.to_le_bytes());
+            winsize[2..4].copy_from_slice(&CONSOLE_COLUMNS.to_le_bytes());
+            usermode::copy_to_user(arg, &winsize)?;
+        }
+        _ => return Err(Errno::ENOTTY),
+    }
+    Ok(0)
+}
+
+// The little endian 64-bit integer at `offset` in `bytes`
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+// Most `struct iovec` that readv and writev take
+const IOV_MAX: u64 = 1024;
+
+// The base and length of each `struct iovec` at `iov`
+fn copy_iovecs(iov: u64, count: u64) -> Result<Vec<(u64, usize)>, Errno> {
+    if count > IOV_MAX {
+        return Err(Errno::EINVAL);
+    }
+    let mut iovecs = Vec::with_capacity(count as usize);
+    let mut total = 0usize;
+    for i in 0..count {
+        let mut bytes = [0u8; 16];
+        usermode::copy_from_user(&mut bytes, iov + i * 16)?;
+        let base = u64_at(&bytes, 0);
+        let len = u64_at(&bytes, 8) as usize;
+        total = total
+            .checked_add(len)
+            .filter(|&total| total <= isize::MAX as usize)
+            .ok_or(Errno::EINVAL)?;
+        if !usermode::is_user_range(base, len) {
+            return Err(Errno::EFAULT);
+        }
+        iovecs.push((base, len));
+    }
+    Ok(iovecs)
+}
+
+// readv(fd, iov, count): one read, spread over the buffers
+fn sys_readv(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [fd, iov, count, ..] = args(frame);
+    let file = syscall::file(fd)?;
+    let iovecs = copy_iovecs(iov, count)?;
+    let total: usize = iovecs.iter().map(|&(_, len)| len).sum();
+    let mut chunk = vec![0u8; total.min(IO_CHUNK)];
+    let n = file.read(&mut chunk)?;
+    let mut copied = 0;
+    for (base, len) in iovecs {
+        if copied == n {
+            break;
+        }
+        let len = len.min(n - copied);
+        usermode::copy_to_user(base, &chunk[copied..copied + len])?;
+        copied += len;
+    }
+    Ok(n as u64)
+}
+
+// writev(fd, iov, count): the buffers in order. Small writes are gathered
+// into one, so that they stay in one piece in a pipe.
+fn sys_writev(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [fd, iov, count, ..] = args(frame);
+    let file = syscall::file(fd)?;
+    let iovecs = copy_iovecs(iov, count)?;
+    let total: usize = iovecs.iter().map(|&(_, len)| len).sum();
+    if total <= IO_CHUNK {
+        let mut data = vec![0u8; total];
+        let mut offset = 0;
+        for (base, len) in iovecs {
+            usermode::copy_from_user(&mut data[offset..offset + len], base)?;
+            offset += len;
+        }
+        return file.write(&data).map(|n| n as u64);
+    }
+
+    let mut written = 0;
+    for (base, len) in iovecs {
+        match syscall::write_from_user(&file, base, len) {
+            Ok(n) => {
+                written += n;
+                if n < len {
+                    break;
+                }
+            }
+            Err(error) if written == 0 => return Err(error),
+            Err(_) => break,
+        }
+    }
+    Ok(written as u64)
+}
+
+// A `struct timespec` at `address`
+fn copy_timespec(address: u64) -> Result<Duration, Errno> {
+    let mut bytes = [0u8; 16];
+    usermode::copy_from_user(&mut bytes, address)?;
+    let secs = u64_at(&bytes, 0) as i64;
+    let nanos = u64_at(&bytes, 8) as i64;
+    if secs < 0 || !(0..1_000_000_000).contains(&nanos) {
+        return Err(Errno::EINVAL);
+    }
+    Ok(Duration::new(secs as u64, nanos as u32))
+}
+
+// Store `secs` and `fraction` as two 64-bit integers at `address`, the layout
+// of `struct timespec` and `struct timeval`
+fn write_time(address: u64, secs: u64, fraction: u64) -> Result<(), Errno> {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&secs.to_le_bytes());
+    bytes[8..].copy_from_slice(&fraction.to_le_bytes());
+    usermode::copy_to_user(address, &bytes)
+}
+
+// nanosleep(duration, remaining): sleeps the whole duration, so nothing
+// remains
+fn sys_nanosleep(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [duration, remaining, ..] = args(frame);
+    time::sleep(copy_timespec(duration)?);
+    if remaining != 0 {
+        write_time(remaining, 0, 0)?;
+    }
+    Ok(0)
+}
+
+// There is no real time clock, so every clock counts from boot, and the
+// time of day is that long after the epoch
+fn now() -> Duration {
+    Duration::from_nanos(time::nanos())
+}
+
+// clock_gettime(clock, timespec)
+fn sys_clock_gettime(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [clock, timespec, ..] = args(frame);
+    // REALTIME, MONOTONIC, PROCESS_CPUTIME_ID, THREAD_CPUTIME_ID,
+    // MONOTONIC_RAW, REALTIME_COARSE, MONOTONIC_COARSE and BOOTTIME
+    if clock > 7 {
+        return Err(Errno::EINVAL);
+    }
+    let now = now();
+    write_time(timespec, now.as_secs(), now.subsec_nanos() as u64)?;
+    Ok(0)
+}
+
+// gettimeofday(timeval, timezone): there are no time zones
+fn sys_gettimeofday(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [timeval, timezone, ..] = args(frame);
+    let now = now();
+    if timeval != 0 {
+        write_time(timeval, now.as_secs(), now.subsec_micros() as u64)?;
+    }
+    if timezone != 0 {
+        usermode::copy_to_user(timezone, &[0u8; 8])?;
+    }
+    Ok(0)
+}
+
+// time(seconds): also stored at `seconds` if not null
+fn sys_time(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let seconds = args(frame)[0];
+    let now = now().as_secs();
+    if seconds != 0 {
+        usermode::copy_to_user(seconds, &now.to_le_bytes())?;
+    }
+    Ok(now)
+}
+
+const SIGCHLD: u64 = signal::SIGCHLD as u64;
+
+// clone(flags, stack, parent_tid, child_tid, tls): only the plain fork a C
+// library's fork makes, with SIGCHLD as the exit signal and nothing shared
+fn sys_clone(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [flags, stack, ..] = args(frame);
+    if flags != SIGCHLD || stack != 0 {
+        return Err(Errno::EINVAL);
+    }
+    syscall::sys_fork(frame)
+}
+
+const WNOHANG: u64 = 1;
+const RUSAGE_SIZE: usize = 144;
+
+// wait4(pid, status, options, rusage): there are no process groups, so 0 and
+// negative `pid`s mean any child. With WNOHANG 0 if none exited yet.
+fn sys_wait4(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [pid, status, options, rusage, ..] = args(frame);
+    let pid = match pid as i64 {
+        pid if pid > 0 => Some(Pid::from_u64(pid as u64)),
+        _ => None,
+    };
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    let reaped = if options & WNOHANG != 0 {
+        process.try_wait(pid)?
+    } else {
+        Some(process.wait(pid)?)
+    };
+    let (pid, exit): (Pid, UserExit) = match reaped {
+        Some(reaped) => reaped,
+        None => return Ok(0),
+    };
+    if status != 0 {
+        usermode::copy_to_user(status, &syscall::wait_status(exit).to_le_bytes())?;
+    }
+    if rusage != 0 {
+        usermode::copy_to_user(rusage, &[0u8; RUSAGE_SIZE])?;
+    }
+    Ok(pid.as_u64())
+}
+
+// Length of each field of `struct utsname`
+const UTS_LEN: usize = 65;
+
+// uname(utsname): claims to be a Linux release new enough for C libraries
+// not to fall back to older calls, like other kernels with a Linux ABI do
+fn sys_uname(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let utsname = args(frame)[0];
+    let fields: [&[u8]; 6] = [
+        b"Linux",
+        b"rustyos",
+        b"5.15.0-rustyos",
+        b"#1",
+        b"x86_64",
+        b"(none)",
+    ];
+    let mut bytes = [0u8; 6 * UTS_LEN];
+    for (i, field) in fields.iter().enumerate() {
+        bytes[i * UTS_LEN..i * UTS_LEN + field.len()].copy_from_slice(field);
+    }
+    usermode::copy_to_user(utsname, &bytes)?;
+    Ok(0)
+}
+
+const F_DUPFD: u64 = 0;
+const F_GETFD: u64 = 1;
+const F_SETFD: u64 = 2;
+const F_GETFL: u64 = 3;
+const F_SETFL: u64 = 4;
+const F_DUPFD_CLOEXEC: u64 = 1030;
+
+// fcntl(fd, command, arg): duplicating, close-on-exec, and the file status
+// flags, which can't change
+fn sys_fcntl(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [fd, command, arg, ..] = args(frame);
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    let file = syscall::file(fd)?;
+    match command {
+        F_DUPFD => process.with_files(|files| files.insert_from(file, arg, false)),
+        F_DUPFD_CLOEXEC => process.with_files(|files| files.insert_from(file, arg, true)),
+        F_GETFD => match process.with_files(|files| files.cloexec(fd))? {
+            true => Ok(FD_CLOEXEC),
+            false => Ok(0),
+        },
+        F_SETFD => {
+            process.with_files(|files| files.set_cloexec(fd, arg & FD_CLOEXEC != 0))?;
+            Ok(0)
+        }
+        F_SETFL => Ok(0),
+        F_GETFL => Ok(file.access_mode()),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+// getcwd(buffer, size): the length of the path with its NUL
+fn sys_getcwd(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [buffer, size, ..] = args(frame);
+    if size < 2 {
+        return Err(Errno::ERANGE);
+    }
+    usermode::copy_to_user(buffer, b"/\0")?;
+    Ok(2)
+}
+
+// umask(mask): nothing is created, so the previous mask is the usual one
+fn sys_umask(_frame: &mut TrapFrame) -> Result<u64, Errno> {
+    Ok(0o022)
+}
+
+// getuid(), getgid(), geteuid() and getegid(): everyone is root
+fn sys_getuid(_frame: &mut TrapFrame) -> Result<u64, Errno> {
+    Ok(0)
+}
+
+// The process `pid`, the calling one if 0
+fn lookup(pid: u64) -> Result<Pid, Errno> {
+    let process = match pid {
+        0 => process::current(),
+        pid => process::lookup(Pid::from_u64(pid)),
+    };
+    Ok(process.ok_or(Errno::ESRCH)?.pid())
+}
+
+// getpgid(pid) and getsid(pid): every process is in a group and session of
+// its own
+fn sys_getpgid(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    Ok(lookup(args(frame)[0])?.as_u64())
+}
+
+// setpgid(pid, pgid): only putting a process in the group it's already in
+fn sys_setpgid(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [pid, pgid, ..] = args(frame);
+    let pid = lookup(pid)?;
+    if pgid != 0 && pgid != pid.as_u64() {
+        return Err(Errno::EPERM);
+    }
+    Ok(0)
+}
+
+const ARCH_SET_FS: u64 = 0x1002;
+const ARCH_GET_FS: u64 = 0x1003;
+
+// arch_prctl(code, address): the FS base, which C libraries point at their
+// thread control block
+fn sys_arch_prctl(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [code, address, ..] = args(frame);
+    match code {
+        ARCH_SET_FS if address >= USER_END => Err(Errno::EPERM),
+        ARCH_SET_FS => {
+            usermode::set_fs_base(address);
+            Ok(0)
+        }
+        ARCH_GET_FS => {
+            usermode::copy_to_user(address, &usermode::fs_base().to_le_bytes())?;
+            Ok(0)
+        }
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+// Send `sig` to the calling process if `tid` is its task, the only thread
+fn kill_thread(tid: u64, sig: u64) -> Result<u64, Errno> {
+    let task = task::current().ok_or(Errno::ESRCH)?;
+    if task.id().as_u64() != tid {
+        return Err(Errno::ESRCH);
+    }
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    signal::kill(process.pid().as_u64(), sig)?;
+    Ok(0)
+}
+
+// tkill(tid, sig)
+fn sys_tkill(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [tid, sig, ..] = args(frame);
+    kill_thread(tid, sig)
+}
+
+// tgkill(pid, tid, sig)
+fn sys_tgkill(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [pid, tid, sig, ..] = args(frame);
+    if lookup(pid)? != lookup(0)? {
+        return Err(Errno::ESRCH);
+    }
+    kill_thread(tid, sig)
+}
+
+// dup3(old, new, flags): like dup2, but `old` and `new` must differ
+fn sys_dup3(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [old, new, flags, ..] = args(frame);
+    if old == new || flags & !O_CLOEXEC != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    let closed = process.with_files(|files| {
+        let closed = files.dup2(old, new)?;
+        files.set_cloexec(new, flags & O_CLOEXEC != 0)?;
+        Ok(closed)
+    })?;
+    drop(closed);
+    Ok(new)
+}
+
+// pipe2(fds, flags): only close-on-exec, the pipe can't be non-blocking
+fn sys_pipe2(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [fds, flags, ..] = args(frame);
+    if flags & !O_CLOEXEC != 0 {
+        return Err(Errno::EINVAL);
+    }
+    syscall::pipe(fds, flags & O_CLOEXEC != 0)
+}
+
+// Run a Linux program made of `code` and return how it exited
+#[cfg(test)]
+fn run_linux(code: &[u8]) -> UserExit {
+    let image = elf::linux_test_image(ET_EXEC, USER_START + 0x400000, code);
+    let program = elf::load(&image, &[], &[]).expect("load");
+    process::spawn_program("linux", program).wait_exit()
+}
+
+#[test_case]
+fn linux_personality() {
+    print!("Linux system calls... ");
+    // Exit with the result of getpid, which is 39 there
+    let exit_group = |code: Asm| {
+        code.mov_reg(Reg::Rdi, Reg::Rax)
+            .syscall(SYS_EXIT_GROUP as u64)
+    };
+    let code = exit_group(Asm::new().syscall(SYS_GETPID as u64));
+    match run_linux(&code.code()) {
+        UserExit::Exit(pid) => assert!(pid > 1),
+        exit => panic!("exited with {:?}", exit),
+    }
+
+    // Point FS at a quadword after the code and exit with what it reads there
+    let code = Asm::new()
+        .lea_rip(Reg::Rsi, 0x40)
+        .mov(Reg::Rdi, ARCH_SET_FS as u32)
+        .syscall(SYS_ARCH_PRCTL as u64)
+        .raw(&[0x64, 0x48, 0x8b, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00]); // mov rax, fs:[0]
+    let code = exit_group(code).at(0x40).raw(&42u64.to_le_bytes());
+    assert_eq!(run_linux(&code.code()), UserExit::Exit(42));
+
+    // writev of two buffers built on the stack returns the total length
+    let message = b"[linux] ";
+    let code = Asm::new()
+        .lea_rip(Reg::Rax, 0x40)
+        .raw(&[0x6a, 0x05]) // push 5
+        .lea(Reg::Rcx, Reg::Rax, 3)
+        .raw(&[0x51]) // push rcx
+        .raw(&[0x6a, 0x03]) // push 3
+        .raw(&[0x50]) // push rax
+        .mov_reg(Reg::Rsi, Reg::Rsp)
+        .mov(Reg::Rdi, 1)
+        .mov(Reg::Rdx, 2)
+        .syscall(SYS_WRITEV as u64);
+    let code = exit_group(code).at(0x40).raw(message);
+    assert_eq!(
+        run_linux(&code.code()),
+        UserExit::Exit(message.len() as i64)
+    );
+
+    // The native system call numbers mean something else, and ones that
+    // aren't there fail
+    let code = Asm::new()
+        .syscall(1000)
+        .mov_reg(Reg::Rdi, Reg::Rax)
+        .syscall(SYS_EXIT as u64);
+    assert_eq!(
+        run_linux(&code.code()),
+        UserExit::Exit(-(Errno::ENOSYS as i64))
+    );
+    println!("[ok]");
+}
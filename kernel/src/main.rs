@@ -1,78 +1,46 @@
 #![no_std]
 #![no_main]
-#![feature(lang_items)]
-#![feature(abi_x86_interrupt)]
 #![feature(custom_test_frameworks)]
-#![test_runner(crate::test_runner)]
+#![test_runner(kernel::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-mod gdt;
-mod graphics;
-mod interrupt;
-mod paging;
-mod serial;
-
-use core::arch::asm;
 use core::panic::PanicInfo;
-use graphics::{FrameBuffer, ModeInfo};
+use kernel::graphics::{FrameBuffer, ModeInfo};
+use kernel::{paging, println};
+
+#[cfg(test)]
+use kernel::print;
 
 #[no_mangle]
 extern "C" fn kernel_main(
     fb: *mut FrameBuffer,
     mi: *mut ModeInfo,
     mm: &paging::MemoryMap,
-    _rsdp: u64,
+    rsdp: u64,
 ) {
-    interrupt::disable();
-
-    serial::initialize();
-    serial::write_byte(b'A');
-    serial::write_byte(b'\n');
-    serial::write_str("Hello serial\n");
-
-    graphics::initialize(fb, mi);
-
-    paging::initialize(mm);
-
-    gdt::initialize();
-    interrupt::init();
-
-    if interrupt::check_apic() {
-        serial::write_str("CPU supports APIC\n");
-    }
-
-    interrupt::enable();
+    unsafe { kernel::initialize(fb, mi, mm, rsdp) };
 
     #[cfg(test)]
     test_main();
 
+    #[cfg(not(test))]
+    kernel::programs::start_init();
+
     // panic!("testpanic");
 
-    loop {
-        unsafe {
-            asm!("hlt");
-        }
-    }
+    kernel::run();
 }
 
-#[lang = "eh_personality"]
-fn eh_personality() {}
-
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{:?}", info);
     loop {}
 }
 
-#[cfg(test)]
-fn test_runner(tests: &[&dyn Fn()]) {
-    println!("Running {} tests", tests.len());
-    for test in tests {
-        test();
-    }
-}
-
+// Checks that the test runner works at all, so the assertion is trivial on
+// purpose
 #[test_case]
+#[allow(clippy::eq_op)]
 fn trivial_assertion() {
     print!("trivial assertion... ");
     assert_eq!(1, 1);
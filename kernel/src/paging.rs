@@ -1,9 +1,49 @@
+use alloc::collections::BTreeMap;
+use core::ops::RangeInclusive;
 use core::slice;
+use core::sync::atomic::{AtomicU64, Ordering};
 
-use crate::println;
+use crate::cpu::{self, Feature};
+use crate::tlb::TlbBatch;
+use crate::{println, sync};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr3Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::{MapToError, MappedFrame, TranslateResult, UnmapError};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
 
 const PAGE_SIZE: usize = 0x1000;
 
+// The firmware identity maps all physical memory and the kernel keeps using
+// those page tables, so a frame is accessed at its physical address.
+//
+// User mappings live in their own range of PML4 entries, above what the
+// identity map needs for any physical address width up to 46 bits. The last
+// page before the non-canonical hole stays unmapped.
+pub const USER_START: u64 = 0x4000_0000_0000;
+pub const USER_END: u64 = 0x7fff_ffff_f000;
+
+// Flags of the page tables above user pages, which leave the protection to
+// the last level
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+// Marks a page that is shared read-only after `fork` but was writable, in a
+// bit the processor ignores
+const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+// Memory below 1 MiB is left alone, application processors start there
+const LOW_MEMORY_END: u64 = 0x100000;
+
+const MAX_REGIONS: usize = 64;
+
 pub struct MemoryDescriptor {
     pub phys_start: u64,
     pub page_count: u64,
@@ -15,9 +55,531 @@ pub struct MemoryMap {
     pub descriptors_len: u64,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MapError {
+    OutOfMemory,
+    AlreadyMapped,
+    // Not page aligned or not within USER_START..USER_END
+    InvalidRange,
+    // Part of the range has no page
+    NotMapped,
+}
+
+// Hands out 4 KiB frames of the free memory reported by the loader. Frames
+// are taken from the regions in order, freed ones are kept in a list linked
+// through their first word.
+struct FramePool {
+    regions: [(u64, u64); MAX_REGIONS],
+    regions_len: usize,
+    // Region and address the next never used frame comes from
+    region: usize,
+    next: u64,
+    free_list: u64,
+    total: u64,
+    allocated: u64,
+}
+
+impl FramePool {
+    const fn new() -> FramePool {
+        FramePool {
+            regions: [(0, 0); MAX_REGIONS],
+            regions_len: 0,
+            region: 0,
+            next: 0,
+            free_list: 0,
+            total: 0,
+            allocated: 0,
+        }
+    }
+
+    fn add_region(&mut self, start: u64, end: u64) {
+        let start = start.max(LOW_MEMORY_END).next_multiple_of(PAGE_SIZE as u64);
+        let end = end & !(PAGE_SIZE as u64 - 1);
+        if start >= end || self.regions_len == MAX_REGIONS {
+            return;
+        }
+        if self.regions_len == 0 {
+            self.next = start;
+        }
+        self.regions[self.regions_len] = (start, end);
+        self.regions_len += 1;
+        self.total += (end - start) / PAGE_SIZE as u64;
+    }
+
+    fn allocate(&mut self) -> Option<u64> {
+        let frame = if self.free_list != 0 {
+            let frame = self.free_list;
+            self.free_list = unsafe { (frame as *const u64).read() };
+            frame
+        } else {
+            while self.region < self.regions_len && self.next >= self.regions[self.region].1 {
+                self.region += 1;
+                if self.region < self.regions_len {
+                    self.next = self.regions[self.region].0;
+                }
+            }
+            if self.region == self.regions_len {
+                return None;
+            }
+            let frame = self.next;
+            self.next += PAGE_SIZE as u64;
+            frame
+        };
+        self.allocated += 1;
+        Some(frame)
+    }
+
+    fn deallocate(&mut self, frame: u64) {
+        unsafe { (frame as *mut u64).write(self.free_list) };
+        self.free_list = frame;
+        self.allocated -= 1;
+    }
+}
+
+static FRAMES: Mutex<FramePool> = Mutex::new(FramePool::new());
+
+// How many more address spaces map a user frame than one, for frames shared
+// by `fork`. Most frames are mapped once and not listed.
+static SHARED_FRAMES: Mutex<BTreeMap<u64, u32>> = Mutex::new(BTreeMap::new());
+
+// Physical address of the kernel's PML4, loaded by tasks without their own
+// address space
+static KERNEL_PML4: AtomicU64 = AtomicU64::new(0);
+
 pub fn initialize(mm: &MemoryMap) {
     let (phys_start, phys_end) = max_available_memory_area(mm);
     println!("0x{:x}, 0x{:x}", phys_start, phys_end);
+
+    let descriptors = unsafe { slice::from_raw_parts(mm.descriptors, mm.descriptors_len as usize) };
+    let mut frames = FRAMES.lock();
+    for d in descriptors {
+        frames.add_region(d.phys_start, d.phys_start + d.page_count * PAGE_SIZE as u64);
+    }
+    println!(
+        "{} MiB of free frames",
+        (frames.total * PAGE_SIZE as u64) >> 20
+    );
+    drop(frames);
+
+    // No-execute mappings need IA32_EFER.NXE (Intel SDM Vol. 3A 4.1.4), and
+    // read-only user pages only stop kernel writes with CR0.WP (4.6.1)
+    if cpu::has(Feature::ExecuteDisable) {
+        unsafe { Efer::update(|efer| efer.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    }
+    unsafe { Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT)) };
+
+    // The firmware's page tables live in boot services memory, which may be
+    // read-only. Continue on a copy of the top level so that user mappings can
+    // be added below it.
+    let pml4 = allocate_frame().expect("no frame for the PML4");
+    let (current, _) = Cr3::read();
+    unsafe {
+        let table = &mut *(pml4.start_address().as_u64() as *mut PageTable);
+        table.clone_from(&*(current.start_address().as_u64() as *const PageTable));
+        assert!(
+            user_entries().all(|i| table[i].is_unused()),
+            "firmware page tables overlap user space"
+        );
+        Cr3::write(pml4, Cr3Flags::empty());
+    }
+    KERNEL_PML4.store(pml4.start_address().as_u64(), Ordering::Relaxed);
+}
+
+// A zeroed frame, or None when memory is exhausted
+pub fn allocate_frame() -> Option<PhysFrame> {
+    let frame = interrupts::without_interrupts(|| FRAMES.lock().allocate())?;
+    unsafe { core::ptr::write_bytes(frame as *mut u8, 0, PAGE_SIZE) };
+    Some(PhysFrame::containing_address(PhysAddr::new(frame)))
+}
+
+// Safety: `frame` came from `allocate_frame` and nothing uses it anymore
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    interrupts::without_interrupts(|| FRAMES.lock().deallocate(frame.start_address().as_u64()));
+}
+
+// Frames currently allocated and the total number of frames
+pub fn frame_usage() -> (u64, u64) {
+    interrupts::without_interrupts(|| {
+        let frames = FRAMES.lock();
+        (frames.allocated, frames.total)
+    })
+}
+
+fn share_frame(frame: PhysFrame) {
+    let address = frame.start_address().as_u64();
+    interrupts::without_interrupts(|| *SHARED_FRAMES.lock().entry(address).or_insert(0) += 1);
+}
+
+fn is_shared(frame: PhysFrame) -> bool {
+    let address = frame.start_address().as_u64();
+    interrupts::without_interrupts(|| SHARED_FRAMES.lock().contains_key(&address))
+}
+
+// Drop one mapping of a user frame, freeing it with the last.
+// Safety: like `deallocate_frame` for the mapping that goes away
+unsafe fn release_frame(frame: PhysFrame) {
+    let address = frame.start_address().as_u64();
+    let last = interrupts::without_interrupts(|| {
+        let mut shared = SHARED_FRAMES.lock();
+        match shared.get_mut(&address) {
+            Some(1) => {
+                shared.remove(&address);
+                false
+            }
+            Some(count) => {
+                *count -= 1;
+                false
+            }
+            None => true,
+        }
+    });
+    if last {
+        deallocate_frame(frame);
+    }
+}
+
+struct Frames;
+
+unsafe impl FrameAllocator<Size4KiB> for Frames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate_frame()
+    }
+}
+
+// Load the page tables with the PML4 at physical address `pml4`, the
+// kernel's alone if zero. Called by the scheduler with interrupts disabled.
+pub fn activate(pml4: u64) {
+    let pml4 = match pml4 {
+        0 => KERNEL_PML4.load(Ordering::Relaxed),
+        pml4 => pml4,
+    };
+    let (current, flags) = Cr3::read();
+    if current.start_address().as_u64() != pml4 {
+        unsafe { Cr3::write(PhysFrame::containing_address(PhysAddr::new(pml4)), flags) };
+    }
+}
+
+fn user_pages(start: u64, len: u64) -> Result<impl Iterator<Item = Page>, MapError> {
+    let end = start.checked_add(len).ok_or(MapError::InvalidRange)?;
+    if !start.is_multiple_of(PAGE_SIZE as u64) || start < USER_START || end > USER_END {
+        return Err(MapError::InvalidRange);
+    }
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+    Ok(Page::range(first, first + len.div_ceil(PAGE_SIZE as u64)))
+}
+
+// PML4 entries covering user space
+fn user_entries() -> RangeInclusive<usize> {
+    (USER_START >> 39) as usize..=(USER_END >> 39) as usize
+}
+
+// Page tables of one user program. The kernel part of the PML4 is copied from
+// the kernel's, so the entries point to the same lower level tables and the
+// kernel's mappings are visible in every address space. The kernel never adds
+// PML4 entries after `initialize`.
+pub struct AddressSpace {
+    pml4: PhysFrame,
+    // Serializes changes. Held across TLB shootdowns, so it blocks instead of
+    // spinning.
+    lock: sync::Mutex<()>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<AddressSpace, MapError> {
+        let pml4 = allocate_frame().ok_or(MapError::OutOfMemory)?;
+        let kernel = KERNEL_PML4.load(Ordering::Relaxed) as *const PageTable;
+        unsafe {
+            let table = &mut *(pml4.start_address().as_u64() as *mut PageTable);
+            for (i, entry) in (*kernel).iter().enumerate() {
+                if !user_entries().contains(&i) {
+                    table[i] = entry.clone();
+                }
+            }
+        }
+        Ok(AddressSpace {
+            pml4,
+            lock: sync::Mutex::new(()),
+        })
+    }
+
+    // Physical address of the PML4, for `activate`
+    pub fn pml4(&self) -> u64 {
+        self.pml4.start_address().as_u64()
+    }
+
+    fn page_table(&self) -> OffsetPageTable<'_> {
+        let pml4 = self.pml4() as *mut PageTable;
+        unsafe { OffsetPageTable::new(&mut *pml4, VirtAddr::zero()) }
+    }
+
+    // Back `len` bytes at `start` with zeroed frames, accessible from user
+    // mode with `flags`. Nothing is mapped if a page already is.
+    pub fn map(&self, start: u64, len: u64, flags: PageTableFlags) -> Result<(), MapError> {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let pages = user_pages(start, len)?;
+
+        let _guard = self.lock.lock();
+        let mut table = self.page_table();
+        let mut mapped = 0;
+        let mut result = Ok(());
+        for page in pages {
+            let frame = match allocate_frame() {
+                Some(frame) => frame,
+                None => {
+                    result = Err(MapError::OutOfMemory);
+                    break;
+                }
+            };
+            match unsafe {
+                table.map_to_with_table_flags(page, frame, flags, TABLE_FLAGS, &mut Frames)
+            } {
+                // Not present before, nothing to invalidate
+                Ok(flush) => flush.ignore(),
+                Err(error) => {
+                    unsafe { deallocate_frame(frame) };
+                    result = Err(match error {
+                        MapToError::FrameAllocationFailed => MapError::OutOfMemory,
+                        _ => MapError::AlreadyMapped,
+                    });
+                    break;
+                }
+            }
+            mapped += 1;
+        }
+        drop(_guard);
+
+        if result.is_err() {
+            self.unmap(start, mapped * PAGE_SIZE as u64);
+        }
+        result
+    }
+
+    // Remove the mappings in `len` bytes at `start` and free their frames.
+    // Pages that aren't mapped are skipped. Page tables stay allocated until
+    // the address space is dropped.
+    pub fn unmap(&self, start: u64, len: u64) {
+        let pages = match user_pages(start, len) {
+            Ok(pages) => pages,
+            Err(_) => return,
+        };
+
+        let mut batch = TlbBatch::new();
+        let _guard = self.lock.lock();
+        let mut table = self.page_table();
+        let mut freed = [PhysFrame::containing_address(PhysAddr::zero()); 32];
+        let mut freed_len = 0;
+        for page in pages {
+            match table.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.ignore();
+                    batch.add(page.start_address());
+                    freed[freed_len] = frame;
+                    freed_len += 1;
+                }
+                Err(UnmapError::PageNotMapped) => continue,
+                Err(error) => panic!("unmap: {:?}", error),
+            }
+            // Frames are only reused once no processor can still reach them
+            if freed_len == freed.len() {
+                batch.flush();
+                freed
+                    .iter()
+                    .for_each(|&frame| unsafe { release_frame(frame) });
+                freed_len = 0;
+            }
+        }
+        batch.flush();
+        freed[..freed_len]
+            .iter()
+            .for_each(|&frame| unsafe { release_frame(frame) });
+    }
+
+    // A copy for a child process. Both share all frames, writable pages
+    // become read-only until either side writes to them, see `copy_on_write`.
+    pub fn fork(&self) -> Result<AddressSpace, MapError> {
+        let child = AddressSpace::new()?;
+        let guard = self.lock.lock();
+        let mut child_table = child.page_table();
+        let result = unsafe {
+            for_each_user_page(self.pml4(), |address, entry| {
+                let frame = PhysFrame::containing_address(entry.addr());
+                let mut flags = entry.flags();
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    entry.set_flags(flags);
+                }
+                share_frame(frame);
+                let page = Page::containing_address(VirtAddr::new(address));
+                match child_table.map_to_with_table_flags(
+                    page,
+                    frame,
+                    flags,
+                    TABLE_FLAGS,
+                    &mut Frames,
+                ) {
+                    Ok(flush) => {
+                        flush.ignore();
+                        Ok(())
+                    }
+                    Err(_) => {
+                        release_frame(frame);
+                        Err(MapError::OutOfMemory)
+                    }
+                }
+            })
+        };
+        // Some writable pages of ours are read-only now
+        let mut batch = TlbBatch::new();
+        batch.add_all();
+        batch.flush();
+        drop(guard);
+        // On failure the pages made copy-on-write stay so, which only costs a
+        // fault on the next write
+        result.map(|()| child)
+    }
+
+    // Handle a write fault at `address` in this address space, which must be
+    // the active one. A copy-on-write page gets its own copy of the frame, or
+    // just becomes writable again if nobody else maps the frame anymore.
+    // Returns false if the page isn't copy-on-write or there is no memory for
+    // the copy.
+    pub fn copy_on_write(&self, address: u64) -> bool {
+        if !(USER_START..USER_END).contains(&address) {
+            return false;
+        }
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(address));
+        let _guard = self.lock.lock();
+        let mut table = self.page_table();
+        let (frame, flags) = match table.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } if flags.contains(COPY_ON_WRITE) => (frame, flags),
+            _ => return false,
+        };
+        let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+        if is_shared(frame) {
+            let copy = match allocate_frame() {
+                Some(copy) => copy,
+                None => return false,
+            };
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    frame.start_address().as_u64() as *const u8,
+                    copy.start_address().as_u64() as *mut u8,
+                    PAGE_SIZE,
+                );
+                let (_, flush) = table.unmap(page).expect("copy-on-write page vanished");
+                flush.ignore();
+                table
+                    .map_to_with_table_flags(page, copy, flags, TABLE_FLAGS, &mut Frames)
+                    .expect("remap copy-on-write page")
+                    .ignore();
+                release_frame(frame);
+            }
+        } else {
+            unsafe {
+                table
+                    .update_flags(page, flags)
+                    .expect("copy-on-write page vanished")
+                    .ignore()
+            };
+        }
+        // Only this processor has the address space loaded
+        tlb::flush(page.start_address());
+        true
+    }
+
+    // Copy `data` to `address` through the frames backing it, so that the
+    // address space doesn't have to be active
+    pub fn copy_to(&self, address: u64, data: &[u8]) -> Result<(), MapError> {
+        if !address
+            .checked_add(data.len() as u64)
+            .is_some_and(|end| end <= USER_END)
+            || address < USER_START
+        {
+            return Err(MapError::InvalidRange);
+        }
+
+        let _guard = self.lock.lock();
+        let table = self.page_table();
+        let mut copied = 0;
+        while copied < data.len() {
+            let virt = address + copied as u64;
+            let phys = table
+                .translate_addr(VirtAddr::new(virt))
+                .ok_or(MapError::NotMapped)?;
+            let n = (data.len() - copied).min(PAGE_SIZE - (virt as usize % PAGE_SIZE));
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[copied..].as_ptr(), phys.as_u64() as *mut u8, n)
+            };
+            copied += n;
+        }
+        Ok(())
+    }
+}
+
+// Frees every user page and page table. Must not be active on any processor,
+// which also means no TLB can hold its translations anymore.
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        unsafe {
+            let pml4 = &mut *(self.pml4() as *mut PageTable);
+            for i in user_entries() {
+                if !pml4[i].is_unused() {
+                    free_table(PhysFrame::containing_address(pml4[i].addr()), 3);
+                }
+            }
+            deallocate_frame(self.pml4);
+        }
+    }
+}
+
+// Free the table in `frame` at `level`, 1 being a page table, with everything
+// it maps. User space only has 4 KiB pages.
+unsafe fn free_table(frame: PhysFrame, level: u8) {
+    let table = &*(frame.start_address().as_u64() as *const PageTable);
+    for entry in table.iter().filter(|entry| !entry.is_unused()) {
+        let next = PhysFrame::containing_address(entry.addr());
+        if level > 1 {
+            free_table(next, level - 1);
+        } else {
+            release_frame(next);
+        }
+    }
+    deallocate_frame(frame);
+}
+
+// Call `f` with the address and last level entry of every mapped user page
+// below `pml4`, until it fails
+unsafe fn for_each_user_page<F>(pml4: u64, mut f: F) -> Result<(), MapError>
+where
+    F: FnMut(u64, &mut PageTableEntry) -> Result<(), MapError>,
+{
+    unsafe fn entries<'a>(table: u64) -> impl Iterator<Item = (u64, &'a mut PageTableEntry)> {
+        let table = &mut *(table as *mut PageTable);
+        table
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, entry)| !entry.is_unused())
+            .map(|(i, entry)| (i as u64, entry))
+    }
+
+    for (i, pml4_entry) in entries(pml4) {
+        if !user_entries().contains(&(i as usize)) {
+            continue;
+        }
+        for (j, pdpt_entry) in entries(pml4_entry.addr().as_u64()) {
+            for (k, pd_entry) in entries(pdpt_entry.addr().as_u64()) {
+                for (l, entry) in entries(pd_entry.addr().as_u64()) {
+                    f(i << 39 | j << 30 | k << 21 | l << 12, entry)?;
+                }
+            }
+        }
+    }
+    Ok(())
 }
 
 // Return maximum contiguous available memory area
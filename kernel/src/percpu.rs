@@ -0,0 +1,198 @@
+use crate::cpu;
+use crate::gdt::CpuTables;
+use crate::idle::IDLE_STATES;
+use crate::smp::{self, MAX_CPUS};
+use core::arch::asm;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+
+// Data owned by one processor, found through the GS base register.
+//
+// While running in the kernel IA32_GS_BASE points at the processor's area and
+// IA32_KERNEL_GS_BASE holds the user GS base. Code entering the kernel from
+// user mode has to `swapgs` before touching `percpu!` and swap back before
+// returning, code entered from kernel mode must not swap.
+//
+// Other processors may read an area at any time, so everything visible outside
+// this module is either immutable after initialization or atomic.
+#[repr(C, align(64))]
+pub struct PerCpu {
+    // Must stay the first field, `current()` reads it with `mov reg, gs:[0]`
+    self_ptr: *const PerCpu,
+    pub cpu_index: usize,
+    pub apic_id: AtomicU32,
+    // Task running on this processor, null until `task::initialize` runs on
+    // it. Holds a reference from `Arc::into_raw`, as do the two fields below.
+    pub current_task: AtomicUsize,
+    // Task switched away from, handed over to `task::finish_switch`
+    pub previous_task: AtomicUsize,
+    // The processor's boot context, run when no other task is ready
+    pub idle_task: AtomicUsize,
+    // Absolute deadline of the current task if it is a deadline task,
+    // u64::MAX otherwise
+    pub running_deadline: AtomicU64,
+    // Set when the current task should be switched out at the next
+    // opportunity, e.g. on time slice expiry
+    pub need_resched: AtomicBool,
+    // Preemption is allowed only while this is zero
+    pub preempt_count: AtomicUsize,
+    // Timer ticks the current task has run since it was switched in
+    pub slice_ticks: AtomicU64,
+    // Number of timer interrupts handled
+    pub ticks: AtomicU64,
+    // Accounting in `time::nanos()`: when time was last charged to the
+    // current task or, while halted, to `idle_ns`, and switches between tasks
+    pub accounted_at: AtomicU64,
+    pub halted: AtomicBool,
+    pub idle_ns: AtomicU64,
+    pub context_switches: AtomicU64,
+    // See `idle`: the state the processor is idle in plus one or zero if it
+    // isn't, since when, whether its timer is stopped and whether a write to
+    // `need_resched` wakes it
+    pub idle_state: AtomicU8,
+    pub idle_since: AtomicU64,
+    pub tickless: AtomicBool,
+    pub mwait_idle: AtomicBool,
+    // Time spent in and number of entries into each idle state
+    pub idle_residency_ns: [AtomicU64; IDLE_STATES],
+    pub idle_entries: [AtomicU64; IDLE_STATES],
+    // Number of NMIs handled
+    pub nmis: AtomicU64,
+    // Timer ticks seen by the last watchdog check and for how many checks
+    // they haven't changed
+    pub watchdog_last_ticks: AtomicU64,
+    pub watchdog_stalled: AtomicU64,
+    // Set before sending this processor an NMI asking it to dump its state
+    pub watchdog_dump_requested: AtomicBool,
+    // Stack `syscall::syscall_entry` switches to, the same as the TSS RSP0,
+    // and where it keeps the user stack pointer meanwhile
+    pub kernel_stack: AtomicU64,
+    pub user_rsp: AtomicU64,
+    tables: CpuTables,
+}
+
+impl PerCpu {
+    const fn new(cpu_index: usize) -> PerCpu {
+        PerCpu {
+            self_ptr: core::ptr::null(),
+            cpu_index,
+            apic_id: AtomicU32::new(0),
+            current_task: AtomicUsize::new(0),
+            previous_task: AtomicUsize::new(0),
+            idle_task: AtomicUsize::new(0),
+            running_deadline: AtomicU64::new(u64::MAX),
+            need_resched: AtomicBool::new(false),
+            preempt_count: AtomicUsize::new(0),
+            slice_ticks: AtomicU64::new(0),
+            ticks: AtomicU64::new(0),
+            accounted_at: AtomicU64::new(0),
+            halted: AtomicBool::new(false),
+            idle_ns: AtomicU64::new(0),
+            context_switches: AtomicU64::new(0),
+            idle_state: AtomicU8::new(0),
+            idle_since: AtomicU64::new(0),
+            tickless: AtomicBool::new(false),
+            mwait_idle: AtomicBool::new(false),
+            idle_residency_ns: [const { AtomicU64::new(0) }; IDLE_STATES],
+            idle_entries: [const { AtomicU64::new(0) }; IDLE_STATES],
+            nmis: AtomicU64::new(0),
+            watchdog_last_ticks: AtomicU64::new(0),
+            watchdog_stalled: AtomicU64::new(0),
+            watchdog_dump_requested: AtomicBool::new(false),
+            kernel_stack: AtomicU64::new(0),
+            user_rsp: AtomicU64::new(0),
+            tables: CpuTables::new(),
+        }
+    }
+}
+
+static mut AREAS: [PerCpu; MAX_CPUS] = {
+    let mut areas = [const { PerCpu::new(0) }; MAX_CPUS];
+    let mut i = 0;
+    while i < MAX_CPUS {
+        areas[i].cpu_index = i;
+        i += 1;
+    }
+    areas
+};
+
+// Number of areas handed out so far
+static INITIALIZED: AtomicUsize = AtomicUsize::new(0);
+
+// Set up the calling processor's area and point GS base at it, then load its
+// own GDT and TSS. Must run before anything else uses `percpu!`.
+pub fn initialize(cpu_index: usize) {
+    assert!(cpu_index < MAX_CPUS);
+
+    // Safety: each area is initialized once, by the processor owning it
+    let area = unsafe { &mut AREAS[cpu_index] };
+    area.self_ptr = area as *const PerCpu;
+    area.apic_id.store(cpu::current_apic_id(), Ordering::Relaxed);
+
+    GsBase::write(VirtAddr::from_ptr(area as *const PerCpu));
+    KernelGsBase::write(VirtAddr::zero());
+
+    INITIALIZED.fetch_max(cpu_index + 1, Ordering::Release);
+
+    area.tables.load(area.self_ptr as u64);
+}
+
+// Offsets for assembly code addressing fields through GS
+pub const KERNEL_STACK_OFFSET: usize = offset_of!(PerCpu, kernel_stack);
+pub const USER_RSP_OFFSET: usize = offset_of!(PerCpu, user_rsp);
+
+// The calling processor's TSS slot for the stack pointer loaded on entry from
+// user mode, see `set_kernel_stack`
+pub fn kernel_stack_slot() -> *mut u64 {
+    let cpu_index = current().cpu_index;
+    unsafe {
+        (*core::ptr::addr_of_mut!(AREAS[cpu_index]))
+            .tables
+            .kernel_stack_slot()
+    }
+}
+
+// Make interrupts, exceptions and system calls from user mode on the calling
+// processor start on `top`. Switched with the task that runs in user mode.
+pub fn set_kernel_stack(top: u64) {
+    unsafe { kernel_stack_slot().write_unaligned(top) };
+    current().kernel_stack.store(top, Ordering::Relaxed);
+}
+
+// The calling processor's area
+#[inline(always)]
+pub fn current() -> &'static PerCpu {
+    let ptr: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, preserves_flags, readonly));
+        &*ptr
+    }
+}
+
+// Area of processor `cpu_index`, if that processor has been started
+pub fn get(cpu_index: usize) -> Option<&'static PerCpu> {
+    if cpu_index < INITIALIZED.load(Ordering::Acquire) {
+        Some(unsafe { &AREAS[cpu_index] })
+    } else {
+        None
+    }
+}
+
+// Areas of all online processors
+pub fn online() -> impl Iterator<Item = &'static PerCpu> {
+    (0..smp::online_cpus()).filter_map(get)
+}
+
+// Access a field of the calling processor's area, e.g.
+// `percpu!(ticks).fetch_add(1, Ordering::Relaxed)`.
+//
+// The reference stays valid if the task later migrates, it then just refers
+// to the area of the processor it was running on when the macro was evaluated.
+#[macro_export]
+macro_rules! percpu {
+    ($field:ident) => {
+        &$crate::percpu::current().$field
+    };
+}
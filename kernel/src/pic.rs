@@ -0,0 +1,126 @@
+use spin::Mutex;
+use x86_64::instructions::port::*;
+
+// Intel 8259A Programmable Interrupt Controller, cascaded master/slave pair
+
+const PIC1: u16 = 0x20; // IO base address for master PIC
+const PIC2: u16 = 0xA0; // IO base address for slave PIC
+const PIC1_COMMAND: u16 = PIC1;
+const PIC1_DATA: u16 = PIC1 + 1;
+const PIC2_COMMAND: u16 = PIC2;
+const PIC2_DATA: u16 = PIC2 + 1;
+
+const ICW1_ICW4: u8 = 0x01; // ICW4 will be present
+const ICW1_INIT: u8 = 0x10; // Initialization
+const ICW4_8086: u8 = 0x01; // 8086/88 mode
+
+const OCW2_EOI: u8 = 0x20; // Non-specific end of interrupt
+const OCW3_READ_ISR: u8 = 0x0b; // Read in-service register on next read
+
+// IRQ line of the master PIC the slave is cascaded to
+const CASCADE_IRQ: u8 = 2;
+
+// Interrupt mask register contents, bit n set masks IRQ n
+static MASK: Mutex<u16> = Mutex::new(0xffff);
+
+// Remap IRQ 0-7 to `offset`..`offset + 8` and IRQ 8-15 to the following eight
+// vectors so they don't collide with CPU exceptions. All IRQs except the
+// cascade line start out masked.
+pub fn initialize(offset: u8) {
+    unsafe {
+        u8::write_to_port(PIC1_COMMAND, ICW1_INIT | ICW1_ICW4);
+        io_wait();
+        u8::write_to_port(PIC2_COMMAND, ICW1_INIT | ICW1_ICW4);
+        io_wait();
+
+        // ICW2: vector offsets
+        u8::write_to_port(PIC1_DATA, offset);
+        io_wait();
+        u8::write_to_port(PIC2_DATA, offset + 8);
+        io_wait();
+
+        // ICW3: slave is attached to IRQ2 of the master, slave's cascade identity
+        u8::write_to_port(PIC1_DATA, 1 << CASCADE_IRQ);
+        io_wait();
+        u8::write_to_port(PIC2_DATA, CASCADE_IRQ);
+        io_wait();
+
+        u8::write_to_port(PIC1_DATA, ICW4_8086);
+        io_wait();
+        u8::write_to_port(PIC2_DATA, ICW4_8086);
+        io_wait();
+    }
+
+    let mut mask = MASK.lock();
+    *mask = !(1 << CASCADE_IRQ);
+    write_mask(*mask);
+}
+
+// Mask every IRQ line, used when the local APIC takes over
+pub fn disable() {
+    let mut mask = MASK.lock();
+    *mask = 0xffff;
+    write_mask(*mask);
+}
+
+pub fn mask(irq: u8) {
+    let mut mask = MASK.lock();
+    *mask |= 1 << irq;
+    write_mask(*mask);
+}
+
+pub fn unmask(irq: u8) {
+    let mut mask = MASK.lock();
+    *mask &= !(1 << irq);
+    write_mask(*mask);
+}
+
+pub fn end_of_interrupt(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            u8::write_to_port(PIC2_COMMAND, OCW2_EOI);
+        }
+        u8::write_to_port(PIC1_COMMAND, OCW2_EOI);
+    }
+}
+
+// IRQ 7 and 15 are raised when an interrupt request goes away before the CPU
+// acknowledges it. Such a spurious IRQ isn't set in the in-service register and
+// must not get an EOI, except that the master still needs one for a spurious
+// IRQ from the slave because the cascade line was really in service.
+pub fn is_spurious(irq: u8) -> bool {
+    let (command, line) = match irq {
+        7 => (PIC1_COMMAND, 7),
+        15 => (PIC2_COMMAND, 7),
+        _ => return false,
+    };
+
+    let isr = unsafe {
+        u8::write_to_port(command, OCW3_READ_ISR);
+        u8::read_from_port(command)
+    };
+    if isr & (1 << line) != 0 {
+        return false;
+    }
+
+    if irq == 15 {
+        unsafe {
+            u8::write_to_port(PIC1_COMMAND, OCW2_EOI);
+        }
+    }
+    true
+}
+
+fn write_mask(mask: u16) {
+    unsafe {
+        u8::write_to_port(PIC1_DATA, mask as u8);
+        u8::write_to_port(PIC2_DATA, (mask >> 8) as u8);
+    }
+}
+
+// Give the PIC time to react to a command, port 0x80 is unused POST code output
+fn io_wait() {
+    unsafe {
+        u8::write_to_port(0x80, 0);
+    }
+}
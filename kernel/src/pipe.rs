@@ -0,0 +1,221 @@
+use crate::file::{self, File, FileKind, Ready, SeekFrom};
+use crate::process;
+use crate::signal::{SigInfo, SIGPIPE};
+use crate::sync::WaitQueue;
+use crate::syscall::Errno;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+#[cfg(test)]
+use crate::{print, println, task};
+#[cfg(test)]
+use alloc::vec;
+
+// Bytes a pipe holds before writers block, like Linux
+const PIPE_SIZE: usize = 16 * 4096;
+
+// Writes of at most this many bytes are never interleaved with others
+pub const PIPE_BUF: usize = 4096;
+
+struct Pipe {
+    buffer: Mutex<VecDeque<u8>>,
+    // Open ends of either kind
+    readers: AtomicUsize,
+    writers: AtomicUsize,
+    // Woken when there is data, when there is room, and when the other side
+    // is closed
+    readable: WaitQueue,
+    writable: WaitQueue,
+}
+
+pub struct PipeReader(Arc<Pipe>);
+
+pub struct PipeWriter(Arc<Pipe>);
+
+// A new pipe with one end of each kind
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        buffer: Mutex::new(VecDeque::new()),
+        readers: AtomicUsize::new(1),
+        writers: AtomicUsize::new(1),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+    (PipeReader(pipe.clone()), PipeWriter(pipe))
+}
+
+impl File for PipeReader {
+    // Block until there is data, or until all writers are closed, which is the
+    // end of the pipe
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Errno> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let pipe = &self.0;
+        let mut read = 0;
+        process::wait_interruptible(&pipe.readable, || {
+            let mut data = pipe.buffer.lock();
+            if data.is_empty() {
+                return pipe.writers.load(Ordering::Acquire) == 0;
+            }
+            read = buffer.len().min(data.len());
+            for (byte, value) in buffer.iter_mut().zip(data.drain(..read)) {
+                *byte = value;
+            }
+            true
+        })?;
+        if read > 0 {
+            pipe.writable.wake_all();
+            file::READINESS.wake_all();
+        }
+        Ok(read)
+    }
+
+    fn seek(&self, _to: SeekFrom) -> Result<u64, Errno> {
+        Err(Errno::ESPIPE)
+    }
+
+    fn kind(&self) -> FileKind {
+        FileKind::Fifo
+    }
+
+    // Readable with data or at the end
+    fn ready(&self) -> Ready {
+        let pipe = &self.0;
+        let empty = interrupts::without_interrupts(|| pipe.buffer.lock().is_empty());
+        Ready {
+            read: !empty || pipe.writers.load(Ordering::Acquire) == 0,
+            write: false,
+        }
+    }
+}
+
+impl File for PipeWriter {
+    // Block until all of `data` is written. Fails with EPIPE and raises
+    // SIGPIPE if there are no readers left, unless part of it was written
+    // before.
+    fn write(&self, data: &[u8]) -> Result<usize, Errno> {
+        let pipe = &self.0;
+        let mut written = 0;
+        while written < data.len() {
+            let mut broken = false;
+            let result = process::wait_interruptible(&pipe.writable, || {
+                if pipe.readers.load(Ordering::Acquire) == 0 {
+                    broken = true;
+                    return true;
+                }
+                let mut buffer = pipe.buffer.lock();
+                let room = PIPE_SIZE - buffer.len();
+                let left = data.len() - written;
+                if room == 0 || (data.len() <= PIPE_BUF && room < left) {
+                    return false;
+                }
+                let n = room.min(left);
+                buffer.extend(&data[written..written + n]);
+                written += n;
+                true
+            });
+            pipe.readable.wake_all();
+            file::READINESS.wake_all();
+
+            let error = match result {
+                _ if broken => {
+                    if let Some(process) = process::current() {
+                        process.send_signal(SIGPIPE, SigInfo::user(None));
+                    }
+                    Errno::EPIPE
+                }
+                Err(error) => error,
+                Ok(()) => continue,
+            };
+            return match written {
+                0 => Err(error),
+                _ => Ok(written),
+            };
+        }
+        Ok(written)
+    }
+
+    fn seek(&self, _to: SeekFrom) -> Result<u64, Errno> {
+        Err(Errno::ESPIPE)
+    }
+
+    fn kind(&self) -> FileKind {
+        FileKind::Fifo
+    }
+
+    // Writable with room for a write of up to PIPE_BUF bytes, or when a write
+    // would fail right away for lack of readers
+    fn ready(&self) -> Ready {
+        let pipe = &self.0;
+        let used = interrupts::without_interrupts(|| pipe.buffer.lock().len());
+        Ready {
+            read: false,
+            write: PIPE_SIZE - used >= PIPE_BUF || pipe.readers.load(Ordering::Acquire) == 0,
+        }
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.readers.fetch_sub(1, Ordering::Release);
+        self.0.writable.wake_all();
+        file::READINESS.wake_all();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.writers.fetch_sub(1, Ordering::Release);
+        self.0.readable.wake_all();
+        file::READINESS.wake_all();
+    }
+}
+
+#[test_case]
+fn pipe_transfer() {
+    print!("pipes... ");
+    let (reader, writer) = pipe();
+    assert_eq!(writer.write(b"hello"), Ok(5));
+    let mut buffer = [0u8; 512];
+    assert_eq!(reader.read(&mut buffer), Ok(5));
+    assert_eq!(&buffer[..5], b"hello");
+
+    // A reader blocks until a writer in another task fills the pipe past its
+    // size, then drains it to the end after the writer is gone
+    let data = vec![0x5a; PIPE_SIZE * 2 + 100];
+    let len = data.len();
+    let task = task::spawn(move || writer.write(&data));
+    let mut total = 0;
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                assert!(buffer[..n].iter().all(|&byte| byte == 0x5a));
+                total += n;
+            }
+            Err(error) => panic!("pipe read failed: {:?}", error),
+        }
+    }
+    assert_eq!(task.join(), Ok(len));
+    assert_eq!(total, len);
+
+    // Readiness for poll follows the data and the other end
+    let (reader, writer) = pipe();
+    assert!(!reader.ready().read && writer.ready().write);
+    assert_eq!(writer.write(b"x"), Ok(1));
+    assert!(reader.ready().read);
+    assert_eq!(reader.read(&mut buffer), Ok(1));
+    assert!(!reader.ready().read);
+    drop(writer);
+    assert!(reader.ready().read);
+
+    // Writing without a reader fails
+    let (reader, writer) = pipe();
+    drop(reader);
+    assert_eq!(writer.write(b"x"), Err(Errno::EPIPE));
+    println!("[ok]");
+}
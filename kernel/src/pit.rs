@@ -0,0 +1,61 @@
+use x86_64::instructions::port::*;
+
+// Intel 8254 Programmable Interval Timer
+
+pub const BASE_FREQUENCY_HZ: u32 = 1_193_182;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const CHANNEL2_DATA: u16 = 0x42;
+const MODE_COMMAND: u16 = 0x43;
+
+// Channel 2 gate and output are wired to the keyboard controller's port B
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT2: u8 = 1 << 5;
+
+// Channel 0, access mode lobyte/hibyte, mode 2 (rate generator), binary
+const CHANNEL0_RATE_GENERATOR: u8 = 0x34;
+// Channel 2, access mode lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+const CHANNEL2_ONE_SHOT: u8 = 0xb0;
+
+// Make channel 0 raise IRQ 0 `hz` times per second
+pub fn initialize(hz: u32) {
+    let divisor = (BASE_FREQUENCY_HZ / hz).clamp(1, 0xffff) as u16;
+
+    unsafe {
+        u8::write_to_port(MODE_COMMAND, CHANNEL0_RATE_GENERATOR);
+        u8::write_to_port(CHANNEL0_DATA, divisor as u8);
+        u8::write_to_port(CHANNEL0_DATA, (divisor >> 8) as u8);
+    }
+}
+
+// Busy wait using channel 2, which doesn't depend on interrupts or on any
+// other timer having been set up yet
+pub fn wait_us(us: u64) {
+    // A single count lasts at most 65535 ticks, about 55 ms
+    const MAX_COUNT: u64 = 0xffff;
+
+    let mut ticks = us * BASE_FREQUENCY_HZ as u64 / 1_000_000;
+
+    while ticks > 0 {
+        let count = ticks.min(MAX_COUNT) as u16;
+        ticks -= count as u64;
+
+        unsafe {
+            let port_b = u8::read_from_port(PORT_B) & !(PORT_B_SPEAKER | PORT_B_GATE2);
+            u8::write_to_port(PORT_B, port_b);
+
+            u8::write_to_port(MODE_COMMAND, CHANNEL2_ONE_SHOT);
+            u8::write_to_port(CHANNEL2_DATA, count as u8);
+            u8::write_to_port(CHANNEL2_DATA, (count >> 8) as u8);
+
+            // Counting starts on the rising edge of the gate
+            u8::write_to_port(PORT_B, port_b | PORT_B_GATE2);
+
+            while u8::read_from_port(PORT_B) & PORT_B_OUT2 == 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
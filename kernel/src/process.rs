@@ -0,0 +1,760 @@
+use crate::elf::{self, Program, HEAP_LIMIT, MMAP_END, MMAP_START};
+use crate::file::FdTable;
+use crate::fpu::FpuState;
+use crate::interrupt::TrapFrame;
+use crate::paging::{AddressSpace, MapError, USER_END, USER_START};
+use crate::signal::{SigInfo, Signals, SIGCHLD};
+use crate::sync::{self, WaitQueue};
+use crate::syscall::Errno;
+use crate::task;
+use crate::usermode::{self, UserExit};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+#[cfg(test)]
+use crate::syscall::{SYS_EXIT, SYS_YIELD};
+#[cfg(test)]
+use crate::usermode::{Asm, Fault, FaultKind, Reg};
+#[cfg(test)]
+use crate::{paging, print, println};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Pid {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(INIT_PID.0);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(pid: u64) -> Pid {
+        Pid(pid)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+// The first process started gets PID 1 and adopts the children of processes
+// that exit before them
+pub const INIT_PID: Pid = Pid(1);
+
+// Every process that hasn't been dropped yet
+static PROCESSES: Mutex<BTreeMap<Pid, Weak<Process>>> = Mutex::new(BTreeMap::new());
+
+// A user program with its own address space, run by one task. After exiting
+// it stays around with its exit status until its parent waits for it.
+pub struct Process {
+    pid: Pid,
+    name: String,
+    // Dangling for processes started by the kernel and orphans without init
+    parent: Mutex<Weak<Process>>,
+    // Children that haven't been waited for, whether they exited or not
+    children: Mutex<Vec<Arc<Process>>>,
+    // Taken when the process exits, which frees all of its memory
+    address_space: Mutex<Option<Arc<AddressSpace>>>,
+    exit: Mutex<Option<UserExit>>,
+    // Woken when the process or one of its children exits
+    exited: WaitQueue,
+    signals: Mutex<Signals>,
+    // Whether `signals` has one to deliver, checked on every return to user
+    // mode without taking the lock
+    signal_ready: AtomicBool,
+    // The queue the process sleeps on in `wait_interruptible`, which a signal
+    // wakes it from
+    waiting_on: Mutex<Option<QueuePointer>>,
+    files: Mutex<FdTable>,
+    heap: sync::Mutex<Heap>,
+    // Memory mapped with `mmap`, as end addresses by start address
+    mappings: sync::Mutex<BTreeMap<u64, u64>>,
+    personality: Mutex<Personality>,
+}
+
+// Which system calls a process makes: rustyos' own, or the subset of Linux'
+// in linux.rs for programs built for Linux. WebAssembly modules never enter
+// user mode, the interpreter in wasm.rs runs them in the kernel.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Personality {
+    Native,
+    Linux,
+    Wasm,
+}
+
+// The heap `brk` grows and shrinks, from the end of the program's segments
+// to the program break. Empty with `start` zero for processes not started
+// from an ELF file, which can't have one.
+#[derive(Debug, Copy, Clone, Default)]
+struct Heap {
+    start: u64,
+    end: u64,
+}
+
+impl Heap {
+    fn new(program: &Program) -> Heap {
+        Heap {
+            start: program.end,
+            end: program.end,
+        }
+    }
+}
+
+// Only dereferenced with `waiting_on` locked, and the queue is borrowed by
+// `wait_interruptible` until it clears it with the lock taken
+struct QueuePointer(*const WaitQueue);
+
+unsafe impl Send for QueuePointer {}
+
+impl Process {
+    // A process that doesn't run yet, as a child of `parent`, with the
+    // console as standard input, output and error
+    pub fn new(name: &str, parent: Option<&Arc<Process>>, space: AddressSpace) -> Arc<Process> {
+        Process::with_state(
+            name,
+            parent,
+            space,
+            Signals::new(),
+            FdTable::console(),
+            Heap::default(),
+        )
+    }
+
+    fn with_state(
+        name: &str,
+        parent: Option<&Arc<Process>>,
+        space: AddressSpace,
+        signals: Signals,
+        files: FdTable,
+        heap: Heap,
+    ) -> Arc<Process> {
+        let process = Arc::new(Process {
+            pid: Pid::new(),
+            name: String::from(name),
+            parent: Mutex::new(parent.map_or(Weak::new(), Arc::downgrade)),
+            children: Mutex::new(Vec::new()),
+            address_space: Mutex::new(Some(Arc::new(space))),
+            exit: Mutex::new(None),
+            exited: WaitQueue::new(),
+            signals: Mutex::new(signals),
+            signal_ready: AtomicBool::new(false),
+            waiting_on: Mutex::new(None),
+            files: Mutex::new(files),
+            heap: sync::Mutex::new(heap),
+            mappings: sync::Mutex::new(BTreeMap::new()),
+            personality: Mutex::new(Personality::Native),
+        });
+        interrupts::without_interrupts(|| {
+            PROCESSES
+                .lock()
+                .insert(process.pid, Arc::downgrade(&process))
+        });
+        if let Some(parent) = parent {
+            parent.children.lock().push(process.clone());
+        }
+        process
+    }
+
+    // Run the process in a new task, entering user mode at `entry` with stack
+    // pointer `stack`. Call only once.
+    pub fn start(self: &Arc<Self>, entry: VirtAddr, stack: VirtAddr) {
+        self.start_frame(usermode::initial_frame(entry, stack), None, 0);
+    }
+
+    // Run `f` in a new task instead of user mode, for processes without user
+    // memory. The process exits with what it returns. Call only once.
+        pub fn start_kernel<F>(self: &Arc<Self>, f: F)
+    where
+        F: FnOnce() -> UserExit + Send + 'static,
+    {
+        let process = self.clone();
+        task::Builder::new().name(&self.name).start(move || {
+            let task = task::current().expect("process outside of a task");
+            *task.user.process.lock() = Some(process.clone());
+            process.exit(f());
+        });
+    }
+
+    // Like `start`, with all registers given
+    fn start_frame(self: &Arc<Self>, frame: TrapFrame, fpu: Option<FpuState>, fs_base: u64) {
+        let pml4 = self
+            .address_space()
+            .expect("starting an exited process")
+            .pml4();
+        let process = self.clone();
+        task::Builder::new().name(&self.name).start(move || {
+            let task = task::current().expect("process outside of a task");
+            *task.user.process.lock() = Some(process.clone());
+            usermode::switch_page_table(pml4);
+            usermode::set_fs_base(fs_base);
+            let exit = usermode::run_frame(frame, fpu);
+            process.exit(exit);
+        });
+    }
+
+    // A child of the calling process for `fork`, with a copy-on-write copy of
+    // its memory. It returns to user mode with the registers of the system
+    // call in `frame` except for a result of 0, the same signal handlers, the
+    // same open files and the same personality.
+    pub fn fork(self: &Arc<Self>, frame: &TrapFrame) -> Result<Arc<Process>, MapError> {
+        let space = self
+            .address_space()
+            .expect("fork of an exited process")
+            .fork()?;
+        let signals = self.with_signals(|signals| signals.fork());
+        let files = self.with_files(|files| files.clone());
+        let heap = *self.heap.lock();
+        let child = Process::with_state(&self.name, Some(self), space, signals, files, heap);
+        *child.mappings.lock() = self.mappings.lock().clone();
+        *child.personality.lock() = self.personality();
+        let mut frame = *frame;
+        frame.rax = 0;
+        child.start_frame(frame, usermode::saved_fpu(), usermode::fs_base());
+        Ok(child)
+    }
+
+    // Replace the memory of the calling process with `program` for `execve`.
+    // The system call returns to its entry point through `frame`. Signal
+    // handlers are reset, they were in the old program.
+    pub fn exec(&self, program: Program, frame: &mut TrapFrame) {
+        let heap = Heap::new(&program);
+        self.replace_image(program.space, heap, program.personality);
+        *frame = usermode::initial_frame(program.entry, program.stack);
+        usermode::reset_fpu();
+        usermode::set_fs_base(0);
+    }
+
+    // Like `exec`, but leave the calling process without user memory, for a
+    // program the kernel runs in the system call. It mustn't return to user
+    // mode afterwards.
+    pub fn exec_kernel(&self, personality: Personality) -> Result<(), MapError> {
+        self.replace_image(AddressSpace::new()?, Heap::default(), personality);
+        Ok(())
+    }
+
+    fn replace_image(&self, space: AddressSpace, heap: Heap, personality: Personality) {
+        *self.heap.lock() = heap;
+        self.mappings.lock().clear();
+        *self.personality.lock() = personality;
+        let pml4 = space.pml4();
+        let old = self.address_space.lock().replace(Arc::new(space));
+        usermode::switch_page_table(pml4);
+        // No longer loaded anywhere
+        drop(old);
+        self.with_signals(Signals::exec);
+        let closed = self.with_files(FdTable::close_on_exec);
+        drop(closed);
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn parent(&self) -> Option<Arc<Process>> {
+        self.parent.lock().upgrade()
+    }
+
+    // None once the process exited
+    pub fn address_space(&self) -> Option<Arc<AddressSpace>> {
+        self.address_space.lock().clone()
+    }
+
+    pub fn exit_status(&self) -> Option<UserExit> {
+        *self.exit.lock()
+    }
+
+    pub fn personality(&self) -> Personality {
+        *self.personality.lock()
+    }
+
+    // Change the signal state
+    pub fn with_signals<R>(&self, f: impl FnOnce(&mut Signals) -> R) -> R {
+        interrupts::without_interrupts(|| {
+            let mut signals = self.signals.lock();
+            let result = f(&mut signals);
+            self.signal_ready
+                .store(signals.deliverable() != 0, Ordering::Relaxed);
+            result
+        })
+    }
+
+    // Whether a signal is pending and not blocked
+    pub fn signal_ready(&self) -> bool {
+        self.signal_ready.load(Ordering::Relaxed)
+    }
+
+    // Send the process `sig`, which it gets the next time it returns to user
+    // mode. Interrupts `wait_interruptible`.
+    pub fn send_signal(&self, sig: u8, info: SigInfo) {
+        self.with_signals(|signals| signals.send(sig, info));
+        interrupts::without_interrupts(|| {
+            if let Some(queue) = self.waiting_on.lock().as_ref() {
+                unsafe { &*queue.0 }.wake_all();
+            }
+        });
+    }
+
+    // Like `queue.wait_until(condition)` for the process's task, but give up
+    // with EINTR once the process has a signal to handle
+    pub fn wait_interruptible<F>(&self, queue: &WaitQueue, condition: F) -> Result<(), Errno>
+    where
+        F: FnMut() -> bool,
+    {
+        self.wait_interruptible_timeout(queue, condition, None)
+            .map(|_| ())
+    }
+
+    // Like `wait_interruptible`, but also give up after `timeout` if there is
+    // one. Returns whether the condition became true.
+    pub fn wait_interruptible_timeout<F>(
+        &self,
+        queue: &WaitQueue,
+        mut condition: F,
+        timeout: Option<Duration>,
+    ) -> Result<bool, Errno>
+    where
+        F: FnMut() -> bool,
+    {
+        interrupts::without_interrupts(|| {
+            *self.waiting_on.lock() = Some(QueuePointer(queue));
+        });
+        let mut interrupted = false;
+        let check = || {
+            if condition() {
+                return true;
+            }
+            interrupted = self.signal_ready();
+            interrupted
+        };
+        let satisfied = match timeout {
+            Some(timeout) => queue.wait_until_timeout(check, timeout),
+            None => {
+                queue.wait_until(check);
+                true
+            }
+        };
+        interrupts::without_interrupts(|| *self.waiting_on.lock() = None);
+        match interrupted {
+            true => Err(Errno::EINTR),
+            false => Ok(satisfied),
+        }
+    }
+
+    // Move the program break to `end` for `brk`, mapping or unmapping heap
+    // pages. Returns the new break, or the old one if it can't move there.
+    pub fn brk(&self, end: u64) -> u64 {
+        let mut heap = self.heap.lock();
+        let space = match self.address_space() {
+            Some(space) => space,
+            None => return heap.end,
+        };
+        if heap.start == 0 || end < heap.start || end > HEAP_LIMIT {
+            return heap.end;
+        }
+        // The page the segments end in is mapped with them
+        let mapped = heap.end.next_multiple_of(4096);
+        let needed = end.next_multiple_of(4096);
+        if needed > mapped {
+            let flags = PageTableFlags::WRITABLE | elf::no_execute();
+            if space.map(mapped, needed - mapped, flags).is_err() {
+                return heap.end;
+            }
+        } else if needed < mapped {
+            space.unmap(needed, mapped - needed);
+        }
+        heap.end = end;
+        end
+    }
+
+    // Map `len` bytes of zeroed memory for `mmap`, accessible with `flags`,
+    // or reserved but inaccessible if None. At `fixed` if given, replacing
+    // what was mapped there, else wherever there is room. Only the range
+    // from MMAP_START to MMAP_END is used for that.
+    pub fn mmap(
+        &self,
+        fixed: Option<u64>,
+        len: u64,
+        flags: Option<PageTableFlags>,
+    ) -> Result<u64, Errno> {
+        let len = len.checked_next_multiple_of(4096).ok_or(Errno::ENOMEM)?;
+        if len == 0 {
+            return Err(Errno::EINVAL);
+        }
+        let space = self.address_space().ok_or(Errno::ESRCH)?;
+        let mut mappings = self.mappings.lock();
+        let start = match fixed {
+            Some(start) => {
+                let end = start.checked_add(len).ok_or(Errno::EINVAL)?;
+                if !start.is_multiple_of(4096) || start < MMAP_START || end > MMAP_END {
+                    return Err(Errno::EINVAL);
+                }
+                unmap_range(&space, &mut mappings, start, end);
+                start
+            }
+            None => free_range(&mappings, len).ok_or(Errno::ENOMEM)?,
+        };
+        if let Some(flags) = flags {
+            space.map(start, len, flags).map_err(|_| Errno::ENOMEM)?;
+        }
+        mappings.insert(start, start + len);
+        Ok(start)
+    }
+
+    // Remove what `mmap` mapped in `len` bytes at `start`
+    pub fn munmap(&self, start: u64, len: u64) -> Result<(), Errno> {
+        let end = start
+            .checked_add(len)
+            .and_then(|end| end.checked_next_multiple_of(4096))
+            .ok_or(Errno::EINVAL)?;
+        if !start.is_multiple_of(4096) || len == 0 || start < MMAP_START || end > MMAP_END {
+            return Err(Errno::EINVAL);
+        }
+        let space = self.address_space().ok_or(Errno::ESRCH)?;
+        unmap_range(&space, &mut self.mappings.lock(), start, end);
+        Ok(())
+    }
+
+    // Use the file descriptor table
+    pub fn with_files<R>(&self, f: impl FnOnce(&mut FdTable) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.files.lock()))
+    }
+
+    // Called by the process's task when it leaves user mode for good
+    fn exit(&self, exit: UserExit) {
+        // Nothing may have the address space loaded when it is freed
+        usermode::switch_page_table(0);
+        let space = self.address_space.lock().take();
+        drop(space);
+        // Readers of pipes it was writing to see their end
+        let files = self.with_files(core::mem::take);
+        drop(files);
+
+        // Children are adopted by init, or have nobody to wait for them
+        let children = core::mem::take(&mut *self.children.lock());
+        if !children.is_empty() {
+            let init = lookup(INIT_PID).filter(|init| init.pid != self.pid);
+            let new_parent = init.as_ref().map_or(Weak::new(), Arc::downgrade);
+            for child in &children {
+                *child.parent.lock() = new_parent.clone();
+            }
+            if let Some(init) = init {
+                // Some of them may have exited already, and sent SIGCHLD to
+                // this process rather than init
+                let exited: Vec<_> = children
+                    .iter()
+                    .filter_map(|child| Some((child.pid, child.exit_status()?)))
+                    .collect();
+                init.children.lock().extend(children);
+                for (pid, exit) in exited {
+                    init.send_signal(SIGCHLD, SigInfo::child(pid, exit));
+                }
+            }
+        }
+
+        *self.exit.lock() = Some(exit);
+        self.exited.wake_all();
+        if let Some(parent) = self.parent() {
+            parent.send_signal(SIGCHLD, SigInfo::child(self.pid, exit));
+        }
+    }
+
+    // Wait for a child to exit, any if `pid` is None, and remove it. Fails
+    // right away with ECHILD if there is no such child, or with EINTR once
+    // the process has a signal to handle.
+    pub fn wait(&self, pid: Option<Pid>) -> Result<(Pid, UserExit), Errno> {
+        let mut reaped = Ok(None);
+        self.wait_interruptible(&self.exited, || {
+            reaped = self.reap(pid);
+            !matches!(reaped, Ok(None))
+        })?;
+        // Dropped here rather than with the wait queue locked
+        let child = reaped?.ok_or(Errno::ECHILD)?;
+        let exit = child.exit_status().ok_or(Errno::ECHILD)?;
+        Ok((child.pid, exit))
+    }
+
+    // Like `wait`, but give up with None after `timeout`. Having no such child
+    // isn't an error, init may still adopt one.
+    pub fn wait_timeout(
+        &self,
+        pid: Option<Pid>,
+        timeout: Duration,
+    ) -> Result<Option<(Pid, UserExit)>, Errno> {
+        let mut reaped = None;
+        self.wait_interruptible_timeout(
+            &self.exited,
+            || {
+                reaped = self.reap(pid).ok().flatten();
+                reaped.is_some()
+            },
+            Some(timeout),
+        )?;
+        match reaped {
+            Some(child) => {
+                let exit = child.exit_status().ok_or(Errno::ECHILD)?;
+                Ok(Some((child.pid, exit)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Like `wait`, but None right away if no such child exited yet
+    pub fn try_wait(&self, pid: Option<Pid>) -> Result<Option<(Pid, UserExit)>, Errno> {
+        match self.reap(pid)? {
+            Some(child) => {
+                let exit = child.exit_status().ok_or(Errno::ECHILD)?;
+                Ok(Some((child.pid, exit)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Remove a child matching `pid` that exited, if there is one
+    fn reap(&self, pid: Option<Pid>) -> Result<Option<Arc<Process>>, Errno> {
+        let mut children = self.children.lock();
+        let matches = |child: &Arc<Process>| pid.is_none_or(|pid| child.pid == pid);
+        if !children.iter().any(matches) {
+            return Err(Errno::ECHILD);
+        }
+        let exited = children
+            .iter()
+            .position(|child| matches(child) && child.exit.lock().is_some());
+        Ok(exited.map(|i| children.swap_remove(i)))
+    }
+
+    // Wait for the process to exit, for kernel code that started it. Unlike
+    // `wait` this leaves it to its parent.
+    pub fn wait_exit(&self) -> UserExit {
+        self.exited.wait_until(|| self.exit.lock().is_some());
+        self.exit_status().expect("woken before exit")
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| PROCESSES.lock().remove(&self.pid));
+    }
+}
+
+// Create a process for `space` as a child of the calling one, and start it
+pub fn spawn(name: &str, space: AddressSpace, entry: VirtAddr, stack: VirtAddr) -> Arc<Process> {
+    let process = Process::new(name, current().as_ref(), space);
+    process.start(entry, stack);
+    process
+}
+
+// Start the loaded `program` in a new process, as a child of the calling one
+pub fn spawn_program(name: &str, program: Program) -> Arc<Process> {
+    spawn_program_with_files(name, program, FdTable::console())
+}
+
+// Like `spawn_program`, with `files` as its file descriptors rather than the
+// console
+pub fn spawn_program_with_files(name: &str, program: Program, files: FdTable) -> Arc<Process> {
+    let heap = Heap::new(&program);
+    let process = Process::with_state(
+        name,
+        current().as_ref(),
+        program.space,
+        Signals::new(),
+        files,
+        heap,
+    );
+    *process.personality.lock() = program.personality;
+    process.start(program.entry, program.stack);
+    process
+}
+
+// Run `f` in a new process without user memory, as a child of the calling
+// one, see `Process::start_kernel`
+pub fn spawn_kernel<F>(name: &str, personality: Personality, f: F) -> Result<Arc<Process>, MapError>
+where
+    F: FnOnce() -> UserExit + Send + 'static,
+{
+    let process = Process::new(name, current().as_ref(), AddressSpace::new()?);
+    *process.personality.lock() = personality;
+    process.start_kernel(f);
+    Ok(process)
+}
+
+// The highest `len` bytes in the `mmap` range that `mappings` leave free
+fn free_range(mappings: &BTreeMap<u64, u64>, len: u64) -> Option<u64> {
+    let mut end = MMAP_END;
+    for (&start, &mapping_end) in mappings.iter().rev() {
+        if end - mapping_end >= len {
+            return Some(end - len);
+        }
+        end = start;
+    }
+    (end - MMAP_START >= len).then(|| end - len)
+}
+
+// Unmap the pages from `start` to `end`, splitting `mappings` where they
+// only partly overlap
+fn unmap_range(space: &AddressSpace, mappings: &mut BTreeMap<u64, u64>, start: u64, end: u64) {
+    let overlapping: Vec<(u64, u64)> = mappings
+        .range(..end)
+        .filter(|&(_, &mapping_end)| mapping_end > start)
+        .map(|(&start, &end)| (start, end))
+        .collect();
+    for (mapping_start, mapping_end) in overlapping {
+        mappings.remove(&mapping_start);
+        if mapping_start < start {
+            mappings.insert(mapping_start, start);
+        }
+        if mapping_end > end {
+            mappings.insert(end, mapping_end);
+        }
+    }
+    space.unmap(start, end - start);
+}
+
+// The process of the calling task
+pub fn current() -> Option<Arc<Process>> {
+    task::current()?.user.process.lock().clone()
+}
+
+// `Process::wait_interruptible` for the calling process, or a plain wait for
+// kernel tasks
+pub fn wait_interruptible<F>(queue: &WaitQueue, condition: F) -> Result<(), Errno>
+where
+    F: FnMut() -> bool,
+{
+    wait_interruptible_timeout(queue, condition, None).map(|_| ())
+}
+
+// The same with a timeout, see `Process::wait_interruptible_timeout`
+pub fn wait_interruptible_timeout<F>(
+    queue: &WaitQueue,
+    condition: F,
+    timeout: Option<Duration>,
+) -> Result<bool, Errno>
+where
+    F: FnMut() -> bool,
+{
+    match (current(), timeout) {
+        (Some(process), _) => process.wait_interruptible_timeout(queue, condition, timeout),
+        (None, Some(timeout)) => Ok(queue.wait_until_timeout(condition, timeout)),
+        (None, None) => {
+            queue.wait_until(condition);
+            Ok(true)
+        }
+    }
+}
+
+// Handle a write fault at `address` in user space on a copy-on-write page of
+// the calling process. Returns false if it wasn't one.
+pub fn copy_on_write(address: u64) -> bool {
+    (USER_START..USER_END).contains(&address)
+        && current()
+            .and_then(|process| process.address_space())
+            .is_some_and(|space| space.copy_on_write(address))
+}
+
+pub fn lookup(pid: Pid) -> Option<Arc<Process>> {
+    let process = interrupts::without_interrupts(|| PROCESSES.lock().get(&pid).cloned());
+    process?.upgrade()
+}
+
+// Send `sig` from the kernel to every process but init that is still running,
+// like kill(-1, sig)
+pub fn signal_all(sig: u8) {
+    let processes: Vec<_> = interrupts::without_interrupts(|| {
+        PROCESSES
+            .lock()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect()
+    });
+    for process in processes {
+        if process.pid != INIT_PID && process.exit_status().is_none() {
+            process.send_signal(sig, SigInfo::user(None));
+        }
+    }
+}
+
+// A page of data after the code of `usermode::test_space`
+#[cfg(test)]
+const TEST_DATA: u64 = USER_START + 0x20000;
+
+// Two processes using the same addresses see their own memory
+#[test_case]
+fn isolated_address_spaces() {
+    print!("process isolation... ");
+    let (frames, _) = paging::frame_usage();
+    let spawn_writer = |value: u8| {
+        let code = Asm::new()
+            .mov64(Reg::Rbx, TEST_DATA)
+            .mov(Reg::Rax, value.into())
+            .store(Reg::Rbx, 0, Reg::Rax)
+            .syscall(SYS_YIELD)
+            .load(Reg::Rax, Reg::Rbx, 0)
+            .exit_with(Reg::Rax)
+            .code();
+        let space = usermode::test_space(&code);
+        space
+            .map(TEST_DATA, 4096, PageTableFlags::WRITABLE)
+            .expect("map user data");
+        spawn(
+            "writer",
+            space,
+            VirtAddr::new(USER_START),
+            VirtAddr::new(usermode::TEST_STACK),
+        )
+    };
+    let writers: Vec<_> = (1..=4).map(spawn_writer).collect();
+    for (value, writer) in (1..=4).zip(writers) {
+        assert_eq!(writer.wait_exit(), UserExit::Exit(value));
+        assert!(writer.address_space().is_none());
+    }
+    // Frames and page tables of the exited processes were freed
+    assert_eq!(paging::frame_usage().0, frames);
+    println!("[ok]");
+}
+
+#[test_case]
+fn wait_for_children() {
+    print!("process wait... ");
+    let space = AddressSpace::new().expect("address space");
+    let parent = Process::new("parent", None, space);
+
+    let start = |code: &[u8]| {
+        let child = Process::new("child", Some(&parent), usermode::test_space(code));
+        child.start(
+            VirtAddr::new(USER_START),
+            VirtAddr::new(usermode::TEST_STACK),
+        );
+        child
+    };
+    let exits = start(&Asm::new().mov(Reg::Rdi, 3).syscall(SYS_EXIT).code());
+    // ud2
+    let faults = start(&[0x0f, 0x0b]);
+    assert!(Arc::ptr_eq(&faults.parent().unwrap(), &parent));
+
+    assert!(matches!(
+        parent.wait(Some(faults.pid())),
+        Ok((
+            _,
+            UserExit::Fault(Fault {
+                kind: FaultKind::InvalidOpcode,
+                ..
+            })
+        ))
+    ));
+    assert_eq!(parent.wait(None), Ok((exits.pid(), UserExit::Exit(3))));
+    assert_eq!(parent.wait(None), Err(Errno::ECHILD));
+    assert_eq!(parent.wait(Some(exits.pid())), Err(Errno::ECHILD));
+    assert!(lookup(exits.pid()).is_some());
+    println!("[ok]");
+}
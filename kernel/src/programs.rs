@@ -0,0 +1,316 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::vec::Vec;
+
+#[cfg(test)]
+use crate::file::{FdTable, OpenFile};
+#[cfg(test)]
+use crate::process::{Personality, Process};
+#[cfg(test)]
+use crate::signal::{SigInfo, SIGKILL};
+use crate::sync::RwLock;
+#[cfg(test)]
+use crate::syscall::{SYS_EXIT, SYS_SLEEP};
+#[cfg(test)]
+use crate::usermode::{Asm, Reg, UserExit};
+use crate::{elf, println, process};
+#[cfg(test)]
+use crate::{pipe, print, wasm};
+#[cfg(test)]
+use alloc::boxed::Box;
+#[cfg(test)]
+use alloc::sync::Arc;
+#[cfg(test)]
+use goblin::elf::header::ET_DYN;
+
+// Executables `execve` can run and the files programs read, by path, until
+// there is a file system
+static PROGRAMS: RwLock<BTreeMap<Vec<u8>, &'static [u8]>> = RwLock::new(BTreeMap::new());
+
+// The user programs and the files for /etc by name, generated by build.rs
+include!(concat!(env!("OUT_DIR"), "/builtin_programs.rs"));
+
+// Make the ELF file, WebAssembly module or other file `image` available at
+// `path`, replacing what was there
+pub fn register(path: &str, image: &'static [u8]) {
+    PROGRAMS.write().insert(Vec::from(path.as_bytes()), image);
+}
+
+pub fn find(path: &[u8]) -> Option<&'static [u8]> {
+    PROGRAMS.read().get(path).copied()
+}
+
+// Whether `path` is a directory with programs in it
+pub fn is_directory(path: &[u8]) -> bool {
+    let path = path.strip_suffix(b"/").unwrap_or(path);
+    PROGRAMS.read().keys().any(|program| {
+        program.len() > path.len() && program.starts_with(path) && program[path.len()] == b'/'
+    })
+}
+
+// Make the user programs built into the kernel available as /bin/<name>,
+// except init which goes to /sbin like on other Unix systems, and the files
+// for /etc as /etc/<name>
+pub fn register_builtin() {
+    for (name, image) in BUILTIN_PROGRAMS {
+        match *name {
+            "init" => register("/sbin/init", image),
+            _ => register(&format!("/bin/{}", name), image),
+        }
+    }
+    for (name, contents) in BUILTIN_FILES {
+        register(&format!("/etc/{}", name), contents);
+    }
+}
+
+// Start /sbin/init as the first process, so it gets PID 1
+#[cfg(not(test))]
+pub fn start_init() {
+    let path = "/sbin/init";
+    let image = match find(path.as_bytes()) {
+        Some(image) => image,
+        None => {
+            println!("{} not found, not starting init", path);
+            return;
+        }
+    };
+    match elf::load(image, &[path.as_bytes()], &[]) {
+        Ok(program) => {
+            let init = process::spawn_program("init", program);
+            assert_eq!(init.pid(), process::INIT_PID);
+        }
+        Err(error) => println!("can't load {}: {:?}", path, error),
+    }
+}
+
+#[test_case]
+fn builtin_programs() {
+    print!("builtin programs... ");
+    // Each of the native ones runs to a successful exit without arguments,
+    // apart from init which never exits. Linux programs from user/linux and
+    // modules from user/wasm are whatever was put there.
+    for (name, _) in BUILTIN_PROGRAMS.iter().filter(|(name, _)| *name != "init") {
+        let path = format!("/bin/{}", name);
+        let image = find(path.as_bytes()).expect("registered");
+        if wasm::is_module(image) {
+            continue;
+        }
+        let program = elf::load(image, &[path.as_bytes()], &[]).expect("load");
+        if program.personality != Personality::Native {
+            continue;
+        }
+        let process = process::spawn_program(name, program);
+        assert_eq!(process.wait_exit(), UserExit::Exit(0), "{}", path);
+    }
+    println!("[ok]");
+}
+
+// /sbin/init run with a test configuration as a child of the test, with its
+// output and that of its services going to a pipe
+#[cfg(test)]
+struct TestInit {
+    process: Arc<Process>,
+    output: Arc<OpenFile>,
+    printed: Vec<u8>,
+    // Where `expect` looks next
+    checked: usize,
+}
+
+#[cfg(test)]
+impl TestInit {
+    // None if init wasn't built
+    fn start(config: &'static str) -> Option<TestInit> {
+        let image = find(b"/sbin/init")?;
+        register("/etc/init-test.conf", config.as_bytes());
+        let argv: [&[u8]; 2] = [b"/sbin/init", b"/etc/init-test.conf"];
+        let program = elf::load(image, &argv, &[]).expect("load init");
+        let (reader, writer) = pipe::pipe();
+        let writer = OpenFile::new(Box::new(writer), false, true);
+        let mut files = FdTable::console();
+        for fd in 1..=2 {
+            files.close(fd).expect("console");
+            assert_eq!(files.insert(writer.clone()), Ok(fd));
+        }
+        Some(TestInit {
+            process: process::spawn_program_with_files("init", program, files),
+            output: OpenFile::new(Box::new(reader), true, false),
+            printed: Vec::new(),
+            checked: 0,
+        })
+    }
+
+    fn printed(&self, text: &str) -> bool {
+        find_text(&self.printed, text).is_some()
+    }
+
+    fn read_more(&mut self, text: &str) {
+        let mut buffer = [0u8; 256];
+        let n = self.output.read(&mut buffer).expect("read init output");
+        assert!(n > 0, "init stopped before printing {:?}", text);
+        self.printed.extend_from_slice(&buffer[..n]);
+    }
+
+    // Read until `text` is printed after what was expected before
+    fn expect(&mut self, text: &str) {
+        loop {
+            if let Some(end) = find_text(&self.printed[self.checked..], text) {
+                self.checked += end;
+                return;
+            }
+            self.read_more(text);
+        }
+    }
+
+    // Read until `text` is printed anywhere
+    fn wait_for(&mut self, text: &str) {
+        while !self.printed(text) {
+            self.read_more(text);
+        }
+    }
+
+    fn stop(self) {
+        self.process.send_signal(SIGKILL, SigInfo::user(None));
+        assert_eq!(self.process.wait_exit(), UserExit::Signal(SIGKILL));
+    }
+}
+
+// The end of the first `text` in `output`
+#[cfg(test)]
+fn find_text(output: &[u8], text: &str) -> Option<usize> {
+    let text = text.as_bytes();
+    output
+        .windows(text.len())
+        .position(|window| window == text)
+        .map(|start| start + text.len())
+}
+
+// Errors are reported with their line and leave the service out
+#[test_case]
+fn init_config_errors() {
+    print!("init configuration errors... ");
+    let config = "    exec /bin/hello
+service a
+    exec /bin/hello
+    colour blue
+service a
+    exec /bin/hello
+service
+service b
+    restart sometimes
+    exec /bin/hello
+service c
+service d
+    exec /bin/hello
+";
+    let mut init = match TestInit::start(config) {
+        Some(init) => init,
+        None => return println!("[not built]"),
+    };
+    let path = "/etc/init-test.conf";
+    init.expect(&format!("{}:1: setting outside of a service", path));
+    init.expect(&format!("{}:4: unknown setting", path));
+    init.expect(&format!("{}:5: duplicate service", path));
+    init.expect(&format!("{}:7: expected `service NAME`", path));
+    init.expect(&format!("{}:9: expected `restart", path));
+    init.expect(&format!("{}: service c has no exec", path));
+    init.expect("init: starting d");
+    init.wait_for("init: d (exit status 0)");
+    for name in ["a", "b", "c"] {
+        assert!(!init.printed(&format!("starting {}", name)), "{}", name);
+    }
+    init.stop();
+    println!("[ok]");
+}
+
+// Services start after those they name, those needing an unknown service or
+// in a cycle don't
+#[test_case]
+fn init_start_order() {
+    print!("init start order... ");
+    let config = "service last
+    exec /bin/hello
+    after middle
+service middle
+    exec /bin/hello
+    after first
+service first
+    exec /bin/hello
+service needy
+    exec /bin/hello
+    after missing
+service x
+    exec /bin/hello
+    after y
+service y
+    exec /bin/hello
+    after x
+service free
+    exec /bin/hello
+";
+    let mut init = match TestInit::start(config) {
+        Some(init) => init,
+        None => return println!("[not built]"),
+    };
+    for name in ["needy", "x", "y"] {
+        init.expect(&format!("init: not starting {}:", name));
+    }
+    for name in ["first", "middle", "last", "free"] {
+        init.expect(&format!("init: starting {}\n", name));
+    }
+    for name in ["first", "middle", "last", "free"] {
+        init.wait_for(&format!("init: {} (exit status 0)", name));
+    }
+    init.stop();
+    println!("[ok]");
+}
+
+// A crashing service is started again, waiting twice as long after each
+// failure in a row until one run lasts long enough to count as started.
+// Takes a while for that.
+#[test_case]
+fn init_restarts() {
+    print!("init restarts... ");
+    let crash = Asm::new().mov(Reg::Rdi, 3).syscall(SYS_EXIT).code();
+    // Exits with the same status after more than init's STABLE
+    let stable = Asm::new()
+        .mov64(Reg::Rdi, 10_100_000_000)
+        .syscall(SYS_SLEEP)
+        .mov(Reg::Rdi, 3)
+        .syscall(SYS_EXIT)
+        .code();
+    let image = |code: &[u8]| -> &'static [u8] {
+        Box::leak(elf::test_image(ET_DYN, 0, code, None).into_boxed_slice())
+    };
+    let (crash, stable) = (image(&crash), image(&stable));
+    register("/bin/init-test", crash);
+
+    let config = "service ok
+    exec /bin/hello
+    restart on-failure
+service crash
+    exec /bin/init-test
+    restart on-failure
+";
+    let mut init = match TestInit::start(config) {
+        Some(init) => init,
+        None => return println!("[not built]"),
+    };
+    for delay in [500, 1000, 2000] {
+        init.expect("init: starting crash");
+        init.expect("init: crash (exit status 3)");
+        init.expect(&format!("init: restarting crash in {} ms", delay));
+    }
+    register("/bin/init-test", stable);
+    init.expect("init: starting crash");
+    init.expect("init: crash (exit status 3)");
+    init.expect("init: restarting crash in 500 ms");
+    // Leave no service running behind
+    register("/bin/init-test", crash);
+    init.expect("init: starting crash");
+    init.expect("init: crash (exit status 3)");
+
+    init.wait_for("init: ok (exit status 0)");
+    assert!(!init.printed("restarting ok"));
+    init.stop();
+    println!("[ok]");
+}
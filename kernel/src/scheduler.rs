@@ -0,0 +1,887 @@
+use crate::idle;
+use crate::interrupt::{self, TIMER_HZ};
+use crate::ipi::{self, DeliveryMode};
+use crate::paging;
+use crate::percpu::{self, PerCpu};
+use crate::smp::MAX_CPUS;
+use crate::task::{self, State, Task, TaskId, PRIORITY_HIGHEST};
+use crate::time;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::FsBase;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+#[cfg(test)]
+use crate::{print, println};
+#[cfg(test)]
+use core::sync::atomic::AtomicBool;
+
+// Vector used to make an idle processor look at its run queue
+pub const RESCHEDULE_VECTOR: u8 = 0xf1;
+
+const TICK_NS: u64 = 1_000_000_000 / TIMER_HZ as u64;
+
+// Every ready task of a processor gets to run once within this period, for a
+// share of it proportional to its weight
+const PERIOD_TICKS: u64 = 6;
+
+// Busy processors pull work from others this often, idle ones whenever they
+// run out of work
+const BALANCE_INTERVAL_TICKS: u64 = 10;
+
+// Weight of each priority from PRIORITY_HIGHEST to PRIORITY_LOWEST, the same
+// as Linux's CFS. A step is about 10% more or less CPU time.
+const WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+const DEFAULT_WEIGHT: u64 = 1024;
+
+// Bandwidth is a fraction of a processor in units of 1 / BANDWIDTH_ONE. The
+// deadline class may reserve up to 95%, so that normal tasks still make
+// progress.
+const BANDWIDTH_ONE: u64 = 1 << 20;
+const DEADLINE_BANDWIDTH_LIMIT: u64 = BANDWIDTH_ONE * 95 / 100;
+
+// Bandwidth reserved by the deadline tasks of each processor
+static DEADLINE_BANDWIDTH: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+// A deadline task runs for up to `runtime` in every `period` and the run time
+// of each period, a job, is done within `deadline` of the period's start. The
+// timer interrupt drives the class, so times are only as precise as a tick.
+#[derive(Debug, Copy, Clone)]
+pub struct DeadlineParams {
+    pub runtime: Duration,
+    pub deadline: Duration,
+    pub period: Duration,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AdmissionError {
+    // Not 0 < runtime <= deadline <= period
+    InvalidParameters,
+    // None of the allowed processors has enough bandwidth left
+    Overloaded,
+}
+
+fn weight(task: &Task) -> u64 {
+    WEIGHTS[(task.priority() - PRIORITY_HIGHEST) as usize]
+}
+
+// Ready tasks of one processor, ordered by virtual runtime. A task's
+// virtual runtime grows with the time it runs, more slowly the higher its
+// weight, and the task furthest behind runs next.
+struct RunQueue {
+    tasks: BTreeMap<(u64, TaskId), Arc<Task>>,
+    // Ready deadline tasks by absolute deadline, run before all others
+    deadline_tasks: BTreeMap<(u64, TaskId), Arc<Task>>,
+    // Lower bound of the virtual runtimes on this queue, used to place tasks
+    // which arrive from elsewhere
+    min_vruntime: u64,
+    // Sum of the weights of the queued tasks
+    load: u64,
+}
+
+impl RunQueue {
+    const fn new() -> RunQueue {
+        RunQueue {
+            tasks: BTreeMap::new(),
+            deadline_tasks: BTreeMap::new(),
+            min_vruntime: 0,
+            load: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.tasks.len() + self.deadline_tasks.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tasks.is_empty() && self.deadline_tasks.is_empty()
+    }
+
+    fn earliest_deadline(&self) -> Option<u64> {
+        self.deadline_tasks.keys().next().map(|key| key.0)
+    }
+
+    fn push(&mut self, cpu_index: usize, task: Arc<Task>) {
+        task.cpu.store(cpu_index, Ordering::Relaxed);
+        if task.is_deadline() {
+            let key = (
+                task.deadline.absolute_deadline.load(Ordering::Relaxed),
+                task.id(),
+            );
+            self.deadline_tasks.insert(key, task);
+            return;
+        }
+        self.load += weight(&task);
+        let key = (task.vruntime.load(Ordering::Relaxed), task.id());
+        self.tasks.insert(key, task);
+    }
+
+    // A task that slept or comes from another queue must not have a virtual
+    // runtime far below the others, or it would monopolize this processor
+    // until it caught up
+    fn push_new(&mut self, cpu_index: usize, task: Arc<Task>) {
+        if task.is_deadline() {
+            self.push(cpu_index, task);
+            return;
+        }
+        let vruntime = task.vruntime.load(Ordering::Relaxed);
+        task.vruntime
+            .store(vruntime.max(self.min_vruntime), Ordering::Relaxed);
+        self.push(cpu_index, task);
+    }
+
+    fn pop(&mut self) -> Option<Arc<Task>> {
+        if let Some(key) = self.deadline_tasks.keys().next().copied() {
+            return self.deadline_tasks.remove(&key);
+        }
+        let key = *self.tasks.keys().next()?;
+        Some(self.remove(key))
+    }
+
+    fn remove(&mut self, key: (u64, TaskId)) -> Arc<Task> {
+        let task = self.tasks.remove(&key).unwrap();
+        self.load -= weight(&task);
+        self.min_vruntime = self.min_vruntime.max(key.0);
+        task
+    }
+
+    // The queued task which ran most and may run on `cpu_index`
+    fn steal(&mut self, cpu_index: usize) -> Option<Arc<Task>> {
+        let key = *self
+            .tasks
+            .iter()
+            .rev()
+            .find(|(_, task)| task.affinity() & (1 << cpu_index) != 0)?
+            .0;
+        let task = self.tasks.remove(&key).unwrap();
+        self.load -= weight(&task);
+        Some(task)
+    }
+}
+
+static RUN_QUEUES: [Mutex<RunQueue>; MAX_CPUS] = [const { Mutex::new(RunQueue::new()) }; MAX_CPUS];
+
+// Run queues are only locked with interrupts disabled, the timer interrupt
+// uses them too
+fn run_queue(cpu_index: usize) -> MutexGuard<'static, RunQueue> {
+    debug_assert!(!interrupts::are_enabled());
+    RUN_QUEUES[cpu_index].lock()
+}
+
+fn is_scheduling(cpu: &PerCpu) -> bool {
+    cpu.idle_task.load(Ordering::Acquire) != 0
+}
+
+fn is_idle(cpu: &PerCpu) -> bool {
+    cpu.current_task.load(Ordering::Relaxed) == cpu.idle_task.load(Ordering::Relaxed)
+}
+
+// Deadline of the job running on `cpu`, u64::MAX for normal tasks
+fn running_deadline(task: &Task) -> u64 {
+    if task.is_deadline() {
+        task.deadline.absolute_deadline.load(Ordering::Relaxed)
+    } else {
+        u64::MAX
+    }
+}
+
+// Make `task` ready and queue it on the least loaded processor it may run on
+pub fn enqueue(task: Arc<Task>) {
+    interrupts::without_interrupts(|| {
+        let target = select_cpu(&task);
+        let deadline = running_deadline(&task);
+        task.set_state(State::Ready);
+        run_queue(target).push_new(target, task);
+        kick(target);
+
+        // Deadline tasks preempt normal ones and later deadlines
+        if let Some(cpu) = percpu::get(target) {
+            if !is_idle(cpu) && deadline < cpu.running_deadline.load(Ordering::Relaxed) {
+                resched(target);
+            }
+        }
+    });
+}
+
+// Prefer the processor the task last ran on, then the current one, among the
+// allowed processors with the fewest tasks
+fn select_cpu(task: &Task) -> usize {
+    let affinity = task.affinity();
+    let current = percpu::current().cpu_index;
+    let last = task.cpu.load(Ordering::Relaxed);
+
+    let mut best: Option<(usize, usize)> = None;
+    for cpu in percpu::online().filter(|cpu| is_scheduling(cpu)) {
+        if affinity & (1 << cpu.cpu_index) == 0 {
+            continue;
+        }
+        let load = run_queue(cpu.cpu_index).len() + !is_idle(cpu) as usize;
+        let better = match best {
+            None => true,
+            Some((index, best_load)) => {
+                load < best_load
+                    || (load == best_load && cpu.cpu_index == last)
+                    || (load == best_load && cpu.cpu_index == current && index != last)
+            }
+        };
+        if better {
+            best = Some((cpu.cpu_index, load));
+        }
+    }
+
+    // None of the allowed processors is running, keep the task on this one
+    best.map_or(current, |(index, _)| index)
+}
+
+// Make a blocked task ready again. Does nothing if it was already woken, so
+// that a timeout and a regular wakeup racing each other are harmless.
+pub fn wake(task: &Arc<Task>) {
+    if task.transition(State::Blocked, State::Ready) {
+        let now = time::nanos();
+        task.stats.woken_at.store(now, Ordering::Relaxed);
+        if task.is_deadline() && !can_continue_job(task, now) {
+            replenish(task, now);
+        }
+        enqueue(task.clone());
+    }
+}
+
+// Whether the calling context is a task that may block. The idle tasks must
+// always be runnable, code running on them has to poll instead.
+pub fn can_block() -> bool {
+    interrupts::without_interrupts(|| {
+        let cpu = percpu::current();
+        let current = cpu.current_task.load(Ordering::Relaxed);
+        current != 0
+            && current != cpu.idle_task.load(Ordering::Relaxed)
+            && cpu.preempt_count.load(Ordering::Relaxed) == 0
+    })
+}
+
+// Get processor `cpu_index` to look at its run queue if it has nothing to do.
+// Also wakes the executor polling futures on its idle task.
+pub fn kick(cpu_index: usize) {
+    let cpu = match percpu::get(cpu_index) {
+        Some(cpu) => cpu,
+        None => return,
+    };
+    if !is_idle(cpu) {
+        return;
+    }
+
+    if cpu_index == percpu::current().cpu_index {
+        cpu.need_resched.store(true, Ordering::Relaxed);
+    } else if cpu.mwait_idle.load(Ordering::SeqCst) {
+        // The write itself wakes it
+        cpu.need_resched.store(true, Ordering::SeqCst);
+    } else if interrupt::has_apic() {
+        ipi::send_to_cpu(cpu_index, DeliveryMode::Fixed(RESCHEDULE_VECTOR));
+    }
+}
+
+// Make processor `cpu_index` switch tasks at the next opportunity
+fn resched(cpu_index: usize) {
+    if cpu_index == percpu::current().cpu_index {
+        percpu::current()
+            .need_resched
+            .store(true, Ordering::Relaxed);
+    } else if interrupt::has_apic() {
+        ipi::send_to_cpu(cpu_index, DeliveryMode::Fixed(RESCHEDULE_VECTOR));
+    }
+}
+
+// Switch to the next ready task, or to the idle task if the current one can't
+// continue. Keeps running the current task if nothing else is ready, or if it
+// is a deadline task due before all ready ones.
+pub fn schedule() {
+    interrupts::without_interrupts(|| {
+        let cpu = percpu::current();
+        let current = cpu.current_task.load(Ordering::Relaxed) as *const Task;
+        if current.is_null() {
+            return;
+        }
+        let current_ref = unsafe { &*current };
+        cpu.need_resched.store(false, Ordering::Relaxed);
+
+        let next = {
+            let mut queue = run_queue(cpu.cpu_index);
+            if current_ref.state() == State::Running
+                && current_ref.is_deadline()
+                && queue
+                    .earliest_deadline()
+                    .is_none_or(|deadline| deadline >= running_deadline(current_ref))
+            {
+                return;
+            }
+            queue.pop()
+        };
+        let next = match next {
+            Some(next) => next,
+            None if current_ref.state() == State::Running => return,
+            None => {
+                let idle = cpu.idle_task.load(Ordering::Relaxed) as *const Task;
+                unsafe {
+                    Arc::increment_strong_count(idle);
+                    Arc::from_raw(idle)
+                }
+            }
+        };
+
+        // Woken up again before it got to switch away
+        if Arc::as_ptr(&next) == current {
+            next.set_state(State::Running);
+            next.stats.woken_at.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        let elapsed = account(cpu, current_ref);
+        cpu.halted.store(false, Ordering::Relaxed);
+        idle::exit(cpu);
+        if current_ref.is_deadline() {
+            charge_deadline(current_ref, elapsed);
+        }
+        cpu.context_switches.fetch_add(1, Ordering::Relaxed);
+        if current_ref.state() == State::Running {
+            current_ref
+                .stats
+                .involuntary_switches
+                .fetch_add(1, Ordering::Relaxed);
+        } else {
+            current_ref
+                .stats
+                .voluntary_switches
+                .fetch_add(1, Ordering::Relaxed);
+        }
+
+        // A task that just blocked or was preempted on another processor may
+        // not have left its stack yet
+        while next.on_cpu.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+
+        let woken_at = next.stats.woken_at.swap(0, Ordering::Relaxed);
+        if woken_at != 0 {
+            let latency = time::nanos().saturating_sub(woken_at);
+            next.stats.wakeups.fetch_add(1, Ordering::Relaxed);
+            next.stats
+                .wakeup_latency_ns
+                .fetch_add(latency, Ordering::Relaxed);
+            next.stats
+                .max_wakeup_latency_ns
+                .fetch_max(latency, Ordering::Relaxed);
+        }
+
+        cpu.running_deadline
+            .store(running_deadline(&next), Ordering::Relaxed);
+        next.set_state(State::Running);
+        next.on_cpu.store(true, Ordering::Relaxed);
+        next.cpu.store(cpu.cpu_index, Ordering::Relaxed);
+        let next = Arc::into_raw(next);
+
+        // The reference to the current task moves to `previous_task`, where
+        // `finish_switch` picks it up once we no longer run on its stack
+        cpu.previous_task.store(current as usize, Ordering::Relaxed);
+        cpu.current_task.store(next as usize, Ordering::Relaxed);
+        cpu.slice_ticks.store(0, Ordering::Relaxed);
+        if let Some(kernel_stack) = unsafe { (*next).user.kernel_stack() } {
+            percpu::set_kernel_stack(kernel_stack);
+        }
+        paging::activate(unsafe { (*next).user.page_table() });
+        FsBase::write(VirtAddr::new(unsafe { (*next).user.fs_base() }));
+
+        unsafe { task::switch(current_ref, &*next) };
+
+        finish_switch();
+    });
+}
+
+// Runs on the new task's stack right after a switch. Puts the previous task
+// back on a run queue if it was only preempted, or drops our reference if it
+// blocked or exited, freeing its stack when nothing else holds it.
+pub fn finish_switch() {
+    let cpu = percpu::current();
+    let previous = cpu.previous_task.swap(0, Ordering::Relaxed) as *const Task;
+    if previous.is_null() {
+        return;
+    }
+
+    let previous = unsafe { Arc::from_raw(previous) };
+    let was_idle = Arc::as_ptr(&previous) as usize == cpu.idle_task.load(Ordering::Relaxed);
+    if previous.state() == State::Exited && previous.is_deadline() {
+        release(&previous);
+    }
+    let requeue = previous.state() == State::Running;
+    if requeue {
+        previous.set_state(State::Ready);
+    }
+    previous.on_cpu.store(false, Ordering::Release);
+
+    if !requeue || was_idle {
+        return;
+    }
+    if previous.affinity() & (1 << cpu.cpu_index) != 0 {
+        run_queue(cpu.cpu_index).push(cpu.cpu_index, previous);
+    } else {
+        enqueue(previous);
+    }
+}
+
+// Charge the time since the last call to `task`, or to the processor's idle
+// time while it is halted. Returns the time charged to `task`. Called with
+// interrupts disabled.
+fn account(cpu: &PerCpu, task: &Task) -> u64 {
+    let now = time::nanos();
+    let elapsed = now.saturating_sub(cpu.accounted_at.swap(now, Ordering::Relaxed));
+    if cpu.halted.load(Ordering::Relaxed) {
+        cpu.idle_ns.fetch_add(elapsed, Ordering::Relaxed);
+        0
+    } else {
+        task.stats.runtime_ns.fetch_add(elapsed, Ordering::Relaxed);
+        elapsed
+    }
+}
+
+// Account the current task's run time on every timer tick and ask for a
+// switch once it used up its share of the period
+pub fn tick() {
+    let cpu = percpu::current();
+    let current = cpu.current_task.load(Ordering::Relaxed) as *const Task;
+    if current.is_null() {
+        return;
+    }
+    let elapsed = account(cpu, unsafe { &*current });
+
+    if cpu
+        .ticks
+        .load(Ordering::Relaxed)
+        .is_multiple_of(BALANCE_INTERVAL_TICKS)
+    {
+        balance(false);
+    }
+
+    let task = unsafe { &*current };
+    if task.is_deadline() {
+        if charge_deadline(task, elapsed) {
+            cpu.need_resched.store(true, Ordering::Relaxed);
+        }
+        return;
+    }
+
+    let queue = run_queue(cpu.cpu_index);
+    if queue.is_empty() {
+        return;
+    }
+    if is_idle(cpu) || !queue.deadline_tasks.is_empty() {
+        cpu.need_resched.store(true, Ordering::Relaxed);
+        return;
+    }
+
+    let weight = weight(task);
+    task.vruntime
+        .fetch_add(TICK_NS * DEFAULT_WEIGHT / weight, Ordering::Relaxed);
+
+    let slice = (PERIOD_TICKS * weight / (queue.load + weight)).max(1);
+    if cpu.slice_ticks.fetch_add(1, Ordering::Relaxed) + 1 >= slice {
+        cpu.need_resched.store(true, Ordering::Relaxed);
+    }
+}
+
+// Reserve bandwidth for `task` on one of its allowed processors, pin it there
+// and start its first period
+pub fn admit(task: &Task, params: DeadlineParams) -> Result<(), AdmissionError> {
+    let runtime = params.runtime.as_nanos() as u64;
+    let deadline = params.deadline.as_nanos() as u64;
+    let period = params.period.as_nanos() as u64;
+    if runtime == 0 || runtime > deadline || deadline > period {
+        return Err(AdmissionError::InvalidParameters);
+    }
+    // Density, which is the utilization for implicit deadlines. Keeping the
+    // sum at most one is sufficient for EDF to meet all deadlines.
+    let bandwidth = (runtime as u128 * BANDWIDTH_ONE as u128 / deadline as u128) as u64;
+
+    // The processor with the most bandwidth left
+    let affinity = task.affinity();
+    let mut candidates: Vec<_> = percpu::online()
+        .filter(|cpu| affinity & (1 << cpu.cpu_index) != 0)
+        .map(|cpu| cpu.cpu_index)
+        .collect();
+    candidates.sort_by_key(|&index| DEADLINE_BANDWIDTH[index].load(Ordering::Relaxed));
+    let cpu_index = candidates
+        .into_iter()
+        .find(|&index| {
+            DEADLINE_BANDWIDTH[index]
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reserved| {
+                    let total = reserved + bandwidth;
+                    (total <= DEADLINE_BANDWIDTH_LIMIT).then_some(total)
+                })
+                .is_ok()
+        })
+        .ok_or(AdmissionError::Overloaded)?;
+
+    task.pin(cpu_index);
+    task.cpu.store(cpu_index, Ordering::Relaxed);
+    task.deadline.deadline.store(deadline, Ordering::Relaxed);
+    task.deadline.period.store(period, Ordering::Relaxed);
+    task.deadline.runtime.store(runtime, Ordering::Relaxed);
+    replenish(task, time::nanos());
+    Ok(())
+}
+
+// Give back the bandwidth of an exited deadline task
+fn release(task: &Task) {
+    let runtime = task.deadline.runtime.load(Ordering::Relaxed) as u128;
+    let deadline = task.deadline.deadline.load(Ordering::Relaxed) as u128;
+    let bandwidth = (runtime * BANDWIDTH_ONE as u128 / deadline) as u64;
+    DEADLINE_BANDWIDTH[task.cpu.load(Ordering::Relaxed)].fetch_sub(bandwidth, Ordering::Relaxed);
+}
+
+// Start a new job in the period beginning at `start`
+fn replenish(task: &Task, start: u64) {
+    let dl = &task.deadline;
+    dl.absolute_deadline.store(
+        start + dl.deadline.load(Ordering::Relaxed),
+        Ordering::Relaxed,
+    );
+    dl.budget
+        .store(dl.runtime.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+// Whether a deadline task waking at `now` may finish its current job with the
+// budget it has left without using more than its share of the processor
+// before the job's deadline
+fn can_continue_job(task: &Task, now: u64) -> bool {
+    let dl = &task.deadline;
+    let absolute_deadline = dl.absolute_deadline.load(Ordering::Relaxed);
+    let budget = dl.budget.load(Ordering::Relaxed) as u128;
+    if absolute_deadline <= now || budget == 0 {
+        return false;
+    }
+    let runtime = dl.runtime.load(Ordering::Relaxed) as u128;
+    let deadline = dl.deadline.load(Ordering::Relaxed) as u128;
+    budget * deadline <= (absolute_deadline - now) as u128 * runtime
+}
+
+// Wake a throttled or waiting deadline task for the period starting at
+// `start`. Unlike `wake` this keeps the task's periods in step even if the
+// timer fires a little late.
+fn start_period(task: &Arc<Task>, start: u64) {
+    if task.transition(State::Blocked, State::Ready) {
+        replenish(task, start);
+        task.stats.woken_at.store(time::nanos(), Ordering::Relaxed);
+        enqueue(task.clone());
+    }
+}
+
+// Block the current deadline task until the period starting at `start`, and
+// start a new job then
+fn wait_for_period(current: &Arc<Task>, start: u64) {
+    interrupts::without_interrupts(|| {
+        let task = current.clone();
+        current.set_state(State::Blocked);
+        let id = time::add_timer(
+            time::deadline_after(Duration::from_nanos(start.saturating_sub(time::nanos()))),
+            move || start_period(&task, start),
+        );
+        schedule();
+        time::cancel_timer(id);
+    });
+}
+
+// Take `elapsed` from the running deadline task's budget. Once it is used up
+// the task is throttled, i.e. blocked until its next period, and true is
+// returned.
+fn charge_deadline(task: &Task, elapsed: u64) -> bool {
+    let dl = &task.deadline;
+    let budget = dl.budget.load(Ordering::Relaxed).saturating_sub(elapsed);
+    dl.budget.store(budget, Ordering::Relaxed);
+    if budget > 0 || !task.transition(State::Running, State::Blocked) {
+        return false;
+    }
+
+    dl.overruns.fetch_add(1, Ordering::Relaxed);
+    let start = dl.absolute_deadline.load(Ordering::Relaxed) - dl.deadline.load(Ordering::Relaxed)
+        + dl.period.load(Ordering::Relaxed);
+    let task = unsafe {
+        Arc::increment_strong_count(task);
+        Arc::from_raw(task)
+    };
+    time::add_timer(
+        time::deadline_after(Duration::from_nanos(start.saturating_sub(time::nanos()))),
+        move || start_period(&task, start),
+    );
+    true
+}
+
+// End the current job of the calling deadline task and wait for the next
+// period. A job ending after its deadline counts as a miss.
+pub fn wait_next_period() {
+    let current = task::current().expect("wait_next_period outside of a task");
+    assert!(current.is_deadline(), "not a deadline task");
+    let dl = &current.deadline;
+
+    let now = time::nanos();
+    let absolute_deadline = dl.absolute_deadline.load(Ordering::Relaxed);
+    if now > absolute_deadline {
+        dl.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let start =
+        absolute_deadline - dl.deadline.load(Ordering::Relaxed) + dl.period.load(Ordering::Relaxed);
+    if start > now {
+        wait_for_period(&current, start);
+    } else {
+        // Already late for the next period, start it right away
+        interrupts::without_interrupts(|| {
+            replenish(&current, now);
+            percpu::current()
+                .running_deadline
+                .store(running_deadline(&current), Ordering::Relaxed);
+        });
+        task::yield_now();
+    }
+}
+
+// Switch tasks if one is due and preemption is enabled. Called on the way out
+// of interrupt handlers, after the end of interrupt was signalled.
+pub fn preempt() {
+    let cpu = percpu::current();
+    if cpu.need_resched.load(Ordering::Relaxed) && cpu.preempt_count.load(Ordering::Relaxed) == 0 {
+        schedule();
+    }
+}
+
+// Keeps the current task on this processor while alive, e.g. to work with
+// `percpu!` data
+pub struct PreemptGuard(());
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        percpu::current()
+            .preempt_count
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn preempt_disable() -> PreemptGuard {
+    percpu::current()
+        .preempt_count
+        .fetch_add(1, Ordering::Relaxed);
+    PreemptGuard(())
+}
+
+// Move a task from the busiest processor to this one if the difference in
+// queue length is large enough. An idle processor takes any waiting task.
+fn balance(idle: bool) {
+    let this = percpu::current().cpu_index;
+    let this_len = run_queue(this).len();
+
+    let busiest = percpu::online()
+        .filter(|cpu| is_scheduling(cpu) && cpu.cpu_index != this)
+        .map(|cpu| (cpu.cpu_index, run_queue(cpu.cpu_index).len()))
+        .max_by_key(|&(_, len)| len);
+    let (busiest, busiest_len) = match busiest {
+        Some(busiest) => busiest,
+        None => return,
+    };
+    if busiest_len <= this_len + !idle as usize {
+        return;
+    }
+
+    // Lock in index order so that two processors balancing against each
+    // other can't deadlock
+    let (mut source, mut destination) = if busiest < this {
+        let source = run_queue(busiest);
+        (source, run_queue(this))
+    } else {
+        let destination = run_queue(this);
+        (run_queue(busiest), destination)
+    };
+
+    if let Some(task) = source.steal(this) {
+        // Keep the task's lag relative to the other tasks of its queue
+        let lag = task
+            .vruntime
+            .load(Ordering::Relaxed)
+            .saturating_sub(source.min_vruntime);
+        task.vruntime
+            .store(destination.min_vruntime + lag, Ordering::Relaxed);
+        destination.push(this, task);
+    }
+}
+
+// Run ready tasks, halting while there are none
+pub fn idle() -> ! {
+    loop {
+        interrupts::disable();
+        idle_once();
+    }
+}
+
+// Run ready tasks until none are left, or sleep until the next interrupt if
+// there were none. Called with interrupts disabled, so that a wakeup between
+// checking for work and halting isn't lost, and returns with them enabled.
+pub fn idle_once() {
+    let cpu = percpu::current();
+    let cpu_index = cpu.cpu_index;
+    if run_queue(cpu_index).is_empty() {
+        balance(true);
+    }
+    if run_queue(cpu_index).is_empty() {
+        let idle = unsafe { &*(cpu.idle_task.load(Ordering::Relaxed) as *const Task) };
+        account(cpu, idle);
+        cpu.halted.store(true, Ordering::Relaxed);
+        idle::enter();
+
+        // An interrupt may have switched to another task and back already
+        interrupts::disable();
+        if cpu.halted.load(Ordering::Relaxed) {
+            account(cpu, idle);
+            cpu.halted.store(false, Ordering::Relaxed);
+        }
+        interrupts::enable();
+    } else {
+        interrupts::enable();
+        schedule();
+    }
+}
+
+pub extern "x86-interrupt" fn reschedule_handler(_stack_frame: InterruptStackFrame) {
+    percpu::current()
+        .need_resched
+        .store(true, Ordering::Relaxed);
+    interrupt::local_apic().eoi();
+    preempt();
+}
+
+// Only preemption lets the second task run while the first one spins
+#[test_case]
+fn preemption() {
+    static FLAG: AtomicBool = AtomicBool::new(false);
+
+    print!("preemption... ");
+    let spinner = task::Builder::new().pin(0).spawn(|| {
+        while !FLAG.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+    });
+    let setter = task::Builder::new().pin(0).spawn(|| {
+        FLAG.store(true, Ordering::Release);
+    });
+    spinner.join();
+    setter.join();
+    println!("[ok]");
+}
+
+#[test_case]
+fn priority_weights() {
+    print!("priority weights... ");
+    assert_eq!(
+        WEIGHTS[(task::PRIORITY_DEFAULT - PRIORITY_HIGHEST) as usize],
+        DEFAULT_WEIGHT
+    );
+    assert!(WEIGHTS.windows(2).all(|pair| pair[0] > pair[1]));
+    println!("[ok]");
+}
+
+#[test_case]
+fn cpu_accounting() {
+    print!("cpu accounting... ");
+    let handle = task::spawn(|| {
+        let start = time::ticks();
+        while time::ticks() < start + 2 {
+            core::hint::spin_loop();
+        }
+        time::sleep(core::time::Duration::from_millis(20));
+    });
+    let task = handle.task().clone();
+    handle.join();
+
+    let stats = &task.stats;
+    assert!(stats.runtime_ns.load(Ordering::Relaxed) >= TICK_NS);
+    // Once when it slept and once when it exited
+    assert!(stats.voluntary_switches.load(Ordering::Relaxed) >= 2);
+    assert_eq!(stats.wakeups.load(Ordering::Relaxed), 1);
+    println!("[ok]");
+}
+
+#[cfg(test)]
+fn deadline_params(runtime_ms: u64, deadline_ms: u64, period_ms: u64) -> DeadlineParams {
+    DeadlineParams {
+        runtime: Duration::from_millis(runtime_ms),
+        deadline: Duration::from_millis(deadline_ms),
+        period: Duration::from_millis(period_ms),
+    }
+}
+
+#[test_case]
+fn deadline_admission() {
+    print!("deadline admission... ");
+    let spawn = |params| {
+        task::Builder::new()
+            .pin(0)
+            .deadline(params)
+            .try_spawn(|| ())
+    };
+
+    assert!(matches!(
+        spawn(deadline_params(20, 10, 30)),
+        Err(AdmissionError::InvalidParameters)
+    ));
+    let first = spawn(deadline_params(50, 100, 100)).unwrap();
+    assert!(matches!(
+        spawn(deadline_params(50, 100, 100)),
+        Err(AdmissionError::Overloaded)
+    ));
+    // Exiting gives the bandwidth back
+    first.join();
+    spawn(deadline_params(50, 100, 100)).unwrap().join();
+    println!("[ok]");
+}
+
+// A periodic task sharing its processor with a task that never blocks still
+// finishes every job in time
+#[test_case]
+fn deadline_misses() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    print!("deadline misses... ");
+    let hog = task::Builder::new().pin(0).spawn(|| {
+        while !DONE.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+    });
+    let periodic = task::Builder::new()
+        .pin(0)
+        .deadline(deadline_params(20, 50, 50))
+        .spawn(|| {
+            for _ in 0..10 {
+                let start = time::nanos();
+                while time::nanos() - start < 5_000_000 {
+                    core::hint::spin_loop();
+                }
+                wait_next_period();
+            }
+            DONE.store(true, Ordering::Release);
+            let current = task::current().unwrap();
+            (
+                current.deadline.misses.load(Ordering::Relaxed),
+                current.deadline.overruns.load(Ordering::Relaxed),
+            )
+        });
+
+    let (misses, overruns) = periodic.join();
+    hog.join();
+    assert_eq!(misses, 0);
+    assert_eq!(overruns, 0);
+    println!("[ok]");
+}
@@ -1,7 +1,26 @@
+use crate::executor::InterruptQueue;
+use crate::file;
+use crate::interrupt::{self, IRQ_COM1};
+use core::fmt;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::stream::{Stream, StreamExt};
 use x86_64::instructions::port::*;
+use x86_64::structures::idt::InterruptStackFrame;
 
 const PORT: u16 = 0x3f8;
 
+// 16550 registers relative to PORT
+const INTERRUPT_ENABLE: u16 = 1;
+const LINE_STATUS: u16 = 5;
+
+const IER_RECEIVED_DATA_AVAILABLE: u8 = 1 << 0;
+const LSR_DATA_READY: u8 = 1 << 0;
+
+const RECEIVE_QUEUE_SIZE: usize = 256;
+
+static RECEIVED: InterruptQueue<u8> = InterruptQueue::new();
+
 pub fn initialize() {
     unsafe {
         u8::write_to_port(PORT + 1, 0x00);
@@ -27,6 +46,59 @@ pub fn initialize() {
     }
 }
 
+// Interrupt on received bytes. Needs the heap for the receive buffer.
+pub fn initialize_receive() {
+    RECEIVED.initialize(RECEIVE_QUEUE_SIZE);
+    unsafe {
+        u8::write_to_port(PORT + INTERRUPT_ENABLE, IER_RECEIVED_DATA_AVAILABLE);
+    }
+    interrupt::enable_irq(IRQ_COM1);
+}
+
+pub extern "x86-interrupt" fn interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // Drain the FIFO, the interrupt is raised again only for new data
+    unsafe {
+        while u8::read_from_port(PORT + LINE_STATUS) & LSR_DATA_READY != 0 {
+            RECEIVED.push(u8::read_from_port(PORT));
+        }
+    }
+    interrupt::end_of_interrupt(IRQ_COM1);
+}
+
+// Bytes received on COM1
+pub struct ReceiveStream {
+    _private: (),
+}
+
+impl ReceiveStream {
+    pub fn new() -> ReceiveStream {
+        ReceiveStream { _private: () }
+    }
+}
+
+impl Stream for ReceiveStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        RECEIVED.poll_pop(cx).map(Some)
+    }
+}
+
+// Type every received byte on the console and send it back, so that typing
+// on the serial console shows
+pub async fn console_input() {
+    let mut received = ReceiveStream::new();
+    while let Some(byte) = received.next().await {
+        match byte {
+            b'\r' => write_str("\r\n"),
+            0x7f => write_str("\x08 \x08"),
+            file::CONSOLE_INTERRUPT => write_str("^C\r\n"),
+            _ => write_byte(byte),
+        }
+        file::console_input(byte);
+    }
+}
+
 fn is_transmit_empty() -> u8 {
     unsafe { u8::read_from_port(PORT + 5) & 0x20 }
 }
@@ -43,3 +115,28 @@ pub fn write_str(s: &str) {
         write_byte(*b);
     }
 }
+
+// Writer that goes straight to the UART without taking any lock, so it can be
+// used from NMI context or while another CPU holds the display lock
+pub struct SerialWriter;
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_str(s);
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ({
+        use core::fmt::Write;
+        let _ = write!($crate::serial::SerialWriter, $($arg)*);
+    });
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
@@ -0,0 +1,812 @@
+use crate::fpu::FpuState;
+use crate::interrupt::TrapFrame;
+use crate::paging::USER_END;
+use crate::process::{self, Pid};
+use crate::syscall::Errno;
+use crate::usermode::{self, Fault, FaultKind, UserExit, USER_RFLAGS, USER_RFLAGS_MASK};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::VirtAddr;
+
+#[cfg(test)]
+use crate::paging::USER_START;
+#[cfg(test)]
+use crate::syscall::{SYS_EXIT, SYS_GETPID, SYS_KILL, SYS_SIGACTION, SYS_SIGRETURN};
+#[cfg(test)]
+use crate::usermode::{Asm, Reg};
+#[cfg(test)]
+use crate::{print, println};
+
+// Signal numbers, with the values Linux uses on x86-64
+pub const SIGINT: u8 = 2;
+pub const SIGILL: u8 = 4;
+pub const SIGTRAP: u8 = 5;
+pub const SIGBUS: u8 = 7;
+pub const SIGFPE: u8 = 8;
+pub const SIGKILL: u8 = 9;
+pub const SIGUSR1: u8 = 10;
+pub const SIGSEGV: u8 = 11;
+pub const SIGUSR2: u8 = 12;
+pub const SIGPIPE: u8 = 13;
+pub const SIGTERM: u8 = 15;
+pub const SIGCHLD: u8 = 17;
+pub const SIGCONT: u8 = 18;
+pub const SIGSTOP: u8 = 19;
+pub const SIGTSTP: u8 = 20;
+pub const SIGTTIN: u8 = 21;
+pub const SIGTTOU: u8 = 22;
+pub const SIGURG: u8 = 23;
+pub const SIGWINCH: u8 = 28;
+
+// Signals are numbered from 1 to NSIG, and a set of them is a u64 with bit
+// `sig - 1` for each
+pub const NSIG: u8 = 64;
+
+// Special handlers
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+// `SigAction` flags. Handlers always get the `siginfo` and `ucontext`
+// arguments of SA_SIGINFO, and must have a restorer.
+pub const SA_SIGINFO: u64 = 0x4;
+pub const SA_RESTORER: u64 = 0x0400_0000;
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+// How `sigprocmask` changes the blocked signals
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+// `SigInfo` codes
+const SI_USER: i32 = 0;
+const SI_KERNEL: i32 = 0x80;
+const ILL_ILLOPN: i32 = 2;
+const FPE_INTDIV: i32 = 1;
+const FPE_FLTINV: i32 = 7;
+const SEGV_MAPERR: i32 = 1;
+const SEGV_ACCERR: i32 = 2;
+const BUS_ADRALN: i32 = 1;
+const TRAP_BRKPT: i32 = 1;
+const CLD_EXITED: i32 = 1;
+const CLD_KILLED: i32 = 2;
+
+// Neither caught, blocked nor ignored
+const UNBLOCKABLE: u64 = bit(SIGKILL) | bit(SIGSTOP);
+
+// Caused by the instruction they interrupt, so delivered before others
+const SYNCHRONOUS: u64 = bit(SIGILL) | bit(SIGTRAP) | bit(SIGBUS) | bit(SIGFPE) | bit(SIGSEGV);
+
+// Below the user stack pointer, which leaf functions may use without moving it
+const RED_ZONE: u64 = 128;
+
+// Most frames of interrupted handlers whose vector registers are kept
+const MAX_SAVED_FPU: usize = 32;
+
+const fn bit(sig: u8) -> u64 {
+    1 << (sig - 1)
+}
+
+fn is_valid(sig: u64) -> bool {
+    (1..=NSIG as u64).contains(&sig)
+}
+
+// What a signal does with SIG_DFL as its handler. There is no job control,
+// so stopping and continuing signals are ignored.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum DefaultAction {
+    Terminate,
+    Ignore,
+}
+
+fn default_action(sig: u8) -> DefaultAction {
+    match sig {
+        SIGCHLD | SIGCONT | SIGURG | SIGWINCH | SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => {
+            DefaultAction::Ignore
+        }
+        _ => DefaultAction::Terminate,
+    }
+}
+
+// Layout of Linux' `struct kernel_sigaction` on x86-64
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct SigAction {
+    pub handler: u64,
+    pub flags: u64,
+    // Where the handler returns to, which makes the sigreturn system call
+    pub restorer: u64,
+    // Blocked while the handler runs, along with the signal itself
+    pub mask: u64,
+}
+
+// Why a signal was sent, given to its handler as `siginfo_t`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SigInfo {
+    code: i32,
+    // The sender, or the child of SIGCHLD
+    pid: u64,
+    // Exit status or signal of the child of SIGCHLD
+    status: i32,
+    // For signals raised by a fault
+    fault: Option<Fault>,
+}
+
+impl SigInfo {
+    const NONE: SigInfo = SigInfo {
+        code: 0,
+        pid: 0,
+        status: 0,
+        fault: None,
+    };
+
+    // Sent by the process `sender` with `kill`, or by the kernel if None
+    pub fn user(sender: Option<Pid>) -> SigInfo {
+        SigInfo {
+            code: if sender.is_some() { SI_USER } else { SI_KERNEL },
+            pid: sender.map_or(0, |pid| pid.as_u64()),
+            ..SigInfo::NONE
+        }
+    }
+
+    // SIGCHLD for the child `pid` that exited with `exit`
+    pub fn child(pid: Pid, exit: UserExit) -> SigInfo {
+        let (code, status) = match exit {
+            UserExit::Exit(status) => (CLD_EXITED, status as i32),
+            UserExit::Fault(fault) => (CLD_KILLED, fault.kind.signal() as i32),
+            UserExit::Signal(sig) => (CLD_KILLED, sig as i32),
+        };
+        SigInfo {
+            code,
+            pid: pid.as_u64(),
+            status,
+            fault: None,
+        }
+    }
+
+    fn fault(fault: Fault) -> SigInfo {
+        let code = match fault.kind {
+            FaultKind::InvalidOpcode => ILL_ILLOPN,
+            FaultKind::DivideError => FPE_INTDIV,
+            FaultKind::FloatingPoint | FaultKind::SimdFloatingPoint => FPE_FLTINV,
+            FaultKind::AlignmentCheck => BUS_ADRALN,
+            FaultKind::Breakpoint => TRAP_BRKPT,
+            // Present bit of the error code
+            FaultKind::PageFault if fault.error_code & 1 == 0 => SEGV_MAPERR,
+            FaultKind::PageFault => SEGV_ACCERR,
+            _ => SI_KERNEL,
+        };
+        SigInfo {
+            code,
+            fault: Some(fault),
+            ..SigInfo::NONE
+        }
+    }
+
+    // `siginfo_t` of Linux, 128 bytes: the number, errno and code, then the
+    // faulting address, or the PID, UID and status
+    fn to_bytes(self, sig: u8) -> [u8; 128] {
+        let mut bytes = [0u8; 128];
+        bytes[0..4].copy_from_slice(&(sig as i32).to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.code.to_le_bytes());
+        match self.fault {
+            Some(fault) => {
+                let address = fault.address.unwrap_or(fault.rip);
+                bytes[16..24].copy_from_slice(&address.to_le_bytes());
+            }
+            None => {
+                bytes[16..20].copy_from_slice(&(self.pid as i32).to_le_bytes());
+                bytes[24..28].copy_from_slice(&self.status.to_le_bytes());
+            }
+        }
+        bytes
+    }
+}
+
+// Signal state of a process
+pub struct Signals {
+    // Indexed by signal number - 1
+    actions: [SigAction; NSIG as usize],
+    pending: u64,
+    blocked: u64,
+    info: [SigInfo; NSIG as usize],
+    // Vector registers of the contexts that handlers interrupted, with the
+    // address of their signal frame. They stay in the kernel, where user code
+    // can't make them invalid.
+    saved_fpu: Vec<(u64, FpuState)>,
+}
+
+impl Signals {
+    pub const fn new() -> Signals {
+        Signals {
+            actions: [SigAction {
+                handler: SIG_DFL,
+                flags: 0,
+                restorer: 0,
+                mask: 0,
+            }; NSIG as usize],
+            pending: 0,
+            blocked: 0,
+            info: [SigInfo::NONE; NSIG as usize],
+            saved_fpu: Vec::new(),
+        }
+    }
+
+    // For a child made by `fork`, which keeps the handlers and the mask but
+    // none of the pending signals
+    pub fn fork(&self) -> Signals {
+        Signals {
+            actions: self.actions,
+            blocked: self.blocked,
+            ..Signals::new()
+        }
+    }
+
+    // For `execve`, after which the handlers are gone, but not whether a
+    // signal is ignored
+    pub fn exec(&mut self) {
+        for action in &mut self.actions {
+            if action.handler != SIG_IGN {
+                *action = SigAction::default();
+            }
+        }
+        self.saved_fpu.clear();
+    }
+
+    // Signals that are pending and not blocked
+    pub fn deliverable(&self) -> u64 {
+        self.pending & !self.blocked
+    }
+
+    fn ignored(&self, sig: u8) -> bool {
+        match self.actions[sig as usize - 1].handler {
+            SIG_IGN => true,
+            SIG_DFL => default_action(sig) == DefaultAction::Ignore,
+            _ => false,
+        }
+    }
+
+    // Make `sig` pending, unless it would be ignored. A blocked signal is
+    // kept, its handler may change before it is unblocked.
+    pub fn send(&mut self, sig: u8, info: SigInfo) {
+        let bit = bit(sig);
+        if self.blocked & bit == 0 && self.ignored(sig) {
+            return;
+        }
+        self.pending |= bit;
+        self.info[sig as usize - 1] = info;
+    }
+
+    // Raise the signal for `fault`. It can't be blocked or ignored since the
+    // instruction can't continue, so it terminates the process in that case.
+    pub fn send_fault(&mut self, fault: Fault) {
+        let sig = fault.kind.signal();
+        let bit = bit(sig);
+        let action = &mut self.actions[sig as usize - 1];
+        if self.blocked & bit != 0 || action.handler == SIG_IGN {
+            *action = SigAction::default();
+            self.blocked &= !bit;
+        }
+        self.send(sig, SigInfo::fault(fault));
+    }
+
+    pub fn action(&self, sig: u8) -> SigAction {
+        self.actions[sig as usize - 1]
+    }
+
+    pub fn set_action(&mut self, sig: u8, action: SigAction) {
+        self.actions[sig as usize - 1] = action;
+        // Pending signals that are now ignored are dropped
+        if self.ignored(sig) {
+            self.pending &= !bit(sig);
+        }
+    }
+
+    pub fn blocked(&self) -> u64 {
+        self.blocked
+    }
+
+    pub fn set_blocked(&mut self, blocked: u64) {
+        self.blocked = blocked & !UNBLOCKABLE;
+    }
+
+    // Take the next signal to deliver with its action. If the process handles
+    // it, the handler's mask is applied and the previous one returned with it.
+    fn take(&mut self) -> Option<(u8, SigInfo, SigAction, u64)> {
+        let deliverable = self.deliverable();
+        let set = match deliverable & SYNCHRONOUS {
+            0 => deliverable,
+            synchronous => synchronous,
+        };
+        if set == 0 {
+            return None;
+        }
+        let sig = set.trailing_zeros() as u8 + 1;
+        self.pending &= !bit(sig);
+        let info = self.info[sig as usize - 1];
+        let action = self.action(sig);
+        let blocked = self.blocked;
+        if action.handler != SIG_DFL && action.handler != SIG_IGN {
+            let mut mask = action.mask;
+            if action.flags & SA_NODEFER == 0 {
+                mask |= bit(sig);
+            }
+            self.set_blocked(blocked | mask);
+            if action.flags & SA_RESETHAND != 0 {
+                self.actions[sig as usize - 1] = SigAction::default();
+            }
+        }
+        Some((sig, info, action, blocked))
+    }
+
+    // The first deliverable signal that terminates the process, for processes
+    // run by the kernel, which check for them now and then instead of having
+    // signals delivered
+    pub fn fatal(&self) -> Option<u8> {
+        let mut set = self.deliverable();
+        while set != 0 {
+            let sig = set.trailing_zeros() as u8 + 1;
+            let default = self.action(sig).handler == SIG_DFL;
+            if default && default_action(sig) == DefaultAction::Terminate {
+                return Some(sig);
+            }
+            set &= set - 1;
+        }
+        None
+    }
+}
+
+// Layout of Linux' `struct sigcontext` on x86-64
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct SigContext {
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rdi: u64,
+    rsi: u64,
+    rbp: u64,
+    rbx: u64,
+    rdx: u64,
+    rax: u64,
+    rcx: u64,
+    rsp: u64,
+    rip: u64,
+    rflags: u64,
+    cs: u16,
+    gs: u16,
+    fs: u16,
+    ss: u16,
+    err: u64,
+    trapno: u64,
+    oldmask: u64,
+    cr2: u64,
+    // Always null, the vector registers are kept by the kernel
+    fpstate: u64,
+    reserved: [u64; 8],
+}
+
+// Layout of Linux' `struct ucontext` on x86-64, with `stack_t` as 3 words
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct UContext {
+    flags: u64,
+    link: u64,
+    stack: [u64; 3],
+    mcontext: SigContext,
+    sigmask: u64,
+}
+
+// Pushed on the user stack for a handler, like Linux' `struct rt_sigframe`.
+// The handler returns to `restorer`, popping it, and sigreturn finds the
+// context at the stack pointer.
+#[repr(C)]
+struct SignalFrame {
+    restorer: u64,
+    context: UContext,
+    info: [u8; 128],
+}
+
+// Vector of the exception of `kind`
+fn trap_number(kind: FaultKind) -> u64 {
+    match kind {
+        FaultKind::DivideError => 0,
+        FaultKind::Breakpoint => 3,
+        FaultKind::Overflow => 4,
+        FaultKind::BoundRangeExceeded => 5,
+        FaultKind::InvalidOpcode => 6,
+        FaultKind::DeviceNotAvailable => 7,
+        FaultKind::SegmentNotPresent => 11,
+        FaultKind::StackSegmentFault => 12,
+        FaultKind::GeneralProtection => 13,
+        FaultKind::PageFault => 14,
+        FaultKind::FloatingPoint => 16,
+        FaultKind::AlignmentCheck => 17,
+        FaultKind::SimdFloatingPoint => 19,
+    }
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+// Called before returning to user mode with its registers in `frame` and its
+// vector registers saved. Runs the default action of pending signals, or
+// makes the process continue in the handler of one.
+pub fn deliver(frame: &mut TrapFrame) {
+    let process = match process::current() {
+        Some(process) => process,
+        None => return,
+    };
+    while let Some((sig, info, action, blocked)) = process.with_signals(Signals::take) {
+        let exit = match action.handler {
+            SIG_IGN => continue,
+            SIG_DFL => match default_action(sig) {
+                DefaultAction::Ignore => continue,
+                DefaultAction::Terminate => {
+                    info.fault.map_or(UserExit::Signal(sig), UserExit::Fault)
+                }
+            },
+            // Returning to it would fault in kernel mode
+            handler if handler >= USER_END => UserExit::Signal(SIGSEGV),
+            handler => match push_frame(frame, sig, info, &action, blocked) {
+                Ok(address) => {
+                    frame.stack_frame.instruction_pointer = VirtAddr::new(handler);
+                    let fpu = usermode::saved_fpu().unwrap_or_else(FpuState::new);
+                    process.with_signals(|signals| {
+                        if signals.saved_fpu.len() == MAX_SAVED_FPU {
+                            signals.saved_fpu.remove(0);
+                        }
+                        signals.saved_fpu.push((address, fpu));
+                    });
+                    return;
+                }
+                // No room on the stack
+                Err(_) => UserExit::Signal(SIGSEGV),
+            },
+        };
+        usermode::request_exit(exit);
+        return;
+    }
+}
+
+// Save the context in `frame` on the user stack and set up the registers
+// for calling a handler of `sig`: its number, the `siginfo_t` and the
+// `ucontext`. Returns the address of the signal frame.
+fn push_frame(
+    frame: &mut TrapFrame,
+    sig: u8,
+    info: SigInfo,
+    action: &SigAction,
+    blocked: u64,
+) -> Result<u64, Errno> {
+    let stack = &frame.stack_frame;
+    let rsp = stack.stack_pointer.as_u64();
+    // As if the handler was called, with the restorer as return address
+    let below = rsp
+        .checked_sub(RED_ZONE + size_of::<SignalFrame>() as u64 + 8)
+        .ok_or(Errno::EFAULT)?;
+    let address = (below & !0xf) + 8;
+
+    let fault = info.fault;
+    let signal_frame = SignalFrame {
+        restorer: action.restorer,
+        context: UContext {
+            mcontext: SigContext {
+                r8: frame.r8,
+                r9: frame.r9,
+                r10: frame.r10,
+                r11: frame.r11,
+                r12: frame.r12,
+                r13: frame.r13,
+                r14: frame.r14,
+                r15: frame.r15,
+                rdi: frame.rdi,
+                rsi: frame.rsi,
+                rbp: frame.rbp,
+                rbx: frame.rbx,
+                rdx: frame.rdx,
+                rax: frame.rax,
+                rcx: frame.rcx,
+                rsp,
+                rip: stack.instruction_pointer.as_u64(),
+                rflags: stack.cpu_flags,
+                cs: stack.code_segment as u16,
+                ss: stack.stack_segment as u16,
+                err: fault.map_or(0, |fault| fault.error_code),
+                trapno: fault.map_or(0, |fault| trap_number(fault.kind)),
+                oldmask: blocked,
+                cr2: fault.and_then(|fault| fault.address).unwrap_or(0),
+                ..SigContext::default()
+            },
+            sigmask: blocked,
+            ..UContext::default()
+        },
+        info: info.to_bytes(sig),
+    };
+    usermode::copy_to_user(address, as_bytes(&signal_frame))?;
+
+    let context = address + size_of::<u64>() as u64;
+    frame.rdi = sig as u64;
+    frame.rsi = context + size_of::<UContext>() as u64;
+    frame.rdx = context;
+    frame.rax = 0;
+    let stack = &mut frame.stack_frame;
+    stack.stack_pointer = VirtAddr::new(address);
+    // Direction and trap flags
+    stack.cpu_flags &= !0x500;
+    Ok(address)
+}
+
+// Restore the context a handler interrupted from its signal frame, for the
+// sigreturn system call. The user stack pointer is just above the return
+// address the handler popped.
+pub fn sigreturn(frame: &mut TrapFrame) -> Result<(), Errno> {
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    let context = frame.stack_frame.stack_pointer.as_u64();
+    let mut uc = UContext::default();
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(&mut uc as *mut _ as *mut u8, size_of::<UContext>())
+    };
+    if let Err(error) = usermode::copy_from_user(bytes, context) {
+        // There is nothing to return to
+        usermode::request_exit(UserExit::Signal(SIGSEGV));
+        return Err(error);
+    }
+
+    let mc = &uc.mcontext;
+    frame.r8 = mc.r8;
+    frame.r9 = mc.r9;
+    frame.r10 = mc.r10;
+    frame.r11 = mc.r11;
+    frame.r12 = mc.r12;
+    frame.r13 = mc.r13;
+    frame.r14 = mc.r14;
+    frame.r15 = mc.r15;
+    frame.rdi = mc.rdi;
+    frame.rsi = mc.rsi;
+    frame.rbp = mc.rbp;
+    frame.rbx = mc.rbx;
+    frame.rdx = mc.rdx;
+    frame.rax = mc.rax;
+    frame.rcx = mc.rcx;
+    // Segments stay those of user mode, and the system call checks the
+    // instruction pointer before returning to it
+    let stack = &mut frame.stack_frame;
+    stack.stack_pointer = VirtAddr::new_truncate(mc.rsp);
+    stack.instruction_pointer = VirtAddr::new_truncate(mc.rip);
+    stack.cpu_flags = mc.rflags & USER_RFLAGS_MASK | USER_RFLAGS;
+
+    let address = context - size_of::<u64>() as u64;
+    let fpu = process.with_signals(|signals| {
+        signals.set_blocked(uc.sigmask);
+        // Frames above this one were left without sigreturn, by longjmp
+        let i = signals
+            .saved_fpu
+            .iter()
+            .rposition(|(frame, _)| *frame == address)?;
+        signals.saved_fpu.drain(i..).next().map(|(_, fpu)| fpu)
+    });
+    if let Some(fpu) = fpu {
+        usermode::load_fpu(fpu);
+    }
+    Ok(())
+}
+
+// sigaction(sig, action, old): install the action at `action` for `sig` if
+// not null, and store the previous one at `old` if not null
+pub fn sigaction(sig: u64, action: u64, old: u64) -> Result<(), Errno> {
+    if !is_valid(sig) {
+        return Err(Errno::EINVAL);
+    }
+    let sig = sig as u8;
+    let new = if action != 0 {
+        if UNBLOCKABLE & bit(sig) != 0 {
+            return Err(Errno::EINVAL);
+        }
+        let mut new = SigAction::default();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(&mut new as *mut _ as *mut u8, size_of::<SigAction>())
+        };
+        usermode::copy_from_user(bytes, action)?;
+        // There is no vDSO to return to, a handler without a restorer would
+        // return to address 0 and fault long after the mistake was made
+        let handler = new.handler != SIG_DFL && new.handler != SIG_IGN;
+        if handler && new.flags & SA_RESTORER == 0 {
+            return Err(Errno::EINVAL);
+        }
+        Some(new)
+    } else {
+        None
+    };
+
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    let previous = process.with_signals(|signals| {
+        let previous = signals.action(sig);
+        if let Some(new) = new {
+            signals.set_action(sig, new);
+        }
+        previous
+    });
+    if old != 0 {
+        usermode::copy_to_user(old, as_bytes(&previous))?;
+    }
+    Ok(())
+}
+
+// sigprocmask(how, set, old): change the blocked signals with the set at
+// `set` if not null, and store the previous ones at `old` if not null
+pub fn sigprocmask(how: u64, set: u64, old: u64) -> Result<(), Errno> {
+    let set = if set != 0 {
+        let mut bytes = [0u8; 8];
+        usermode::copy_from_user(&mut bytes, set)?;
+        Some(u64::from_le_bytes(bytes))
+    } else {
+        None
+    };
+    if set.is_some() && how > SIG_SETMASK {
+        return Err(Errno::EINVAL);
+    }
+
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    let previous = process.with_signals(|signals| {
+        let previous = signals.blocked();
+        if let Some(set) = set {
+            signals.set_blocked(match how {
+                SIG_BLOCK => previous | set,
+                SIG_UNBLOCK => previous & !set,
+                _ => set,
+            });
+        }
+        previous
+    });
+    if old != 0 {
+        usermode::copy_to_user(old, &previous.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+// kill(pid, sig): send `sig` to the process `pid`. Only checks whether it
+// exists if `sig` is 0.
+pub fn kill(pid: u64, sig: u64) -> Result<(), Errno> {
+    if sig != 0 && !is_valid(sig) {
+        return Err(Errno::EINVAL);
+    }
+    if pid as i64 <= 0 {
+        // No process groups
+        return Err(Errno::EINVAL);
+    }
+    let target = process::lookup(Pid::from_u64(pid)).ok_or(Errno::ESRCH)?;
+    if sig != 0 {
+        let sender = process::current().map(|process| process.pid());
+        target.send_signal(sig as u8, SigInfo::user(sender));
+    }
+    Ok(())
+}
+
+// A signal frame and the sigaction structure in a test process, behind its
+// code
+#[cfg(test)]
+const TEST_ACTION: u64 = USER_START + 0x200;
+#[cfg(test)]
+const TEST_FLAG: u64 = USER_START + 0x300;
+
+// Code for a test process: `main` at its start, `handler` at 0x100, a
+// restorer making the sigreturn system call at 0x180, and a sigaction
+// structure for the handler at `TEST_ACTION`
+#[cfg(test)]
+fn test_code(main: Asm, handler: Asm) -> Vec<u8> {
+    let action = SigAction {
+        handler: USER_START + 0x100,
+        flags: SA_SIGINFO | SA_RESTORER,
+        restorer: USER_START + 0x180,
+        mask: 0,
+    };
+    main.at(0x100)
+        .raw(&handler.code())
+        .at(0x180)
+        .syscall(SYS_SIGRETURN)
+        .at((TEST_ACTION - USER_START) as usize)
+        .raw(as_bytes(&action))
+        .at(0x400)
+        .code()
+}
+
+// sigaction(sig, TEST_ACTION, 0)
+#[cfg(test)]
+fn install_handler(code: Asm, sig: u8) -> Asm {
+    code.mov(Reg::Rdi, sig.into())
+        .mov64(Reg::Rsi, TEST_ACTION)
+        .mov(Reg::Rdx, 0)
+        .syscall(SYS_SIGACTION)
+}
+
+// kill(getpid(), sig)
+#[cfg(test)]
+fn kill_self(code: Asm, sig: u8) -> Asm {
+    code.syscall(SYS_GETPID)
+        .mov_reg(Reg::Rdi, Reg::Rax)
+        .mov(Reg::Rsi, sig.into())
+        .syscall(SYS_KILL)
+}
+
+// A handler runs when the process sends itself a signal, and the interrupted
+// code continues with its registers after sigreturn
+#[test_case]
+fn handle_signal() {
+    print!("signal handler... ");
+    let main = install_handler(Asm::new().mov(Reg::Rbx, 0x1234), SIGUSR1);
+    let main = kill_self(main, SIGUSR1)
+        .mov64(Reg::Rax, TEST_FLAG)
+        .load(Reg::Rax, Reg::Rax, 0)
+        .raw(&[0x48, 0x8d, 0x3c, 0x18]) // lea rdi, [rax + rbx]
+        .syscall(SYS_EXIT);
+    let handler = Asm::new()
+        .raw(&[0x48, 0xc1, 0xe7, 0x10]) // shl rdi, 16
+        .mov64(Reg::Rax, TEST_FLAG)
+        .store(Reg::Rax, 0, Reg::Rdi)
+        .mov(Reg::Rbx, 0xffff)
+        .raw(&[0xc3]); // ret
+    assert_eq!(
+        usermode::run_code(&test_code(main, handler)),
+        UserExit::Exit(((SIGUSR1 as i64) << 16) + 0x1234)
+    );
+    println!("[ok]");
+}
+
+// Signals without a handler terminate the process or are ignored
+#[test_case]
+fn default_actions() {
+    print!("signal default actions... ");
+    let main = kill_self(kill_self(Asm::new(), SIGCHLD), SIGTERM)
+        .mov(Reg::Rdi, 0)
+        .syscall(SYS_EXIT);
+    assert_eq!(usermode::run_code(&main.code()), UserExit::Signal(SIGTERM));
+
+    // SIGKILL can't be caught
+    let main = install_handler(Asm::new(), SIGKILL).exit_with(Reg::Rax);
+    assert_eq!(
+        usermode::run_code(&test_code(main, Asm::new())),
+        UserExit::Exit(-(Errno::EINVAL as i64))
+    );
+    println!("[ok]");
+}
+
+// Handlers have to come with a restorer to return to
+#[test_case]
+fn handler_needs_restorer() {
+    print!("signal restorer... ");
+    // Clear SA_RESTORER in the test sigaction structure, at offset 8
+    let main = Asm::new()
+        .mov64(Reg::Rax, TEST_ACTION)
+        .mov(Reg::Rbx, SA_SIGINFO as u32)
+        .store(Reg::Rax, 8, Reg::Rbx);
+    let main = install_handler(main, SIGUSR1).exit_with(Reg::Rax);
+    assert_eq!(
+        usermode::run_code(&test_code(main, Asm::new())),
+        UserExit::Exit(-(Errno::EINVAL as i64))
+    );
+    println!("[ok]");
+}
+
+// A fault in user mode raises a signal the process can handle
+#[test_case]
+fn fault_signal() {
+    print!("fault signals... ");
+    let unmapped = USER_START + 0x20000;
+    let main = install_handler(Asm::new(), SIGSEGV)
+        .mov64(Reg::Rbx, unmapped)
+        .load(Reg::Rax, Reg::Rbx, 0);
+    // exit(si_addr)
+    let handler = Asm::new().load(Reg::Rdi, Reg::Rsi, 16).syscall(SYS_EXIT);
+    assert_eq!(
+        usermode::run_code(&test_code(main, handler)),
+        UserExit::Exit(unmapped as i64)
+    );
+    println!("[ok]");
+}
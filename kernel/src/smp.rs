@@ -0,0 +1,281 @@
+use crate::{acpi, fpu, interrupt, percpu, pit, println, scheduler, syscall, task, watchdog};
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use x86_64::registers::control::{Cr0, Cr3, Cr4};
+use x86_64::registers::model_specific::Efer;
+
+pub const MAX_CPUS: usize = 16;
+
+// Physical address the real mode trampoline is copied to. It must be below
+// 1 MiB and page aligned because a startup IPI only carries the page number.
+const TRAMPOLINE_BASE: u64 = 0x8000;
+
+const AP_STACK_SIZE: usize = 4096 * 4;
+
+#[repr(C, align(16))]
+struct Stack([u8; AP_STACK_SIZE]);
+
+static mut AP_STACKS: [Stack; MAX_CPUS] = [const { Stack([0; AP_STACK_SIZE]) }; MAX_CPUS];
+
+// Number of processors which have reached `ap_main`, including the BSP
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+// Whether the processor being started has reached `ap_main`. The BSP gives up
+// on it by switching WAITING to ABANDONED, so exactly one of them wins when
+// the processor is late.
+static STARTUP: AtomicU8 = AtomicU8::new(WAITING);
+const WAITING: u8 = 0;
+const ARRIVED: u8 = 1;
+const ABANDONED: u8 = 2;
+
+// Values the trampoline needs to enter long mode the same way as the BSP and
+// jump into the kernel. Lives at TRAMPOLINE_DATA_OFFSET in the trampoline.
+#[repr(C)]
+struct TrampolineData {
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu_index: u64,
+}
+
+// Fixed layout at the start of the trampoline, so that the code can address
+// its data with plain constants: a jump over the data, TrampolineData at 8,
+// the GDT at 64 and the GDT pointer at 96
+const TRAMPOLINE_DATA_OFFSET: usize = 8;
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_end: u8;
+}
+
+// Application processors start in 16-bit real mode at TRAMPOLINE_BASE. The
+// trampoline switches to protected mode with its own GDT, enables paging with
+// the BSP's page tables, enters long mode and calls `entry` on `stack_top`.
+// Code is copied to TRAMPOLINE_BASE, so every absolute address is computed
+// relative to it rather than to where the kernel was linked.
+global_asm!(
+    ".global smp_trampoline_start",
+    ".global smp_trampoline_end",
+    // Alignment inside the trampoline is relative to the section, so the start
+    // has to be aligned at least as strictly
+    ".balign 16",
+    ".code16",
+    "smp_trampoline_start:",
+    "jmp smp_trampoline_16",
+    ".align 8",
+    // TrampolineData
+    ".fill 7, 8, 0",
+    "smp_trampoline_gdt:",
+    ".quad 0",
+    ".quad 0x00cf9a000000ffff", // 0x08: 32-bit code
+    ".quad 0x00cf92000000ffff", // 0x10: data
+    ".quad 0x00af9a000000ffff", // 0x18: 64-bit code
+    ".word 4 * 8 - 1",
+    ".long {base} + smp_trampoline_gdt - smp_trampoline_start",
+    "smp_trampoline_16:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "lgdt [96]",
+    "mov eax, cr0",
+    "or eax, 1",
+    "mov cr0, eax",
+    // jmp far 0x08:smp_trampoline_32 with a 32-bit offset
+    ".byte 0x66, 0xea",
+    ".long {base} + smp_trampoline_32 - smp_trampoline_start",
+    ".word 0x08",
+    ".code32",
+    "smp_trampoline_32:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov esi, {base}",
+    // CR4.PAE has to be set before paging is enabled in long mode
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, [esi + {data} + 8]",
+    "mov cr3, eax",
+    "mov ecx, 0xc0000080",
+    "mov eax, [esi + {data} + 24]",
+    "mov edx, [esi + {data} + 28]",
+    "wrmsr",
+    "mov eax, [esi + {data}]",
+    "mov cr0, eax",
+    // jmp far 0x18:smp_trampoline_64
+    ".byte 0xea",
+    ".long {base} + smp_trampoline_64 - smp_trampoline_start",
+    ".word 0x18",
+    ".code64",
+    "smp_trampoline_64:",
+    "mov rax, [rsi + {data} + 16]",
+    "mov cr4, rax",
+    "mov rsp, [rsi + {data} + 32]",
+    "mov rdi, [rsi + {data} + 48]",
+    "mov rax, [rsi + {data} + 40]",
+    "call rax",
+    "ud2",
+    "smp_trampoline_end:",
+    base = const TRAMPOLINE_BASE,
+    data = const TRAMPOLINE_DATA_OFFSET,
+);
+
+pub fn online_cpus() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+// Start every enabled application processor listed in the MADT with the
+// INIT-SIPI-SIPI sequence and wait for each one to report in
+pub fn initialize() {
+    if !interrupt::has_apic() {
+        return;
+    }
+
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => {
+            println!("SMP: no MADT, running on the bootstrap processor only");
+            return;
+        }
+    };
+
+    // The trampoline loads CR3 while still in 32-bit mode
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    if cr3 > u32::MAX as u64 {
+        println!("SMP: page tables above 4 GiB, not starting application processors");
+        return;
+    }
+
+    let trampoline = unsafe {
+        let start = &smp_trampoline_start as *const u8;
+        let end = &smp_trampoline_end as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            trampoline.as_ptr(),
+            TRAMPOLINE_BASE as *mut u8,
+            trampoline.len(),
+        );
+    }
+    let data = (TRAMPOLINE_BASE as usize + TRAMPOLINE_DATA_OFFSET) as *mut TrampolineData;
+
+    let bsp_id = interrupt::local_apic().id();
+
+    for apic_id in madt.processors() {
+        if apic_id == bsp_id {
+            continue;
+        }
+        // A startup IPI in xAPIC mode would go to the processor with the low
+        // 8 bits of the ID
+        if apic_id > interrupt::XAPIC_MAX_ID && !interrupt::is_x2apic() {
+            continue;
+        }
+
+        let cpu_index = online_cpus();
+        if cpu_index >= MAX_CPUS {
+            println!("SMP: more than {} processors, ignoring the rest", MAX_CPUS);
+            break;
+        }
+
+        unsafe {
+            let stack = &AP_STACKS[cpu_index] as *const Stack as u64;
+            data.write_volatile(TrampolineData {
+                cr0: Cr0::read_raw(),
+                cr3,
+                cr4: Cr4::read_raw(),
+                // LMA is read only and set by the CPU once paging is enabled
+                efer: Efer::read_raw() & !(1 << 10),
+                stack_top: stack + AP_STACK_SIZE as u64,
+                entry: ap_main as *const () as u64,
+                cpu_index: cpu_index as u64,
+            });
+        }
+
+        // A processor that didn't respond still owns its stack and the
+        // trampoline data, so none of the rest can be started with them
+        if !start_ap(apic_id, cpu_index) {
+            println!(
+                "SMP: processor with APIC ID {} did not respond, not starting the rest",
+                apic_id
+            );
+            break;
+        }
+    }
+
+    println!("SMP: {} processors online", online_cpus());
+}
+
+// Intel MultiProcessor Specification 1.4, B.4 Application Processor Startup
+fn start_ap(apic_id: u32, cpu_index: usize) -> bool {
+    let lapic = interrupt::local_apic();
+    STARTUP.store(WAITING, Ordering::Release);
+
+    lapic.send_init(apic_id);
+    pit::wait_us(10_000);
+
+    for _ in 0..2 {
+        lapic.send_startup(apic_id, (TRAMPOLINE_BASE >> 12) as u8);
+        pit::wait_us(200);
+        if online_cpus() > cpu_index {
+            return true;
+        }
+    }
+
+    // Give the processor up to 100 ms to get through the trampoline
+    for _ in 0..100 {
+        if online_cpus() > cpu_index {
+            return true;
+        }
+        pit::wait_us(1_000);
+    }
+
+    if STARTUP
+        .compare_exchange(WAITING, ABANDONED, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        // It reached `ap_main` after all and is about to report in
+        while online_cpus() <= cpu_index {
+            core::hint::spin_loop();
+        }
+        return true;
+    }
+    // Park it in wait-for-SIPI, wherever it got to in the trampoline
+    lapic.send_init(apic_id);
+    false
+}
+
+extern "C" fn ap_main(cpu_index: u64) -> ! {
+    if STARTUP
+        .compare_exchange(WAITING, ARRIVED, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        // Too late, the BSP is about to send INIT
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+    fpu::initialize();
+    percpu::initialize(cpu_index as usize);
+    interrupt::init_ap();
+    syscall::initialize();
+    watchdog::initialize_ap();
+
+    println!(
+        "CPU {} online (APIC ID {})",
+        cpu_index,
+        interrupt::local_apic().id()
+    );
+    // Release the BSP only once the trampoline data is no longer needed
+    ONLINE.fetch_add(1, Ordering::Release);
+
+    task::initialize();
+    interrupt::enable();
+
+    scheduler::idle();
+}
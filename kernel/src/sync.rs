@@ -0,0 +1,594 @@
+use crate::task::{self, State, Task};
+use crate::{scheduler, time};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+#[cfg(test)]
+use crate::{print, println};
+#[cfg(test)]
+use alloc::vec::Vec;
+
+// Sleeping synchronization primitives. Unlike `spin::Mutex` a task waiting
+// for one of these is taken off the processor until it is woken up. They can
+// only block in task context, boot and idle contexts and code running with
+// preemption disabled fall back to polling, and interrupt handlers must stick
+// to the `try_` variants.
+
+// Tasks waiting for some condition to become true
+pub struct WaitQueue {
+    waiters: spin::Mutex<VecDeque<Arc<Task>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: spin::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // Sleep until `condition` returns true. The condition is checked with the
+    // queue locked, so a wakeup between checking it and going to sleep can't
+    // be missed as long as wakers change the state before calling `wake_*`.
+    pub fn wait_until<F>(&self, condition: F)
+    where
+        F: FnMut() -> bool,
+    {
+        self.wait_until_deadline(condition, None);
+    }
+
+    // Like `wait_until`, but give up after `timeout`. Returns whether the
+    // condition became true.
+    pub fn wait_until_timeout<F>(&self, condition: F, timeout: Duration) -> bool
+    where
+        F: FnMut() -> bool,
+    {
+        self.wait_until_deadline(condition, Some(time::deadline_after(timeout)))
+    }
+
+    fn wait_until_deadline<F>(&self, mut condition: F, deadline: Option<u64>) -> bool
+    where
+        F: FnMut() -> bool,
+    {
+        let expired = |deadline: Option<u64>| deadline.is_some_and(|d| time::ticks() >= d);
+
+        let current = match task::current() {
+            Some(current) if scheduler::can_block() => current,
+            _ => loop {
+                if interrupts::without_interrupts(|| {
+                    let _waiters = self.waiters.lock();
+                    condition()
+                }) {
+                    return true;
+                }
+                if expired(deadline) {
+                    return false;
+                }
+                task::yield_now();
+                core::hint::spin_loop();
+            },
+        };
+
+        loop {
+            let satisfied = interrupts::without_interrupts(|| {
+                let mut waiters = self.waiters.lock();
+                if condition() {
+                    return true;
+                }
+                if expired(deadline) {
+                    return false;
+                }
+
+                current.set_state(State::Blocked);
+                waiters.push_back(current.clone());
+                drop(waiters);
+
+                let timer = deadline.map(|deadline| {
+                    let task = current.clone();
+                    time::add_timer(deadline, move || scheduler::wake(&task))
+                });
+
+                scheduler::schedule();
+
+                if let Some(timer) = timer {
+                    time::cancel_timer(timer);
+                }
+                // Still queued if the timer or a spurious wakeup woke us
+                self.remove(&current);
+                false
+            });
+
+            if satisfied {
+                return true;
+            }
+            if expired(deadline) {
+                // One last look, the condition may have become true just as
+                // the timer fired
+                return interrupts::without_interrupts(|| {
+                    let _waiters = self.waiters.lock();
+                    condition()
+                });
+            }
+        }
+    }
+
+    fn remove(&self, task: &Arc<Task>) {
+        self.waiters
+            .lock()
+            .retain(|waiter| !Arc::ptr_eq(waiter, task));
+    }
+
+    // Wake the longest waiting task, returns whether there was one
+    pub fn wake_one(&self) -> bool {
+        interrupts::without_interrupts(|| {
+            let waiter = self.waiters.lock().pop_front();
+            match waiter {
+                Some(waiter) => {
+                    scheduler::wake(&waiter);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    // Wake every waiting task, returns how many there were
+    pub fn wake_all(&self) -> usize {
+        interrupts::without_interrupts(|| {
+            let waiters = core::mem::take(&mut *self.waiters.lock());
+            for waiter in &waiters {
+                scheduler::wake(waiter);
+            }
+            waiters.len()
+        })
+    }
+}
+
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if !self.acquire() {
+            self.waiters.wait_until(|| self.acquire());
+        }
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if self.acquire() {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<MutexGuard<'_, T>> {
+        if self.acquire() || self.waiters.wait_until_timeout(|| self.acquire(), timeout) {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn release(&self) {
+        self.locked.store(false, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    fn mutex(&self) -> &'a Mutex<T> {
+        self.mutex
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.release();
+    }
+}
+
+// Many readers or one writer. Waiting writers keep new readers out so that a
+// steady stream of readers can't starve them.
+pub struct RwLock<T: ?Sized> {
+    // Number of readers, or WRITER while write locked
+    state: AtomicUsize,
+    writers_waiting: AtomicUsize,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+const WRITER: usize = usize::MAX;
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> RwLock<T> {
+        RwLock {
+            state: AtomicUsize::new(0),
+            writers_waiting: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.waiters.wait_until(|| self.acquire_read());
+        RwLockReadGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        if self.acquire_read() {
+            Some(RwLockReadGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn try_read_for(&self, timeout: Duration) -> Option<RwLockReadGuard<'_, T>> {
+        if self
+            .waiters
+            .wait_until_timeout(|| self.acquire_read(), timeout)
+        {
+            Some(RwLockReadGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.writers_waiting.fetch_add(1, Ordering::Relaxed);
+        self.waiters.wait_until(|| self.acquire_write());
+        self.writers_waiting.fetch_sub(1, Ordering::Relaxed);
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        if self.acquire_write() {
+            Some(RwLockWriteGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn try_write_for(&self, timeout: Duration) -> Option<RwLockWriteGuard<'_, T>> {
+        self.writers_waiting.fetch_add(1, Ordering::Relaxed);
+        let acquired = self
+            .waiters
+            .wait_until_timeout(|| self.acquire_write(), timeout);
+        self.writers_waiting.fetch_sub(1, Ordering::Relaxed);
+        if acquired {
+            Some(RwLockWriteGuard { lock: self })
+        } else {
+            // Readers held back by us may go ahead now
+            self.waiters.wake_all();
+            None
+        }
+    }
+
+    fn acquire_read(&self) -> bool {
+        if self.writers_waiting.load(Ordering::Relaxed) != 0 {
+            return false;
+        }
+        let state = self.state.load(Ordering::Relaxed);
+        state < WRITER - 1
+            && self
+                .state
+                .compare_exchange(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    fn acquire_write(&self) -> bool {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.lock.waiters.wake_all();
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.waiters.wake_all();
+    }
+}
+
+// Counting semaphore
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        self.waiters
+            .wait_until_timeout(|| self.try_acquire(), timeout)
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+// Condition variable used together with `Mutex`. Waiting may wake up
+// spuriously, callers have to recheck their condition.
+pub struct Condvar {
+    // Bumped by every notification, a waiter sleeps until it changes
+    sequence: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar {
+            sequence: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        let sequence = self.sequence.load(Ordering::Acquire);
+        drop(guard);
+
+        self.waiters
+            .wait_until(|| self.sequence.load(Ordering::Acquire) != sequence);
+        mutex.lock()
+    }
+
+    // Returns the guard and whether the timeout elapsed without notification
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let mutex = guard.mutex();
+        let sequence = self.sequence.load(Ordering::Acquire);
+        drop(guard);
+
+        let notified = self.waiters.wait_until_timeout(
+            || self.sequence.load(Ordering::Acquire) != sequence,
+            timeout,
+        );
+        (mutex.lock(), !notified)
+    }
+
+    // Wait until `condition` returns false for the protected data
+    pub fn wait_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
+
+#[test_case]
+fn mutex_contention() {
+    static COUNTER: Mutex<u64> = Mutex::new(0);
+
+    print!("mutex contention... ");
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            task::spawn(|| {
+                for _ in 0..1000 {
+                    let mut counter = COUNTER.lock();
+                    let value = *counter;
+                    task::yield_now();
+                    *counter = value + 1;
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(*COUNTER.lock(), 4000);
+    println!("[ok]");
+}
+
+#[test_case]
+fn semaphore_and_condvar_timeouts() {
+    print!("semaphore and condvar timeouts... ");
+    task::spawn(|| {
+        let semaphore = Semaphore::new(1);
+        assert!(semaphore.try_acquire());
+        assert!(!semaphore.acquire_timeout(Duration::from_millis(30)));
+        semaphore.release();
+        assert!(semaphore.acquire_timeout(Duration::from_millis(30)));
+
+        let mutex = Mutex::new(());
+        let condvar = Condvar::new();
+        let start = time::ticks();
+        let (_guard, timed_out) = condvar.wait_timeout(mutex.lock(), Duration::from_millis(50));
+        assert!(timed_out);
+        assert!(time::ticks() - start >= time::duration_to_ticks(Duration::from_millis(50)));
+    })
+    .join();
+    println!("[ok]");
+}
+
+#[test_case]
+fn condvar_notify() {
+    static READY: Mutex<bool> = Mutex::new(false);
+    static CONDVAR: Condvar = Condvar::new();
+
+    print!("condvar notify... ");
+    let waiter = task::spawn(|| {
+        let guard = CONDVAR.wait_while(READY.lock(), |ready| !*ready);
+        assert!(*guard);
+    });
+    task::spawn(|| {
+        time::sleep(Duration::from_millis(20));
+        *READY.lock() = true;
+        CONDVAR.notify_all();
+    })
+    .join();
+    waiter.join();
+    println!("[ok]");
+}
+
+#[test_case]
+fn try_variants() {
+    print!("try variants... ");
+    task::spawn(|| {
+        let mut mutex = Mutex::new(1);
+        let guard = mutex.try_lock().unwrap();
+        assert!(mutex.try_lock().is_none());
+        assert!(mutex.try_lock_for(Duration::from_millis(20)).is_none());
+        drop(guard);
+        *mutex.get_mut() += 1;
+        assert_eq!(mutex.into_inner(), 2);
+
+        let lock = RwLock::new(());
+        let read = lock.try_read().unwrap();
+        assert!(lock.try_read().is_some());
+        assert!(lock.try_write().is_none());
+        assert!(lock.try_write_for(Duration::from_millis(20)).is_none());
+        drop(read);
+        let write = lock.try_write().unwrap();
+        assert!(lock.try_read_for(Duration::from_millis(20)).is_none());
+        drop(write);
+
+        let semaphore = Semaphore::new(2);
+        semaphore.acquire();
+        assert_eq!(semaphore.available(), 1);
+        semaphore.release();
+        assert_eq!(semaphore.available(), 2);
+    })
+    .join();
+    println!("[ok]");
+}
@@ -0,0 +1,856 @@
+use crate::elf::{self, LoadError, ARG_MAX};
+use crate::file::OpenFile;
+use crate::gdt::{
+    KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR,
+};
+use crate::interrupt::TrapFrame;
+use crate::paging::{MapError, USER_END};
+use crate::pipe::{self, PIPE_BUF};
+use crate::process::{Personality, Pid};
+use crate::sync::WaitQueue;
+use crate::usermode::{self, FaultKind, UserExit};
+use crate::{file, linux, percpu, process, programs, signal, task, time, wasi, wasm};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+#[cfg(test)]
+use crate::paging::{self, USER_START};
+#[cfg(test)]
+use crate::usermode::{Asm, Reg};
+#[cfg(test)]
+use crate::{print, println};
+#[cfg(test)]
+use goblin::elf::header::ET_DYN;
+#[cfg(test)]
+use x86_64::structures::paging::PageTableFlags;
+
+// System call numbers, passed in RAX. Arguments go in RDI, RSI, RDX, R10, R8
+// and R9 and the result comes back in RAX, negative for an error, like the
+// Linux x86-64 convention.
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_YIELD: u64 = 2;
+pub const SYS_GETTID: u64 = 3;
+pub const SYS_GETPID: u64 = 4;
+pub const SYS_GETPPID: u64 = 5;
+pub const SYS_FORK: u64 = 6;
+pub const SYS_EXECVE: u64 = 7;
+pub const SYS_WAIT: u64 = 8;
+pub const SYS_SIGACTION: u64 = 9;
+pub const SYS_SIGPROCMASK: u64 = 10;
+pub const SYS_KILL: u64 = 11;
+pub const SYS_SIGRETURN: u64 = 12;
+pub const SYS_READ: u64 = 13;
+pub const SYS_OPEN: u64 = 14;
+pub const SYS_CLOSE: u64 = 15;
+pub const SYS_DUP: u64 = 16;
+pub const SYS_DUP2: u64 = 17;
+pub const SYS_PIPE: u64 = 18;
+pub const SYS_BRK: u64 = 19;
+pub const SYS_SLEEP: u64 = 20;
+pub const SYS_CLOCK: u64 = 21;
+const SYSCALL_COUNT: usize = 22;
+
+// Error numbers returned negated, with the values Linux uses
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EEXIST = 17,
+    ENODEV = 19,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENOTTY = 25,
+    ESPIPE = 29,
+    EPIPE = 32,
+    ERANGE = 34,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+}
+
+pub type Handler = fn(&mut TrapFrame) -> Result<u64, Errno>;
+
+// Indexed by system call number
+static TABLE: [Option<Handler>; SYSCALL_COUNT] = {
+    let mut table: [Option<Handler>; SYSCALL_COUNT] = [None; SYSCALL_COUNT];
+    table[SYS_EXIT as usize] = Some(sys_exit);
+    table[SYS_WRITE as usize] = Some(sys_write);
+    table[SYS_YIELD as usize] = Some(sys_yield);
+    table[SYS_GETTID as usize] = Some(sys_gettid);
+    table[SYS_GETPID as usize] = Some(sys_getpid);
+    table[SYS_GETPPID as usize] = Some(sys_getppid);
+    table[SYS_FORK as usize] = Some(sys_fork);
+    table[SYS_EXECVE as usize] = Some(sys_execve);
+    table[SYS_WAIT as usize] = Some(sys_wait);
+    table[SYS_SIGACTION as usize] = Some(sys_sigaction);
+    table[SYS_SIGPROCMASK as usize] = Some(sys_sigprocmask);
+    table[SYS_KILL as usize] = Some(sys_kill);
+    table[SYS_SIGRETURN as usize] = Some(sys_sigreturn);
+    table[SYS_READ as usize] = Some(sys_read);
+    table[SYS_OPEN as usize] = Some(sys_open);
+    table[SYS_CLOSE as usize] = Some(sys_close);
+    table[SYS_DUP as usize] = Some(sys_dup);
+    table[SYS_DUP2 as usize] = Some(sys_dup2);
+    table[SYS_PIPE as usize] = Some(sys_pipe);
+    table[SYS_BRK as usize] = Some(sys_brk);
+    table[SYS_SLEEP as usize] = Some(sys_sleep);
+    table[SYS_CLOCK as usize] = Some(sys_clock);
+    table
+};
+
+// Bytes of user memory copied per step by `read` and `write`, enough for
+// small pipe writes to stay in one piece
+pub const IO_CHUNK: usize = PIPE_BUF;
+
+// Longest path `execve` takes
+const PATH_MAX: usize = 4096;
+
+// RFLAGS bits cleared on entry: interrupts stay off until the kernel stack is
+// in use, and user code can't leave traps, string direction or alignment
+// checks enabled for the kernel
+const SYSCALL_FLAG_MASK: RFlags = RFlags::from_bits_truncate(
+    RFlags::INTERRUPT_FLAG.bits()
+        | RFlags::TRAP_FLAG.bits()
+        | RFlags::DIRECTION_FLAG.bits()
+        | RFlags::ALIGNMENT_CHECK.bits(),
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+// Enable SYSCALL on the calling processor. Intel SDM Vol. 3A 5.8.8 Fast
+// System Calls in 64-Bit Mode: SYSCALL loads CS from STAR[47:32] and SS from
+// the selector after it, SYSRET CS and SS from STAR[63:48] plus 16 and 8.
+pub fn initialize() {
+    Star::write(
+        USER_CODE_SELECTOR,
+        USER_DATA_SELECTOR,
+        KERNEL_CODE_SELECTOR,
+        KERNEL_DATA_SELECTOR,
+    )
+    .expect("GDT layout doesn't fit SYSRET");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    SFMask::write(SYSCALL_FLAG_MASK);
+    unsafe { Efer::update(|efer| efer.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+// SYSCALL leaves the user stack pointer in RSP, the return address in RCX and
+// RFLAGS in R11. Switch to the task's kernel stack and build a `TrapFrame`
+// there like the one of an interrupt from user mode, so that code handling
+// either sees the same user state. Return with SYSRET, which restores RIP and
+// RFLAGS from RCX and R11, unless the handler returns true because they
+// differ, after sigreturn or signal delivery. Then `restore_user_frame`
+// returns with `iretq` like an interrupt.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov gs:[{user_rsp}], rsp",
+    "mov rsp, gs:[{kernel_stack}]",
+    "and rsp, -16",
+    "push {user_ss}",
+    "push gs:[{user_rsp}]",
+    "push r11",
+    "push {user_cs}",
+    "push rcx",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "call {handler}",
+    "test al, al",
+    "jnz restore_user_frame",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "mov rcx, [rsp]",
+    "mov r11, [rsp + 16]",
+    "mov rsp, [rsp + 24]",
+    "swapgs",
+    "sysretq",
+    user_rsp = const percpu::USER_RSP_OFFSET,
+    kernel_stack = const percpu::KERNEL_STACK_OFFSET,
+    user_ss = const USER_DATA_SELECTOR.0,
+    user_cs = const USER_CODE_SELECTOR.0,
+    handler = sym syscall_handler,
+);
+
+extern "C" fn syscall_handler(frame: &mut TrapFrame) -> bool {
+    usermode::save_fpu();
+    interrupts::enable();
+
+    // Programs built for Linux make its system calls instead
+    let table: &[Option<Handler>] = match process::current().map(|p| p.personality()) {
+        Some(Personality::Linux) => &linux::TABLE,
+        _ => &TABLE,
+    };
+    let result = match table.get(frame.rax as usize) {
+        Some(Some(handler)) => handler(frame),
+        _ => Err(Errno::ENOSYS),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    };
+
+    interrupts::disable();
+    // SYSRET to a non-canonical address faults in kernel mode on Intel
+    // processors, so such a frame is never returned to
+    let rip = frame.stack_frame.instruction_pointer.as_u64();
+    if rip >= USER_END {
+        usermode::request_exit(UserExit::Fault(usermode::Fault {
+            kind: FaultKind::GeneralProtection,
+            rip,
+            error_code: 0,
+            address: None,
+        }));
+    }
+    usermode::prepare_return(frame);
+    usermode::restore_fpu();
+    frame.rcx != frame.stack_frame.instruction_pointer.as_u64()
+        || frame.r11 != frame.stack_frame.cpu_flags
+}
+
+// Arguments in order
+pub fn args(frame: &TrapFrame) -> [u64; 6] {
+    [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ]
+}
+
+// exit(status)
+pub fn sys_exit(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    usermode::request_exit(UserExit::Exit(args(frame)[0] as i64));
+    Ok(0)
+}
+
+// The open file of `fd` in the calling process
+pub fn file(fd: u64) -> Result<Arc<OpenFile>, Errno> {
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    process.with_files(|files| files.get(fd))
+}
+
+// write(fd, buffer, length): the number of bytes written, which is less than
+// `length` only if something went wrong after writing them
+pub fn sys_write(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [fd, buffer, length, ..] = args(frame);
+    let file = file(fd)?;
+    write_from_user(&file, buffer, length as usize).map(|n| n as u64)
+}
+
+// Write `length` bytes at `buffer` in user memory to `file`, like `write`
+pub fn write_from_user(file: &OpenFile, buffer: u64, length: usize) -> Result<usize, Errno> {
+    if !usermode::is_user_range(buffer, length) {
+        return Err(Errno::EFAULT);
+    }
+
+    let mut chunk = vec![0u8; length.min(IO_CHUNK)];
+    let mut written = 0;
+    while written < length {
+        let n = (length - written).min(IO_CHUNK);
+        let result = usermode::copy_from_user(&mut chunk[..n], buffer + written as u64)
+            .and_then(|_| file.write(&chunk[..n]));
+        match result {
+            Ok(count) => written += count,
+            Err(error) if written == 0 => return Err(error),
+            Err(_) => break,
+        }
+    }
+    Ok(written)
+}
+
+// read(fd, buffer, length): the number of bytes read, zero at the end of the
+// file. Blocks until there are some if the file does.
+pub fn sys_read(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [fd, buffer, length, ..] = args(frame);
+    let file = file(fd)?;
+    let length = length as usize;
+    if !usermode::is_user_range(buffer, length) {
+        return Err(Errno::EFAULT);
+    }
+    let mut chunk = vec![0u8; length.min(IO_CHUNK)];
+    let n = file.read(&mut chunk)?;
+    usermode::copy_to_user(buffer, &chunk[..n])?;
+    Ok(n as u64)
+}
+
+// open(path, flags): a new file descriptor for the file at `path`
+pub fn sys_open(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [path, flags, ..] = args(frame);
+    let path = copy_path(path)?;
+    let file = file::open(&path, flags)?;
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    process.with_files(|files| files.insert(file))
+}
+
+// close(fd)
+pub fn sys_close(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let fd = args(frame)[0];
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    let file = process.with_files(|files| files.close(fd))?;
+    drop(file);
+    Ok(0)
+}
+
+// dup(fd): the lowest free file descriptor, for the same file as `fd`
+pub fn sys_dup(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let fd = args(frame)[0];
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    process.with_files(|files| files.dup(fd))
+}
+
+// dup2(old, new): make `new` refer to the file of `old`, closing it first
+// if it was open. Returns `new`.
+pub fn sys_dup2(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [old, new, ..] = args(frame);
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    let closed = process.with_files(|files| files.dup2(old, new))?;
+    drop(closed);
+    Ok(new)
+}
+
+// pipe(fds): store file descriptors for the read and the write end of a new
+// pipe as two 32-bit integers at `fds`
+pub fn sys_pipe(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    pipe(args(frame)[0], false)
+}
+
+// `pipe` with both file descriptors closed on exec if `cloexec` is set
+pub fn pipe(fds: u64, cloexec: bool) -> Result<u64, Errno> {
+    let (reader, writer) = pipe::pipe();
+    let reader = OpenFile::new(Box::new(reader), true, false);
+    let writer = OpenFile::new(Box::new(writer), false, true);
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    let (read_fd, write_fd) = process.with_files(|files| {
+        let read_fd = files.insert_from(reader, 0, cloexec)?;
+        match files.insert_from(writer, 0, cloexec) {
+            Ok(write_fd) => Ok((read_fd, write_fd)),
+            Err(error) => {
+                files.close(read_fd)?;
+                Err(error)
+            }
+        }
+    })?;
+
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&(read_fd as u32).to_le_bytes());
+    bytes[4..].copy_from_slice(&(write_fd as u32).to_le_bytes());
+    if let Err(error) = usermode::copy_to_user(fds, &bytes) {
+        let closed = process.with_files(|files| (files.close(read_fd), files.close(write_fd)));
+        drop(closed);
+        return Err(error);
+    }
+    Ok(0)
+}
+
+// yield()
+pub fn sys_yield(_frame: &mut TrapFrame) -> Result<u64, Errno> {
+    task::yield_now();
+    Ok(0)
+}
+
+// gettid(): ID of the calling task
+pub fn sys_gettid(_frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let task = task::current().ok_or(Errno::ENOSYS)?;
+    Ok(task.id().as_u64())
+}
+
+// getpid(): ID of the calling process
+pub fn sys_getpid(_frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    Ok(process.pid().as_u64())
+}
+
+// getppid(): ID of the parent process, zero if there is none
+pub fn sys_getppid(_frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    Ok(process.parent().map_or(0, |parent| parent.pid().as_u64()))
+}
+
+// fork(): the ID of the child, 0 in the child
+pub fn sys_fork(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    let child = process.fork(frame).map_err(|_| Errno::ENOMEM)?;
+    Ok(child.pid().as_u64())
+}
+
+// execve(path, argv, envp): argv and envp are null terminated arrays of
+// strings. Doesn't return if successful.
+pub fn sys_execve(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [path, argv, envp, ..] = args(frame);
+    let path = copy_path(path)?;
+    let mut left = ARG_MAX;
+    let argv = copy_string_array(argv, &mut left)?;
+    let envp = copy_string_array(envp, &mut left)?;
+
+    let image = programs::find(&path).ok_or(Errno::ENOENT)?;
+    let argv: Vec<&[u8]> = argv.iter().map(Vec::as_slice).collect();
+    let envp: Vec<&[u8]> = envp.iter().map(Vec::as_slice).collect();
+    if wasm::is_module(image) {
+        return wasi::exec(image, &argv, &envp);
+    }
+    let program = elf::load(image, &argv, &envp).map_err(|error| match error {
+        LoadError::ArgumentsTooLong => Errno::E2BIG,
+        LoadError::Map(MapError::OutOfMemory) => Errno::ENOMEM,
+        _ => Errno::ENOEXEC,
+    })?;
+
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    process.exec(program, frame);
+    Ok(0)
+}
+
+// A path from user memory at `path`
+pub fn copy_path(path: u64) -> Result<Vec<u8>, Errno> {
+    usermode::copy_string_from_user(path, PATH_MAX, Errno::ENAMETOOLONG)
+}
+
+// The strings of a null terminated array of pointers in user memory, which
+// may be null itself. At most `left` bytes in total.
+fn copy_string_array(array: u64, left: &mut usize) -> Result<Vec<Vec<u8>>, Errno> {
+    let mut strings = Vec::new();
+    if array == 0 {
+        return Ok(strings);
+    }
+    for i in 0.. {
+        let mut pointer = [0u8; 8];
+        usermode::copy_from_user(&mut pointer, array + i * 8)?;
+        let pointer = u64::from_le_bytes(pointer);
+        if pointer == 0 {
+            break;
+        }
+        let string = usermode::copy_string_from_user(pointer, *left, Errno::E2BIG)?;
+        // With the NUL and the pointer
+        *left = left.checked_sub(string.len() + 1 + 8).ok_or(Errno::E2BIG)?;
+        strings.push(string);
+    }
+    Ok(strings)
+}
+
+// Options of `wait`: return 0 right away if no such child exited yet, or
+// after the nanoseconds in the fourth argument
+const WNOHANG: u64 = 1;
+const WTIMEOUT: u64 = 2;
+
+// wait(pid, status, options, timeout): wait for the child `pid` to exit, any
+// child if -1. Stores its status like Linux' wait4 if `status` isn't null and
+// returns its ID. With WTIMEOUT having no children isn't an error, init may
+// still adopt some, so it can wait for orphans and a timer at once.
+fn sys_wait(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [pid, status, options, timeout, ..] = args(frame);
+    let pid = match pid as i64 {
+        -1 => None,
+        pid if pid > 0 => Some(Pid::from_u64(pid as u64)),
+        _ => return Err(Errno::EINVAL),
+    };
+    if options & !(WNOHANG | WTIMEOUT) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    let exited = if options & WNOHANG != 0 {
+        process.try_wait(pid)?
+    } else if options & WTIMEOUT != 0 {
+        process.wait_timeout(pid, Duration::from_nanos(timeout))?
+    } else {
+        Some(process.wait(pid)?)
+    };
+    let (pid, exit) = match exited {
+        Some(exited) => exited,
+        None => return Ok(0),
+    };
+    if status != 0 {
+        usermode::copy_to_user(status, &wait_status(exit).to_le_bytes())?;
+    }
+    Ok(pid.as_u64())
+}
+
+// Exit status in bits 8-15, or the number of the signal that killed the
+// process in the low bits
+pub fn wait_status(exit: UserExit) -> u32 {
+    match exit {
+        UserExit::Exit(status) => ((status & 0xff) as u32) << 8,
+        UserExit::Fault(fault) => fault.kind.signal() as u32,
+        UserExit::Signal(sig) => sig as u32,
+    }
+}
+
+// sleep(nanoseconds): block for at least that long, or fail with EINTR once
+// the process has a signal to handle
+fn sys_sleep(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let deadline = time::deadline_after(Duration::from_nanos(args(frame)[0]));
+    let queue = Arc::new(WaitQueue::new());
+    let waker = queue.clone();
+    let timer = time::add_timer(deadline, move || {
+        waker.wake_all();
+    });
+    let slept = process::wait_interruptible(&queue, || time::ticks() >= deadline);
+    time::cancel_timer(timer);
+    slept.map(|()| 0)
+}
+
+// clock(): nanoseconds since boot
+fn sys_clock(_frame: &mut TrapFrame) -> Result<u64, Errno> {
+    Ok(time::nanos())
+}
+
+// brk(end): move the end of the heap to `end`. Returns where it ends now,
+// which is unchanged if it can't move, or if `end` is 0.
+pub fn sys_brk(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    Ok(process.brk(args(frame)[0]))
+}
+
+// sigaction(sig, action, old), see `signal::sigaction`
+fn sys_sigaction(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [sig, action, old, ..] = args(frame);
+    signal::sigaction(sig, action, old)?;
+    Ok(0)
+}
+
+// sigprocmask(how, set, old), see `signal::sigprocmask`
+fn sys_sigprocmask(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [how, set, old, ..] = args(frame);
+    signal::sigprocmask(how, set, old)?;
+    Ok(0)
+}
+
+// kill(pid, sig)
+pub fn sys_kill(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    let [pid, sig, ..] = args(frame);
+    signal::kill(pid, sig)?;
+    Ok(0)
+}
+
+// sigreturn(): made by the restorer a signal handler returns to. Doesn't
+// return, the interrupted context continues with its own registers.
+pub fn sys_sigreturn(frame: &mut TrapFrame) -> Result<u64, Errno> {
+    signal::sigreturn(frame)?;
+    Ok(frame.rax)
+}
+
+#[test_case]
+fn write_and_exit() {
+    print!("system calls... ");
+    let message = b"[ring 3] ";
+    let code = Asm::new()
+        .lea_rip(Reg::Rsi, 0x40)
+        .mov(Reg::Rdi, 1)
+        .mov(Reg::Rdx, message.len() as u32)
+        .syscall(SYS_WRITE)
+        .exit_with(Reg::Rax)
+        .at(0x40)
+        .raw(message)
+        .code();
+    assert_eq!(
+        usermode::run_code(&code),
+        UserExit::Exit(message.len() as i64)
+    );
+
+    // Callee-saved registers survive a system call that switches tasks
+    let code = Asm::new()
+        .mov(Reg::Rbx, 0x1234)
+        .syscall(SYS_YIELD)
+        .exit_with(Reg::Rbx)
+        .code();
+    assert_eq!(usermode::run_code(&code), UserExit::Exit(0x1234));
+
+    let code = Asm::new().syscall(1000).exit_with(Reg::Rax).code();
+    assert_eq!(
+        usermode::run_code(&code),
+        UserExit::Exit(-(Errno::ENOSYS as i64))
+    );
+
+    let code = Asm::new().syscall(SYS_GETPID).exit_with(Reg::Rax).code();
+    let process = process::spawn(
+        "getpid",
+        usermode::test_space(&code),
+        VirtAddr::new(USER_START),
+        VirtAddr::new(usermode::TEST_STACK),
+    );
+    assert_eq!(
+        process.wait_exit(),
+        UserExit::Exit(process.pid().as_u64() as i64)
+    );
+    println!("[ok]");
+}
+
+// Bad buffers are reported to the caller, whether outside of user space or
+// not mapped
+#[test_case]
+fn copy_from_bad_pointer() {
+    print!("system call with bad pointer... ");
+    for buffer in [0x100000, USER_START + 0x20000] {
+        let code = Asm::new()
+            .mov64(Reg::Rsi, buffer)
+            .mov(Reg::Rdi, 1)
+            .mov(Reg::Rdx, 16)
+            .syscall(SYS_WRITE)
+            .exit_with(Reg::Rax)
+            .code();
+        assert_eq!(
+            usermode::run_code(&code),
+            UserExit::Exit(-(Errno::EFAULT as i64))
+        );
+    }
+    println!("[ok]");
+}
+
+// The child writes to its copy of a page, the parent still sees its own and
+// gets the child's status from wait
+#[test_case]
+fn fork_and_wait() {
+    print!("fork with copy-on-write... ");
+    let (frames, _) = paging::frame_usage();
+    let data = USER_START + 0x20000;
+    let code = Asm::new()
+        .mov64(Reg::Rbx, data)
+        .raw(&[0x48, 0xc7, 0x03, 0x01, 0x00, 0x00, 0x00]) // mov qword ptr [rbx], 1
+        .syscall(SYS_FORK)
+        .raw(&[0x48, 0x85, 0xc0]) // test rax, rax
+        .jnz(0x40)
+        // The child
+        .raw(&[0x48, 0xc7, 0x03, 0x02, 0x00, 0x00, 0x00]) // mov qword ptr [rbx], 2
+        .load(Reg::Rdi, Reg::Rbx, 0)
+        .syscall(SYS_EXIT)
+        // The parent, the status is written to the stack, also copy-on-write
+        .at(0x40)
+        .mov_reg(Reg::Rdi, Reg::Rax)
+        .lea(Reg::Rsi, Reg::Rsp, -8)
+        .mov(Reg::Rdx, 0)
+        .syscall(SYS_WAIT)
+        .raw(&[0x8b, 0x7c, 0x24, 0xf8]) // mov edi, [rsp - 8]
+        .raw(&[0x48, 0x03, 0x3b]) // add rdi, [rbx]
+        .syscall(SYS_EXIT)
+        .code();
+    let space = usermode::test_space(&code);
+    space
+        .map(data, 4096, PageTableFlags::WRITABLE)
+        .expect("map user data");
+    let process = process::spawn(
+        "fork",
+        space,
+        VirtAddr::new(USER_START),
+        VirtAddr::new(usermode::TEST_STACK),
+    );
+    // Child exit status 2 in bits 8-15, plus the parent's value
+    assert_eq!(process.wait_exit(), UserExit::Exit(0x201));
+    // Shared frames were freed once
+    assert_eq!(paging::frame_usage().0, frames);
+    println!("[ok]");
+}
+
+#[test_case]
+fn execve() {
+    print!("execve... ");
+    // Exit with argc
+    let code = Asm::new()
+        .load(Reg::Rdi, Reg::Rsp, 0)
+        .syscall(SYS_EXIT)
+        .code();
+    let image = elf::test_image(ET_DYN, 0, &code, None);
+    programs::register("/bin/argc", Box::leak(image.into_boxed_slice()));
+
+    // execve(path, ["a", "b", "c"], ["X=1"]), exit with the result if it
+    // returns
+    let exec = |path: &[u8]| {
+        let address = |offset: u64| (USER_START + offset).to_le_bytes();
+        Asm::new()
+            .mov64(Reg::Rdi, USER_START + 0x100)
+            .mov64(Reg::Rsi, USER_START + 0x140)
+            .mov64(Reg::Rdx, USER_START + 0x160)
+            .syscall(SYS_EXECVE)
+            .exit_with(Reg::Rax)
+            .at(0x100)
+            .raw(path)
+            .at(0x110)
+            .raw(b"a\0\0\0\0\0\0\0b\0\0\0\0\0\0\0c\0\0\0\0\0\0\0X=1\0")
+            .at(0x140)
+            .raw(&address(0x110))
+            .raw(&address(0x118))
+            .raw(&address(0x120))
+            .at(0x160)
+            .raw(&address(0x128))
+            .at(0x170)
+            .code()
+    };
+
+    let (frames, _) = paging::frame_usage();
+    assert_eq!(usermode::run_code(&exec(b"/bin/argc")), UserExit::Exit(3));
+    assert_eq!(
+        usermode::run_code(&exec(b"/bin/missing")),
+        UserExit::Exit(-(Errno::ENOENT as i64))
+    );
+    assert_eq!(paging::frame_usage().0, frames);
+    println!("[ok]");
+}
+
+// Data written to a pipe through a duplicate of its write end comes out of
+// the read end, followed by the end of the file once no writer is left
+#[test_case]
+fn pipe_and_dup() {
+    print!("pipe system calls... ");
+    // fds at data, a message 16 bytes after them, then a buffer
+    let data = USER_START + 0x200;
+    let mut code = Asm::new()
+        .mov64(Reg::Rbx, data)
+        .mov_reg(Reg::Rdi, Reg::Rbx)
+        .syscall(SYS_PIPE)
+        .raw(&[0x8b, 0x7b, 0x04]) // mov edi, [rbx + 4]
+        .mov(Reg::Rsi, 9)
+        .syscall(SYS_DUP2)
+        .raw(&[0x8b, 0x7b, 0x04]) // mov edi, [rbx + 4]
+        .syscall(SYS_CLOSE)
+        .mov(Reg::Rdi, 9)
+        .lea(Reg::Rsi, Reg::Rbx, 0x10)
+        .mov(Reg::Rdx, 3)
+        .syscall(SYS_WRITE)
+        .mov(Reg::Rdi, 9)
+        .syscall(SYS_CLOSE);
+    // Two reads into the buffer, with the results in r12 and r13
+    for result in [Reg::R12, Reg::R13] {
+        code = code
+            .raw(&[0x8b, 0x3b]) // mov edi, [rbx]
+            .lea(Reg::Rsi, Reg::Rbx, 0x20)
+            .mov(Reg::Rdx, 16)
+            .syscall(SYS_READ)
+            .mov_reg(result, Reg::Rax);
+    }
+    // Exit with the first byte read plus the results in the bytes above
+    let code = code
+        .raw(&[0x0f, 0xb6, 0x7b, 0x20]) // movzx edi, byte ptr [rbx + 0x20]
+        .raw(&[0x49, 0xc1, 0xe4, 0x08]) // shl r12, 8
+        .raw(&[0x4c, 0x01, 0xe7]) // add rdi, r12
+        .raw(&[0x49, 0xc1, 0xe5, 0x10]) // shl r13, 16
+        .raw(&[0x4c, 0x01, 0xef]) // add rdi, r13
+        .syscall(SYS_EXIT)
+        .at(0x210)
+        .raw(b"hey")
+        .at(0x240)
+        .code();
+    assert_eq!(
+        usermode::run_code(&code),
+        UserExit::Exit(b'h' as i64 + (3 << 8))
+    );
+    println!("[ok]");
+}
+
+// The heap grows after the program's segments
+#[test_case]
+fn brk() {
+    print!("brk... ");
+    let code = Asm::new()
+        .mov(Reg::Rdi, 0)
+        .syscall(SYS_BRK)
+        .mov_reg(Reg::Rbx, Reg::Rax)
+        .lea(Reg::Rdi, Reg::Rax, 0x3000)
+        .syscall(SYS_BRK)
+        .raw(&[0xc6, 0x40, 0xff, 0x01]) // mov byte ptr [rax - 1], 1
+        .raw(&[0x48, 0x29, 0xd8]) // sub rax, rbx
+        .exit_with(Reg::Rax)
+        .code();
+    let (frames, _) = paging::frame_usage();
+    let program = elf::load(&elf::test_image(ET_DYN, 0, &code, None), &[], &[]).expect("load");
+    let process = process::spawn_program("brk", program);
+    assert_eq!(process.wait_exit(), UserExit::Exit(0x3000));
+    assert_eq!(paging::frame_usage().0, frames);
+    println!("[ok]");
+}
+
+#[test_case]
+fn sleep_and_clock() {
+    print!("sleep system call... ");
+    let code = Asm::new()
+        .syscall(SYS_CLOCK)
+        .mov_reg(Reg::Rbx, Reg::Rax)
+        .mov(Reg::Rdi, 20_000_000)
+        .syscall(SYS_SLEEP)
+        .syscall(SYS_CLOCK)
+        .raw(&[0x48, 0x29, 0xd8]) // sub rax, rbx
+        .exit_with(Reg::Rax)
+        .code();
+    match usermode::run_code(&code) {
+        UserExit::Exit(slept) => assert!(slept >= 20_000_000, "slept {} ns", slept),
+        exit => panic!("sleep test ended with {:?}", exit),
+    }
+
+    // wait with a timeout and no children runs out, exit with the time it
+    // took, negative if it failed
+    let code = Asm::new()
+        .syscall(SYS_CLOCK)
+        .mov_reg(Reg::R12, Reg::Rax)
+        .mov64(Reg::Rdi, -1i64 as u64)
+        .mov(Reg::Rsi, 0)
+        .mov(Reg::Rdx, WTIMEOUT as u32)
+        .mov(Reg::R10, 10_000_000)
+        .syscall(SYS_WAIT)
+        .mov_reg(Reg::Rbx, Reg::Rax)
+        .syscall(SYS_CLOCK)
+        .raw(&[0x4c, 0x29, 0xe0]) // sub rax, r12
+        .raw(&[0x48, 0x09, 0xd8]) // or rax, rbx
+        .exit_with(Reg::Rax)
+        .code();
+    match usermode::run_code(&code) {
+        UserExit::Exit(waited) => assert!(waited >= 10_000_000, "waited {} ns", waited),
+        exit => panic!("wait test ended with {:?}", exit),
+    }
+
+    // wait with WNOHANG and no children
+    let code = Asm::new()
+        .mov64(Reg::Rdi, -1i64 as u64)
+        .mov(Reg::Rsi, 0)
+        .mov(Reg::Rdx, WNOHANG as u32)
+        .syscall(SYS_WAIT)
+        .exit_with(Reg::Rax)
+        .code();
+    assert_eq!(
+        usermode::run_code(&code),
+        UserExit::Exit(-(Errno::ECHILD as i64))
+    );
+    println!("[ok]");
+}
@@ -0,0 +1,501 @@
+use crate::fpu::FpuState;
+use crate::scheduler::{self, AdmissionError, DeadlineParams};
+use crate::usermode::UserState;
+use crate::{percpu, time};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicI8, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+#[cfg(test)]
+use crate::{print, println};
+
+const STACK_SIZE: usize = 4096 * 8;
+
+// Priorities work like Unix nice values: lower numbers get more CPU time
+pub const PRIORITY_HIGHEST: i8 = -20;
+pub const PRIORITY_LOWEST: i8 = 19;
+pub const PRIORITY_DEFAULT: i8 = 0;
+
+// Affinity mask allowing every processor
+pub const ALL_CPUS: u64 = u64::MAX;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> TaskId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+// Every task that hasn't been dropped yet, for listing them
+static TASKS: Mutex<BTreeMap<TaskId, Weak<Task>>> = Mutex::new(BTreeMap::new());
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    Blocked,
+    Exited,
+}
+
+// Where a task's time went, updated by the scheduler. Times are in
+// nanoseconds of `time::nanos()`.
+#[derive(Default)]
+pub struct Stats {
+    pub runtime_ns: AtomicU64,
+    // Switched out because it blocked or exited
+    pub voluntary_switches: AtomicU64,
+    // Switched out while it could have continued, or yielded
+    pub involuntary_switches: AtomicU64,
+    // Time from being woken to running again
+    pub wakeups: AtomicU64,
+    pub wakeup_latency_ns: AtomicU64,
+    pub max_wakeup_latency_ns: AtomicU64,
+    // When the task was last woken, zero once it ran since
+    pub woken_at: AtomicU64,
+}
+
+// Reservation and current job of a task in the deadline class, in
+// nanoseconds of `time::nanos()`. `runtime` is zero for tasks in the normal
+// class.
+#[derive(Default)]
+pub struct Deadline {
+    pub runtime: AtomicU64,
+    pub deadline: AtomicU64,
+    pub period: AtomicU64,
+    // Deadline of the current job and run time it has left
+    pub absolute_deadline: AtomicU64,
+    pub budget: AtomicU64,
+    // Jobs finished after their deadline, and times the budget ran out
+    pub misses: AtomicU64,
+    pub overruns: AtomicU64,
+}
+
+pub struct Task {
+    id: TaskId,
+    name: String,
+    state: AtomicU8,
+    // Stack pointer saved by `switch_context` while the task isn't running
+    rsp: UnsafeCell<u64>,
+    // None for boot contexts, which keep running on the stack they came with
+    _stack: Option<Box<[u8]>>,
+    // x87, SSE and AVX registers while the task isn't running
+    fpu: UnsafeCell<FpuState>,
+    entry: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    priority: AtomicI8,
+    // Bit n set if the task may run on the processor with index n
+    affinity: AtomicU64,
+    // Scheduler bookkeeping: weighted run time in nanoseconds, the processor
+    // the task was last queued or run on and whether its context is still in
+    // use by a processor
+    pub vruntime: AtomicU64,
+    pub cpu: AtomicUsize,
+    pub on_cpu: AtomicBool,
+    pub stats: Stats,
+    pub deadline: Deadline,
+    pub user: UserState,
+}
+
+// `rsp` and `fpu` are only touched by the processor switching away from or to
+// the task, and a task is never switched to while `on_cpu` is set
+unsafe impl Sync for Task {}
+unsafe impl Send for Task {}
+
+impl Task {
+    fn new(name: String, stack: Option<Box<[u8]>>, rsp: u64, priority: i8, affinity: u64) -> Task {
+        Task {
+            id: TaskId::new(),
+            name,
+            state: AtomicU8::new(State::Ready as u8),
+            rsp: UnsafeCell::new(rsp),
+            _stack: stack,
+            fpu: UnsafeCell::new(FpuState::new()),
+            entry: Mutex::new(None),
+            priority: AtomicI8::new(priority),
+            affinity: AtomicU64::new(affinity),
+            vruntime: AtomicU64::new(0),
+            cpu: AtomicUsize::new(0),
+            on_cpu: AtomicBool::new(false),
+            stats: Stats::default(),
+            deadline: Deadline::default(),
+            user: UserState::new(),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> State {
+        match self.state.load(Ordering::Acquire) {
+            0 => State::Ready,
+            1 => State::Running,
+            2 => State::Blocked,
+            _ => State::Exited,
+        }
+    }
+
+    // Only for the scheduler, other code changes the state through it
+    pub fn set_state(&self, state: State) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    // Change the state only if it is `from`, returns whether it was
+    pub fn transition(&self, from: State, to: State) -> bool {
+        self.state
+            .compare_exchange(from as u8, to as u8, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    pub fn priority(&self) -> i8 {
+        self.priority.load(Ordering::Relaxed)
+    }
+
+    pub fn set_priority(&self, priority: i8) {
+        self.priority.store(
+            priority.clamp(PRIORITY_HIGHEST, PRIORITY_LOWEST),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn affinity(&self) -> u64 {
+        self.affinity.load(Ordering::Relaxed)
+    }
+
+    // Restrict the task to the processors in `mask`. A running task moves the
+    // next time it is switched out.
+    pub fn set_affinity(&self, mask: u64) {
+        assert!(mask != 0, "empty affinity mask");
+        self.affinity.store(mask, Ordering::Relaxed);
+    }
+
+    pub fn pin(&self, cpu_index: usize) {
+        self.set_affinity(1 << cpu_index);
+    }
+
+    pub fn is_deadline(&self) -> bool {
+        self.deadline.runtime.load(Ordering::Relaxed) != 0
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| TASKS.lock().remove(&self.id));
+    }
+}
+
+fn register(task: &Arc<Task>) {
+    interrupts::without_interrupts(|| TASKS.lock().insert(task.id, Arc::downgrade(task)));
+}
+
+// Tasks that haven't exited, ordered by ID
+pub fn all() -> Vec<Arc<Task>> {
+    let tasks: Vec<_> = interrupts::without_interrupts(|| {
+        TASKS.lock().values().filter_map(Weak::upgrade).collect()
+    });
+    // Filtered outside the lock, dropping the last reference takes it
+    tasks
+        .into_iter()
+        .filter(|task| task.state() != State::Exited)
+        .collect()
+}
+
+// Save callee-saved registers and the stack pointer of the running context in
+// `*old_rsp`, then continue the context saved at `new_rsp`. Returns when some
+// processor switches back to the old context.
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+// Continue `to` on this processor. Must be called with interrupts disabled and
+// `to` not running anywhere else. Vector registers are switched eagerly, the
+// compiler may use SSE anywhere.
+pub unsafe fn switch(from: &Task, to: &Task) {
+    (*from.fpu.get()).save();
+    switch_context(from.rsp.get(), *to.rsp.get());
+    // Switched back to `from`, possibly on another processor
+    (*from.fpu.get()).restore();
+}
+
+// Turn the calling processor's boot context into its idle task. Must run once
+// on each processor before it takes part in scheduling.
+pub fn initialize() {
+    let cpu = percpu::current();
+    let idle = Arc::new(Task::new(
+        format!("idle/{}", cpu.cpu_index),
+        None,
+        0,
+        PRIORITY_LOWEST,
+        1 << cpu.cpu_index,
+    ));
+    idle.set_state(State::Running);
+    idle.on_cpu.store(true, Ordering::Relaxed);
+    idle.cpu.store(cpu.cpu_index, Ordering::Relaxed);
+    register(&idle);
+    cpu.accounted_at.store(time::nanos(), Ordering::Relaxed);
+
+    cpu.idle_task
+        .store(Arc::into_raw(idle.clone()) as usize, Ordering::Relaxed);
+    cpu.current_task
+        .store(Arc::into_raw(idle) as usize, Ordering::Release);
+}
+
+// The task running on this processor
+pub fn current() -> Option<Arc<Task>> {
+    interrupts::without_interrupts(|| {
+        let task = percpu::current().current_task.load(Ordering::Relaxed) as *const Task;
+        if task.is_null() {
+            return None;
+        }
+        unsafe {
+            Arc::increment_strong_count(task);
+            Some(Arc::from_raw(task))
+        }
+    })
+}
+
+pub struct JoinHandle<T> {
+    task: Arc<Task>,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn task(&self) -> &Arc<Task> {
+        &self.task
+    }
+
+    // Wait for the task to exit and return the value it returned
+    pub fn join(self) -> T {
+        while self.task.state() != State::Exited {
+            yield_now();
+        }
+        self.result
+            .lock()
+            .take()
+            .expect("task exited without a result")
+    }
+}
+
+// Options for a new task, e.g.
+// `Builder::new().name("worker").priority(5).pin(1).spawn(f)`
+pub struct Builder {
+    name: Option<String>,
+    priority: i8,
+    affinity: u64,
+    deadline: Option<DeadlineParams>,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder {
+            name: None,
+            priority: PRIORITY_DEFAULT,
+            affinity: ALL_CPUS,
+            deadline: None,
+        }
+    }
+
+    pub fn name(mut self, name: &str) -> Builder {
+        self.name = Some(String::from(name));
+        self
+    }
+
+    pub fn priority(mut self, priority: i8) -> Builder {
+        self.priority = priority.clamp(PRIORITY_HIGHEST, PRIORITY_LOWEST);
+        self
+    }
+
+    pub fn affinity(mut self, mask: u64) -> Builder {
+        assert!(mask != 0, "empty affinity mask");
+        self.affinity = mask;
+        self
+    }
+
+    pub fn pin(self, cpu_index: usize) -> Builder {
+        self.affinity(1 << cpu_index)
+    }
+
+    // Put the task in the deadline class, which runs before all normal tasks.
+    // It is pinned to one of the allowed processors with enough bandwidth
+    // left.
+    pub fn deadline(mut self, params: DeadlineParams) -> Builder {
+        self.deadline = Some(params);
+        self
+    }
+
+    pub fn spawn<F, T>(self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.try_spawn(f).expect("deadline task not admitted")
+    }
+
+    // Like `spawn`, but fails instead of panicking if the deadline class
+    // can't take the task
+    pub fn try_spawn<F, T>(self, f: F) -> Result<JoinHandle<T>, AdmissionError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let result = Arc::new(Mutex::new(None));
+        let task_result = result.clone();
+        let task = self.try_start(move || {
+            let value = f();
+            *task_result.lock() = Some(value);
+        })?;
+        Ok(JoinHandle { task, result })
+    }
+
+    // Run `f` in a new task that nobody joins
+    pub fn start<F>(self, f: F) -> Arc<Task>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_start(f).expect("deadline task not admitted")
+    }
+
+    fn try_start<F>(self, f: F) -> Result<Arc<Task>, AdmissionError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let entry: Box<dyn FnOnce() + Send> = Box::new(f);
+        let stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+
+        // Initial frame popped by `switch_context`: six callee-saved registers
+        // and the return address. `task_start` is entered with the stack
+        // aligned as if it had been called.
+        let top = (stack.as_ptr() as u64 + STACK_SIZE as u64) & !0xf;
+        let rsp = top - 16 - 6 * 8;
+        unsafe {
+            let frame = rsp as *mut u64;
+            for i in 0..6 {
+                frame.add(i).write(0);
+            }
+            frame.add(6).write(task_start as *const () as u64);
+        }
+
+        let mut task = Task::new(
+            String::new(),
+            Some(stack),
+            rsp,
+            self.priority,
+            self.affinity,
+        );
+        task.name = match self.name {
+            Some(name) => name,
+            None => format!("task/{}", task.id.0),
+        };
+        *task.entry.get_mut() = Some(entry);
+        let task = Arc::new(task);
+        if let Some(params) = self.deadline {
+            scheduler::admit(&task, params)?;
+        }
+        register(&task);
+
+        scheduler::enqueue(task.clone());
+
+        Ok(task)
+    }
+}
+
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Builder::new().spawn(f)
+}
+
+// Let other ready tasks run. Returns immediately if there are none.
+pub fn yield_now() {
+    scheduler::schedule();
+}
+
+// End the calling task. Its stack is freed once another task runs.
+pub fn exit() -> ! {
+    interrupts::disable();
+    let cpu = percpu::current();
+    let task = cpu.current_task.load(Ordering::Relaxed) as *const Task;
+    assert!(!task.is_null(), "exit outside of a task");
+    assert!(
+        task as usize != cpu.idle_task.load(Ordering::Relaxed),
+        "the idle task can't exit"
+    );
+
+    unsafe { (*task).set_state(State::Exited) };
+    scheduler::schedule();
+    unreachable!("exited task was scheduled again");
+}
+
+// First code run by a new task, "returned" to from `switch_context`
+extern "C" fn task_start() -> ! {
+    if let Some(task) = current() {
+        unsafe { (*task.fpu.get()).restore() };
+    }
+    scheduler::finish_switch();
+    interrupts::enable();
+
+    let entry = current().and_then(|task| task.entry.lock().take());
+    if let Some(entry) = entry {
+        entry();
+    }
+
+    exit();
+}
+
+#[test_case]
+fn spawn_and_join() {
+    print!("spawn and join... ");
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            spawn(move || {
+                yield_now();
+                i * 2
+            })
+        })
+        .collect();
+    let sum: usize = handles.into_iter().map(|handle| handle.join()).sum();
+    assert_eq!(sum, 12);
+    println!("[ok]");
+}
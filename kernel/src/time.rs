@@ -0,0 +1,229 @@
+use crate::cpu::{self, Feature};
+use crate::interrupt::TIMER_HZ;
+use crate::{percpu, pit, println, scheduler, task};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Timer periods since boot. Counted by the bootstrap processor's timer
+// interrupts, or derived from the TSC if there is one, so that processors can
+// skip ticks while idle.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+const TICK_NS: u64 = 1_000_000_000 / TIMER_HZ as u64;
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+// Pending timers by expiry tick, the second key keeps them unique
+static TIMERS: Mutex<BTreeMap<(u64, u64), Callback>> = Mutex::new(BTreeMap::new());
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimerId(u64, u64);
+
+// Time stamp counter frequency, zero if there is no usable TSC
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+// `nanos()` at tick zero
+static TICK_BASE_NS: AtomicU64 = AtomicU64::new(0);
+
+// Measure the TSC frequency against the PIT. The TSC is assumed to run at a
+// constant rate and in step on all processors, as it does with an invariant
+// TSC.
+pub fn initialize() {
+    if !cpu::has(Feature::Tsc) {
+        println!("No TSC, time accounting has tick resolution");
+        return;
+    }
+
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    pit::wait_us(10_000);
+    let elapsed = unsafe { core::arch::x86_64::_rdtsc() } - start;
+    let khz = elapsed / 10;
+    TSC_KHZ.store(khz, Ordering::Relaxed);
+    TICK_BASE_NS.store(nanos(), Ordering::Relaxed);
+    println!(
+        "TSC: {} kHz{}",
+        khz,
+        if cpu::has(Feature::InvariantTsc) {
+            ""
+        } else {
+            " (not invariant)"
+        }
+    );
+}
+
+// Nanoseconds since an arbitrary point before boot, for measuring intervals
+// shorter than a tick
+pub fn nanos() -> u64 {
+    let khz = TSC_KHZ.load(Ordering::Relaxed);
+    if khz == 0 {
+        return ticks() * TICK_NS;
+    }
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    (tsc as u128 * 1_000_000 / khz as u128) as u64
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// Whether `nanos()` keeps counting without timer interrupts
+pub fn has_tsc() -> bool {
+    TSC_KHZ.load(Ordering::Relaxed) != 0
+}
+
+// Value of `nanos()` at which the tick count reaches `tick`
+pub fn tick_to_nanos(tick: u64) -> u64 {
+    TICK_BASE_NS.load(Ordering::Relaxed) + tick * TICK_NS
+}
+
+// Expiry tick of the earliest pending timer
+pub fn next_timer() -> Option<u64> {
+    interrupts::without_interrupts(|| TIMERS.lock().keys().next().map(|key| key.0))
+}
+
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+// Rounded up, so that waiting this many ticks takes at least `duration`
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    duration.as_nanos().div_ceil(TICK_NS as u128) as u64
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(ticks * TICK_NS)
+}
+
+// Tick at which something waiting `duration` from now is due. One tick is
+// added because the current tick is already partly over.
+pub fn deadline_after(duration: Duration) -> u64 {
+    ticks() + duration_to_ticks(duration) + 1
+}
+
+// Run `callback` from the timer interrupt once the tick count reaches
+// `deadline`. It runs with interrupts disabled and must not block.
+pub fn add_timer<F>(deadline: u64, callback: F) -> TimerId
+where
+    F: FnOnce() + Send + 'static,
+{
+    let id = TimerId(deadline, NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed));
+    let callback: Callback = Box::new(callback);
+    interrupts::without_interrupts(|| TIMERS.lock().insert((id.0, id.1), callback));
+    id
+}
+
+// Returns false if the timer already fired or is firing right now
+pub fn cancel_timer(id: TimerId) -> bool {
+    let callback = interrupts::without_interrupts(|| TIMERS.lock().remove(&(id.0, id.1)));
+    callback.is_some()
+}
+
+// Called from the timer interrupt on every processor. With a TSC any of them
+// may bring the tick count up to date and run the expired timers, since idle
+// processors stop their timer.
+pub fn tick() {
+    let now = if has_tsc() {
+        let now = (nanos() - TICK_BASE_NS.load(Ordering::Relaxed)) / TICK_NS;
+        TICKS.fetch_max(now, Ordering::Relaxed).max(now)
+    } else if percpu::current().cpu_index == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed) + 1
+    } else {
+        return;
+    };
+
+    // The lock is dropped before each callback so that it can add timers
+    loop {
+        let callback = {
+            let mut timers = TIMERS.lock();
+            match timers.keys().next() {
+                Some(&key) if key.0 <= now => timers.remove(&key),
+                _ => None,
+            }
+        };
+        match callback {
+            Some(callback) => callback(),
+            None => break,
+        }
+    }
+}
+
+// Block the calling task for at least `duration`
+pub fn sleep(duration: Duration) {
+    sleep_until(deadline_after(duration));
+}
+
+pub fn sleep_until(deadline: u64) {
+    let current = match task::current() {
+        Some(current) if scheduler::can_block() => current,
+        // Boot and idle contexts can't sleep, they just wait for the time
+        _ => {
+            while ticks() < deadline {
+                task::yield_now();
+                core::hint::spin_loop();
+            }
+            return;
+        }
+    };
+
+    while ticks() < deadline {
+        let task = current.clone();
+        interrupts::without_interrupts(|| {
+            current.set_state(task::State::Blocked);
+            let id = add_timer(deadline, move || scheduler::wake(&task));
+            scheduler::schedule();
+            cancel_timer(id);
+        });
+    }
+}
+
+// Future completing once the tick count reaches a deadline, e.g.
+// `Timer::after(Duration::from_millis(10)).await`
+pub struct Timer {
+    deadline: u64,
+    timer: Option<TimerId>,
+}
+
+impl Timer {
+    pub fn after(duration: Duration) -> Timer {
+        Timer::at(deadline_after(duration))
+    }
+
+    pub fn at(deadline: u64) -> Timer {
+        Timer {
+            deadline,
+            timer: None,
+        }
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if let Some(id) = self.timer.take() {
+            cancel_timer(id);
+        }
+        if ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        // Registered again on every poll since the waker may have changed
+        let waker = cx.waker().clone();
+        self.timer = Some(add_timer(self.deadline, move || waker.wake()));
+        Poll::Pending
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some(id) = self.timer.take() {
+            cancel_timer(id);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use crate::ipi;
+use x86_64::instructions::tlb;
+use x86_64::VirtAddr;
+
+// Past this many pages a full flush is cheaper than single invalidations
+const MAX_BATCH_PAGES: usize = 32;
+
+// Pages whose mappings changed and have to be invalidated on every processor.
+// Collect all changes of one operation, then `flush` once so that remote
+// processors are interrupted a single time.
+pub struct TlbBatch {
+    pages: [VirtAddr; MAX_BATCH_PAGES],
+    len: usize,
+    full_flush: bool,
+}
+
+impl TlbBatch {
+    pub const fn new() -> TlbBatch {
+        TlbBatch {
+            pages: [VirtAddr::zero(); MAX_BATCH_PAGES],
+            len: 0,
+            full_flush: false,
+        }
+    }
+
+    pub fn add(&mut self, page: VirtAddr) {
+        if self.full_flush {
+            return;
+        }
+        if self.len == MAX_BATCH_PAGES {
+            self.full_flush = true;
+            return;
+        }
+        self.pages[self.len] = page;
+        self.len += 1;
+    }
+
+    // Invalidate everything instead of individual pages, e.g. after changing
+    // the mapping of a large range
+    pub fn add_all(&mut self) {
+        self.full_flush = true;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0 && !self.full_flush
+    }
+
+    // Invalidate the collected pages here and on all other online processors,
+    // returning once every processor has done so
+    pub fn flush(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+
+        ipi::call_on_all(invalidate, self as *const TlbBatch as usize);
+
+        self.len = 0;
+        self.full_flush = false;
+    }
+
+    fn invalidate_local(&self) {
+        if self.full_flush {
+            tlb::flush_all();
+        } else {
+            for page in &self.pages[..self.len] {
+                tlb::flush(*page);
+            }
+        }
+    }
+}
+
+fn invalidate(batch: usize) {
+    // Safety: `flush` waits for all processors before the batch goes away
+    let batch = unsafe { &*(batch as *const TlbBatch) };
+    batch.invalidate_local();
+}
+
+// Invalidate a single page on every processor
+pub fn shootdown(page: VirtAddr) {
+    let mut batch = TlbBatch::new();
+    batch.add(page);
+    batch.flush();
+}
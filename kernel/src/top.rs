@@ -0,0 +1,168 @@
+use crate::idle::{IdleState, IDLE_STATES};
+use crate::smp::MAX_CPUS;
+use crate::task::{self, State, TaskId};
+use crate::time::{self, Timer};
+use crate::{allocator, graphics, paging, percpu, print};
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+// Processor usage per task and per processor since the previous sample, like
+// Unix `top`
+pub struct Top {
+    sampled_at: u64,
+    runtimes: BTreeMap<TaskId, u64>,
+    idle: [u64; MAX_CPUS],
+    residency: [[u64; IDLE_STATES]; MAX_CPUS],
+}
+
+impl Top {
+    pub fn new() -> Top {
+        let mut top = Top {
+            sampled_at: 0,
+            runtimes: BTreeMap::new(),
+            idle: [0; MAX_CPUS],
+            residency: [[0; IDLE_STATES]; MAX_CPUS],
+        };
+        top.render();
+        top
+    }
+
+    // Format the table for the time since the last call
+    pub fn render(&mut self) -> String {
+        let now = time::nanos();
+        let interval = now.saturating_sub(self.sampled_at).max(1);
+        self.sampled_at = now;
+
+        let mut out = String::new();
+        let uptime = time::uptime();
+        let tasks = task::all();
+        let _ = writeln!(
+            out,
+            "uptime {}.{:02}s, {} tasks",
+            uptime.as_secs(),
+            uptime.subsec_millis() / 10,
+            tasks.len()
+        );
+        let (heap_used, heap_size) = allocator::usage();
+        let (frames_used, frames) = paging::frame_usage();
+        let _ = writeln!(
+            out,
+            "heap {} of {} KiB, {} of {} frames",
+            heap_used / 1024,
+            heap_size / 1024,
+            frames_used,
+            frames
+        );
+
+        for cpu in percpu::online() {
+            let idle = cpu.idle_ns.load(Ordering::Relaxed);
+            let delta = idle.saturating_sub(self.idle[cpu.cpu_index]);
+            self.idle[cpu.cpu_index] = idle;
+            let _ = write!(
+                out,
+                "cpu{}: {:>5} idle, {} switches,",
+                cpu.cpu_index,
+                percent(delta, interval),
+                cpu.context_switches.load(Ordering::Relaxed)
+            );
+
+            // Share of the interval spent in each idle state
+            for state in IdleState::ALL {
+                let residency = cpu.idle_residency_ns[state as usize].load(Ordering::Relaxed);
+                let previous = &mut self.residency[cpu.cpu_index][state as usize];
+                let delta = residency.saturating_sub(*previous);
+                *previous = residency;
+                let _ = write!(out, " {} {}", state.name(), percent(delta, interval));
+            }
+            let _ = writeln!(out);
+        }
+
+        let _ = writeln!(
+            out,
+            "\n{:>3} {:<12} {:<7} {:>3} {:>3} {:>5} {:>8} {:>6} {:>6} {:>7} {:>7}",
+            "ID",
+            "NAME",
+            "STATE",
+            "CPU",
+            "PRI",
+            "%CPU",
+            "TIME",
+            "VOL",
+            "INVOL",
+            "AVG-LAT",
+            "MAX-LAT"
+        );
+
+        let mut runtimes = BTreeMap::new();
+        for task in tasks {
+            let stats = &task.stats;
+            let runtime = stats.runtime_ns.load(Ordering::Relaxed);
+            let delta = runtime.saturating_sub(*self.runtimes.get(&task.id()).unwrap_or(&0));
+            runtimes.insert(task.id(), runtime);
+
+            let wakeups = stats.wakeups.load(Ordering::Relaxed);
+            let average_latency = stats.wakeup_latency_ns.load(Ordering::Relaxed) / wakeups.max(1);
+            let state = match task.state() {
+                State::Ready => "ready",
+                State::Running => "running",
+                State::Blocked => "blocked",
+                State::Exited => "exited",
+            };
+            // Deadline tasks don't have a priority, they all run first
+            let priority = if task.is_deadline() {
+                String::from("dl")
+            } else {
+                format!("{}", task.priority())
+            };
+            let mut name = String::from(task.name());
+            name.truncate(12);
+
+            let _ = writeln!(
+                out,
+                "{:>3} {:<12} {:<7} {:>3} {:>3} {:>5} {:>7}s {:>6} {:>6} {:>7} {:>7}",
+                task.id().as_u64(),
+                name,
+                state,
+                task.cpu.load(Ordering::Relaxed),
+                priority,
+                percent(delta, interval),
+                seconds(runtime),
+                stats.voluntary_switches.load(Ordering::Relaxed),
+                stats.involuntary_switches.load(Ordering::Relaxed),
+                micros(average_latency),
+                micros(stats.max_wakeup_latency_ns.load(Ordering::Relaxed))
+            );
+        }
+        self.runtimes = runtimes;
+
+        out
+    }
+}
+
+fn percent(part: u64, whole: u64) -> String {
+    let permille = (part as u128 * 1000 / whole as u128).min(1000);
+    format!("{}.{}%", permille / 10, permille % 10)
+}
+
+fn seconds(ns: u64) -> String {
+    format!("{}.{:02}", ns / 1_000_000_000, ns / 10_000_000 % 100)
+}
+
+fn micros(ns: u64) -> String {
+    format!("{}us", ns / 1000)
+}
+
+// Redraw the table on the console every `interval`
+pub async fn refresh(interval: Duration) {
+    let mut top = Top::new();
+    loop {
+        Timer::after(interval).await;
+        let table = top.render();
+        graphics::clear();
+        print!("{}", table);
+    }
+}
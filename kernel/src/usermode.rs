@@ -0,0 +1,848 @@
+use crate::fpu::FpuState;
+use crate::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::interrupt::TrapFrame;
+use crate::paging;
+use crate::paging::{USER_END, USER_START};
+use crate::percpu;
+use crate::process::Process;
+use crate::signal::{self, SIGBUS, SIGFPE, SIGILL, SIGSEGV, SIGTRAP};
+use crate::syscall::Errno;
+use crate::task::{self, Task};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::FsBase;
+use x86_64::structures::idt::{InterruptStackFrame, InterruptStackFrameValue};
+use x86_64::VirtAddr;
+
+#[cfg(test)]
+use crate::paging::AddressSpace;
+#[cfg(test)]
+use crate::process;
+#[cfg(test)]
+use crate::syscall::SYS_EXIT;
+#[cfg(test)]
+use crate::{print, println};
+#[cfg(test)]
+use alloc::vec;
+#[cfg(test)]
+use core::convert::TryFrom;
+#[cfg(test)]
+use x86_64::structures::paging::PageTableFlags;
+
+// RFLAGS of a new user context: only IF and the always set bit 1
+pub const USER_RFLAGS: u64 = 0x202;
+
+// RFLAGS bits user code may change: CF, PF, AF, ZF, SF, TF, DF, OF and AC
+pub const USER_RFLAGS_MASK: u64 = 0x40dd5;
+
+// Exceptions a user context can raise, see Intel SDM Vol. 3A 6.15 Exception
+// and Interrupt Reference
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FaultKind {
+    DivideError,
+    Breakpoint,
+    Overflow,
+    BoundRangeExceeded,
+    InvalidOpcode,
+    DeviceNotAvailable,
+    SegmentNotPresent,
+    StackSegmentFault,
+    GeneralProtection,
+    PageFault,
+    FloatingPoint,
+    AlignmentCheck,
+    SimdFloatingPoint,
+}
+
+impl FaultKind {
+    // The signal Linux sends for the exception
+    pub fn signal(&self) -> u8 {
+        match self {
+            FaultKind::DivideError | FaultKind::FloatingPoint | FaultKind::SimdFloatingPoint => {
+                SIGFPE
+            }
+            FaultKind::Breakpoint => SIGTRAP,
+            FaultKind::InvalidOpcode => SIGILL,
+            FaultKind::AlignmentCheck => SIGBUS,
+            _ => SIGSEGV,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    // User instruction that faulted
+    pub rip: u64,
+    pub error_code: u64,
+    // Accessed address of a page fault
+    pub address: Option<u64>,
+}
+
+// Why a user context returned to the kernel
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UserExit {
+    Fault(Fault),
+    // The exit system call with its status
+    Exit(i64),
+    // Terminated by a signal, other than one raised by a fault
+    Signal(u8),
+}
+
+// Per task state for running in user mode
+pub struct UserState {
+    // Kernel stack pointer saved by `enter_user`, zero while the task isn't
+    // in user mode. Loaded from the TSS on every entry from user mode, so the
+    // scheduler switches it along with the task.
+    kernel_rsp: AtomicU64,
+    exit: Mutex<Option<UserExit>>,
+    // Physical address of the PML4 the scheduler loads for the task, zero
+    // for the kernel's
+    page_table: AtomicU64,
+    // None for tasks that aren't running a user program
+    pub process: Mutex<Option<Arc<Process>>>,
+    // x87, SSE and AVX registers of user mode while in a system call, which
+    // preserves them. None until the first one.
+    fpu: Mutex<Option<FpuState>>,
+    // FS base of user mode, where C libraries keep thread-local storage. The
+    // scheduler loads it along with the page table, the kernel doesn't use
+    // FS.
+    fs_base: AtomicU64,
+}
+
+impl UserState {
+    pub const fn new() -> UserState {
+        UserState {
+            kernel_rsp: AtomicU64::new(0),
+            exit: Mutex::new(None),
+            page_table: AtomicU64::new(0),
+            process: Mutex::new(None),
+            fpu: Mutex::new(None),
+            fs_base: AtomicU64::new(0),
+        }
+    }
+
+    pub fn page_table(&self) -> u64 {
+        self.page_table.load(Ordering::Relaxed)
+    }
+
+    pub fn fs_base(&self) -> u64 {
+        self.fs_base.load(Ordering::Relaxed)
+    }
+
+    pub fn kernel_stack(&self) -> Option<u64> {
+        match self.kernel_rsp.load(Ordering::Relaxed) {
+            0 => None,
+            rsp => Some(rsp),
+        }
+    }
+}
+
+extern "C" {
+    fn enter_user(frame: *const TrapFrame, kernel_rsp: *mut u64, tss_rsp0: *mut u64);
+    fn exit_user(kernel_rsp: u64) -> !;
+    fn return_to_user();
+    fn return_to_user_error();
+    fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn copy_user_movs();
+    fn copy_user_fault();
+}
+
+// `enter_user` saves the callee-saved registers like `switch_context`, and
+// the resulting stack pointer for `exit_user`, as RSP0, below which
+// interrupts from user mode push their frames, and for system calls. Then it
+// loads the registers from `frame`, which lies above, and `iretq`s to ring 3
+// with the rest of it (Vol. 3A 6.14.1 64-Bit Mode Stack Frame).
+//
+// `exit_user` returns from `enter_user` on the kernel stack, discarding
+// everything below it.
+global_asm!(
+    ".global enter_user",
+    "enter_user:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rsi], rsp",
+    "mov [rdx], rsp",
+    "mov gs:[{kernel_stack}], rsp",
+    "mov rsp, rdi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "swapgs",
+    "iretq",
+    "",
+    ".global exit_user",
+    "exit_user:",
+    "mov rsp, rdi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    kernel_stack = const percpu::KERNEL_STACK_OFFSET,
+);
+
+// Interrupt handlers are `extern "x86-interrupt"` functions, which can't swap
+// GS around their body. For a vector that may arrive in user mode, the IDT
+// points at a stub made with this macro instead. Coming from kernel mode the
+// stub jumps straight to the handler. Coming from user mode it swaps GS and
+// has the handler return to `return_to_user` in kernel mode through a copy of
+// the frame, below the one the CPU pushed. The copy is aligned like the
+// original, and the error code is repeated below it if there is one.
+#[macro_export]
+macro_rules! user_entry {
+    ($entry:ident, $handler:path) => {
+        extern "C" {
+            fn $entry();
+        }
+        core::arch::global_asm!(
+            concat!(".global ", stringify!($entry)),
+            concat!(stringify!($entry), ":"),
+            "test byte ptr [rsp + 8], 3",
+            "jz {handler}",
+            "swapgs",
+            "push rax",
+            "lea rax, [rsp + 8]",
+            "push {kernel_ss}",
+            "push rax",
+            "pushfq",
+            "push {kernel_cs}",
+            "lea rax, [rip + return_to_user]",
+            "push rax",
+            "mov rax, [rsp + 40]",
+            "jmp {handler}",
+            handler = sym $handler,
+            kernel_ss = const $crate::gdt::KERNEL_DATA_SELECTOR.0,
+            kernel_cs = const $crate::gdt::KERNEL_CODE_SELECTOR.0,
+        );
+    };
+    ($entry:ident, $handler:path, error_code) => {
+        extern "C" {
+            fn $entry();
+        }
+        core::arch::global_asm!(
+            concat!(".global ", stringify!($entry)),
+            concat!(stringify!($entry), ":"),
+            "test byte ptr [rsp + 16], 3",
+            "jz {handler}",
+            "swapgs",
+            "push rax",
+            "sub rsp, 8",
+            "lea rax, [rsp + 16]",
+            "push {kernel_ss}",
+            "push rax",
+            "pushfq",
+            "push {kernel_cs}",
+            "lea rax, [rip + return_to_user_error]",
+            "push rax",
+            "push qword ptr [rsp + 56]",
+            "mov rax, [rsp + 56]",
+            "jmp {handler}",
+            handler = sym $handler,
+            kernel_ss = const $crate::gdt::KERNEL_DATA_SELECTOR.0,
+            kernel_cs = const $crate::gdt::KERNEL_CODE_SELECTOR.0,
+        );
+    };
+}
+
+// Reached by the handler's `iretq` with the stack pointer at the frame the CPU
+// pushed on entry from user mode and all registers holding user values. Saves
+// them for `user_return`, which may leave user mode for good, then swaps GS
+// back and returns to user mode. Interrupts stay disabled throughout.
+// System calls whose frame changed in a way SYSRET can't restore return
+// through `restore_user_frame` with the stack pointer at their `TrapFrame`.
+global_asm!(
+    ".global return_to_user_error",
+    "return_to_user_error:",
+    "add rsp, 8",
+    ".global return_to_user",
+    "return_to_user:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call {hook}",
+    ".global restore_user_frame",
+    "restore_user_frame:",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "swapgs",
+    "iretq",
+    hook = sym user_return,
+);
+
+// Nothing here saves the vector registers of user mode, so they are saved
+// around anything more than checking whether there is work to do
+extern "C" fn user_return(frame: &mut TrapFrame) {
+    if has_work() {
+        save_fpu();
+        prepare_return(frame);
+        restore_fpu();
+    }
+}
+
+fn has_work() -> bool {
+    let task = current_task();
+    task.user.exit.lock().is_some()
+        || task
+            .user
+            .process
+            .lock()
+            .as_ref()
+            .is_some_and(|process| process.signal_ready())
+}
+
+// Last work before returning to user mode, with interrupts disabled and the
+// vector registers of user mode saved. Delivers pending signals, and leaves
+// user mode for good instead if the task is to stop running in it.
+pub fn prepare_return(frame: &mut TrapFrame) {
+    signal::deliver(frame);
+    let task = current_task();
+    if task.user.exit.lock().is_some() {
+        let kernel_rsp = task.user.kernel_rsp.load(Ordering::Relaxed);
+        unsafe { exit_user(kernel_rsp) };
+    }
+}
+
+// Without taking a reference, which `exit_user` would leak
+fn current_task() -> &'static Task {
+    let task = percpu::current().current_task.load(Ordering::Relaxed) as *const Task;
+    unsafe { &*task }
+}
+
+// Save the vector registers of user mode on entry to a system call, before
+// kernel code can change them
+pub fn save_fpu() {
+    let mut fpu = current_task().user.fpu.lock();
+    fpu.get_or_insert_with(FpuState::new).save();
+}
+
+// Load them again before returning to user mode
+pub fn restore_fpu() {
+    if let Some(fpu) = current_task().user.fpu.lock().as_ref() {
+        fpu.restore();
+    }
+}
+
+// The vector registers user mode had when it made the current system call
+pub fn saved_fpu() -> Option<FpuState> {
+    current_task().user.fpu.lock().clone()
+}
+
+// Reset them to their initial state, once the system call returns
+pub fn reset_fpu() {
+    load_fpu(FpuState::new());
+}
+
+// Make them `fpu` once the system call returns
+pub fn load_fpu(fpu: FpuState) {
+    *current_task().user.fpu.lock() = Some(fpu);
+}
+
+// Leave user mode with `exit` the next time the current task would return to
+// it. The first reason given wins.
+pub fn request_exit(exit: UserExit) {
+    current_task().user.exit.lock().get_or_insert(exit);
+}
+
+// Make the calling task use the page tables with the PML4 at `pml4` from now
+// on, the kernel's alone if zero
+pub fn switch_page_table(pml4: u64) {
+    interrupts::without_interrupts(|| {
+        current_task()
+            .user
+            .page_table
+            .store(pml4, Ordering::Relaxed);
+        paging::activate(pml4);
+    });
+}
+
+// The FS base of user mode of the calling task
+pub fn fs_base() -> u64 {
+    current_task().user.fs_base()
+}
+
+// Change it, which takes effect right away. `base` must be canonical.
+pub fn set_fs_base(base: u64) {
+    interrupts::without_interrupts(|| {
+        current_task().user.fs_base.store(base, Ordering::Relaxed);
+        FsBase::write(VirtAddr::new(base));
+    });
+}
+
+// Registers of a new user context starting at `entry` with stack pointer
+// `stack`, all others zero
+pub fn initial_frame(entry: VirtAddr, stack: VirtAddr) -> TrapFrame {
+    TrapFrame {
+        r15: 0,
+        r14: 0,
+        r13: 0,
+        r12: 0,
+        r11: 0,
+        r10: 0,
+        r9: 0,
+        r8: 0,
+        rbp: 0,
+        rdi: 0,
+        rsi: 0,
+        rdx: 0,
+        rcx: 0,
+        rbx: 0,
+        rax: 0,
+        stack_frame: InterruptStackFrameValue {
+            instruction_pointer: entry,
+            code_segment: USER_CODE_SELECTOR.0 as u64,
+            cpu_flags: USER_RFLAGS,
+            stack_pointer: stack,
+            stack_segment: USER_DATA_SELECTOR.0 as u64,
+        },
+    }
+}
+
+// Run the calling task in ring 3 with the registers in `frame`, and the
+// vector registers in `fpu` if given, until it exits or faults. Segments and
+// privileged flags are always those of user mode, and user code and stack
+// must be mapped with `PageTableFlags::USER_ACCESSIBLE`.
+pub fn run_frame(mut frame: TrapFrame, fpu: Option<FpuState>) -> UserExit {
+    let task = task::current().expect("user mode outside of a task");
+    let state = &task.user;
+    *state.exit.lock() = None;
+
+    let stack_frame = &mut frame.stack_frame;
+    stack_frame.code_segment = USER_CODE_SELECTOR.0 as u64;
+    stack_frame.stack_segment = USER_DATA_SELECTOR.0 as u64;
+    stack_frame.cpu_flags = stack_frame.cpu_flags & USER_RFLAGS_MASK | USER_RFLAGS;
+
+    // Interrupts are enabled again by `iretq`, and are disabled when
+    // `exit_user` comes back here
+    interrupts::disable();
+    if let Some(fpu) = &fpu {
+        fpu.restore();
+    }
+    unsafe {
+        enter_user(
+            &frame,
+            state.kernel_rsp.as_ptr(),
+            percpu::kernel_stack_slot(),
+        );
+    }
+    state.kernel_rsp.store(0, Ordering::Relaxed);
+    interrupts::enable();
+
+    let exit = state.exit.lock().take();
+    exit.expect("left user mode without a reason")
+}
+
+// The frame the CPU pushed on entry from user mode, if the handler that got
+// `stack_frame` was entered through a `user_entry!` stub from user mode
+pub fn user_frame(stack_frame: &InterruptStackFrame) -> Option<InterruptStackFrameValue> {
+    let rip = stack_frame.instruction_pointer.as_u64();
+    let frame = if rip == return_to_user as *const () as u64 {
+        stack_frame.stack_pointer
+    } else if rip == return_to_user_error as *const () as u64 {
+        // Skip the error code
+        stack_frame.stack_pointer + 8u64
+    } else {
+        return None;
+    };
+    let frame = unsafe { frame.as_ptr::<InterruptStackFrameValue>().read() };
+    (frame.code_segment & 3 == 3).then_some(frame)
+}
+
+// Called by exception handlers. If the exception came from user mode, raise
+// its signal in the current process, or make a task without one leave user
+// mode with the fault once the handler returns, and return true. Otherwise it
+// is a kernel bug and the handler should panic.
+pub fn handle_fault(
+    stack_frame: &InterruptStackFrame,
+    kind: FaultKind,
+    error_code: u64,
+    address: Option<u64>,
+) -> bool {
+    let frame = match user_frame(stack_frame) {
+        Some(frame) => frame,
+        None => return false,
+    };
+    let fault = Fault {
+        kind,
+        rip: frame.instruction_pointer.as_u64(),
+        error_code,
+        address,
+    };
+    match current_task().user.process.lock().as_ref() {
+        Some(process) => process.with_signals(|signals| signals.send_fault(fault)),
+        None => request_exit(UserExit::Fault(fault)),
+    }
+    true
+}
+
+// Copies between kernel and user memory. A fault at `copy_user_movs` resumes
+// at `copy_user_fault` (see `fixup_fault`), so the count of bytes left is
+// returned either way.
+global_asm!(
+    ".global copy_user",
+    ".global copy_user_movs",
+    ".global copy_user_fault",
+    "copy_user:",
+    "mov rcx, rdx",
+    "copy_user_movs:",
+    "rep movsb",
+    "copy_user_fault:",
+    "mov rax, rcx",
+    "ret",
+);
+
+// Called by the page fault handler for faults in kernel mode. A fault while
+// copying user memory makes the copy return early, so that bad pointers
+// passed by user code end in an error instead of a kernel panic.
+pub fn fixup_fault(stack_frame: &mut InterruptStackFrame) -> bool {
+    if stack_frame.instruction_pointer.as_u64() != copy_user_movs as *const () as u64 {
+        return false;
+    }
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(copy_user_fault as *const () as u64)
+        });
+    }
+    true
+}
+
+// Whether `len` bytes at `address` are all within user space
+pub fn is_user_range(address: u64, len: usize) -> bool {
+    address >= USER_START
+        && address
+            .checked_add(len as u64)
+            .is_some_and(|end| end <= USER_END)
+}
+
+// Fill `dst` from user memory at `src`
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), Errno> {
+    if !is_user_range(src, dst.len()) {
+        return Err(Errno::EFAULT);
+    }
+    match unsafe { copy_user(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+// Copy `src` to user memory at `dst`
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), Errno> {
+    if !is_user_range(dst, src.len()) {
+        return Err(Errno::EFAULT);
+    }
+    match unsafe { copy_user(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+// A NUL terminated string of at most `max` bytes from user memory at `src`,
+// without the NUL. Fails with `too_long` if it is longer.
+pub fn copy_string_from_user(mut src: u64, max: usize, too_long: Errno) -> Result<Vec<u8>, Errno> {
+    let mut string = Vec::new();
+    let mut chunk = [0u8; 64];
+    loop {
+        // The string may end right before an unmapped page
+        let n = chunk.len().min(4096 - (src % 4096) as usize);
+        copy_from_user(&mut chunk[..n], src)?;
+        let end = chunk[..n].iter().position(|&byte| byte == 0);
+        string.extend_from_slice(&chunk[..end.unwrap_or(n)]);
+        if string.len() > max {
+            return Err(too_long);
+        }
+        if end.is_some() {
+            return Ok(string);
+        }
+        src += n as u64;
+    }
+}
+
+// Top of the stack page of `test_space`
+#[cfg(test)]
+pub const TEST_STACK: u64 = USER_START + 0x11000;
+
+// An address space with `code` at the start of user space and a stack page
+// after it
+#[cfg(test)]
+pub fn test_space(code: &[u8]) -> AddressSpace {
+    let space = AddressSpace::new().expect("address space");
+    space
+        .map(USER_START, code.len() as u64, PageTableFlags::WRITABLE)
+        .expect("map user code");
+    space
+        .map(TEST_STACK - 4096, 4096, PageTableFlags::WRITABLE)
+        .expect("map user stack");
+    space.copy_to(USER_START, code).expect("copy user code");
+    space
+}
+
+// Run `code` in a new process until it exits
+#[cfg(test)]
+pub fn run_code(code: &[u8]) -> UserExit {
+    process::spawn(
+        "user-test",
+        test_space(code),
+        VirtAddr::new(USER_START),
+        VirtAddr::new(TEST_STACK),
+    )
+    .wait_exit()
+}
+
+// General purpose registers by their number in instruction encodings, the
+// ones tests use
+#[cfg(test)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsp = 4,
+    Rsi = 6,
+    Rdi = 7,
+    R10 = 10,
+    R12 = 12,
+    R13 = 13,
+}
+
+// Machine code for test programs, one instruction per call, e.g.
+// `Asm::new().mov(Reg::Rdi, 3).syscall(SYS_EXIT).code()`. Instructions
+// without a method go in as bytes with `raw`.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Asm(Vec<u8>);
+
+#[cfg(test)]
+impl Asm {
+    pub fn new() -> Asm {
+        Asm(Vec::new())
+    }
+
+    pub fn raw(mut self, bytes: &[u8]) -> Asm {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    // Pad with zeros up to `offset` from the start, e.g. to put data at an
+    // address the code refers to
+    pub fn at(mut self, offset: usize) -> Asm {
+        assert!(self.0.len() <= offset, "code runs past {:#x}", offset);
+        self.0.resize(offset, 0);
+        self
+    }
+
+    // mov r32, imm32, which clears the upper half
+    pub fn mov(self, reg: Reg, value: u32) -> Asm {
+        let reg = reg as u8;
+        self.rex(false, 0, reg)
+            .raw(&[0xb8 + (reg & 7)])
+            .raw(&value.to_le_bytes())
+    }
+
+    // mov r64, imm64
+    pub fn mov64(self, reg: Reg, value: u64) -> Asm {
+        let reg = reg as u8;
+        self.rex(true, 0, reg)
+            .raw(&[0xb8 + (reg & 7)])
+            .raw(&value.to_le_bytes())
+    }
+
+    // mov dst, src
+    pub fn mov_reg(self, dst: Reg, src: Reg) -> Asm {
+        let (dst, src) = (dst as u8, src as u8);
+        self.rex(true, src, dst)
+            .raw(&[0x89, 0xc0 | (src & 7) << 3 | (dst & 7)])
+    }
+
+    // mov dst, [base + offset]
+    pub fn load(self, dst: Reg, base: Reg, offset: i32) -> Asm {
+        self.memory(0x8b, dst, base, offset)
+    }
+
+    // mov [base + offset], src
+    pub fn store(self, base: Reg, offset: i32, src: Reg) -> Asm {
+        self.memory(0x89, src, base, offset)
+    }
+
+    // lea dst, [base + offset]
+    pub fn lea(self, dst: Reg, base: Reg, offset: i32) -> Asm {
+        self.memory(0x8d, dst, base, offset)
+    }
+
+    // lea dst, [rip + ...] for the address of `target` bytes from the start
+    pub fn lea_rip(self, dst: Reg, target: usize) -> Asm {
+        let dst = dst as u8;
+        let end = self.0.len() as i64 + 7;
+        self.rex(true, dst, 0)
+            .raw(&[0x8d, (dst & 7) << 3 | 0b101])
+            .raw(&((target as i64 - end) as i32).to_le_bytes())
+    }
+
+    // jnz to `target` bytes from the start, which must be close
+    pub fn jnz(self, target: usize) -> Asm {
+        let end = self.0.len() as i64 + 2;
+        let offset = i8::try_from(target as i64 - end).expect("jump too far");
+        self.raw(&[0x75, offset as u8])
+    }
+
+    // mov eax, number; syscall
+    pub fn syscall(self, number: u64) -> Asm {
+        self.mov(Reg::Rax, number as u32).raw(&[0x0f, 0x05])
+    }
+
+    // Exit with the value in `reg` as the status
+    pub fn exit_with(self, reg: Reg) -> Asm {
+        self.mov_reg(Reg::Rdi, reg).syscall(SYS_EXIT)
+    }
+
+    pub fn code(self) -> Vec<u8> {
+        self.0
+    }
+
+    // REX prefix for 64-bit operands or registers above rdi, extending the
+    // ModRM reg field with `reg` and the base with `base`
+    fn rex(self, wide: bool, reg: u8, base: u8) -> Asm {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | base >> 3;
+        match rex {
+            0x40 => self,
+            rex => self.raw(&[rex]),
+        }
+    }
+
+    // A 64-bit instruction `opcode` with `reg` and the memory operand
+    // [base + offset]
+    fn memory(self, opcode: u8, reg: Reg, base: Reg, offset: i32) -> Asm {
+        let (reg, base) = (reg as u8, base as u8);
+        // rbp and r13 have no form without displacement
+        let (mode, displacement) = match i8::try_from(offset) {
+            Ok(0) if base & 7 != 5 => (0b00, Vec::new()),
+            Ok(offset) => (0b01, vec![offset as u8]),
+            Err(_) => (0b10, offset.to_le_bytes().to_vec()),
+        };
+        let mut code = self
+            .rex(true, reg, base)
+            .raw(&[opcode, mode << 6 | (reg & 7) << 3 | (base & 7)]);
+        // rsp and r12 as the base need a SIB byte
+        if base & 7 == 4 {
+            code = code.raw(&[0x24]);
+        }
+        code.raw(&displacement)
+    }
+}
+
+// Faults in user mode end up with the task that caused them
+#[test_case]
+fn user_faults() {
+    print!("user mode faults... ");
+    let (frames, _) = paging::frame_usage();
+
+    // ud2
+    let exit = run_code(&[0x0f, 0x0b]);
+    assert!(matches!(
+        exit,
+        UserExit::Fault(Fault {
+            kind: FaultKind::InvalidOpcode,
+            rip: USER_START,
+            ..
+        })
+    ));
+
+    // mov rax, [0x100000]: the kernel image isn't user accessible
+    let exit = run_code(&[0x48, 0x8b, 0x04, 0x25, 0x00, 0x00, 0x10, 0x00]);
+    let fault = match exit {
+        UserExit::Fault(fault) => fault,
+        exit => panic!("unexpected {:?}", exit),
+    };
+    assert_eq!(fault.kind, FaultKind::PageFault);
+    assert_eq!(fault.address, Some(0x100000));
+    // Protection violation in user mode
+    assert_eq!(fault.error_code & 0b101, 0b101);
+
+    // cli is privileged
+    let exit = run_code(&[0xfa]);
+    assert!(matches!(
+        exit,
+        UserExit::Fault(Fault {
+            kind: FaultKind::GeneralProtection,
+            ..
+        })
+    ));
+
+    // Everything is freed with the process
+    assert_eq!(paging::frame_usage().0, frames);
+    println!("[ok]");
+}
+
+// Interrupts taken in user mode return there with the registers intact
+#[test_case]
+fn user_interrupted() {
+    print!("user mode interrupted... ");
+    let unmapped = USER_START + 0x20000;
+    let code = Asm::new()
+        .mov64(Reg::Rbx, unmapped)
+        .mov(Reg::Rcx, 0x1000000)
+        .raw(&[0xe2, 0xfe]) // loop $
+        .load(Reg::Rax, Reg::Rbx, 0)
+        .code();
+    let exit = run_code(&code);
+    assert_eq!(
+        exit,
+        UserExit::Fault(Fault {
+            kind: FaultKind::PageFault,
+            rip: USER_START + 17,
+            // Not present, read, user mode
+            error_code: 0b100,
+            address: Some(unmapped),
+        })
+    );
+    println!("[ok]");
+}
@@ -0,0 +1,755 @@
+// The WASI preview 1 host functions for WebAssembly modules run by wasm.rs,
+// as a process without user memory. Modules use the file descriptors of the
+// process, so they share the console and pipes with native programs, and get
+// the root directory opened as their one preopened directory. Only what a
+// C library's stdio and a few file operations need is there, other WASI
+// functions fail with NOSYS.
+use crate::file::{self, FileKind, SeekFrom, O_RDONLY, O_RDWR, O_WRONLY};
+use crate::process::{self, Personality, Process};
+use crate::syscall::{self, Errno, IO_CHUNK};
+use crate::usermode::{self, UserExit};
+use crate::wasm::{Error, FuncType, Host, Instance, Module, Trap, ValType};
+use crate::{elf, signal, task, time};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+#[cfg(test)]
+use crate::signal::SigInfo;
+#[cfg(test)]
+use crate::wasm::TestModule;
+#[cfg(test)]
+use crate::{print, println};
+
+const MODULE: &str = "wasi_snapshot_preview1";
+
+// Environment variable limiting the instructions a module may run, e.g.
+// WASM_FUEL=1000000. Running out ends it like SIGKILL.
+const FUEL_VARIABLE: &[u8] = b"WASM_FUEL=";
+
+// WASI error numbers
+const ERRNO_2BIG: u64 = 1;
+const ERRNO_ACCES: u64 = 2;
+const ERRNO_BADF: u64 = 8;
+const ERRNO_CHILD: u64 = 10;
+const ERRNO_EXIST: u64 = 20;
+const ERRNO_FAULT: u64 = 21;
+const ERRNO_INTR: u64 = 27;
+const ERRNO_INVAL: u64 = 28;
+const ERRNO_ISDIR: u64 = 31;
+const ERRNO_MFILE: u64 = 33;
+const ERRNO_NAMETOOLONG: u64 = 37;
+const ERRNO_NODEV: u64 = 43;
+const ERRNO_NOENT: u64 = 44;
+const ERRNO_NOEXEC: u64 = 45;
+const ERRNO_NOMEM: u64 = 48;
+const ERRNO_NOSYS: u64 = 52;
+const ERRNO_NOTDIR: u64 = 54;
+const ERRNO_NOTTY: u64 = 59;
+const ERRNO_PERM: u64 = 63;
+const ERRNO_PIPE: u64 = 64;
+const ERRNO_RANGE: u64 = 68;
+const ERRNO_SPIPE: u64 = 70;
+const ERRNO_SRCH: u64 = 71;
+
+// File types
+const FILETYPE_UNKNOWN: u8 = 0;
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+const FILETYPE_DIRECTORY: u8 = 3;
+const FILETYPE_REGULAR_FILE: u8 = 4;
+
+// All of the 30 rights. They aren't checked beyond the access mode a file
+// was opened with.
+const RIGHTS_ALL: u64 = (1 << 30) - 1;
+const RIGHT_FD_READ: u64 = 1 << 1;
+const RIGHT_FD_WRITE: u64 = 1 << 6;
+
+// Flags of `path_open`
+const OFLAGS_CREAT: u64 = 1;
+const OFLAGS_DIRECTORY: u64 = 2;
+const OFLAGS_EXCL: u64 = 4;
+const OFLAGS_TRUNC: u64 = 8;
+
+// Clocks, all counting from boot like Linux' in linux.rs
+const CLOCK_COUNT: u64 = 4;
+
+// Kinds of subscriptions and events of `poll_oneoff`
+const EVENTTYPE_CLOCK: u8 = 0;
+const SUBCLOCKFLAGS_ABSTIME: u16 = 1;
+const SUBSCRIPTION_SIZE: usize = 48;
+const EVENT_SIZE: usize = 32;
+
+// Why a host function failed: an error returned to the module, or a trap
+// ending it
+enum Fail {
+    Errno(Errno),
+    Trap(Trap),
+}
+
+impl From<Errno> for Fail {
+    fn from(errno: Errno) -> Fail {
+        Fail::Errno(errno)
+    }
+}
+
+type HostFunction = fn(&mut Wasi, &mut [u8], &[u64]) -> Result<(), Fail>;
+
+const I: ValType = ValType::I32;
+const L: ValType = ValType::I64;
+
+// Name, parameters, whether it returns an error number, and handler
+static FUNCTIONS: &[(&str, &[ValType], bool, HostFunction)] = &[
+    ("args_get", &[I, I], true, args_get),
+    ("args_sizes_get", &[I, I], true, args_sizes_get),
+    ("environ_get", &[I, I], true, environ_get),
+    ("environ_sizes_get", &[I, I], true, environ_sizes_get),
+    ("clock_res_get", &[I, I], true, clock_res_get),
+    ("clock_time_get", &[I, L, I], true, clock_time_get),
+    ("fd_close", &[I], true, fd_close),
+    ("fd_fdstat_get", &[I, I], true, fd_fdstat_get),
+    ("fd_filestat_get", &[I, I], true, fd_filestat_get),
+    ("fd_prestat_get", &[I, I], true, fd_prestat_get),
+    ("fd_prestat_dir_name", &[I, I, I], true, fd_prestat_dir_name),
+    ("fd_read", &[I, I, I, I], true, fd_read),
+    ("fd_seek", &[I, L, I, I], true, fd_seek),
+    ("fd_write", &[I, I, I, I], true, fd_write),
+    (
+        "path_filestat_get",
+        &[I, I, I, I, I],
+        true,
+        path_filestat_get,
+    ),
+    ("path_open", &[I, I, I, I, I, L, L, I, I], true, path_open),
+    ("poll_oneoff", &[I, I, I, I], true, poll_oneoff),
+    ("proc_exit", &[I], false, proc_exit),
+    ("random_get", &[I, I], true, random_get),
+    ("sched_yield", &[], true, sched_yield),
+];
+
+// Stands in for the functions of the module that aren't above
+const NOSYS: usize = usize::MAX;
+
+// The state of a module's WASI functions
+pub struct Wasi {
+    args: Vec<Vec<u8>>,
+    env: Vec<Vec<u8>>,
+    // Paths of the file descriptors that are open on directories, which
+    // paths are looked up from
+    directories: BTreeMap<u32, Vec<u8>>,
+    // The file descriptor of the root directory, opened by `run`
+    preopen: Option<u32>,
+}
+
+impl Wasi {
+    fn new(args: &[&[u8]], env: &[&[u8]]) -> Wasi {
+        Wasi {
+            args: args.iter().map(|arg| arg.to_vec()).collect(),
+            env: env.iter().map(|var| var.to_vec()).collect(),
+            directories: BTreeMap::new(),
+            preopen: None,
+        }
+    }
+
+    // `path` relative to the directory `fd`, from the root. Absolute paths
+    // aren't allowed, and ".." stops at the root.
+    fn path(&self, memory: &[u8], fd: u64, path: u64, len: u64) -> Result<Vec<u8>, Errno> {
+        let directory = match self.directories.get(&(fd as u32)) {
+            Some(directory) => directory,
+            None => {
+                syscall::file(fd)?;
+                return Err(Errno::ENOTDIR);
+            }
+        };
+        let path = bytes(memory, path, len)?;
+        if path.first() == Some(&b'/') {
+            return Err(Errno::EPERM);
+        }
+        let mut joined = directory.clone();
+        joined.push(b'/');
+        joined.extend_from_slice(path);
+        Ok(file::normalize(&joined))
+    }
+}
+
+impl Host for Wasi {
+    fn resolve(&self, module: &str, name: &str, ty: &FuncType) -> Option<usize> {
+        if module != MODULE {
+            return None;
+        }
+        let errno = ty.results == [ValType::I32];
+        match FUNCTIONS.iter().position(|function| function.0 == name) {
+            Some(index) => {
+                let (_, params, returns_errno, _) = FUNCTIONS[index];
+                let matches = ty.params == params && errno == returns_errno;
+                let no_results = !returns_errno && ty.results.is_empty();
+                (matches && (errno || no_results)).then_some(index)
+            }
+            None => errno.then_some(NOSYS),
+        }
+    }
+
+    fn call(&mut self, index: usize, memory: &mut [u8], args: &[u64]) -> Result<u64, Trap> {
+        if index == NOSYS {
+            return Ok(ERRNO_NOSYS);
+        }
+        match (FUNCTIONS[index].3)(self, memory, args) {
+            Ok(()) => Ok(0),
+            Err(Fail::Errno(errno)) => Ok(wasi_errno(errno)),
+            Err(Fail::Trap(trap)) => Err(trap),
+        }
+    }
+
+    // Let other tasks run, and end the module if a signal would terminate
+    // the process. It can't have handlers.
+    fn preempt(&mut self) -> Result<(), Trap> {
+        task::yield_now();
+        let process = process::current().expect("module outside of a process");
+        match process.with_signals(|signals| signals.fatal()) {
+            Some(sig) => Err(Trap::Signal(sig)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn wasi_errno(errno: Errno) -> u64 {
+    match errno {
+        Errno::EPERM => ERRNO_PERM,
+        Errno::ENOENT => ERRNO_NOENT,
+        Errno::ESRCH => ERRNO_SRCH,
+        Errno::EINTR => ERRNO_INTR,
+        Errno::E2BIG => ERRNO_2BIG,
+        Errno::ENOEXEC => ERRNO_NOEXEC,
+        Errno::EBADF => ERRNO_BADF,
+        Errno::ECHILD => ERRNO_CHILD,
+        Errno::ENOMEM => ERRNO_NOMEM,
+        Errno::EACCES => ERRNO_ACCES,
+        Errno::EFAULT => ERRNO_FAULT,
+        Errno::EEXIST => ERRNO_EXIST,
+        Errno::ENODEV => ERRNO_NODEV,
+        Errno::ENOTDIR => ERRNO_NOTDIR,
+        Errno::EISDIR => ERRNO_ISDIR,
+        Errno::EINVAL => ERRNO_INVAL,
+        Errno::EMFILE => ERRNO_MFILE,
+        Errno::ENOTTY => ERRNO_NOTTY,
+        Errno::ESPIPE => ERRNO_SPIPE,
+        Errno::EPIPE => ERRNO_PIPE,
+        Errno::ERANGE => ERRNO_RANGE,
+        Errno::ENAMETOOLONG => ERRNO_NAMETOOLONG,
+        Errno::ENOSYS => ERRNO_NOSYS,
+    }
+}
+
+// `len` bytes of linear memory at `address`
+fn bytes(memory: &[u8], address: u64, len: u64) -> Result<&[u8], Errno> {
+    let start = address as u32 as usize;
+    memory
+        .get(start..start + len as u32 as usize)
+        .ok_or(Errno::EFAULT)
+}
+
+fn bytes_mut(memory: &mut [u8], address: u64, len: u64) -> Result<&mut [u8], Errno> {
+    let start = address as u32 as usize;
+    memory
+        .get_mut(start..start + len as u32 as usize)
+        .ok_or(Errno::EFAULT)
+}
+
+fn write(memory: &mut [u8], address: u64, data: &[u8]) -> Result<(), Errno> {
+    bytes_mut(memory, address, data.len() as u64)?.copy_from_slice(data);
+    Ok(())
+}
+
+fn u32_at(memory: &[u8], address: u64) -> Result<u32, Errno> {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(bytes(memory, address, 4)?);
+    Ok(u32::from_le_bytes(value))
+}
+
+fn u64_at(memory: &[u8], address: u64) -> Result<u64, Errno> {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(bytes(memory, address, 8)?);
+    Ok(u64::from_le_bytes(value))
+}
+
+// Store pointers to `strings` at `array` and the strings, NUL terminated, at
+// `buffer`, for `args_get` and `environ_get`
+fn strings_get(
+    memory: &mut [u8],
+    strings: &[Vec<u8>],
+    array: u64,
+    buffer: u64,
+) -> Result<(), Fail> {
+    let mut at = buffer as u32 as u64;
+    for (i, string) in strings.iter().enumerate() {
+        write(memory, array + i as u64 * 4, &(at as u32).to_le_bytes())?;
+        write(memory, at, string)?;
+        write(memory, at + string.len() as u64, &[0])?;
+        at += string.len() as u64 + 1;
+    }
+    Ok(())
+}
+
+fn strings_sizes_get(
+    memory: &mut [u8],
+    strings: &[Vec<u8>],
+    count: u64,
+    size: u64,
+) -> Result<(), Fail> {
+    let total: usize = strings.iter().map(|string| string.len() + 1).sum();
+    write(memory, count, &(strings.len() as u32).to_le_bytes())?;
+    write(memory, size, &(total as u32).to_le_bytes())?;
+    Ok(())
+}
+
+// args_get(argv, argv_buf)
+fn args_get(wasi: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    strings_get(memory, &wasi.args, args[0], args[1])
+}
+
+// args_sizes_get(argc, argv_buf_size)
+fn args_sizes_get(wasi: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    strings_sizes_get(memory, &wasi.args, args[0], args[1])
+}
+
+// environ_get(environ, environ_buf)
+fn environ_get(wasi: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    strings_get(memory, &wasi.env, args[0], args[1])
+}
+
+// environ_sizes_get(count, environ_buf_size)
+fn environ_sizes_get(wasi: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    strings_sizes_get(memory, &wasi.env, args[0], args[1])
+}
+
+// clock_res_get(id, resolution): nanoseconds, one with the TSC
+fn clock_res_get(_: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    if args[0] as u32 as u64 >= CLOCK_COUNT {
+        return Err(Errno::EINVAL.into());
+    }
+    let resolution = match time::has_tsc() {
+        true => 1,
+        false => time::tick_to_nanos(1),
+    };
+    write(memory, args[1], &resolution.to_le_bytes())?;
+    Ok(())
+}
+
+// clock_time_get(id, precision, time): nanoseconds since boot
+fn clock_time_get(_: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    if args[0] as u32 as u64 >= CLOCK_COUNT {
+        return Err(Errno::EINVAL.into());
+    }
+    write(memory, args[2], &time::nanos().to_le_bytes())?;
+    Ok(())
+}
+
+// fd_close(fd)
+fn fd_close(wasi: &mut Wasi, _: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    let fd = args[0] as u32;
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    let file = process.with_files(|files| files.close(fd as u64))?;
+    drop(file);
+    wasi.directories.remove(&fd);
+    if wasi.preopen == Some(fd) {
+        wasi.preopen = None;
+    }
+    Ok(())
+}
+
+fn filetype(kind: FileKind) -> u8 {
+    match kind {
+        FileKind::Regular => FILETYPE_REGULAR_FILE,
+        FileKind::Directory => FILETYPE_DIRECTORY,
+        FileKind::CharDevice => FILETYPE_CHARACTER_DEVICE,
+        FileKind::Fifo => FILETYPE_UNKNOWN,
+    }
+}
+
+// fd_fdstat_get(fd, fdstat): the type, no flags and all rights
+fn fd_fdstat_get(_: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    let file = syscall::file(args[0])?;
+    let mut fdstat = [0u8; 24];
+    fdstat[0] = filetype(file.kind());
+    fdstat[8..16].copy_from_slice(&RIGHTS_ALL.to_le_bytes());
+    fdstat[16..24].copy_from_slice(&RIGHTS_ALL.to_le_bytes());
+    write(memory, args[1], &fdstat)?;
+    Ok(())
+}
+
+// A `filestat` of a file of `kind` and `size`, without times
+fn write_filestat(memory: &mut [u8], address: u64, kind: FileKind, size: u64) -> Result<(), Errno> {
+    let mut filestat = [0u8; 64];
+    filestat[16] = filetype(kind);
+    filestat[24..32].copy_from_slice(&1u64.to_le_bytes());
+    filestat[32..40].copy_from_slice(&size.to_le_bytes());
+    write(memory, address, &filestat)
+}
+
+// fd_filestat_get(fd, filestat): open files don't know their size
+fn fd_filestat_get(_: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    let file = syscall::file(args[0])?;
+    write_filestat(memory, args[1], file.kind(), file.size())?;
+    Ok(())
+}
+
+// fd_prestat_get(fd, prestat): only the root directory is preopened
+fn fd_prestat_get(wasi: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    let fd = args[0] as u32;
+    if wasi.preopen != Some(fd) {
+        return Err(Errno::EBADF.into());
+    }
+    let name = &wasi.directories[&fd];
+    let mut prestat = [0u8; 8];
+    prestat[4..8].copy_from_slice(&(name.len() as u32).to_le_bytes());
+    write(memory, args[1], &prestat)?;
+    Ok(())
+}
+
+// fd_prestat_dir_name(fd, path, path_len)
+fn fd_prestat_dir_name(wasi: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    let fd = args[0] as u32;
+    if wasi.preopen != Some(fd) {
+        return Err(Errno::EBADF.into());
+    }
+    let name = &wasi.directories[&fd];
+    let len = name.len().min(args[2] as u32 as usize);
+    write(memory, args[1], &name[..len])?;
+    Ok(())
+}
+
+// The buffer and length of each `iovec` at `iovs`, which must be in memory
+fn iovecs(memory: &[u8], iovs: u64, count: u64) -> Result<Vec<(u64, u64)>, Errno> {
+    let mut iovecs = Vec::new();
+    for i in 0..count as u32 as u64 {
+        let iovec = iovs as u32 as u64 + i * 8;
+        let buffer = u32_at(memory, iovec)? as u64;
+        let len = u32_at(memory, iovec + 4)? as u64;
+        bytes(memory, buffer, len)?;
+        iovecs.push((buffer, len));
+    }
+    Ok(iovecs)
+}
+
+// fd_read(fd, iovs, iovs_len, nread): one read, spread over the buffers
+fn fd_read(_: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    let file = syscall::file(args[0])?;
+    let iovecs = iovecs(memory, args[1], args[2])?;
+    let total: u64 = iovecs.iter().map(|&(_, len)| len).sum();
+    let mut buffer = vec![0u8; (total as usize).min(IO_CHUNK)];
+    let read = file.read(&mut buffer)?;
+    let mut data = &buffer[..read];
+    for (address, len) in iovecs {
+        let (part, rest) = data.split_at(data.len().min(len as usize));
+        write(memory, address, part)?;
+        data = rest;
+    }
+    write(memory, args[3], &(read as u32).to_le_bytes())?;
+    Ok(())
+}
+
+// fd_write(fd, iovs, iovs_len, nwritten): one write of all the buffers
+fn fd_write(_: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    let file = syscall::file(args[0])?;
+    let mut data = Vec::new();
+    for (address, len) in iovecs(memory, args[1], args[2])? {
+        data.extend_from_slice(bytes(memory, address, len)?);
+    }
+    let written = file.write(&data)?;
+    write(memory, args[3], &(written as u32).to_le_bytes())?;
+    Ok(())
+}
+
+// fd_seek(fd, offset, whence, newoffset): like Linux' lseek, with the same
+// values of whence
+fn fd_seek(_: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    let file = syscall::file(args[0])?;
+    let offset = args[1] as i64;
+    let to = match args[2] {
+        0 if offset >= 0 => SeekFrom::Start(offset as u64),
+        1 => SeekFrom::Current(offset),
+        2 => SeekFrom::End(offset),
+        _ => return Err(Errno::EINVAL.into()),
+    };
+    let position = file.seek(to)?;
+    write(memory, args[3], &position.to_le_bytes())?;
+    Ok(())
+}
+
+// path_filestat_get(fd, flags, path, path_len, filestat)
+fn path_filestat_get(wasi: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    let path = wasi.path(memory, args[0], args[2], args[3])?;
+    let (kind, size) = file::metadata(&path)?;
+    write_filestat(memory, args[4], kind, size)?;
+    Ok(())
+}
+
+// path_open(fd, dirflags, path, path_len, oflags, rights_base,
+// rights_inheriting, fdflags, opened_fd): the access mode is taken from the
+// rights. Nothing can be created or truncated, there is no file system to
+// write to.
+fn path_open(wasi: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    let path = wasi.path(memory, args[0], args[2], args[3])?;
+    let oflags = args[4];
+    let kind = match file::metadata(&path) {
+        Ok(_) if oflags & OFLAGS_CREAT != 0 && oflags & OFLAGS_EXCL != 0 => {
+            return Err(Errno::EEXIST.into())
+        }
+        Ok((kind, _)) => kind,
+        Err(Errno::ENOENT) if oflags & OFLAGS_CREAT != 0 => return Err(Errno::EACCES.into()),
+        Err(errno) => return Err(errno.into()),
+    };
+    if oflags & OFLAGS_DIRECTORY != 0 && kind != FileKind::Directory {
+        return Err(Errno::ENOTDIR.into());
+    }
+    if oflags & OFLAGS_TRUNC != 0 && kind == FileKind::Regular {
+        return Err(Errno::EACCES.into());
+    }
+
+    let rights = args[5];
+    let flags = match (rights & RIGHT_FD_READ != 0, rights & RIGHT_FD_WRITE != 0) {
+        _ if kind == FileKind::Directory => O_RDONLY,
+        (true, true) => O_RDWR,
+        (false, true) => O_WRONLY,
+        _ => O_RDONLY,
+    };
+    let file = file::open(&path, flags)?;
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    let fd = process.with_files(|files| files.insert(file))? as u32;
+    if kind == FileKind::Directory {
+        wasi.directories.insert(fd, path);
+    } else {
+        // The descriptor may have been a directory closed by something else
+        wasi.directories.remove(&fd);
+    }
+    write(memory, args[8], &fd.to_le_bytes())?;
+    Ok(())
+}
+
+// poll_oneoff(in, out, nsubscriptions, nevents): files are always ready, so
+// with any among the subscriptions it returns right away, otherwise it
+// sleeps until the first clock subscription is due
+fn poll_oneoff(_: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    let [input, output, count, nevents] = [args[0], args[1], args[2], args[3]];
+    let count = count as u32 as u64;
+    if count == 0 {
+        return Err(Errno::EINVAL.into());
+    }
+    // User data, type, and the deadline of clocks or the descriptor of files
+    let mut subscriptions = Vec::new();
+    for i in 0..count {
+        let subscription = bytes(memory, input + i * SUBSCRIPTION_SIZE as u64, 48)?;
+        let userdata = u64_at(subscription, 0)?;
+        let tag = subscription[8];
+        let value = if tag == EVENTTYPE_CLOCK {
+            let timeout = u64_at(subscription, 24)?;
+            let flags = u16::from_le_bytes([subscription[40], subscription[41]]);
+            match flags & SUBCLOCKFLAGS_ABSTIME {
+                0 => time::nanos().saturating_add(timeout),
+                _ => timeout,
+            }
+        } else {
+            u32_at(subscription, 16)? as u64
+        };
+        subscriptions.push((userdata, tag, value));
+    }
+
+    let files_ready = subscriptions
+        .iter()
+        .any(|&(_, tag, _)| tag != EVENTTYPE_CLOCK);
+    let first_deadline = subscriptions
+        .iter()
+        .filter(|&&(_, tag, _)| tag == EVENTTYPE_CLOCK)
+        .map(|&(_, _, deadline)| deadline)
+        .min();
+    if let (false, Some(deadline)) = (files_ready, first_deadline) {
+        let now = time::nanos();
+        if deadline > now {
+            time::sleep(Duration::from_nanos(deadline - now));
+        }
+    }
+
+    let now = time::nanos();
+    let mut events = 0;
+    for (userdata, tag, value) in subscriptions {
+        let error = match tag {
+            EVENTTYPE_CLOCK if value > now => continue,
+            EVENTTYPE_CLOCK => 0,
+            _ => syscall::file(value).err().map_or(0, wasi_errno),
+        };
+        let mut event = [0u8; EVENT_SIZE];
+        event[0..8].copy_from_slice(&userdata.to_le_bytes());
+        event[8..10].copy_from_slice(&(error as u16).to_le_bytes());
+        event[10] = tag;
+        write(memory, output + events * EVENT_SIZE as u64, &event)?;
+        events += 1;
+    }
+    write(memory, nevents, &(events as u32).to_le_bytes())?;
+    Ok(())
+}
+
+// proc_exit(status)
+fn proc_exit(_: &mut Wasi, _: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    Err(Fail::Trap(Trap::Exit(args[0] as i32)))
+}
+
+// random_get(buffer, len)
+fn random_get(_: &mut Wasi, memory: &mut [u8], args: &[u64]) -> Result<(), Fail> {
+    for chunk in bytes_mut(memory, args[0], args[1])?.chunks_mut(8) {
+        let random = elf::random_u64().to_le_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+    Ok(())
+}
+
+// sched_yield()
+fn sched_yield(_: &mut Wasi, _: &mut [u8], _: &[u64]) -> Result<(), Fail> {
+    task::yield_now();
+    Ok(())
+}
+
+// Decode `image` and link it with the WASI functions, for running it with
+// `run`. It must export `_start`, which takes and returns nothing. Its fuel
+// is limited if `env` sets `FUEL_VARIABLE`.
+pub fn load(image: &[u8], args: &[&[u8]], env: &[&[u8]]) -> Result<(Instance, Wasi), Error> {
+    let module = Module::parse(image)?;
+    let wasi = Wasi::new(args, env);
+    let mut instance = Instance::new(module, &wasi)?;
+    instance.set_fuel(fuel_limit(env));
+    let start = instance
+        .export("_start")
+        .ok_or(Error::Malformed("no _start function"))?;
+    let ty = instance.function_type(start);
+    if !ty.params.is_empty() || !ty.results.is_empty() {
+        return Err(Error::Malformed("_start has parameters or results"));
+    }
+    Ok((instance, wasi))
+}
+
+fn fuel_limit(env: &[&[u8]]) -> Option<u64> {
+    env.iter().find_map(|variable| {
+        let value = variable.strip_prefix(FUEL_VARIABLE)?;
+        core::str::from_utf8(value).ok()?.parse().ok()
+    })
+}
+
+// Run a module loaded by `load` in the calling process until it exits.
+// Traps end it like the signal of the fault a native program would get.
+pub fn run(mut instance: Instance, mut wasi: Wasi) -> UserExit {
+    let process = process::current().expect("module outside of a process");
+    let root = file::open(b"/", O_RDONLY).expect("no root directory");
+    if let Ok(fd) = process.with_files(|files| files.insert(root)) {
+        wasi.directories.insert(fd as u32, b"/".to_vec());
+        wasi.preopen = Some(fd as u32);
+    }
+
+    let start = instance.export("_start").expect("checked by load");
+    let result = instance
+        .start(&mut wasi)
+        .and_then(|()| instance.invoke(&mut wasi, start, &[]));
+    match result {
+        Ok(_) => UserExit::Exit(0),
+        Err(Trap::Exit(status)) => UserExit::Exit(status as i64),
+        Err(Trap::Signal(sig)) => UserExit::Signal(sig),
+        Err(Trap::MemoryOutOfBounds | Trap::StackOverflow) => UserExit::Signal(signal::SIGSEGV),
+        Err(Trap::DivisionByZero | Trap::IntegerOverflow | Trap::InvalidConversion) => {
+            UserExit::Signal(signal::SIGFPE)
+        }
+        Err(Trap::OutOfFuel) => UserExit::Signal(signal::SIGKILL),
+        Err(_) => UserExit::Signal(signal::SIGILL),
+    }
+}
+
+fn load_error(error: Error) -> Errno {
+    match error {
+        Error::TooLarge => Errno::ENOMEM,
+        _ => Errno::ENOEXEC,
+    }
+}
+
+// Replace the calling process with the module `image` for `execve`, and run
+// it to the end. The process exits when the system call returns.
+pub fn exec(image: &[u8], argv: &[&[u8]], envp: &[&[u8]]) -> Result<u64, Errno> {
+    let (instance, wasi) = load(image, argv, envp).map_err(load_error)?;
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    process
+        .exec_kernel(Personality::Wasm)
+        .map_err(|_| Errno::ENOMEM)?;
+    usermode::request_exit(run(instance, wasi));
+    Ok(0)
+}
+
+// Run the module `image` in a new process, as a child of the calling one
+pub fn spawn(
+    name: &str,
+    image: &[u8],
+    args: &[&[u8]],
+    env: &[&[u8]],
+) -> Result<Arc<Process>, Errno> {
+    let (instance, wasi) = load(image, args, env).map_err(load_error)?;
+    process::spawn_kernel(name, Personality::Wasm, move || run(instance, wasi))
+        .map_err(|_| Errno::ENOMEM)
+}
+
+#[test_case]
+fn wasi_module() {
+    print!("wasi modules... ");
+    // fd_write(1, iovs = 0, 1, nwritten = 16), then proc_exit(nwritten)
+    let start: &[u8] = &[
+        0x00, 0x41, 0x01, 0x41, 0x00, 0x41, 0x01, 0x41, 0x10, 0x10, 0x00, 0x1a, 0x41, 0x10, 0x28,
+        0x02, 0x00, 0x10, 0x01, 0x0b,
+    ];
+    let image = TestModule {
+        types: &[
+            &[0x04, 0x7f, 0x7f, 0x7f, 0x7f, 0x01, 0x7f],
+            &[0x01, 0x7f, 0x00],
+            &[0x00, 0x00],
+        ],
+        imports: &[(MODULE, "fd_write", 0), (MODULE, "proc_exit", 1)],
+        functions: &[(2, start)],
+        memory: Some((1, 1)),
+        exports: &[("_start", 2)],
+        data: &[(0, &[0x20, 0, 0, 0, 6, 0, 0, 0]), (0x20, b"hello ")],
+        ..TestModule::default()
+    }
+    .encode();
+    let process = spawn("hello", &image, &[b"hello"], &[]).unwrap();
+    assert_eq!(process.wait_exit(), UserExit::Exit(6));
+
+    // Imports it doesn't know, and modules without _start, aren't run
+    let image = TestModule {
+        types: &[&[0x00, 0x00]],
+        imports: &[("env", "missing", 0)],
+        ..TestModule::default()
+    }
+    .encode();
+    assert_eq!(
+        spawn("missing", &image, &[], &[]).err(),
+        Some(Errno::ENOEXEC)
+    );
+    println!("[ok]");
+}
+
+// A module that never ends still lets other tasks run, and can be killed
+#[test_case]
+fn runaway_module() {
+    print!("wasi preemption... ");
+    let image = TestModule {
+        types: &[&[0x00, 0x00]],
+        functions: &[(0, &[0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x0b])],
+        exports: &[("_start", 0)],
+        ..TestModule::default()
+    }
+    .encode();
+    let process = spawn("spin", &image, &[], &[]).unwrap();
+    time::sleep(Duration::from_millis(20));
+    assert_eq!(process.exit_status(), None);
+    process.send_signal(signal::SIGKILL, SigInfo::user(None));
+    assert_eq!(process.wait_exit(), UserExit::Signal(signal::SIGKILL));
+
+    // Or it runs out of fuel
+    let process = spawn("spin", &image, &[], &[b"WASM_FUEL=100000"]).unwrap();
+    assert_eq!(process.wait_exit(), UserExit::Signal(signal::SIGKILL));
+    println!("[ok]");
+}
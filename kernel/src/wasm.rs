@@ -0,0 +1,2167 @@
+// A WebAssembly interpreter for running sandboxed modules in the kernel, see
+// wasi.rs for the host functions they get. It supports the MVP instruction
+// set with multiple values, sign extension, saturating conversions and the
+// bulk memory copy and fill instructions.
+//
+// Function bodies are decoded once into `Op`s with their branch targets and
+// operand stack heights worked out, which also checks that the stack can't
+// underflow and indices are in range. Values are untyped 64-bit slots, i32
+// and f32 in the low 32 bits and floats as their bits, so a module confusing
+// types computes nonsense but can't break the interpreter. It doesn't
+// recurse, calls use a stack on the heap, and every `FUEL_SLICE` instructions
+// it lets the host preempt it.
+use crate::task;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::convert::TryFrom;
+use core::fmt;
+
+#[cfg(test)]
+use crate::{print, println};
+
+// Bytes per page of linear memory
+pub const PAGE_SIZE: usize = 0x10000;
+
+// Most pages of linear memory an instance may have. Memory comes from the
+// kernel heap, so this is well below what WebAssembly allows.
+const MAX_PAGES: u32 = 64;
+
+// Most elements of a table
+const MAX_TABLE: u32 = 0x10000;
+
+// Most value slots on the stack, counting locals, and most nested calls
+const MAX_STACK: usize = 0x10000;
+const MAX_FRAMES: usize = 1024;
+
+// Instructions run between calls of `Host::preempt`
+pub const FUEL_SLICE: u32 = 10_000;
+
+const MAGIC: &[u8] = b"\0asm";
+const VERSION: &[u8] = &[1, 0, 0, 0];
+
+// Whether `image` looks like a WebAssembly module rather than an ELF file
+pub fn is_module(image: &[u8]) -> bool {
+    image.starts_with(MAGIC)
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Malformed(&'static str),
+    Unsupported(&'static str),
+    UnsupportedInstruction(u8),
+    // An import the host doesn't have, or not with that type, as module and
+    // name
+    UnknownImport(String, String),
+    // Memory or a table larger than an instance may have
+    TooLarge,
+    // A data or element segment outside its memory or table
+    SegmentOutOfBounds,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Malformed(what) => write!(f, "malformed module: {}", what),
+            Error::Unsupported(what) => write!(f, "unsupported: {}", what),
+            Error::UnsupportedInstruction(op) => write!(f, "unsupported instruction {:#04x}", op),
+            Error::UnknownImport(module, name) => write!(f, "unknown import {}.{}", module, name),
+            Error::TooLarge => write!(f, "memory or table too large"),
+            Error::SegmentOutOfBounds => write!(f, "segment out of bounds"),
+        }
+    }
+}
+
+// Why a module stopped running
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Trap {
+    Unreachable,
+    MemoryOutOfBounds,
+    DivisionByZero,
+    IntegerOverflow,
+    InvalidConversion,
+    UndefinedElement,
+    UninitializedElement,
+    IndirectCallTypeMismatch,
+    StackOverflow,
+    OutOfFuel,
+    // Ended by the host, with an exit status or by a signal
+    Exit(i32),
+    Signal(u8),
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trap::Unreachable => write!(f, "unreachable executed"),
+            Trap::MemoryOutOfBounds => write!(f, "out of bounds memory access"),
+            Trap::DivisionByZero => write!(f, "integer divide by zero"),
+            Trap::IntegerOverflow => write!(f, "integer overflow"),
+            Trap::InvalidConversion => write!(f, "invalid conversion to integer"),
+            Trap::UndefinedElement => write!(f, "undefined element"),
+            Trap::UninitializedElement => write!(f, "uninitialized element"),
+            Trap::IndirectCallTypeMismatch => write!(f, "indirect call type mismatch"),
+            Trap::StackOverflow => write!(f, "call stack exhausted"),
+            Trap::OutOfFuel => write!(f, "out of fuel"),
+            Trap::Exit(status) => write!(f, "exited with status {}", status),
+            Trap::Signal(sig) => write!(f, "killed by signal {}", sig),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+// What an instance calls for its imports
+pub trait Host {
+    // The index of the host function `name` in `module`, if there is one of
+    // type `ty`
+    fn resolve(&self, module: &str, name: &str, ty: &FuncType) -> Option<usize>;
+
+    // Call the host function `index` with `args`. The result is ignored if
+    // its type has none.
+    fn call(&mut self, index: usize, memory: &mut [u8], args: &[u64]) -> Result<u64, Trap>;
+
+    // Called every `FUEL_SLICE` instructions, to let other tasks run and end
+    // the module if it should stop
+    fn preempt(&mut self) -> Result<(), Trap> {
+        task::yield_now();
+        Ok(())
+    }
+}
+
+// Where a branch goes: the instruction, and the operand stack height of the
+// frame to drop to, keeping the top `keep` values
+#[derive(Debug, Copy, Clone)]
+struct Target {
+    pc: u32,
+    height: u32,
+    keep: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Op {
+    Unreachable,
+    Br(Target),
+    BrIf(Target),
+    // Indexes `targets` of the function, the last one is the default
+    BrTable { start: u32, len: u32 },
+    // To `else_pc` if the condition is zero
+    If { else_pc: u32 },
+    Jump(u32),
+    Return,
+    Call(u32),
+    CallIndirect(u32),
+    Drop,
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    // Opcode and offset
+    Load(u8, u32),
+    Store(u8, u32),
+    MemorySize,
+    MemoryGrow,
+    MemoryCopy,
+    MemoryFill,
+    Const(u64),
+    // The one byte numeric instructions, from i32.eqz to i64.extend32_s
+    Numeric(u8),
+    // The saturating conversions after the 0xfc prefix
+    TruncSat(u8),
+}
+
+struct Import {
+    module: String,
+    name: String,
+    ty: u32,
+}
+
+// A function defined by the module
+struct Function {
+    ty: u32,
+    params: u32,
+    results: u32,
+    // Besides the parameters
+    locals: u32,
+    // Most values on its operand stack
+    max_height: u32,
+    code: Vec<Op>,
+    targets: Vec<Target>,
+}
+
+#[derive(Copy, Clone)]
+struct Limits {
+    min: u32,
+    max: Option<u32>,
+}
+
+struct Global {
+    mutable: bool,
+    value: u64,
+}
+
+// A decoded module. Imported functions come first in the function index
+// space, then the ones it defines.
+pub struct Module {
+    types: Vec<FuncType>,
+    imports: Vec<Import>,
+    functions: Vec<Function>,
+    table: Option<Limits>,
+    memory: Option<Limits>,
+    globals: Vec<Global>,
+    exports: Vec<(String, u32)>,
+    start: Option<u32>,
+    // Active segments as offset and function indices or bytes
+    elements: Vec<(u32, Vec<u32>)>,
+    data: Vec<(u32, Vec<u8>)>,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        let byte = *self
+            .bytes
+            .get(self.pos)
+            .ok_or(Error::Malformed("unexpected end"))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(Error::Malformed("unexpected end"))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    // LEB128 with at most `bits` significant bits
+    fn leb(&mut self, bits: u32, signed: bool) -> Result<u64, Error> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            if shift >= bits {
+                return Err(Error::Malformed("integer too long"));
+            }
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if signed && shift < 64 && byte & 0x40 != 0 {
+                    value |= !0 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let value = self.leb(32, false)?;
+        u32::try_from(value).map_err(|_| Error::Malformed("integer too large"))
+    }
+
+    fn len(&mut self) -> Result<usize, Error> {
+        self.u32().map(|len| len as usize)
+    }
+
+    fn name(&mut self) -> Result<String, Error> {
+        let len = self.len()?;
+        let bytes = self.bytes(len)?;
+        let name = core::str::from_utf8(bytes).map_err(|_| Error::Malformed("name isn't UTF-8"))?;
+        Ok(String::from(name))
+    }
+
+    fn val_type(&mut self) -> Result<ValType, Error> {
+        match self.byte()? {
+            0x7f => Ok(ValType::I32),
+            0x7e => Ok(ValType::I64),
+            0x7d => Ok(ValType::F32),
+            0x7c => Ok(ValType::F64),
+            0x7b => Err(Error::Unsupported("SIMD")),
+            0x70 | 0x6f => Err(Error::Unsupported("reference types")),
+            _ => Err(Error::Malformed("value type")),
+        }
+    }
+
+    fn limits(&mut self) -> Result<Limits, Error> {
+        match self.byte()? {
+            0x00 => Ok(Limits {
+                min: self.u32()?,
+                max: None,
+            }),
+            0x01 => Ok(Limits {
+                min: self.u32()?,
+                max: Some(self.u32()?),
+            }),
+            0x02 | 0x03 => Err(Error::Unsupported("shared memory")),
+            _ => Err(Error::Unsupported("64-bit memory")),
+        }
+    }
+
+    // A constant expression, for initial values and segment offsets. Only
+    // earlier globals may be read.
+    fn const_expr(&mut self, globals: &[Global]) -> Result<u64, Error> {
+        let value = match self.byte()? {
+            0x41 => self.leb(32, true)? as u32 as u64,
+            0x42 => self.leb(64, true)?,
+            0x43 => u32::from_le_bytes(self.array()?) as u64,
+            0x44 => u64::from_le_bytes(self.array()?),
+            0x23 => {
+                let global = globals
+                    .get(self.len()?)
+                    .ok_or(Error::Malformed("global index"))?;
+                global.value
+            }
+            _ => return Err(Error::Unsupported("constant expression")),
+        };
+        match self.byte()? {
+            0x0b => Ok(value),
+            _ => Err(Error::Unsupported("constant expression")),
+        }
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+}
+
+impl Module {
+    pub fn parse(image: &[u8]) -> Result<Module, Error> {
+        let mut reader = Reader::new(image);
+        if reader.bytes(4).ok() != Some(MAGIC) {
+            return Err(Error::Malformed("not a WebAssembly module"));
+        }
+        if reader.bytes(4)? != VERSION {
+            return Err(Error::Unsupported("version"));
+        }
+
+        let mut module = Module {
+            types: Vec::new(),
+            imports: Vec::new(),
+            functions: Vec::new(),
+            table: None,
+            memory: None,
+            globals: Vec::new(),
+            exports: Vec::new(),
+            start: None,
+            elements: Vec::new(),
+            data: Vec::new(),
+        };
+        // Types of the defined functions, from the function section
+        let mut declared: Vec<u32> = Vec::new();
+        while !reader.is_empty() {
+            let id = reader.byte()?;
+            let len = reader.len()?;
+            let mut section = Reader::new(reader.bytes(len)?);
+            match id {
+                0 => continue,
+                1 => module.parse_types(&mut section)?,
+                2 => module.parse_imports(&mut section)?,
+                3 => {
+                    for _ in 0..section.len()? {
+                        let ty = section.u32()?;
+                        if ty as usize >= module.types.len() {
+                            return Err(Error::Malformed("type index"));
+                        }
+                        declared.push(ty);
+                    }
+                }
+                4 => {
+                    for _ in 0..section.len()? {
+                        if section.byte()? != 0x70 || module.table.is_some() {
+                            return Err(Error::Unsupported("tables other than one of functions"));
+                        }
+                        module.table = Some(section.limits()?);
+                    }
+                }
+                5 => {
+                    for _ in 0..section.len()? {
+                        if module.memory.is_some() {
+                            return Err(Error::Unsupported("multiple memories"));
+                        }
+                        module.memory = Some(section.limits()?);
+                    }
+                }
+                6 => {
+                    for _ in 0..section.len()? {
+                        section.val_type()?;
+                        let mutable = match section.byte()? {
+                            0 => false,
+                            1 => true,
+                            _ => return Err(Error::Malformed("global mutability")),
+                        };
+                        let value = section.const_expr(&module.globals)?;
+                        module.globals.push(Global { mutable, value });
+                    }
+                }
+                7 => module.parse_exports(&mut section)?,
+                8 => module.start = Some(section.u32()?),
+                9 => module.parse_elements(&mut section)?,
+                10 => module.parse_code(&mut section, &declared)?,
+                11 => module.parse_data(&mut section)?,
+                // The data count
+                12 => {
+                    section.u32()?;
+                }
+                _ => return Err(Error::Malformed("section id")),
+            }
+            if !section.is_empty() {
+                return Err(Error::Malformed("section size"));
+            }
+        }
+
+        if module.functions.len() != declared.len() {
+            return Err(Error::Malformed("function and code sections differ"));
+        }
+        let count = module.function_count();
+        let start_ok = module
+            .start
+            .is_none_or(|start| start < count && module.function_type(start).params.is_empty());
+        let exports_ok = module.exports.iter().all(|&(_, index)| index < count);
+        let elements_ok = module
+            .elements
+            .iter()
+            .all(|(_, functions)| functions.iter().all(|&index| index < count));
+        if !start_ok || !exports_ok || !elements_ok {
+            return Err(Error::Malformed("function index"));
+        }
+        Ok(module)
+    }
+
+    fn parse_types(&mut self, section: &mut Reader) -> Result<(), Error> {
+        for _ in 0..section.len()? {
+            if section.byte()? != 0x60 {
+                return Err(Error::Malformed("function type"));
+            }
+            let mut ty = FuncType {
+                params: Vec::new(),
+                results: Vec::new(),
+            };
+            for _ in 0..section.len()? {
+                ty.params.push(section.val_type()?);
+            }
+            for _ in 0..section.len()? {
+                ty.results.push(section.val_type()?);
+            }
+            self.types.push(ty);
+        }
+        Ok(())
+    }
+
+    fn parse_imports(&mut self, section: &mut Reader) -> Result<(), Error> {
+        for _ in 0..section.len()? {
+            let module = section.name()?;
+            let name = section.name()?;
+            if section.byte()? != 0x00 {
+                return Err(Error::Unsupported("importing tables, memories or globals"));
+            }
+            let ty = section.u32()?;
+            if ty as usize >= self.types.len() {
+                return Err(Error::Malformed("type index"));
+            }
+            self.imports.push(Import { module, name, ty });
+        }
+        Ok(())
+    }
+
+    fn parse_exports(&mut self, section: &mut Reader) -> Result<(), Error> {
+        for _ in 0..section.len()? {
+            let name = section.name()?;
+            let kind = section.byte()?;
+            let index = section.u32()?;
+            // Only functions can be used from outside
+            if kind == 0x00 {
+                self.exports.push((name, index));
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_elements(&mut self, section: &mut Reader) -> Result<(), Error> {
+        for _ in 0..section.len()? {
+            let flags = section.u32()?;
+            let offset = match flags {
+                0 => Some(section.const_expr(&self.globals)?),
+                2 => {
+                    if section.u32()? != 0 {
+                        return Err(Error::Malformed("table index"));
+                    }
+                    Some(section.const_expr(&self.globals)?)
+                }
+                // Passive and declarative segments, only of use to
+                // instructions that aren't supported
+                1 | 3 => None,
+                _ => return Err(Error::Unsupported("element expressions")),
+            };
+            if flags != 0 && section.byte()? != 0x00 {
+                return Err(Error::Malformed("element kind"));
+            }
+            let mut functions = Vec::new();
+            for _ in 0..section.len()? {
+                functions.push(section.u32()?);
+            }
+            if let Some(offset) = offset {
+                if self.table.is_none() {
+                    return Err(Error::Malformed("element segment without a table"));
+                }
+                self.elements.push((offset as u32, functions));
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_data(&mut self, section: &mut Reader) -> Result<(), Error> {
+        for _ in 0..section.len()? {
+            let offset = match section.u32()? {
+                0 => section.const_expr(&self.globals)?,
+                2 if section.u32()? == 0 => section.const_expr(&self.globals)?,
+                1 => return Err(Error::Unsupported("passive data segments")),
+                _ => return Err(Error::Malformed("data segment")),
+            };
+            if self.memory.is_none() {
+                return Err(Error::Malformed("data segment without a memory"));
+            }
+            let len = section.len()?;
+            self.data
+                .push((offset as u32, section.bytes(len)?.to_vec()));
+        }
+        Ok(())
+    }
+
+    fn parse_code(&mut self, section: &mut Reader, declared: &[u32]) -> Result<(), Error> {
+        if section.len()? != declared.len() {
+            return Err(Error::Malformed("function and code sections differ"));
+        }
+        for &ty in declared {
+            let len = section.len()?;
+            let mut body = Reader::new(section.bytes(len)?);
+            let function = Compiler::new(self, declared, ty).compile(&mut body)?;
+            if !body.is_empty() {
+                return Err(Error::Malformed("code after the end of a function"));
+            }
+            self.functions.push(function);
+        }
+        Ok(())
+    }
+
+    fn function_count(&self) -> u32 {
+        (self.imports.len() + self.functions.len()) as u32
+    }
+
+    // The type of function `index`, which must exist
+    pub fn function_type(&self, index: u32) -> &FuncType {
+        let index = index as usize;
+        let ty = match self.imports.get(index) {
+            Some(import) => import.ty,
+            None => self.functions[index - self.imports.len()].ty,
+        };
+        &self.types[ty as usize]
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum LabelKind {
+    Block,
+    Loop,
+    If,
+}
+
+// Where the end of a block is filled in once it's known
+enum Fixup {
+    Op(usize),
+    Target(usize),
+}
+
+struct Label {
+    kind: LabelKind,
+    // Operand stack height below its parameters
+    height: u32,
+    params: u32,
+    results: u32,
+    // The first instruction of a loop
+    start: u32,
+    fixups: Vec<Fixup>,
+    // The If instruction, until its else
+    if_op: Option<usize>,
+    // After an unconditional branch, where the stack is whatever is needed
+    unreachable: bool,
+}
+
+// Decodes a function body into `Op`s
+struct Compiler<'a> {
+    module: &'a Module,
+    // Types of the defined functions
+    declared: &'a [u32],
+    ty: &'a FuncType,
+    locals: u32,
+    labels: Vec<Label>,
+    height: u32,
+    max_height: u32,
+    code: Vec<Op>,
+    targets: Vec<Target>,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(module: &'a Module, declared: &'a [u32], ty: u32) -> Compiler<'a> {
+        Compiler {
+            module,
+            declared,
+            ty: &module.types[ty as usize],
+            locals: 0,
+            labels: Vec::new(),
+            height: 0,
+            max_height: 0,
+            code: Vec::new(),
+            targets: Vec::new(),
+        }
+    }
+
+    fn compile(mut self, body: &mut Reader) -> Result<Function, Error> {
+        for _ in 0..body.len()? {
+            let count = body.u32()?;
+            body.val_type()?;
+            self.locals = self
+                .locals
+                .checked_add(count)
+                .filter(|&locals| locals as usize <= MAX_STACK)
+                .ok_or(Error::Unsupported("too many locals"))?;
+        }
+        let results = self.ty.results.len() as u32;
+        self.labels.push(Label {
+            kind: LabelKind::Block,
+            height: 0,
+            params: 0,
+            results,
+            start: 0,
+            fixups: Vec::new(),
+            if_op: None,
+            unreachable: false,
+        });
+        while !self.labels.is_empty() {
+            let op = body.byte()?;
+            self.instruction(op, body)?;
+        }
+
+        let ty = self.declared_type();
+        Ok(Function {
+            ty,
+            params: self.ty.params.len() as u32,
+            results,
+            locals: self.locals,
+            max_height: self.max_height,
+            code: self.code,
+            targets: self.targets,
+        })
+    }
+
+    fn declared_type(&self) -> u32 {
+        let index = self.module.functions.len();
+        self.declared[index]
+    }
+
+    fn label(&mut self) -> &mut Label {
+        self.labels.last_mut().expect("no label")
+    }
+
+    fn pop(&mut self, n: u32) -> Result<(), Error> {
+        let label = self.labels.last().expect("no label");
+        match self.height.checked_sub(n) {
+            Some(height) if height >= label.height => self.height = height,
+            _ if label.unreachable => self.height = label.height,
+            _ => return Err(Error::Malformed("operand stack underflow")),
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, n: u32) -> Result<(), Error> {
+        self.height += n;
+        if self.height as usize > MAX_STACK {
+            return Err(Error::Unsupported("operand stack too deep"));
+        }
+        self.max_height = self.max_height.max(self.height);
+        Ok(())
+    }
+
+    fn emit(&mut self, op: Op) {
+        self.code.push(op);
+    }
+
+    fn pc(&self) -> u32 {
+        self.code.len() as u32
+    }
+
+    // The rest of the block can't be reached
+    fn set_unreachable(&mut self) {
+        let label = self.labels.last_mut().expect("no label");
+        label.unreachable = true;
+        self.height = label.height;
+    }
+
+    // Parameter and result counts of a block type
+    fn block_type(&mut self, body: &mut Reader) -> Result<(u32, u32), Error> {
+        match body.bytes.get(body.pos) {
+            Some(0x40) => {
+                body.pos += 1;
+                Ok((0, 0))
+            }
+            Some(0x7f | 0x7e | 0x7d | 0x7c | 0x7b | 0x70 | 0x6f) => {
+                body.val_type()?;
+                Ok((0, 1))
+            }
+            _ => {
+                let index = body.leb(33, true)? as i64;
+                let ty = usize::try_from(index)
+                    .ok()
+                    .and_then(|index| self.module.types.get(index))
+                    .ok_or(Error::Malformed("block type"))?;
+                Ok((ty.params.len() as u32, ty.results.len() as u32))
+            }
+        }
+    }
+
+    fn begin(&mut self, kind: LabelKind, body: &mut Reader) -> Result<(), Error> {
+        let (params, results) = self.block_type(body)?;
+        if kind == LabelKind::If {
+            self.pop(1)?;
+        }
+        self.pop(params)?;
+        let height = self.height;
+        self.push(params)?;
+        let if_op = (kind == LabelKind::If).then_some(self.code.len());
+        if if_op.is_some() {
+            self.emit(Op::If { else_pc: 0 });
+        }
+        let start = self.pc();
+        self.labels.push(Label {
+            kind,
+            height,
+            params,
+            results,
+            start,
+            fixups: Vec::new(),
+            if_op,
+            unreachable: false,
+        });
+        Ok(())
+    }
+
+    // The branch target of the label `depth` blocks out. Blocks and ifs are
+    // branched to at their end, which isn't known yet, so the caller records
+    // where to fill it in.
+    fn target(&self, depth: u32) -> Result<(Target, Option<usize>), Error> {
+        let index = self
+            .labels
+            .len()
+            .checked_sub(depth as usize + 1)
+            .ok_or(Error::Malformed("label depth"))?;
+        let label = &self.labels[index];
+        Ok(match label.kind {
+            LabelKind::Loop => (
+                Target {
+                    pc: label.start,
+                    height: label.height,
+                    keep: label.params,
+                },
+                None,
+            ),
+            _ => (
+                Target {
+                    pc: 0,
+                    height: label.height,
+                    keep: label.results,
+                },
+                Some(index),
+            ),
+        })
+    }
+
+    // Check that the block ends with its results on the stack
+    fn check_results(&self) -> Result<(), Error> {
+        let label = self.labels.last().expect("no label");
+        if !label.unreachable && self.height != label.height + label.results {
+            return Err(Error::Malformed("block leaves the wrong number of values"));
+        }
+        Ok(())
+    }
+
+    fn patch(&mut self, fixup: &Fixup, pc: u32) {
+        match *fixup {
+            Fixup::Op(i) => match &mut self.code[i] {
+                Op::Br(target) | Op::BrIf(target) => target.pc = pc,
+                Op::Jump(to) => *to = pc,
+                _ => unreachable!("fixup of another instruction"),
+            },
+            Fixup::Target(i) => self.targets[i].pc = pc,
+        }
+    }
+
+    fn local(&self, index: u32) -> Result<u32, Error> {
+        match index < self.ty.params.len() as u32 + self.locals {
+            true => Ok(index),
+            false => Err(Error::Malformed("local index")),
+        }
+    }
+
+    fn global(&self, index: u32) -> Result<&Global, Error> {
+        self.module
+            .globals
+            .get(index as usize)
+            .ok_or(Error::Malformed("global index"))
+    }
+
+    fn require_memory(&self) -> Result<(), Error> {
+        match self.module.memory {
+            Some(_) => Ok(()),
+            None => Err(Error::Malformed("memory instruction without a memory")),
+        }
+    }
+
+    fn function_type(&self, index: u32) -> Result<&'a FuncType, Error> {
+        let imports = self.module.imports.len();
+        let ty = match self.module.imports.get(index as usize) {
+            Some(import) => import.ty,
+            None => *self
+                .declared
+                .get(index as usize - imports)
+                .ok_or(Error::Malformed("function index"))?,
+        };
+        Ok(&self.module.types[ty as usize])
+    }
+
+    fn instruction(&mut self, op: u8, body: &mut Reader) -> Result<(), Error> {
+        match op {
+            0x00 => {
+                self.emit(Op::Unreachable);
+                self.set_unreachable();
+            }
+            0x01 => {}
+            0x02 => self.begin(LabelKind::Block, body)?,
+            0x03 => self.begin(LabelKind::Loop, body)?,
+            0x04 => self.begin(LabelKind::If, body)?,
+            0x05 => {
+                let label = self.labels.last().expect("no label");
+                if label.kind != LabelKind::If || label.if_op.is_none() {
+                    return Err(Error::Malformed("else without if"));
+                }
+                self.check_results()?;
+                let jump = self.code.len();
+                self.emit(Op::Jump(0));
+                let else_pc = self.pc();
+                let label = self.label();
+                label.fixups.push(Fixup::Op(jump));
+                let if_op = label.if_op.take().expect("checked");
+                label.unreachable = false;
+                let height = label.height + label.params;
+                self.code[if_op] = Op::If { else_pc };
+                self.height = height;
+            }
+            0x0b => {
+                self.check_results()?;
+                let label = self.labels.pop().expect("no label");
+                if let Some(if_op) = label.if_op {
+                    if label.params != label.results {
+                        return Err(Error::Malformed("if without else changes the stack"));
+                    }
+                    self.code[if_op] = Op::If { else_pc: self.pc() };
+                }
+                let end = self.pc();
+                for fixup in &label.fixups {
+                    self.patch(fixup, end);
+                }
+                self.height = label.height + label.results;
+                if self.labels.is_empty() {
+                    self.emit(Op::Return);
+                }
+            }
+            0x0c | 0x0d => {
+                let depth = body.u32()?;
+                if op == 0x0d {
+                    self.pop(1)?;
+                }
+                let (target, fixup) = self.target(depth)?;
+                self.pop(target.keep)?;
+                if let Some(label) = fixup {
+                    let fixup = Fixup::Op(self.code.len());
+                    self.labels[label].fixups.push(fixup);
+                }
+                if op == 0x0c {
+                    self.emit(Op::Br(target));
+                    self.set_unreachable();
+                } else {
+                    self.emit(Op::BrIf(target));
+                    self.push(target.keep)?;
+                }
+            }
+            0x0e => {
+                let count = body.len()?;
+                let start = self.targets.len() as u32;
+                self.pop(1)?;
+                let mut keep = None;
+                for _ in 0..=count {
+                    let (target, fixup) = self.target(body.u32()?)?;
+                    if keep.is_some_and(|keep| keep != target.keep) {
+                        return Err(Error::Malformed("br_table targets differ"));
+                    }
+                    keep = Some(target.keep);
+                    if let Some(label) = fixup {
+                        let fixup = Fixup::Target(self.targets.len());
+                        self.labels[label].fixups.push(fixup);
+                    }
+                    self.targets.push(target);
+                }
+                self.pop(keep.unwrap_or(0))?;
+                self.emit(Op::BrTable {
+                    start,
+                    len: count as u32 + 1,
+                });
+                self.set_unreachable();
+            }
+            0x0f => {
+                self.pop(self.ty.results.len() as u32)?;
+                self.emit(Op::Return);
+                self.set_unreachable();
+            }
+            0x10 => {
+                let index = body.u32()?;
+                let ty = self.function_type(index)?;
+                self.pop(ty.params.len() as u32)?;
+                self.push(ty.results.len() as u32)?;
+                self.emit(Op::Call(index));
+            }
+            0x11 => {
+                let index = body.u32()?;
+                let ty = self
+                    .module
+                    .types
+                    .get(index as usize)
+                    .ok_or(Error::Malformed("type index"))?;
+                if body.u32()? != 0 || self.module.table.is_none() {
+                    return Err(Error::Malformed("table index"));
+                }
+                self.pop(1)?;
+                self.pop(ty.params.len() as u32)?;
+                self.push(ty.results.len() as u32)?;
+                self.emit(Op::CallIndirect(index));
+            }
+            0x1a => {
+                self.pop(1)?;
+                self.emit(Op::Drop);
+            }
+            0x1b | 0x1c => {
+                if op == 0x1c {
+                    for _ in 0..body.len()? {
+                        body.val_type()?;
+                    }
+                }
+                self.pop(3)?;
+                self.push(1)?;
+                self.emit(Op::Select);
+            }
+            0x20 => {
+                let index = self.local(body.u32()?)?;
+                self.push(1)?;
+                self.emit(Op::LocalGet(index));
+            }
+            0x21 => {
+                let index = self.local(body.u32()?)?;
+                self.pop(1)?;
+                self.emit(Op::LocalSet(index));
+            }
+            0x22 => {
+                let index = self.local(body.u32()?)?;
+                self.pop(1)?;
+                self.push(1)?;
+                self.emit(Op::LocalTee(index));
+            }
+            0x23 => {
+                let index = body.u32()?;
+                self.global(index)?;
+                self.push(1)?;
+                self.emit(Op::GlobalGet(index));
+            }
+            0x24 => {
+                let index = body.u32()?;
+                if !self.global(index)?.mutable {
+                    return Err(Error::Malformed("global is immutable"));
+                }
+                self.pop(1)?;
+                self.emit(Op::GlobalSet(index));
+            }
+            0x28..=0x3e => {
+                self.require_memory()?;
+                // Alignment is only a hint
+                body.u32()?;
+                let offset = body.u32()?;
+                if op <= 0x35 {
+                    self.pop(1)?;
+                    self.push(1)?;
+                    self.emit(Op::Load(op, offset));
+                } else {
+                    self.pop(2)?;
+                    self.emit(Op::Store(op, offset));
+                }
+            }
+            0x3f | 0x40 => {
+                self.require_memory()?;
+                if body.byte()? != 0 {
+                    return Err(Error::Malformed("memory index"));
+                }
+                if op == 0x3f {
+                    self.push(1)?;
+                    self.emit(Op::MemorySize);
+                } else {
+                    self.pop(1)?;
+                    self.push(1)?;
+                    self.emit(Op::MemoryGrow);
+                }
+            }
+            0x41..=0x44 => {
+                let value = match op {
+                    0x41 => body.leb(32, true)? as u32 as u64,
+                    0x42 => body.leb(64, true)?,
+                    0x43 => u32::from_le_bytes(body.array()?) as u64,
+                    _ => u64::from_le_bytes(body.array()?),
+                };
+                self.push(1)?;
+                self.emit(Op::Const(value));
+            }
+            0x45..=0xc4 => {
+                self.pop(if is_binary(op) { 2 } else { 1 })?;
+                self.push(1)?;
+                self.emit(Op::Numeric(op));
+            }
+            0xfc => match body.u32()? {
+                sub @ 0..=7 => {
+                    self.pop(1)?;
+                    self.push(1)?;
+                    self.emit(Op::TruncSat(sub as u8));
+                }
+                10 => {
+                    self.require_memory()?;
+                    if body.byte()? != 0 || body.byte()? != 0 {
+                        return Err(Error::Malformed("memory index"));
+                    }
+                    self.pop(3)?;
+                    self.emit(Op::MemoryCopy);
+                }
+                11 => {
+                    self.require_memory()?;
+                    if body.byte()? != 0 {
+                        return Err(Error::Malformed("memory index"));
+                    }
+                    self.pop(3)?;
+                    self.emit(Op::MemoryFill);
+                }
+                _ => return Err(Error::UnsupportedInstruction(op)),
+            },
+            _ => return Err(Error::UnsupportedInstruction(op)),
+        }
+        Ok(())
+    }
+}
+
+// Whether the numeric instruction `op` takes two operands rather than one
+fn is_binary(op: u8) -> bool {
+    matches!(
+        op,
+        0x46..=0x4f | 0x51..=0x5a | 0x5b..=0x66 | 0x6a..=0x78 | 0x7c..=0x8a | 0x92..=0x98 | 0xa0..=0xa6
+    )
+}
+
+// A function being run, with where its locals and operands start on the
+// stack
+#[derive(Copy, Clone)]
+struct Frame {
+    function: usize,
+    pc: usize,
+    base: usize,
+    operands: usize,
+}
+
+// A module with its memory, table and globals, ready to run
+pub struct Instance {
+    module: Module,
+    // The host function of each import
+    host_functions: Vec<usize>,
+    memory: Vec<u8>,
+    max_pages: u32,
+    table: Vec<Option<u32>>,
+    globals: Vec<u64>,
+    stack: Vec<u64>,
+    // Instructions left to run, unlimited if None
+    fuel: Option<u64>,
+    // Instructions left until the host is asked whether to preempt
+    slice: u32,
+}
+
+impl Instance {
+    // Link `module` with `host` and initialize its memory and table. The
+    // start function isn't run yet, see `start`.
+    pub fn new<H: Host>(module: Module, host: &H) -> Result<Instance, Error> {
+        let mut host_functions = Vec::with_capacity(module.imports.len());
+        for import in &module.imports {
+            let ty = &module.types[import.ty as usize];
+            let index = host
+                .resolve(&import.module, &import.name, ty)
+                .ok_or_else(|| Error::UnknownImport(import.module.clone(), import.name.clone()))?;
+            host_functions.push(index);
+        }
+
+        let mut memory = Vec::new();
+        let mut max_pages = 0;
+        if let Some(limits) = module.memory {
+            if limits.min > MAX_PAGES {
+                return Err(Error::TooLarge);
+            }
+            let len = limits.min as usize * PAGE_SIZE;
+            memory.try_reserve_exact(len).map_err(|_| Error::TooLarge)?;
+            memory.resize(len, 0);
+            max_pages = limits.max.unwrap_or(MAX_PAGES).min(MAX_PAGES);
+        }
+        for (offset, bytes) in &module.data {
+            let start = *offset as usize;
+            let end = start + bytes.len();
+            if end > memory.len() {
+                return Err(Error::SegmentOutOfBounds);
+            }
+            memory[start..end].copy_from_slice(bytes);
+        }
+
+        let mut table = Vec::new();
+        if let Some(limits) = module.table {
+            if limits.min > MAX_TABLE {
+                return Err(Error::TooLarge);
+            }
+            table = vec![None; limits.min as usize];
+        }
+        for (offset, functions) in &module.elements {
+            let start = *offset as usize;
+            let end = start + functions.len();
+            if end > table.len() {
+                return Err(Error::SegmentOutOfBounds);
+            }
+            for (slot, &function) in table[start..end].iter_mut().zip(functions) {
+                *slot = Some(function);
+            }
+        }
+
+        let globals = module.globals.iter().map(|global| global.value).collect();
+        Ok(Instance {
+            module,
+            host_functions,
+            memory,
+            max_pages,
+            table,
+            globals,
+            stack: Vec::new(),
+            fuel: None,
+            slice: FUEL_SLICE,
+        })
+    }
+
+    // Run the start function, if the module has one
+    pub fn start<H: Host>(&mut self, host: &mut H) -> Result<(), Trap> {
+        match self.module.start {
+            Some(start) => self.invoke(host, start, &[]).map(drop),
+            None => Ok(()),
+        }
+    }
+
+    // The index of the exported function `name`
+    pub fn export(&self, name: &str) -> Option<u32> {
+        self.module
+            .exports
+            .iter()
+            .find(|(export, _)| export == name)
+            .map(|&(_, index)| index)
+    }
+
+    pub fn function_type(&self, index: u32) -> &FuncType {
+        self.module.function_type(index)
+    }
+
+    // Limit the instructions the instance may run from now on, None for no
+    // limit. Running out traps with OutOfFuel.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    // Call function `index` with `args`, which must match its parameters,
+    // and return its results
+    pub fn invoke<H: Host>(
+        &mut self,
+        host: &mut H,
+        index: u32,
+        args: &[u64],
+    ) -> Result<Vec<u64>, Trap> {
+        let ty = self.module.function_type(index);
+        assert_eq!(args.len(), ty.params.len(), "wrong number of arguments");
+        let results = ty.results.len();
+        if let Some(&function) = self.host_functions.get(index as usize) {
+            let result = host.call(function, &mut self.memory, args)?;
+            return Ok(vec![result; results]);
+        }
+
+        self.stack.clear();
+        self.stack.extend_from_slice(args);
+        let result = self.execute(host, index as usize - self.host_functions.len());
+        let stack = core::mem::take(&mut self.stack);
+        result.map(|_| stack)
+    }
+
+    // Run the defined function `function` with its arguments on the stack,
+    // leaving its results there
+    fn execute<H: Host>(&mut self, host: &mut H, function: usize) -> Result<(), Trap> {
+        let module = &self.module;
+        let stack = &mut self.stack;
+        let memory = &mut self.memory;
+        let imports = self.host_functions.len();
+        let mut frames: Vec<Frame> = Vec::new();
+        let mut frame = enter(module, stack, function)?;
+        let mut code = &module.functions[function].code[..];
+
+        loop {
+            if self.slice == 0 {
+                self.slice = FUEL_SLICE;
+                host.preempt()?;
+            }
+            self.slice -= 1;
+            if let Some(fuel) = &mut self.fuel {
+                *fuel = fuel.checked_sub(1).ok_or(Trap::OutOfFuel)?;
+            }
+
+            let op = code[frame.pc];
+            frame.pc += 1;
+            let mut callee = None;
+            match op {
+                Op::Unreachable => return Err(Trap::Unreachable),
+                Op::Br(target) => branch(stack, &mut frame, target),
+                Op::BrIf(target) => {
+                    if pop(stack) as u32 != 0 {
+                        branch(stack, &mut frame, target);
+                    }
+                }
+                Op::BrTable { start, len } => {
+                    let index = (pop(stack) as u32).min(len - 1);
+                    let targets = &module.functions[frame.function].targets;
+                    branch(stack, &mut frame, targets[(start + index) as usize]);
+                }
+                Op::If { else_pc } => {
+                    if pop(stack) as u32 == 0 {
+                        frame.pc = else_pc as usize;
+                    }
+                }
+                Op::Jump(pc) => frame.pc = pc as usize,
+                Op::Return => {
+                    let results = module.functions[frame.function].results as usize;
+                    let from = stack.len() - results;
+                    stack.copy_within(from.., frame.base);
+                    stack.truncate(frame.base + results);
+                    match frames.pop() {
+                        Some(caller) => {
+                            frame = caller;
+                            code = &module.functions[frame.function].code;
+                        }
+                        None => return Ok(()),
+                    }
+                }
+                Op::Call(index) => callee = Some(index),
+                Op::CallIndirect(ty) => {
+                    let index = pop(stack) as u32;
+                    let index = self
+                        .table
+                        .get(index as usize)
+                        .ok_or(Trap::UndefinedElement)?
+                        .ok_or(Trap::UninitializedElement)?;
+                    if module.function_type(index) != &module.types[ty as usize] {
+                        return Err(Trap::IndirectCallTypeMismatch);
+                    }
+                    callee = Some(index);
+                }
+                Op::Drop => {
+                    pop(stack);
+                }
+                Op::Select => {
+                    let condition = pop(stack) as u32;
+                    let second = pop(stack);
+                    let first = pop(stack);
+                    stack.push(if condition != 0 { first } else { second });
+                }
+                Op::LocalGet(index) => stack.push(stack[frame.base + index as usize]),
+                Op::LocalSet(index) => stack[frame.base + index as usize] = pop(stack),
+                Op::LocalTee(index) => {
+                    stack[frame.base + index as usize] = *stack.last().expect("validated")
+                }
+                Op::GlobalGet(index) => stack.push(self.globals[index as usize]),
+                Op::GlobalSet(index) => self.globals[index as usize] = pop(stack),
+                Op::Load(op, offset) => {
+                    let address = pop(stack);
+                    stack.push(load(memory, op, address, offset)?);
+                }
+                Op::Store(op, offset) => {
+                    let value = pop(stack);
+                    let address = pop(stack);
+                    store(memory, op, address, offset, value)?;
+                }
+                Op::MemorySize => stack.push((memory.len() / PAGE_SIZE) as u64),
+                Op::MemoryGrow => {
+                    let delta = pop(stack) as u32;
+                    let pages = (memory.len() / PAGE_SIZE) as u32;
+                    stack.push(match grow(memory, pages, delta, self.max_pages) {
+                        true => pages as u64,
+                        false => u32::MAX as u64,
+                    });
+                }
+                Op::MemoryCopy => {
+                    let len = pop(stack) as u32 as usize;
+                    let src = pop(stack) as u32 as usize;
+                    let dst = pop(stack) as u32 as usize;
+                    if src + len > memory.len() || dst + len > memory.len() {
+                        return Err(Trap::MemoryOutOfBounds);
+                    }
+                    memory.copy_within(src..src + len, dst);
+                }
+                Op::MemoryFill => {
+                    let len = pop(stack) as u32 as usize;
+                    let value = pop(stack) as u8;
+                    let dst = pop(stack) as u32 as usize;
+                    memory
+                        .get_mut(dst..dst + len)
+                        .ok_or(Trap::MemoryOutOfBounds)?
+                        .fill(value);
+                }
+                Op::Const(value) => stack.push(value),
+                Op::Numeric(op) => {
+                    let value = if is_binary(op) {
+                        let b = pop(stack);
+                        let a = pop(stack);
+                        binary(op, a, b)?
+                    } else {
+                        unary(op, pop(stack))?
+                    };
+                    stack.push(value);
+                }
+                Op::TruncSat(op) => {
+                    let value = trunc_sat(op, pop(stack));
+                    stack.push(value);
+                }
+            }
+
+            if let Some(index) = callee {
+                let ty = module.function_type(index);
+                if let Some(&function) = self.host_functions.get(index as usize) {
+                    let args = stack.len() - ty.params.len();
+                    let result = host.call(function, memory, &stack[args..])?;
+                    stack.truncate(args);
+                    if !ty.results.is_empty() {
+                        stack.push(result);
+                    }
+                } else {
+                    if frames.len() == MAX_FRAMES {
+                        return Err(Trap::StackOverflow);
+                    }
+                    frames.push(frame);
+                    let function = index as usize - imports;
+                    frame = enter(module, stack, function)?;
+                    code = &module.functions[function].code;
+                }
+            }
+        }
+    }
+}
+
+// Set up the frame of the defined function `function`, whose arguments are
+// on top of the stack
+fn enter(module: &Module, stack: &mut Vec<u64>, function: usize) -> Result<Frame, Trap> {
+    let f = &module.functions[function];
+    let base = stack.len() - f.params as usize;
+    let operands = stack.len() + f.locals as usize;
+    if operands + f.max_height as usize > MAX_STACK {
+        return Err(Trap::StackOverflow);
+    }
+    stack.resize(operands, 0);
+    Ok(Frame {
+        function,
+        pc: 0,
+        base,
+        operands,
+    })
+}
+
+fn branch(stack: &mut Vec<u64>, frame: &mut Frame, target: Target) {
+    let to = frame.operands + target.height as usize;
+    let from = stack.len() - target.keep as usize;
+    if from != to {
+        stack.copy_within(from.., to);
+        stack.truncate(to + target.keep as usize);
+    }
+    frame.pc = target.pc as usize;
+}
+
+fn pop(stack: &mut Vec<u64>) -> u64 {
+    stack
+        .pop()
+        .expect("operand stack underflow in validated code")
+}
+
+fn grow(memory: &mut Vec<u8>, pages: u32, delta: u32, max_pages: u32) -> bool {
+    let new = match pages.checked_add(delta) {
+        Some(new) if new <= max_pages => new as usize * PAGE_SIZE,
+        _ => return false,
+    };
+    if memory.try_reserve_exact(new - memory.len()).is_err() {
+        return false;
+    }
+    memory.resize(new, 0);
+    true
+}
+
+// Bytes accessed by the load or store `op`
+fn access_size(op: u8) -> usize {
+    match op {
+        0x2c | 0x2d | 0x30 | 0x31 | 0x3a | 0x3c => 1,
+        0x2e | 0x2f | 0x32 | 0x33 | 0x3b | 0x3d => 2,
+        0x28 | 0x2a | 0x34 | 0x35 | 0x36 | 0x38 | 0x3e => 4,
+        _ => 8,
+    }
+}
+
+// The bytes at `address` plus `offset`
+fn access(memory: &mut [u8], address: u64, offset: u32, size: usize) -> Result<&mut [u8], Trap> {
+    let start = address as u32 as usize + offset as usize;
+    memory
+        .get_mut(start..start + size)
+        .ok_or(Trap::MemoryOutOfBounds)
+}
+
+fn load(memory: &mut [u8], op: u8, address: u64, offset: u32) -> Result<u64, Trap> {
+    let size = access_size(op);
+    let mut bytes = [0u8; 8];
+    bytes[..size].copy_from_slice(access(memory, address, offset, size)?);
+    let raw = u64::from_le_bytes(bytes);
+    Ok(match op {
+        0x2c => raw as i8 as u32 as u64,
+        0x2e => raw as i16 as u32 as u64,
+        0x30 => raw as i8 as u64,
+        0x32 => raw as i16 as u64,
+        0x34 => raw as i32 as u64,
+        _ => raw,
+    })
+}
+
+fn store(memory: &mut [u8], op: u8, address: u64, offset: u32, value: u64) -> Result<(), Trap> {
+    let size = access_size(op);
+    access(memory, address, offset, size)?.copy_from_slice(&value.to_le_bytes()[..size]);
+    Ok(())
+}
+
+const F32_SIGN: u32 = 0x8000_0000;
+const F64_SIGN: u64 = 0x8000_0000_0000_0000;
+
+fn f32_of(value: u64) -> f32 {
+    f32::from_bits(value as u32)
+}
+
+fn f64_of(value: u64) -> f64 {
+    f64::from_bits(value)
+}
+
+fn from_f32(value: f32) -> u64 {
+    value.to_bits() as u64
+}
+
+fn from_f64(value: f64) -> u64 {
+    value.to_bits()
+}
+
+fn copysign(magnitude: f64, sign: f64) -> f64 {
+    f64::from_bits((magnitude.to_bits() & !F64_SIGN) | (sign.to_bits() & F64_SIGN))
+}
+
+fn abs(value: f64) -> f64 {
+    f64::from_bits(value.to_bits() & !F64_SIGN)
+}
+
+// The rounding functions, which core doesn't have. Values this large are
+// integers already, and f32 values are rounded as f64 without loss.
+const INTEGRAL: f64 = 4503599627370496.0;
+
+fn trunc(x: f64) -> f64 {
+    if x.is_nan() || abs(x) >= INTEGRAL {
+        return x;
+    }
+    copysign(x as i64 as f64, x)
+}
+
+fn floor(x: f64) -> f64 {
+    let t = trunc(x);
+    if t > x {
+        t - 1.0
+    } else {
+        t
+    }
+}
+
+fn ceil(x: f64) -> f64 {
+    let t = trunc(x);
+    if t < x {
+        t + 1.0
+    } else {
+        t
+    }
+}
+
+// Round to the nearest integer, ties to even
+fn nearest(x: f64) -> f64 {
+    if x.is_nan() || abs(x) >= INTEGRAL {
+        return x;
+    }
+    let t = trunc(x);
+    let fraction = abs(x - t);
+    let rounded = if fraction > 0.5 || (fraction == 0.5 && t as i64 % 2 != 0) {
+        t + copysign(1.0, x)
+    } else {
+        t
+    };
+    copysign(rounded, x)
+}
+
+fn sqrt_f32(mut x: f32) -> f32 {
+    unsafe { asm!("sqrtss {0}, {0}", inout(xmm_reg) x, options(pure, nomem, nostack)) };
+    x
+}
+
+fn sqrt_f64(mut x: f64) -> f64 {
+    unsafe { asm!("sqrtsd {0}, {0}", inout(xmm_reg) x, options(pure, nomem, nostack)) };
+    x
+}
+
+// min and max propagate NaN and order -0 below +0
+fn min_f64(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        f64::from_bits(a.to_bits() | b.to_bits())
+    } else if a < b {
+        a
+    } else {
+        b
+    }
+}
+
+fn max_f64(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        f64::from_bits(a.to_bits() & b.to_bits())
+    } else if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+fn min_f32(a: f32, b: f32) -> f32 {
+    if a.is_nan() || b.is_nan() {
+        f32::NAN
+    } else if a == b {
+        f32::from_bits(a.to_bits() | b.to_bits())
+    } else if a < b {
+        a
+    } else {
+        b
+    }
+}
+
+fn max_f32(a: f32, b: f32) -> f32 {
+    if a.is_nan() || b.is_nan() {
+        f32::NAN
+    } else if a == b {
+        f32::from_bits(a.to_bits() & b.to_bits())
+    } else if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+// `x` if it truncates to an integer between `min` and `max`, exclusive
+fn checked_trunc(x: f64, min: f64, max: f64) -> Result<f64, Trap> {
+    if x.is_nan() {
+        Err(Trap::InvalidConversion)
+    } else if x > min && x < max {
+        Ok(x)
+    } else {
+        Err(Trap::IntegerOverflow)
+    }
+}
+
+const I32_MIN: f64 = -2147483649.0;
+const I32_MAX: f64 = 2147483648.0;
+const U32_MAX: f64 = 4294967296.0;
+const I64_MIN: f64 = -9223372036854777856.0;
+const I64_MAX: f64 = 9223372036854775808.0;
+const U64_MAX: f64 = 18446744073709551616.0;
+
+fn unary(op: u8, a: u64) -> Result<u64, Trap> {
+    let i = a as u32;
+    Ok(match op {
+        0x45 => (i == 0) as u64,
+        0x50 => (a == 0) as u64,
+        0x67 => i.leading_zeros() as u64,
+        0x68 => i.trailing_zeros() as u64,
+        0x69 => i.count_ones() as u64,
+        0x79 => a.leading_zeros() as u64,
+        0x7a => a.trailing_zeros() as u64,
+        0x7b => a.count_ones() as u64,
+        0x8b => (i & !F32_SIGN) as u64,
+        0x8c => (i ^ F32_SIGN) as u64,
+        0x8d => from_f32(ceil(f32_of(a) as f64) as f32),
+        0x8e => from_f32(floor(f32_of(a) as f64) as f32),
+        0x8f => from_f32(trunc(f32_of(a) as f64) as f32),
+        0x90 => from_f32(nearest(f32_of(a) as f64) as f32),
+        0x91 => from_f32(sqrt_f32(f32_of(a))),
+        0x99 => a & !F64_SIGN,
+        0x9a => a ^ F64_SIGN,
+        0x9b => from_f64(ceil(f64_of(a))),
+        0x9c => from_f64(floor(f64_of(a))),
+        0x9d => from_f64(trunc(f64_of(a))),
+        0x9e => from_f64(nearest(f64_of(a))),
+        0x9f => from_f64(sqrt_f64(f64_of(a))),
+        0xa7 => i as u64,
+        0xa8 => checked_trunc(f32_of(a) as f64, I32_MIN, I32_MAX)? as i32 as u32 as u64,
+        0xa9 => checked_trunc(f32_of(a) as f64, -1.0, U32_MAX)? as u32 as u64,
+        0xaa => checked_trunc(f64_of(a), I32_MIN, I32_MAX)? as i32 as u32 as u64,
+        0xab => checked_trunc(f64_of(a), -1.0, U32_MAX)? as u32 as u64,
+        0xac => i as i32 as u64,
+        0xad => i as u64,
+        0xae => checked_trunc(f32_of(a) as f64, I64_MIN, I64_MAX)? as i64 as u64,
+        0xaf => checked_trunc(f32_of(a) as f64, -1.0, U64_MAX)? as u64,
+        0xb0 => checked_trunc(f64_of(a), I64_MIN, I64_MAX)? as i64 as u64,
+        0xb1 => checked_trunc(f64_of(a), -1.0, U64_MAX)? as u64,
+        0xb2 => from_f32(i as i32 as f32),
+        0xb3 => from_f32(i as f32),
+        0xb4 => from_f32(a as i64 as f32),
+        0xb5 => from_f32(a as f32),
+        0xb6 => from_f32(f64_of(a) as f32),
+        0xb7 => from_f64(i as i32 as f64),
+        0xb8 => from_f64(i as f64),
+        0xb9 => from_f64(a as i64 as f64),
+        0xba => from_f64(a as f64),
+        0xbb => from_f64(f32_of(a) as f64),
+        // Reinterpretations, the bits are already there
+        0xbc..=0xbf => a,
+        0xc0 => i as i8 as u32 as u64,
+        0xc1 => i as i16 as u32 as u64,
+        0xc2 => a as i8 as u64,
+        0xc3 => a as i16 as u64,
+        0xc4 => a as i32 as u64,
+        _ => unreachable!("not a unary instruction: {:#x}", op),
+    })
+}
+
+fn binary(op: u8, a: u64, b: u64) -> Result<u64, Trap> {
+    let (x, y) = (a as u32, b as u32);
+    let (fx, fy) = (f32_of(a), f32_of(b));
+    let (dx, dy) = (f64_of(a), f64_of(b));
+    Ok(match op {
+        0x46 => (x == y) as u64,
+        0x47 => (x != y) as u64,
+        0x48 => ((x as i32) < (y as i32)) as u64,
+        0x49 => (x < y) as u64,
+        0x4a => (x as i32 > y as i32) as u64,
+        0x4b => (x > y) as u64,
+        0x4c => (x as i32 <= y as i32) as u64,
+        0x4d => (x <= y) as u64,
+        0x4e => (x as i32 >= y as i32) as u64,
+        0x4f => (x >= y) as u64,
+        0x51 => (a == b) as u64,
+        0x52 => (a != b) as u64,
+        0x53 => ((a as i64) < (b as i64)) as u64,
+        0x54 => (a < b) as u64,
+        0x55 => (a as i64 > b as i64) as u64,
+        0x56 => (a > b) as u64,
+        0x57 => (a as i64 <= b as i64) as u64,
+        0x58 => (a <= b) as u64,
+        0x59 => (a as i64 >= b as i64) as u64,
+        0x5a => (a >= b) as u64,
+        0x5b => (fx == fy) as u64,
+        0x5c => (fx != fy) as u64,
+        0x5d => (fx < fy) as u64,
+        0x5e => (fx > fy) as u64,
+        0x5f => (fx <= fy) as u64,
+        0x60 => (fx >= fy) as u64,
+        0x61 => (dx == dy) as u64,
+        0x62 => (dx != dy) as u64,
+        0x63 => (dx < dy) as u64,
+        0x64 => (dx > dy) as u64,
+        0x65 => (dx <= dy) as u64,
+        0x66 => (dx >= dy) as u64,
+        0x6a => x.wrapping_add(y) as u64,
+        0x6b => x.wrapping_sub(y) as u64,
+        0x6c => x.wrapping_mul(y) as u64,
+        0x6d => {
+            if y == 0 {
+                return Err(Trap::DivisionByZero);
+            }
+            (x as i32)
+                .checked_div(y as i32)
+                .ok_or(Trap::IntegerOverflow)? as u32 as u64
+        }
+        0x6e => x.checked_div(y).ok_or(Trap::DivisionByZero)? as u64,
+        0x6f => {
+            if y == 0 {
+                return Err(Trap::DivisionByZero);
+            }
+            (x as i32).wrapping_rem(y as i32) as u32 as u64
+        }
+        0x70 => x.checked_rem(y).ok_or(Trap::DivisionByZero)? as u64,
+        0x71 => (x & y) as u64,
+        0x72 => (x | y) as u64,
+        0x73 => (x ^ y) as u64,
+        0x74 => x.wrapping_shl(y) as u64,
+        0x75 => (x as i32).wrapping_shr(y) as u32 as u64,
+        0x76 => x.wrapping_shr(y) as u64,
+        0x77 => x.rotate_left(y % 32) as u64,
+        0x78 => x.rotate_right(y % 32) as u64,
+        0x7c => a.wrapping_add(b),
+        0x7d => a.wrapping_sub(b),
+        0x7e => a.wrapping_mul(b),
+        0x7f => {
+            if b == 0 {
+                return Err(Trap::DivisionByZero);
+            }
+            (a as i64)
+                .checked_div(b as i64)
+                .ok_or(Trap::IntegerOverflow)? as u64
+        }
+        0x80 => a.checked_div(b).ok_or(Trap::DivisionByZero)?,
+        0x81 => {
+            if b == 0 {
+                return Err(Trap::DivisionByZero);
+            }
+            (a as i64).wrapping_rem(b as i64) as u64
+        }
+        0x82 => a.checked_rem(b).ok_or(Trap::DivisionByZero)?,
+        0x83 => a & b,
+        0x84 => a | b,
+        0x85 => a ^ b,
+        0x86 => a.wrapping_shl(b as u32),
+        0x87 => (a as i64).wrapping_shr(b as u32) as u64,
+        0x88 => a.wrapping_shr(b as u32),
+        0x89 => a.rotate_left((b % 64) as u32),
+        0x8a => a.rotate_right((b % 64) as u32),
+        0x92 => from_f32(fx + fy),
+        0x93 => from_f32(fx - fy),
+        0x94 => from_f32(fx * fy),
+        0x95 => from_f32(fx / fy),
+        0x96 => from_f32(min_f32(fx, fy)),
+        0x97 => from_f32(max_f32(fx, fy)),
+        0x98 => ((x & !F32_SIGN) | (y & F32_SIGN)) as u64,
+        0xa0 => from_f64(dx + dy),
+        0xa1 => from_f64(dx - dy),
+        0xa2 => from_f64(dx * dy),
+        0xa3 => from_f64(dx / dy),
+        0xa4 => from_f64(min_f64(dx, dy)),
+        0xa5 => from_f64(max_f64(dx, dy)),
+        0xa6 => (a & !F64_SIGN) | (b & F64_SIGN),
+        _ => unreachable!("not a binary instruction: {:#x}", op),
+    })
+}
+
+// The saturating conversions, which Rust's `as` does
+fn trunc_sat(op: u8, a: u64) -> u64 {
+    match op {
+        0 => f32_of(a) as i32 as u32 as u64,
+        1 => f32_of(a) as u32 as u64,
+        2 => f64_of(a) as i32 as u32 as u64,
+        3 => f64_of(a) as u32 as u64,
+        4 => f32_of(a) as i64 as u64,
+        5 => f32_of(a) as u64,
+        6 => f64_of(a) as i64 as u64,
+        _ => f64_of(a) as u64,
+    }
+}
+
+// Builds the binary form of a module for tests. Types are given without the
+// 0x60 that starts them, function bodies with their locals.
+#[cfg(test)]
+#[derive(Default)]
+pub struct TestModule<'a> {
+    pub types: &'a [&'a [u8]],
+    pub imports: &'a [(&'a str, &'a str, u32)],
+    pub functions: &'a [(u32, &'a [u8])],
+    pub table: &'a [u32],
+    // Minimum and maximum pages
+    pub memory: Option<(u32, u32)>,
+    pub exports: &'a [(&'a str, u32)],
+    pub data: &'a [(u32, &'a [u8])],
+}
+
+#[cfg(test)]
+impl TestModule<'_> {
+    pub fn encode(&self) -> Vec<u8> {
+        fn leb(out: &mut Vec<u8>, mut value: u32) {
+            loop {
+                let byte = (value & 0x7f) as u8;
+                value >>= 7;
+                if value == 0 {
+                    out.push(byte);
+                    return;
+                }
+                out.push(byte | 0x80);
+            }
+        }
+        fn name(out: &mut Vec<u8>, name: &str) {
+            leb(out, name.len() as u32);
+            out.extend_from_slice(name.as_bytes());
+        }
+        fn section(image: &mut Vec<u8>, id: u8, count: usize, content: &[u8]) {
+            if count == 0 {
+                return;
+            }
+            let mut payload = Vec::new();
+            leb(&mut payload, count as u32);
+            payload.extend_from_slice(content);
+            image.push(id);
+            leb(image, payload.len() as u32);
+            image.extend_from_slice(&payload);
+        }
+        // Offsets are i32.const, which is signed
+        fn offset(out: &mut Vec<u8>, offset: u32) {
+            assert!(offset < 0x2000, "offset too large for the test encoder");
+            out.push(0x41);
+            leb(out, offset);
+            if offset & 0x40 != 0 && offset < 0x80 {
+                // A lone byte with bit 6 set would be negative
+                out.pop();
+                out.extend_from_slice(&[offset as u8 | 0x80, 0x00]);
+            }
+            out.push(0x0b);
+        }
+
+        let mut image = b"\0asm\x01\0\0\0".to_vec();
+        let mut content = Vec::new();
+        for ty in self.types {
+            content.push(0x60);
+            content.extend_from_slice(ty);
+        }
+        section(&mut image, 1, self.types.len(), &content);
+
+        content.clear();
+        for (module, field, ty) in self.imports {
+            name(&mut content, module);
+            name(&mut content, field);
+            content.push(0x00);
+            leb(&mut content, *ty);
+        }
+        section(&mut image, 2, self.imports.len(), &content);
+
+        content.clear();
+        for (ty, _) in self.functions {
+            leb(&mut content, *ty);
+        }
+        section(&mut image, 3, self.functions.len(), &content);
+
+        if !self.table.is_empty() {
+            content.clear();
+            content.extend_from_slice(&[0x70, 0x00]);
+            leb(&mut content, self.table.len() as u32);
+            section(&mut image, 4, 1, &content);
+        }
+        if let Some((min, max)) = self.memory {
+            content.clear();
+            content.push(0x01);
+            leb(&mut content, min);
+            leb(&mut content, max);
+            section(&mut image, 5, 1, &content);
+        }
+
+        content.clear();
+        for (field, index) in self.exports {
+            name(&mut content, field);
+            content.push(0x00);
+            leb(&mut content, *index);
+        }
+        section(&mut image, 7, self.exports.len(), &content);
+
+        if !self.table.is_empty() {
+            content.clear();
+            content.push(0x00);
+            offset(&mut content, 0);
+            leb(&mut content, self.table.len() as u32);
+            for index in self.table {
+                leb(&mut content, *index);
+            }
+            section(&mut image, 9, 1, &content);
+        }
+
+        content.clear();
+        for (_, body) in self.functions {
+            leb(&mut content, body.len() as u32);
+            content.extend_from_slice(body);
+        }
+        section(&mut image, 10, self.functions.len(), &content);
+
+        content.clear();
+        for (at, bytes) in self.data {
+            content.push(0x00);
+            offset(&mut content, *at);
+            leb(&mut content, bytes.len() as u32);
+            content.extend_from_slice(bytes);
+        }
+        section(&mut image, 11, self.data.len(), &content);
+        image
+    }
+}
+
+// Provides env.double, and counts how often it was asked to preempt
+#[cfg(test)]
+#[derive(Default)]
+struct TestHost {
+    preempted: u32,
+}
+
+#[cfg(test)]
+impl Host for TestHost {
+    fn resolve(&self, module: &str, name: &str, ty: &FuncType) -> Option<usize> {
+        let i32_to_i32 = ty.params == [ValType::I32] && ty.results == [ValType::I32];
+        (module == "env" && name == "double" && i32_to_i32).then_some(0)
+    }
+
+    fn call(&mut self, _index: usize, _memory: &mut [u8], args: &[u64]) -> Result<u64, Trap> {
+        Ok((args[0] as u32).wrapping_mul(2) as u64)
+    }
+
+    fn preempt(&mut self) -> Result<(), Trap> {
+        self.preempted += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+const TEST_TYPES: &[&[u8]] = &[
+    &[0x01, 0x7e, 0x01, 0x7e],       // (i64) -> i64
+    &[0x02, 0x7f, 0x7f, 0x01, 0x7f], // (i32, i32) -> i32
+    &[0x00, 0x00],                   // () -> ()
+    &[0x01, 0x7f, 0x01, 0x7f],       // (i32) -> i32
+];
+
+// Function 0 is the import env.double, the others are in order
+#[cfg(test)]
+fn test_instance() -> (Instance, TestHost) {
+    let functions: &[(u32, &[u8])] = &[
+        // 1: factorial, recursively
+        (
+            0,
+            &[
+                0x00, 0x20, 0x00, 0x50, 0x04, 0x7e, 0x42, 0x01, 0x05, 0x20, 0x00, 0x20, 0x00, 0x42,
+                0x01, 0x7d, 0x10, 0x01, 0x7e, 0x0b, 0x0b,
+            ],
+        ),
+        // 2: sum of 1 to n in a loop
+        (
+            3,
+            &[
+                0x01, 0x01, 0x7f, 0x02, 0x40, 0x03, 0x40, 0x20, 0x00, 0x45, 0x0d, 0x01, 0x20, 0x01,
+                0x20, 0x00, 0x6a, 0x21, 0x01, 0x20, 0x00, 0x41, 0x01, 0x6b, 0x21, 0x00, 0x0c, 0x00,
+                0x0b, 0x0b, 0x20, 0x01, 0x0b,
+            ],
+        ),
+        // 3: br_table to 10, 20 or 30
+        (
+            3,
+            &[
+                0x00, 0x02, 0x40, 0x02, 0x40, 0x02, 0x40, 0x20, 0x00, 0x0e, 0x02, 0x00, 0x01, 0x02,
+                0x0b, 0x41, 0x0a, 0x0f, 0x0b, 0x41, 0x14, 0x0f, 0x0b, 0x41, 0x1e, 0x0b,
+            ],
+        ),
+        // 4: i32.div_s
+        (1, &[0x00, 0x20, 0x00, 0x20, 0x01, 0x6d, 0x0b]),
+        // 5: store the argument at 65532, grow memory by a page and return
+        // the value loaded back plus the old size
+        (
+            3,
+            &[
+                0x00, 0x41, 0xfc, 0xff, 0x03, 0x20, 0x00, 0x36, 0x02, 0x00, 0x41, 0xfc, 0xff, 0x03,
+                0x28, 0x02, 0x00, 0x41, 0x01, 0x40, 0x00, 0x6a, 0x0b,
+            ],
+        ),
+        // 6: i32.load
+        (3, &[0x00, 0x20, 0x00, 0x28, 0x02, 0x00, 0x0b]),
+        // 7: call table element a with argument b
+        (1, &[0x00, 0x20, 0x01, 0x20, 0x00, 0x11, 0x03, 0x00, 0x0b]),
+        // 8: unreachable
+        (2, &[0x00, 0x00, 0x0b]),
+        // 9: env.double
+        (3, &[0x00, 0x20, 0x00, 0x10, 0x00, 0x0b]),
+        // 10: an endless loop
+        (2, &[0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x0b]),
+        // 11: endless recursion
+        (2, &[0x00, 0x10, 0x0b, 0x0b]),
+    ];
+    let image = TestModule {
+        types: TEST_TYPES,
+        imports: &[("env", "double", 3)],
+        functions,
+        table: &[1, 2],
+        memory: Some((1, 2)),
+        exports: &[
+            ("fac", 1),
+            ("sum", 2),
+            ("switch", 3),
+            ("div", 4),
+            ("grow", 5),
+            ("load", 6),
+            ("indirect", 7),
+            ("trap", 8),
+            ("double", 9),
+            ("spin", 10),
+            ("recurse", 11),
+        ],
+        data: &[(16, b"wasm")],
+    }
+    .encode();
+    let host = TestHost::default();
+    let module = Module::parse(&image).expect("parse");
+    (Instance::new(module, &host).expect("instantiate"), host)
+}
+
+#[cfg(test)]
+fn call(
+    instance: &mut Instance,
+    host: &mut TestHost,
+    name: &str,
+    args: &[u64],
+) -> Result<u64, Trap> {
+    let index = instance.export(name).expect("exported");
+    let results = instance.invoke(host, index, args)?;
+    Ok(results.first().copied().unwrap_or(0))
+}
+
+#[test_case]
+fn run_module() {
+    print!("wasm interpreter... ");
+    let (mut instance, mut host) = test_instance();
+    let instance = &mut instance;
+    let host = &mut host;
+    assert_eq!(call(instance, host, "fac", &[20]), Ok(2432902008176640000));
+    assert_eq!(call(instance, host, "sum", &[100]), Ok(5050));
+    assert_eq!(call(instance, host, "switch", &[0]), Ok(10));
+    assert_eq!(call(instance, host, "switch", &[1]), Ok(20));
+    assert_eq!(call(instance, host, "switch", &[7]), Ok(30));
+    assert_eq!(
+        call(instance, host, "div", &[7, (-2i32) as u32 as u64]),
+        Ok((-3i32) as u32 as u64)
+    );
+    assert_eq!(call(instance, host, "double", &[21]), Ok(42));
+    assert_eq!(call(instance, host, "indirect", &[1, 10]), Ok(55));
+    assert_eq!(
+        call(instance, host, "load", &[16]),
+        Ok(u32::from_le_bytes(*b"wasm") as u64)
+    );
+    // Grows to the maximum of two pages, then fails with -1
+    assert_eq!(call(instance, host, "grow", &[41]), Ok(42));
+    assert_eq!(call(instance, host, "grow", &[41]), Ok(40));
+    println!("[ok]");
+}
+
+#[test_case]
+fn module_traps() {
+    print!("wasm traps... ");
+    let (mut instance, mut host) = test_instance();
+    let instance = &mut instance;
+    let host = &mut host;
+    assert_eq!(
+        call(instance, host, "div", &[1, 0]),
+        Err(Trap::DivisionByZero)
+    );
+    let min = i32::MIN as u32 as u64;
+    let minus_one = u32::MAX as u64;
+    assert_eq!(
+        call(instance, host, "div", &[min, minus_one]),
+        Err(Trap::IntegerOverflow)
+    );
+    assert_eq!(
+        call(instance, host, "load", &[0xfffe]),
+        Err(Trap::MemoryOutOfBounds)
+    );
+    assert_eq!(call(instance, host, "trap", &[]), Err(Trap::Unreachable));
+    assert_eq!(
+        call(instance, host, "indirect", &[0, 1]),
+        Err(Trap::IndirectCallTypeMismatch)
+    );
+    assert_eq!(
+        call(instance, host, "indirect", &[2, 1]),
+        Err(Trap::UndefinedElement)
+    );
+    assert_eq!(
+        call(instance, host, "recurse", &[]),
+        Err(Trap::StackOverflow)
+    );
+    // Still usable afterwards
+    assert_eq!(call(instance, host, "sum", &[10]), Ok(55));
+
+    assert!(matches!(
+        Module::parse(b"\0asm\x02\0\0\0"),
+        Err(Error::Unsupported(_))
+    ));
+    // A function popping more than it pushed
+    let image = TestModule {
+        types: TEST_TYPES,
+        functions: &[(3, &[0x00, 0x6a, 0x0b])],
+        ..TestModule::default()
+    }
+    .encode();
+    assert!(matches!(Module::parse(&image), Err(Error::Malformed(_))));
+    println!("[ok]");
+}
+
+#[test_case]
+fn fuel() {
+    print!("wasm fuel... ");
+    let (mut instance, mut host) = test_instance();
+    instance.set_fuel(Some(10 * FUEL_SLICE as u64));
+    let spin = instance.export("spin").unwrap();
+    assert_eq!(instance.invoke(&mut host, spin, &[]), Err(Trap::OutOfFuel));
+    assert_eq!(instance.fuel(), Some(0));
+    // The host got to preempt it along the way
+    assert!(host.preempted >= 9);
+    println!("[ok]");
+}
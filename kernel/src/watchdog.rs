@@ -0,0 +1,322 @@
+use crate::cpu::{self, Feature};
+use crate::interrupt::{self, TrapFrame};
+use crate::ipi::{self, DeliveryMode};
+use crate::percpu::{self, PerCpu};
+use crate::{hpet, serial_println};
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::instructions::port::PortRead;
+use x86_64::registers::model_specific::Msr;
+
+// Report a hard lockup once the timer has not ticked for this long
+const LOCKUP_THRESHOLD_SECS: u64 = 60;
+
+// Intel 64 and IA-32 Architectures Software Developer's Manual Vol. 3B
+// 20.2 Architectural Performance Monitoring
+const IA32_PMC0: u32 = 0xc1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38e;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+// UnHalted Core Cycles, counted in both user and kernel mode with an
+// interrupt on overflow
+const EVENT_UNHALTED_CORE_CYCLES: u64 = 0x3c;
+const PERFEVTSEL_USR: u64 = 1 << 16;
+const PERFEVTSEL_OS: u64 = 1 << 17;
+const PERFEVTSEL_INT: u64 = 1 << 20;
+const PERFEVTSEL_EN: u64 = 1 << 22;
+
+// Cycles between two watchdog NMIs. Writes to the counter are sign extended
+// from 32 bits, so the period has to fit in 31 bits. This is about a second on
+// current hardware.
+const PERF_PERIOD_CYCLES: u64 = 1 << 30;
+
+// LVT delivery mode for non-maskable interrupts
+const DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+
+// Port 0x61 (system control port B) reports the source of legacy NMIs
+const NMI_STATUS_PORT: u16 = 0x61;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+enum Source {
+    None = 0,
+    PerformanceCounter,
+    Hpet,
+}
+
+static SOURCE: AtomicU8 = AtomicU8::new(Source::None as u8);
+
+fn source() -> Source {
+    match SOURCE.load(Ordering::Relaxed) {
+        1 => Source::PerformanceCounter,
+        2 => Source::Hpet,
+        _ => Source::None,
+    }
+}
+
+// Start a periodic NMI which checks that the timer interrupt keeps firing.
+// The performance counter is preferred as it keeps counting while interrupts
+// are disabled and every processor has its own, the HPET is used when the CPU
+// has no architectural PMU and only interrupts the bootstrap processor, which
+// then checks all processors.
+pub fn initialize() {
+    // Both sources deliver the NMI through the local APIC
+    if !interrupt::has_apic() {
+        serial_println!("watchdog: no local APIC, hard lockups will not be detected");
+        return;
+    }
+
+    let source = if start_performance_counter() {
+        Source::PerformanceCounter
+    } else if start_hpet() {
+        Source::Hpet
+    } else {
+        serial_println!("watchdog: no NMI source available, hard lockups will not be detected");
+        return;
+    };
+
+    SOURCE.store(source as u8, Ordering::Relaxed);
+    serial_println!("watchdog: using {:?}", source);
+}
+
+// Application processors only need their own performance counter started
+pub fn initialize_ap() {
+    if source() == Source::PerformanceCounter {
+        start_performance_counter();
+    }
+}
+
+fn start_performance_counter() -> bool {
+    if !cpu::has(Feature::ArchPerfmon) {
+        return false;
+    }
+    let version = cpu::info().perfmon_version;
+
+    unsafe {
+        Msr::new(IA32_PERFEVTSEL0).write(0);
+    }
+    reload_performance_counter();
+    unsafe {
+        Msr::new(IA32_PERFEVTSEL0).write(
+            EVENT_UNHALTED_CORE_CYCLES
+                | PERFEVTSEL_USR
+                | PERFEVTSEL_OS
+                | PERFEVTSEL_INT
+                | PERFEVTSEL_EN,
+        );
+        if version >= 2 {
+            let mut ctrl = Msr::new(IA32_PERF_GLOBAL_CTRL);
+            let value = ctrl.read();
+            ctrl.write(value | 1);
+        }
+    }
+
+    true
+}
+
+fn reload_performance_counter() {
+    unsafe {
+        Msr::new(IA32_PMC0).write(PERF_PERIOD_CYCLES.wrapping_neg() & 0xffff_ffff);
+    }
+    // The local APIC masks the LVT entry each time a counter overflow is
+    // delivered, so it has to be rearmed on every NMI
+    interrupt::local_apic().write(
+        interrupt::Offset::PerformanceCounterLocalVectorTableEntry,
+        DELIVERY_MODE_NMI,
+    );
+}
+
+// Whether PMC0 has overflowed since it was last reloaded
+fn performance_counter_overflowed() -> bool {
+    unsafe {
+        if cpu::info().perfmon_version >= 2 {
+            Msr::new(IA32_PERF_GLOBAL_STATUS).read() & 1 != 0
+        } else {
+            // The counter was loaded with a negative value, so the top bit is
+            // clear only once it has wrapped around
+            Msr::new(IA32_PMC0).read() & (1 << 31) == 0
+        }
+    }
+}
+
+fn start_hpet() -> bool {
+    let hpet = match hpet::initialize() {
+        Some(hpet) => hpet,
+        None => return false,
+    };
+    let timer = match hpet.find_periodic_fsb_timer() {
+        Some(timer) => timer,
+        None => return false,
+    };
+
+    // MSI address and data targeting this CPU's local APIC with NMI delivery
+    let address = 0xfee0_0000 | interrupt::local_apic().id() << 12;
+    hpet.start_periodic_fsb(timer, hpet.ticks_per_second(), address, DELIVERY_MODE_NMI);
+
+    true
+}
+
+pub fn handle_nmi(frame: &mut TrapFrame) {
+    // Another processor found this one stuck and wants its registers
+    if percpu::current()
+        .watchdog_dump_requested
+        .swap(false, Ordering::AcqRel)
+    {
+        dump(frame);
+        return;
+    }
+
+    let is_watchdog = match source() {
+        Source::PerformanceCounter => {
+            if performance_counter_overflowed() {
+                if cpu::info().perfmon_version >= 2 {
+                    unsafe {
+                        Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1);
+                    }
+                }
+                reload_performance_counter();
+                true
+            } else {
+                false
+            }
+        }
+        // HPET messages carry no status we could check, assume every NMI
+        // is a watchdog tick
+        Source::Hpet => true,
+        Source::None => false,
+    };
+
+    if is_watchdog {
+        check_progress(frame);
+    } else {
+        let status: u8 = unsafe { u8::read_from_port(NMI_STATUS_PORT) };
+        serial_println!("NMI received (status port 0x{:02x})", status);
+        dump(frame);
+    }
+}
+
+fn check_progress(frame: &TrapFrame) {
+    let current = percpu::current();
+
+    if source() == Source::PerformanceCounter {
+        if check_cpu(current) {
+            dump(frame);
+        }
+        return;
+    }
+
+    // Only this processor receives the HPET NMI, ask stuck ones for a dump
+    // with an NMI IPI since they may be running with interrupts disabled
+    for cpu in percpu::online() {
+        if !check_cpu(cpu) {
+            continue;
+        }
+        if cpu.cpu_index == current.cpu_index {
+            dump(frame);
+        } else {
+            cpu.watchdog_dump_requested.store(true, Ordering::Release);
+            ipi::send_to_cpu(cpu.cpu_index, DeliveryMode::Nmi);
+        }
+    }
+}
+
+// Returns true the first time `cpu` is found to have been stuck for longer than
+// the threshold
+fn check_cpu(cpu: &PerCpu) -> bool {
+    let ticks = cpu.ticks.load(Ordering::Relaxed);
+
+    if ticks != cpu.watchdog_last_ticks.swap(ticks, Ordering::Relaxed) {
+        cpu.watchdog_stalled.store(0, Ordering::Relaxed);
+        return false;
+    }
+
+    let stalled = cpu.watchdog_stalled.fetch_add(1, Ordering::Relaxed) + 1;
+    if stalled != LOCKUP_THRESHOLD_SECS {
+        return false;
+    }
+
+    serial_println!(
+        "\nwatchdog: hard lockup detected on CPU {}, no timer interrupt for {} seconds",
+        cpu.cpu_index,
+        stalled
+    );
+    true
+}
+
+fn dump(frame: &TrapFrame) {
+    let sf = &frame.stack_frame;
+
+    serial_println!(
+        "RIP: {:#018x} CS: {:#06x} RFLAGS: {:#018x}",
+        sf.instruction_pointer.as_u64(),
+        sf.code_segment,
+        sf.cpu_flags
+    );
+    serial_println!(
+        "RSP: {:#018x} SS: {:#06x}",
+        sf.stack_pointer.as_u64(),
+        sf.stack_segment
+    );
+    serial_println!(
+        "RAX: {:#018x} RBX: {:#018x} RCX: {:#018x}",
+        frame.rax,
+        frame.rbx,
+        frame.rcx
+    );
+    serial_println!(
+        "RDX: {:#018x} RSI: {:#018x} RDI: {:#018x}",
+        frame.rdx,
+        frame.rsi,
+        frame.rdi
+    );
+    serial_println!(
+        "RBP: {:#018x} R8:  {:#018x} R9:  {:#018x}",
+        frame.rbp,
+        frame.r8,
+        frame.r9
+    );
+    serial_println!(
+        "R10: {:#018x} R11: {:#018x} R12: {:#018x}",
+        frame.r10,
+        frame.r11,
+        frame.r12
+    );
+    serial_println!(
+        "R13: {:#018x} R14: {:#018x} R15: {:#018x}",
+        frame.r13,
+        frame.r14,
+        frame.r15
+    );
+
+    backtrace(sf.instruction_pointer.as_u64(), frame.rbp);
+}
+
+// Walk the frame pointer chain of the interrupted code. The kernel is built
+// with frame pointers enabled, so each frame starts with the caller's RBP
+// followed by the return address.
+pub fn backtrace(rip: u64, mut rbp: u64) {
+    const MAX_DEPTH: usize = 32;
+
+    serial_println!("Backtrace:");
+    serial_println!("  #0  {:#018x}", rip);
+
+    for depth in 1..MAX_DEPTH {
+        if rbp == 0 || rbp & 7 != 0 {
+            break;
+        }
+
+        let frame = rbp as *const u64;
+        let (next, ret) = unsafe { (*frame, *frame.add(1)) };
+        if ret == 0 {
+            break;
+        }
+        serial_println!("  #{:<2} {:#018x}", depth, ret);
+
+        // Stacks grow down, so callers' frames are always at higher addresses
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
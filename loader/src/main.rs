@@ -91,13 +91,10 @@ fn efi_main(image: Handle, mut st: SystemTable<Boot>) -> Status {
     for d in memory_descriptor {
         // Unified Extensible Firmware Interface (UEFI) Specification, version 2.8
         // 7.2 Memory Allocation Services
-        // Check available memory after calling exit boot services
-        if matches!(
-            d.ty,
-            MemoryType::CONVENTIONAL
-                | MemoryType::BOOT_SERVICES_CODE
-                | MemoryType::BOOT_SERVICES_DATA
-        ) {
+        // Check available memory after calling exit boot services. Boot
+        // services memory is left out, the kernel still runs on the stack and
+        // page tables the firmware allocated there.
+        if d.ty == MemoryType::CONVENTIONAL {
             descriptors.push(memory::MemoryDescriptor {
                 phys_start: d.phys_start,
                 page_count: d.page_count,
@@ -7,6 +7,7 @@ KERNEL=./target/x86_64-unknown-rustyos/release/kernel.elf
 MOUNT_DIR=./mnt
 OVMF_DIR=./OVMF
 QEMU_OPT=
+SMP_OPT=
 
 for OPT in "$@"
 do
@@ -21,6 +22,10 @@ case $OPT in
         QEMU_OPT="-monitor stdio"
         echo monitor
         ;;
+    "--smp")
+        # Start 4 processors to test application processor bring-up
+        SMP_OPT="-smp 4"
+        ;;
     "--cui")
         # Disable GUI
         QEMU_OPT="-chardev stdio,mux=on,id=com1 \
@@ -46,7 +51,7 @@ esac
 shift
 done
 
-QEMU_OPT="${QEMU_OPT} \
+QEMU_OPT="${QEMU_OPT} ${SMP_OPT} \
     -drive if=pflash,format=raw,readonly,file=${OVMF_DIR}/OVMF_CODE.fd \
     -drive if=pflash,format=raw,file=${OVMF_DIR}/OVMF_VARS.fd \
     -drive format=raw,file=fat:rw:${MOUNT_DIR}"
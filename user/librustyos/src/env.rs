@@ -0,0 +1,83 @@
+use core::slice;
+use core::str;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+// Set by the entry point from the initial stack, which the strings live on
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+static ENVP: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+
+pub(crate) unsafe fn initialize(argc: usize, argv: *const *const u8, envp: *const *const u8) {
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv as *mut _, Ordering::Relaxed);
+    ENVP.store(envp as *mut _, Ordering::Relaxed);
+}
+
+// The bytes of the NUL terminated string at `string`
+unsafe fn c_str(string: *const u8) -> &'static [u8] {
+    let mut len = 0;
+    while *string.add(len) != 0 {
+        len += 1;
+    }
+    slice::from_raw_parts(string, len)
+}
+
+// Strings that aren't UTF-8 are replaced by U+FFFD
+fn to_str(bytes: &'static [u8]) -> &'static str {
+    str::from_utf8(bytes).unwrap_or("\u{fffd}")
+}
+
+// The strings of a null terminated array of pointers
+#[derive(Clone)]
+pub struct CStrings(*const *const u8);
+
+impl Iterator for CStrings {
+    type Item = &'static [u8];
+
+    fn next(&mut self) -> Option<&'static [u8]> {
+        if self.0.is_null() {
+            return None;
+        }
+        let string = unsafe { *self.0 };
+        if string.is_null() {
+            return None;
+        }
+        self.0 = unsafe { self.0.add(1) };
+        Some(unsafe { c_str(string) })
+    }
+}
+
+// The arguments the program was started with, the program name first
+pub fn args_bytes() -> CStrings {
+    CStrings(ARGV.load(Ordering::Relaxed))
+}
+
+pub fn args() -> impl Iterator<Item = &'static str> + Clone {
+    args_bytes().map(to_str)
+}
+
+pub fn arg_count() -> usize {
+    ARGC.load(Ordering::Relaxed)
+}
+
+// The environment as "name=value" strings
+pub fn vars_bytes() -> CStrings {
+    CStrings(ENVP.load(Ordering::Relaxed))
+}
+
+// The environment as names and values
+pub fn vars() -> impl Iterator<Item = (&'static str, &'static str)> + Clone {
+    vars_bytes().map(|var| {
+        let split = var
+            .iter()
+            .position(|&byte| byte == b'=')
+            .unwrap_or(var.len());
+        let value = var.get(split + 1..).unwrap_or(&[]);
+        (to_str(&var[..split]), to_str(value))
+    })
+}
+
+// The value of the environment variable `name`
+pub fn var(name: &str) -> Option<&'static str> {
+    vars().find(|&(key, _)| key == name).map(|(_, value)| value)
+}
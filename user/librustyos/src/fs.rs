@@ -0,0 +1,78 @@
+use crate::syscall::*;
+
+// A file descriptor
+pub type Fd = u32;
+
+// Access modes of `open`
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+
+// Longest path `open` and `execve` take, with the terminating NUL
+pub const PATH_MAX: usize = 4096;
+
+// `path` with a terminating NUL in `buffer`
+pub(crate) fn c_path<'a>(path: &str, buffer: &'a mut [u8; PATH_MAX]) -> Result<&'a [u8]> {
+    if path.len() >= PATH_MAX {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    if path.as_bytes().contains(&0) {
+        return Err(Errno::EINVAL);
+    }
+    buffer[..path.len()].copy_from_slice(path.as_bytes());
+    buffer[path.len()] = 0;
+    Ok(&buffer[..=path.len()])
+}
+
+pub fn open(path: &str, flags: u64) -> Result<Fd> {
+    let mut buffer = [0u8; PATH_MAX];
+    let path = c_path(path, &mut buffer)?;
+    let fd = result(unsafe { syscall2(SYS_OPEN, path.as_ptr() as u64, flags) })?;
+    Ok(fd as Fd)
+}
+
+pub fn close(fd: Fd) -> Result<()> {
+    result(unsafe { syscall1(SYS_CLOSE, fd as u64) }).map(drop)
+}
+
+// Bytes read into `buffer`, zero at the end of the file
+pub fn read(fd: Fd, buffer: &mut [u8]) -> Result<usize> {
+    let n = unsafe {
+        syscall3(
+            SYS_READ,
+            fd as u64,
+            buffer.as_mut_ptr() as u64,
+            buffer.len() as u64,
+        )
+    };
+    result(n).map(|n| n as usize)
+}
+
+// Bytes written from `data`
+pub fn write(fd: Fd, data: &[u8]) -> Result<usize> {
+    let n = unsafe {
+        syscall3(
+            SYS_WRITE,
+            fd as u64,
+            data.as_ptr() as u64,
+            data.len() as u64,
+        )
+    };
+    result(n).map(|n| n as usize)
+}
+
+pub fn dup(fd: Fd) -> Result<Fd> {
+    result(unsafe { syscall1(SYS_DUP, fd as u64) }).map(|fd| fd as Fd)
+}
+
+// Make `new` refer to the file of `old`
+pub fn dup2(old: Fd, new: Fd) -> Result<Fd> {
+    result(unsafe { syscall2(SYS_DUP2, old as u64, new as u64) }).map(|fd| fd as Fd)
+}
+
+// A new pipe as its read and write ends
+pub fn pipe() -> Result<(Fd, Fd)> {
+    let mut fds = [0u32; 2];
+    result(unsafe { syscall1(SYS_PIPE, fds.as_mut_ptr() as u64) })?;
+    Ok((fds[0], fds[1]))
+}
@@ -0,0 +1,66 @@
+use crate::syscall::{syscall1, SYS_BRK};
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+
+// The heap grows by at least this much at a time
+const GROW_SIZE: usize = 64 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: BrkAllocator = BrkAllocator(Mutex::new(Heap::empty()));
+
+// A heap after the program's segments that grows with brk when it runs out
+// of room. Signal handlers must not allocate, the lock isn't reentrant.
+struct BrkAllocator(Mutex<Heap>);
+
+// Move the end of the heap to `end`, returning where it is now
+fn brk(end: usize) -> usize {
+    unsafe { syscall1(SYS_BRK, end as u64) as usize }
+}
+
+impl BrkAllocator {
+    // Make room for an allocation of `layout`, false if there is no memory
+    fn grow(heap: &mut Heap, layout: Layout) -> bool {
+        // Enough for the allocation at any alignment, with the allocator's
+        // bookkeeping
+        let needed = layout.size() + layout.align() + 4 * size_of::<usize>();
+        let by = needed.div_ceil(GROW_SIZE) * GROW_SIZE;
+        if heap.size() == 0 {
+            // Aligned, so that the heap ends exactly at the break
+            let start = (brk(0) + 15) & !15;
+            if brk(start + by) != start + by {
+                return false;
+            }
+            unsafe { heap.init(start as *mut u8, by) };
+        } else {
+            let end = heap.top() as usize;
+            if brk(end + by) != end + by {
+                return false;
+            }
+            unsafe { heap.extend(by) };
+        }
+        true
+    }
+}
+
+unsafe impl GlobalAlloc for BrkAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.0.lock();
+        if let Ok(p) = heap.allocate_first_fit(layout) {
+            return p.as_ptr();
+        }
+        if !BrkAllocator::grow(&mut heap, layout) {
+            return ptr::null_mut();
+        }
+        heap.allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), |p| p.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0
+            .lock()
+            .deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+}
@@ -0,0 +1,29 @@
+// Runtime for rustyos programs: the entry point, system call wrappers, a heap
+// and printing. A program looks like
+//
+//     #![no_std]
+//     #![no_main]
+//
+//     use librustyos::println;
+//
+//     librustyos::entry!(main);
+//
+//     fn main() {
+//         println!("hello");
+//     }
+#![no_std]
+
+extern crate alloc;
+
+pub mod env;
+pub mod fs;
+mod heap;
+pub mod io;
+pub mod process;
+#[doc(hidden)]
+pub mod rt;
+pub mod signal;
+pub mod syscall;
+pub mod time;
+
+pub use syscall::{Errno, Result};
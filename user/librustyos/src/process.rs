@@ -0,0 +1,139 @@
+use crate::fs::{c_path, PATH_MAX};
+use crate::syscall::*;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr;
+use core::time::Duration;
+
+pub type Pid = u64;
+
+pub fn exit(status: i32) -> ! {
+    unsafe { syscall1(SYS_EXIT, status as u64) };
+    unreachable!("exit returned")
+}
+
+pub fn getpid() -> Pid {
+    unsafe { syscall0(SYS_GETPID) }
+}
+
+// Zero if there is no parent
+pub fn getppid() -> Pid {
+    unsafe { syscall0(SYS_GETPPID) }
+}
+
+// ID of the calling thread
+pub fn gettid() -> u64 {
+    unsafe { syscall0(SYS_GETTID) }
+}
+
+pub fn yield_now() {
+    unsafe { syscall0(SYS_YIELD) };
+}
+
+// The ID of the child in the parent, zero in the child
+pub fn fork() -> Result<Pid> {
+    result(unsafe { syscall0(SYS_FORK) })
+}
+
+// Replace the program of this process with the one at `path`. Returns only
+// if that fails.
+pub fn execve(path: &str, argv: &[&str], envp: &[&str]) -> Errno {
+    let mut buffer = [0u8; PATH_MAX];
+    let path = match c_path(path, &mut buffer) {
+        Ok(path) => path,
+        Err(error) => return error,
+    };
+    // NUL terminated copies of the strings, and null terminated arrays of
+    // pointers to them
+    let argv: Vec<Vec<u8>> = argv.iter().map(|arg| c_string(arg)).collect();
+    let envp: Vec<Vec<u8>> = envp.iter().map(|var| c_string(var)).collect();
+    let pointers = |strings: &[Vec<u8>]| -> Vec<*const u8> {
+        let pointers = strings.iter().map(|string| string.as_ptr());
+        pointers.chain(Some(ptr::null())).collect()
+    };
+    let (argv, envp) = (pointers(&argv), pointers(&envp));
+    let ret = unsafe {
+        syscall3(
+            SYS_EXECVE,
+            path.as_ptr() as u64,
+            argv.as_ptr() as u64,
+            envp.as_ptr() as u64,
+        )
+    };
+    match result(ret) {
+        Err(error) => error,
+        Ok(_) => unreachable!("execve returned"),
+    }
+}
+
+fn c_string(string: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(string.len() + 1);
+    bytes.extend_from_slice(string.as_bytes());
+    bytes.push(0);
+    bytes
+}
+
+// How a process ended, as `wait` reports it
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct ExitStatus(pub u32);
+
+impl ExitStatus {
+    // The status it passed to `exit`
+    pub fn code(self) -> Option<i32> {
+        match self.0 & 0x7f {
+            0 => Some(((self.0 >> 8) & 0xff) as i32),
+            _ => None,
+        }
+    }
+
+    // The signal that killed it
+    pub fn signal(self) -> Option<i32> {
+        match self.0 & 0x7f {
+            0 => None,
+            sig => Some(sig as i32),
+        }
+    }
+
+    pub fn success(self) -> bool {
+        self.code() == Some(0)
+    }
+}
+
+impl fmt::Debug for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.signal() {
+            Some(sig) => write!(f, "killed by signal {}", sig),
+            None => write!(f, "exit status {}", (self.0 >> 8) & 0xff),
+        }
+    }
+}
+
+// Options of `wait` for not blocking, and for blocking for at most the
+// timeout in its last argument
+const WNOHANG: u64 = 1;
+const WTIMEOUT: u64 = 2;
+
+// Wait for the child `pid` to exit, or any child if None
+pub fn wait(pid: Option<Pid>) -> Result<(Pid, ExitStatus)> {
+    wait_options(pid, 0, 0).map(|exited| exited.expect("wait returned without a child"))
+}
+
+// Like `wait`, but None right away if no such child exited yet
+pub fn try_wait(pid: Option<Pid>) -> Result<Option<(Pid, ExitStatus)>> {
+    wait_options(pid, WNOHANG, 0)
+}
+
+// Like `wait`, but None once `timeout` is over. Having no children isn't an
+// error, for init, which may still get orphans.
+pub fn wait_timeout(pid: Option<Pid>, timeout: Duration) -> Result<Option<(Pid, ExitStatus)>> {
+    let nanos = timeout.as_nanos().min(u64::MAX as u128) as u64;
+    wait_options(pid, WTIMEOUT, nanos)
+}
+
+fn wait_options(pid: Option<Pid>, options: u64, timeout: u64) -> Result<Option<(Pid, ExitStatus)>> {
+    let pid = pid.map_or(-1, |pid| pid as i64);
+    let mut status = 0u32;
+    let status_pointer = &mut status as *mut u32 as u64;
+    let pid = result(unsafe { syscall4(SYS_WAIT, pid as u64, status_pointer, options, timeout) })?;
+    Ok((pid != 0).then_some((pid, ExitStatus(status))))
+}
@@ -0,0 +1,92 @@
+use crate::{env, eprintln, process};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+
+// The kernel starts programs here with the stack pointer at argc, followed by
+// the null terminated argv and envp arrays and the auxiliary vector. Clear
+// the frame pointer to end backtraces, and call `start` with the stack
+// pointer on an aligned stack.
+global_asm!(
+    ".global _start",
+    "_start:",
+    "xor ebp, ebp",
+    "mov rdi, rsp",
+    "and rsp, -16",
+    "call {start}",
+    "ud2",
+    start = sym start,
+);
+
+// An ELF note telling the kernel that this is a native program, which makes
+// rustyos system calls rather than Linux ones. It matches NOTE_NAME and
+// NT_RUSTYOS_ABI in the kernel's elf.rs.
+global_asm!(
+    ".section .note.rustyos, \"a\", @note",
+    ".balign 4",
+    ".long 8", // name size, with the NUL
+    ".long 4", // descriptor size
+    ".long 1", // type
+    ".asciz \"rustyos\"",
+    ".long 0", // descriptor: ABI version
+    ".previous",
+);
+
+extern "Rust" {
+    // The program's main function, defined by `entry!`
+    fn rustyos_main() -> i32;
+}
+
+unsafe extern "C" fn start(stack: *const usize) -> ! {
+    let argc = *stack;
+    let argv = stack.add(1) as *const *const u8;
+    let envp = argv.add(argc + 1);
+    env::initialize(argc, argv, envp);
+    process::exit(rustyos_main())
+}
+
+// Make `main` the program's main function. It takes no arguments, see
+// `env::args`, and returns nothing or an exit status.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[export_name = "rustyos_main"]
+        fn __rustyos_main() -> i32 {
+            $crate::rt::Termination::report($main())
+        }
+    };
+}
+
+// What a main function can return
+pub trait Termination {
+    fn report(self) -> i32;
+}
+
+impl Termination for () {
+    fn report(self) -> i32 {
+        0
+    }
+}
+
+impl Termination for i32 {
+    fn report(self) -> i32 {
+        self
+    }
+}
+
+impl<E: core::fmt::Debug> Termination for Result<(), E> {
+    fn report(self) -> i32 {
+        match self {
+            Ok(()) => 0,
+            Err(error) => {
+                eprintln!("Error: {:?}", error);
+                1
+            }
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{}", info);
+    process::exit(101)
+}
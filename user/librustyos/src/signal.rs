@@ -0,0 +1,153 @@
+use crate::process::Pid;
+use crate::syscall::*;
+use core::arch::global_asm;
+
+// Signal numbers, with the values Linux uses on x86-64
+pub const SIGHUP: i32 = 1;
+pub const SIGINT: i32 = 2;
+pub const SIGQUIT: i32 = 3;
+pub const SIGILL: i32 = 4;
+pub const SIGTRAP: i32 = 5;
+pub const SIGABRT: i32 = 6;
+pub const SIGBUS: i32 = 7;
+pub const SIGFPE: i32 = 8;
+pub const SIGKILL: i32 = 9;
+pub const SIGUSR1: i32 = 10;
+pub const SIGSEGV: i32 = 11;
+pub const SIGUSR2: i32 = 12;
+pub const SIGPIPE: i32 = 13;
+pub const SIGALRM: i32 = 14;
+pub const SIGTERM: i32 = 15;
+pub const SIGCHLD: i32 = 17;
+pub const SIGCONT: i32 = 18;
+pub const SIGSTOP: i32 = 19;
+
+// `SigAction` flags
+pub const SA_SIGINFO: u64 = 0x4;
+pub const SA_RESTORER: u64 = 0x0400_0000;
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+// How `sigprocmask` changes the blocked signals
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+const SIG_DFL: u64 = 0;
+const SIG_IGN: u64 = 1;
+
+// A set of signals, with bit `sig - 1` for each
+pub type SigSet = u64;
+
+pub const fn sigmask(sig: i32) -> SigSet {
+    1 << (sig - 1)
+}
+
+// Layout of Linux' `struct kernel_sigaction` on x86-64
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SigAction {
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    pub mask: SigSet,
+}
+
+// What to do with a signal
+#[derive(Debug, Copy, Clone)]
+pub enum Handler {
+    Default,
+    Ignore,
+    // Called with the signal number. It also gets pointers to the siginfo and
+    // the interrupted context, which a handler taking them can declare.
+    Handler(extern "C" fn(i32)),
+}
+
+impl SigAction {
+    // `handler` with the restorer below, blocking the signals in `mask` while
+    // it runs
+    pub fn new(handler: Handler, flags: u64, mask: SigSet) -> SigAction {
+        let handler = match handler {
+            Handler::Default => SIG_DFL,
+            Handler::Ignore => SIG_IGN,
+            Handler::Handler(handler) => handler as *const () as u64,
+        };
+        SigAction {
+            handler,
+            flags: flags | SA_SIGINFO | SA_RESTORER,
+            restorer: rustyos_restore_rt as *const () as u64,
+            mask,
+        }
+    }
+}
+
+// Where handlers return to, with the signal frame at the stack pointer
+extern "C" {
+    fn rustyos_restore_rt();
+}
+
+global_asm!(
+    ".global rustyos_restore_rt",
+    "rustyos_restore_rt:",
+    "mov eax, {sigreturn}",
+    "syscall",
+    "ud2",
+    sigreturn = const SYS_SIGRETURN,
+);
+
+// Install `action` for `sig`, returning the previous one
+pub fn sigaction(sig: i32, action: &SigAction) -> Result<SigAction> {
+    let mut old = SigAction::default();
+    let ret = unsafe {
+        syscall3(
+            SYS_SIGACTION,
+            sig as u64,
+            action as *const SigAction as u64,
+            &mut old as *mut SigAction as u64,
+        )
+    };
+    result(ret).map(|_| old)
+}
+
+// Handle `sig` with `handler`, like `signal` in C
+pub fn signal(sig: i32, handler: Handler) -> Result<SigAction> {
+    sigaction(sig, &SigAction::new(handler, 0, 0))
+}
+
+// Change the blocked signals with `set` like `how` says, returning the ones
+// blocked before
+pub fn sigprocmask(how: u64, set: SigSet) -> Result<SigSet> {
+    let mut old: SigSet = 0;
+    let ret = unsafe {
+        syscall3(
+            SYS_SIGPROCMASK,
+            how,
+            &set as *const SigSet as u64,
+            &mut old as *mut SigSet as u64,
+        )
+    };
+    result(ret).map(|_| old)
+}
+
+// The signals blocked now
+pub fn blocked() -> Result<SigSet> {
+    let mut old: SigSet = 0;
+    let ret = unsafe {
+        syscall3(
+            SYS_SIGPROCMASK,
+            SIG_BLOCK,
+            0,
+            &mut old as *mut SigSet as u64,
+        )
+    };
+    result(ret).map(|_| old)
+}
+
+pub fn kill(pid: Pid, sig: i32) -> Result<()> {
+    result(unsafe { syscall2(SYS_KILL, pid, sig as u64) }).map(drop)
+}
+
+// Send `sig` to the calling process
+pub fn raise(sig: i32) -> Result<()> {
+    kill(crate::process::getpid(), sig)
+}
@@ -0,0 +1,171 @@
+use core::arch::asm;
+use core::fmt;
+
+// System call numbers, the same as in the kernel's syscall.rs
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_YIELD: u64 = 2;
+pub const SYS_GETTID: u64 = 3;
+pub const SYS_GETPID: u64 = 4;
+pub const SYS_GETPPID: u64 = 5;
+pub const SYS_FORK: u64 = 6;
+pub const SYS_EXECVE: u64 = 7;
+pub const SYS_WAIT: u64 = 8;
+pub const SYS_SIGACTION: u64 = 9;
+pub const SYS_SIGPROCMASK: u64 = 10;
+pub const SYS_KILL: u64 = 11;
+pub const SYS_SIGRETURN: u64 = 12;
+pub const SYS_READ: u64 = 13;
+pub const SYS_OPEN: u64 = 14;
+pub const SYS_CLOSE: u64 = 15;
+pub const SYS_DUP: u64 = 16;
+pub const SYS_DUP2: u64 = 17;
+pub const SYS_PIPE: u64 = 18;
+pub const SYS_BRK: u64 = 19;
+pub const SYS_SLEEP: u64 = 20;
+pub const SYS_CLOCK: u64 = 21;
+
+// An error number a system call failed with, with the values Linux uses
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Errno(pub i32);
+
+impl Errno {
+    pub const EPERM: Errno = Errno(1);
+    pub const ENOENT: Errno = Errno(2);
+    pub const ESRCH: Errno = Errno(3);
+    pub const EINTR: Errno = Errno(4);
+    pub const E2BIG: Errno = Errno(7);
+    pub const ENOEXEC: Errno = Errno(8);
+    pub const EBADF: Errno = Errno(9);
+    pub const ECHILD: Errno = Errno(10);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EINVAL: Errno = Errno(22);
+    pub const EMFILE: Errno = Errno(24);
+    pub const EPIPE: Errno = Errno(32);
+    pub const ENAMETOOLONG: Errno = Errno(36);
+    pub const ENOSYS: Errno = Errno(38);
+
+    pub fn name(self) -> Option<&'static str> {
+        Some(match self {
+            Errno::EPERM => "EPERM",
+            Errno::ENOENT => "ENOENT",
+            Errno::ESRCH => "ESRCH",
+            Errno::EINTR => "EINTR",
+            Errno::E2BIG => "E2BIG",
+            Errno::ENOEXEC => "ENOEXEC",
+            Errno::EBADF => "EBADF",
+            Errno::ECHILD => "ECHILD",
+            Errno::ENOMEM => "ENOMEM",
+            Errno::EFAULT => "EFAULT",
+            Errno::EINVAL => "EINVAL",
+            Errno::EMFILE => "EMFILE",
+            Errno::EPIPE => "EPIPE",
+            Errno::ENAMETOOLONG => "ENAMETOOLONG",
+            Errno::ENOSYS => "ENOSYS",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Debug for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "Errno({})", self.0),
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Errno>;
+
+// The kernel returns errors as -errno, which no successful call returns
+pub fn result(value: u64) -> Result<u64> {
+    match value as i64 {
+        -4095..=-1 => Err(Errno(-(value as i64) as i32)),
+        _ => Ok(value),
+    }
+}
+
+// The number goes in RAX and the arguments in RDI, RSI, RDX, R10, R8 and R9,
+// like on Linux. SYSCALL clobbers RCX and R11. Callers make sure that the
+// arguments are valid for the call, pointers in particular.
+#[allow(clippy::missing_safety_doc)]
+#[inline(always)]
+pub unsafe fn syscall0(number: u64) -> u64 {
+    let ret;
+    asm!("syscall", inlateout("rax") number => ret, out("rcx") _, out("r11") _, options(nostack));
+    ret
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[inline(always)]
+pub unsafe fn syscall1(number: u64, arg0: u64) -> u64 {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") number => ret,
+        in("rdi") arg0,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[inline(always)]
+pub unsafe fn syscall2(number: u64, arg0: u64, arg1: u64) -> u64 {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") number => ret,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[inline(always)]
+pub unsafe fn syscall3(number: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") number => ret,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[inline(always)]
+pub unsafe fn syscall4(number: u64, arg0: u64, arg1: u64, arg2: u64, arg3: u64) -> u64 {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") number => ret,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+        in("r10") arg3,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    ret
+}
@@ -0,0 +1,14 @@
+use crate::syscall::*;
+use core::time::Duration;
+
+// Block for at least `duration`. Fails with EINTR if a signal handler ran
+// before it was over.
+pub fn sleep(duration: Duration) -> Result<()> {
+    let nanos = duration.as_nanos().min(u64::MAX as u128) as u64;
+    result(unsafe { syscall1(SYS_SLEEP, nanos) }).map(drop)
+}
+
+// Time since boot
+pub fn uptime() -> Duration {
+    Duration::from_nanos(unsafe { syscall0(SYS_CLOCK) })
+}
@@ -0,0 +1,26 @@
+#![no_std]
+#![no_main]
+
+use librustyos::{env, print};
+
+librustyos::entry!(main);
+
+// Print the arguments separated by spaces, without the newline after -n
+fn main() {
+    let mut args = env::args().skip(1).peekable();
+    let newline = args.peek() != Some(&"-n");
+    if !newline {
+        args.next();
+    }
+    let mut first = true;
+    for arg in args {
+        if !first {
+            print!(" ");
+        }
+        print!("{}", arg);
+        first = false;
+    }
+    if newline {
+        print!("\n");
+    }
+}
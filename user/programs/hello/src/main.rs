@@ -0,0 +1,39 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use librustyos::{env, println, process};
+
+librustyos::entry!(main);
+
+fn main() {
+    println!(
+        "Hello from user mode, pid {} (parent {})",
+        process::getpid(),
+        process::getppid()
+    );
+    for (i, arg) in env::args().enumerate() {
+        println!("argv[{}] = {}", i, arg);
+    }
+    for (name, value) in env::vars() {
+        println!("{}={}", name, value);
+    }
+
+    // Enough to grow the heap a few times
+    let squares: Vec<u64> = (0..50_000).map(|i| i * i).collect();
+    let sum: u64 = squares.iter().sum();
+    let mut words = String::new();
+    for word in ["heap", "allocated", "strings"].iter() {
+        words.push_str(word);
+        words.push(' ');
+    }
+    println!(
+        "{}and {} squares adding up to {}",
+        words,
+        squares.len(),
+        sum
+    );
+}
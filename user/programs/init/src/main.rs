@@ -0,0 +1,286 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::time::Duration;
+use librustyos::process::{self, ExitStatus, Pid};
+use librustyos::{env, eprintln, fs, println, time, Errno, Result};
+
+librustyos::entry!(main);
+
+const CONFIG: &str = "/etc/init.conf";
+
+// Environment of every service
+const ENV: [&str; 1] = ["PATH=/bin:/sbin"];
+
+// Wait before the first restart, doubled for each failure in a row
+const BACKOFF_MIN: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+// A service running this long counts as started, resetting its backoff
+const STABLE: Duration = Duration::from_secs(10);
+
+// When a service is started again after exiting
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Restart {
+    No,
+    OnFailure,
+    Always,
+}
+
+struct Service {
+    name: String,
+    argv: Vec<String>,
+    // Services started before this one
+    after: Vec<String>,
+    restart: Restart,
+    pid: Option<Pid>,
+    started: Duration,
+    // Restarts in a row without running for STABLE
+    failures: u32,
+    // When to start the service again
+    start_at: Option<Duration>,
+}
+
+impl Service {
+    fn new(name: &str) -> Service {
+        Service {
+            name: name.to_string(),
+            argv: Vec::new(),
+            after: Vec::new(),
+            restart: Restart::No,
+            pid: None,
+            started: Duration::ZERO,
+            failures: 0,
+            start_at: None,
+        }
+    }
+}
+
+// Start the services in /etc/init.conf and keep them running. As PID 1 init
+// also gets the orphans of exiting processes, and reaps them. Given another
+// configuration file as its argument it runs as any process, to try it out.
+fn main() -> Result<()> {
+    let config = match env::args().nth(1) {
+        Some(config) => config,
+        None if process::getpid() != 1 => {
+            eprintln!("init: must run as PID 1");
+            return Err(Errno::EPERM);
+        }
+        None => CONFIG,
+    };
+
+    let mut services = match read(config) {
+        Ok(contents) => parse(config, &contents),
+        Err(error) => {
+            eprintln!("init: can't read {}: {}", config, error);
+            Vec::new()
+        }
+    };
+    for i in start_order(&services) {
+        start(&mut services[i]);
+    }
+    supervise(&mut services)
+}
+
+fn read(path: &str) -> Result<String> {
+    let fd = fs::open(path, fs::O_RDONLY)?;
+    let mut contents = Vec::new();
+    let mut buffer = [0u8; 512];
+    let result = loop {
+        match fs::read(fd, &mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(n) => contents.extend_from_slice(&buffer[..n]),
+            Err(Errno::EINTR) => continue,
+            Err(error) => break Err(error),
+        }
+    };
+    fs::close(fd)?;
+    result?;
+    String::from_utf8(contents).map_err(|_| Errno::EINVAL)
+}
+
+// Parse the configuration file `path`: a `service NAME` line followed by indented
+// `exec PATH ARGS...`, `after SERVICES...` and `restart no|on-failure|always`
+// lines for each service. Services with errors are reported and left out.
+fn parse(path: &str, config: &str) -> Vec<Service> {
+    let mut services: Vec<Service> = Vec::new();
+    // Indices into `services` of those with errors
+    let mut broken = Vec::new();
+    for (i, line) in config.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        let key = match words.next() {
+            Some(key) => key,
+            None => continue,
+        };
+        let error = if !line.starts_with(char::is_whitespace) {
+            // A bad service line still starts a service, so its settings
+            // don't end up in the previous one
+            let name = match (key, words.next(), words.next()) {
+                ("service", Some(name), None) => name,
+                _ => "",
+            };
+            let duplicate = services.iter().any(|s| s.name == name);
+            services.push(Service::new(name));
+            match name {
+                "" => Some("expected `service NAME`"),
+                _ if duplicate => Some("duplicate service"),
+                _ => None,
+            }
+        } else if let Some(service) = services.last_mut() {
+            setting(service, key, words)
+        } else {
+            Some("setting outside of a service")
+        };
+        if let Some(error) = error {
+            eprintln!("init: {}:{}: {}", path, i + 1, error);
+            if let Some(last) = services.len().checked_sub(1) {
+                broken.push(last);
+            }
+        }
+    }
+    for (i, service) in services.iter().enumerate() {
+        if service.argv.is_empty() && !broken.contains(&i) {
+            eprintln!("init: {}: service {} has no exec", path, service.name);
+            broken.push(i);
+        }
+    }
+    services
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !broken.contains(i))
+        .map(|(_, service)| service)
+        .collect()
+}
+
+fn setting<'a>(
+    service: &mut Service,
+    key: &str,
+    mut words: impl Iterator<Item = &'a str>,
+) -> Option<&'static str> {
+    match key {
+        "exec" => service.argv = words.map(String::from).collect(),
+        "after" => service.after.extend(words.map(String::from)),
+        "restart" => {
+            service.restart = match (words.next(), words.next()) {
+                (Some("no"), None) => Restart::No,
+                (Some("on-failure"), None) => Restart::OnFailure,
+                (Some("always"), None) => Restart::Always,
+                _ => return Some("expected `restart no|on-failure|always`"),
+            }
+        }
+        _ => return Some("unknown setting"),
+    }
+    None
+}
+
+// Indices of the services with each one after those it names in `after`,
+// otherwise in configuration order. Services needing an unknown service or
+// part of a cycle are reported and left out.
+fn start_order(services: &[Service]) -> Vec<usize> {
+    let index = |name: &String| services.iter().position(|s| &s.name == name);
+    let mut placed = alloc::vec![false; services.len()];
+    let mut order = Vec::new();
+    while let Some(i) = (0..services.len()).find(|&i| {
+        !placed[i]
+            && services[i]
+                .after
+                .iter()
+                .all(|dep| index(dep).is_some_and(|d| placed[d]))
+    }) {
+        placed[i] = true;
+        order.push(i);
+    }
+    for (service, _) in services.iter().zip(placed).filter(|(_, placed)| !placed) {
+        eprintln!(
+            "init: not starting {}: it needs a service that is unknown or in a cycle",
+            service.name
+        );
+    }
+    order
+}
+
+fn start(service: &mut Service) {
+    service.start_at = None;
+    println!("init: starting {}", service.name);
+    match process::fork() {
+        Ok(0) => {
+            let argv: Vec<&str> = service.argv.iter().map(String::as_str).collect();
+            let error = process::execve(argv[0], &argv, &ENV);
+            eprintln!("init: can't run {}: {}", argv[0], error);
+            process::exit(127);
+        }
+        Ok(pid) => {
+            service.pid = Some(pid);
+            service.started = time::uptime();
+        }
+        Err(error) => {
+            eprintln!("init: can't start {}: {}", service.name, error);
+            schedule(service);
+        }
+    }
+}
+
+// Start `service` again after its backoff
+fn schedule(service: &mut Service) {
+    let delay = BACKOFF_MAX.min(BACKOFF_MIN * (1 << service.failures.min(16)));
+    service.failures = service.failures.saturating_add(1);
+    service.start_at = Some(time::uptime() + delay);
+    println!(
+        "init: restarting {} in {} ms",
+        service.name,
+        delay.as_millis()
+    );
+}
+
+fn exited(services: &mut [Service], pid: Pid, status: ExitStatus) {
+    // Anything else was an orphan, which only needed reaping
+    let service = match services.iter_mut().find(|s| s.pid == Some(pid)) {
+        Some(service) => service,
+        None => return,
+    };
+    service.pid = None;
+    println!("init: {} ({:?})", service.name, status);
+    let restart = match service.restart {
+        Restart::No => false,
+        Restart::OnFailure => !status.success(),
+        Restart::Always => true,
+    };
+    if restart {
+        if time::uptime() - service.started >= STABLE {
+            service.failures = 0;
+        }
+        schedule(service);
+    }
+}
+
+// Reap exited children and restart services when they are due. Waiting with a
+// timeout checks for exited children and sleeps in one system call, so none
+// can exit in between unnoticed.
+fn supervise(services: &mut [Service]) -> ! {
+    loop {
+        let now = time::uptime();
+        for service in services.iter_mut() {
+            if service.start_at.is_some_and(|at| at <= now) {
+                start(service);
+            }
+        }
+
+        let timeout = match services.iter().filter_map(|s| s.start_at).min() {
+            Some(at) => at.saturating_sub(time::uptime()),
+            None => Duration::MAX,
+        };
+        match process::wait_timeout(None, timeout) {
+            Ok(Some((pid, status))) => exited(services, pid, status),
+            Ok(None) | Err(Errno::EINTR) => {}
+            Err(error) => {
+                eprintln!("init: wait failed: {}", error);
+                let _ = time::sleep(BACKOFF_MIN);
+            }
+        }
+    }
+}
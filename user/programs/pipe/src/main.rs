@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use librustyos::{eprintln, fs, io, println, process, Errno, Result};
+
+librustyos::entry!(main);
+
+// Run echo with its output going through a pipe, and print what it wrote
+fn main() -> Result<()> {
+    let (reader, writer) = fs::pipe()?;
+    let child = process::fork()?;
+    if child == 0 {
+        fs::close(reader)?;
+        fs::dup2(writer, io::STDOUT)?;
+        fs::close(writer)?;
+        let argv = ["echo", "hello", "through", "a", "pipe"];
+        let error = process::execve("/bin/echo", &argv, &[]);
+        eprintln!("pipe: can't run /bin/echo: {}", error);
+        process::exit(127);
+    }
+
+    // Only the child may write now, so the pipe ends when it exits
+    fs::close(writer)?;
+    let mut output = Vec::new();
+    let mut buffer = [0u8; 256];
+    loop {
+        match fs::read(reader, &mut buffer) {
+            Ok(0) => break,
+            Ok(n) => output.extend_from_slice(&buffer[..n]),
+            Err(Errno::EINTR) => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    fs::close(reader)?;
+
+    let (pid, status) = process::wait(Some(child))?;
+    let output = String::from_utf8_lossy(&output);
+    println!("child {} ({:?}) wrote: {}", pid, status, output.trim_end());
+    match status.success() {
+        true => Ok(()),
+        false => Err(Errno::ECHILD),
+    }
+}
@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use librustyos::signal::{self, sigmask, Handler, SIGCHLD, SIGUSR1, SIG_BLOCK, SIG_SETMASK};
+use librustyos::{println, process, Errno, Result};
+
+librustyos::entry!(main);
+
+static USR1_COUNT: AtomicUsize = AtomicUsize::new(0);
+static CHLD_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_usr1(_sig: i32) {
+    USR1_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+extern "C" fn on_chld(_sig: i32) {
+    CHLD_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+fn main() -> Result<()> {
+    // Handled before kill returns
+    signal::signal(SIGUSR1, Handler::Handler(on_usr1))?;
+    for _ in 0..3 {
+        signal::raise(SIGUSR1)?;
+    }
+    assert_eq!(USR1_COUNT.load(Ordering::Relaxed), 3);
+
+    // Pending while blocked, and delivered once when unblocked
+    let old = signal::sigprocmask(SIG_BLOCK, sigmask(SIGUSR1))?;
+    signal::raise(SIGUSR1)?;
+    signal::raise(SIGUSR1)?;
+    assert_eq!(USR1_COUNT.load(Ordering::Relaxed), 3);
+    signal::sigprocmask(SIG_SETMASK, old)?;
+    assert_eq!(USR1_COUNT.load(Ordering::Relaxed), 4);
+    println!(
+        "SIGUSR1 handled {} times",
+        USR1_COUNT.load(Ordering::Relaxed)
+    );
+
+    // The parent hears of its child's exit
+    signal::signal(SIGCHLD, Handler::Handler(on_chld))?;
+    let child = process::fork()?;
+    if child == 0 {
+        process::exit(7);
+    }
+    let (_, status) = loop {
+        match process::wait(Some(child)) {
+            Err(Errno::EINTR) => continue,
+            result => break result?,
+        }
+    };
+    assert_eq!(status.code(), Some(7));
+    assert_eq!(CHLD_COUNT.load(Ordering::Relaxed), 1);
+    println!(
+        "SIGCHLD handled after child {} exited ({:?})",
+        child, status
+    );
+    Ok(())
+}